@@ -0,0 +1,64 @@
+# Compare --target=aarch64-linux-gnu output with shell-tests/golden-aarch64/<example>.<level>.s
+# Set UPDATE=1 to regenerate golden files. Where qemu-aarch64 and a cross
+# compiler exist, also compare exit codes with the IR interpreter (--run).
+# Programs that fail in the interpreter, like a division by zero, are skipped.
+dir="$(cd $(dirname $0); pwd)"
+golden="$dir/golden-aarch64"
+cc="${AARCH64_CC:-aarch64-linux-gnu-gcc}"
+status=0
+tmp="$(mktemp -d)"
+
+for expected in "$golden"/*.s
+do
+  name="$(basename "$expected" .s)"
+  level="${name##*.}"
+  example="./examples/${name%.*}"
+  actual="$(cargo run -q -- --target=aarch64-linux-gnu "-$level" "$example")"
+  if [ -n "$UPDATE" ]
+  then
+    echo "$actual" > "$expected"
+  elif [ "$actual" = "$(cat "$expected")" ]
+  then
+    echo "OK: $name"
+  else
+    echo "ERROR: $name differs from golden file"
+    status=1
+  fi
+done
+
+if ! command -v qemu-aarch64 > /dev/null || ! command -v "$cc" > /dev/null
+then
+  echo "SKIP: aarch64 execution needs qemu-aarch64 and $cc"
+  rm -r "$tmp"
+  exit $status
+fi
+
+for program in ./examples/*.c ./shell-tests/programs/*.c ./shell-tests/programs/*.fir
+do
+  name="$(basename "$program")"
+  case "$program" in
+    *.fir) flags="--from-ir --passes=" ;;
+    *) flags="-O2" ;;
+  esac
+  cargo run -q -- --run $flags "$program" 2> "$tmp/run.err"
+  expected="$?"
+  if grep -q "runtime error" "$tmp/run.err"
+  then
+    echo "SKIP: $name: runtime error in the interpreter"
+    continue
+  fi
+  cargo run -q -- --target=aarch64-linux-gnu $flags "$program" > "$tmp/$name.s" &&
+    "$cc" -static -o "$tmp/a.out" "$tmp/$name.s" || { status=1; continue; }
+  qemu-aarch64 "$tmp/a.out"
+  actual="$?"
+  if [ "$expected" = "$actual" ]
+  then
+    echo "OK: $name aarch64"
+  else
+    echo "ERROR: $name: interpreter $expected, aarch64 $actual"
+    status=1
+  fi
+done
+
+rm -r "$tmp"
+exit $status
@@ -0,0 +1,47 @@
+# Build the --emit=c output of every program with the system C compiler at each
+# level and compare the exit code with the IR interpreter (--run).
+# Programs that fail in the interpreter, like a division by zero, are skipped.
+dir="$(cd $(dirname $0); pwd)"
+cc="${CC:-cc}"
+status=0
+tmp="$(mktemp -d)"
+
+if ! command -v "$cc" > /dev/null
+then
+  echo "SKIP: C execution needs $cc"
+  rm -r "$tmp"
+  exit 0
+fi
+
+for program in ./examples/*.c ./shell-tests/programs/*.c ./shell-tests/programs/*.fir
+do
+  name="$(basename "$program" .c)"
+  case "$program" in
+    *.fir) levels="--passes= --passes=mem2reg" ; base="--from-ir" ;;
+    *) levels="-O0 -O1 -O2 --passes=mem2reg" ; base="" ;;
+  esac
+  for level in $levels
+  do
+    cargo run -q -- --run $base $level "$program" 2> "$tmp/run.err"
+    expected="$?"
+    if grep -q "runtime error" "$tmp/run.err"
+    then
+      echo "SKIP: $name $level: runtime error in the interpreter"
+      continue
+    fi
+    cargo run -q -- --emit=c $base $level "$program" > "$tmp/$name.c" &&
+      "$cc" -std=c99 -Wall -Werror -o "$tmp/a.out" "$tmp/$name.c" || { status=1; continue; }
+    "$tmp/a.out"
+    actual="$?"
+    if [ "$expected" = "$actual" ]
+    then
+      echo "OK: $name c $level"
+    else
+      echo "ERROR: $name $level: interpreter $expected, C $actual"
+      status=1
+    fi
+  done
+done
+
+rm -r "$tmp"
+exit $status
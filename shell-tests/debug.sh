@@ -0,0 +1,58 @@
+# Assemble the output of -g for every program, check the DWARF with readelf and
+# that the code still runs like --run. Set CC to choose the assembler driver.
+# Programs that fail in the interpreter, like a division by zero, are skipped.
+dir="$(cd $(dirname $0); pwd)"
+cc="${CC:-cc}"
+status=0
+tmp="$(mktemp -d)"
+
+if ! command -v "$cc" > /dev/null || ! command -v readelf > /dev/null
+then
+  echo "SKIP: debug info needs $cc and readelf"
+  rm -r "$tmp"
+  exit 0
+fi
+
+for program in ./examples/*.c ./shell-tests/programs/*.c
+do
+  name="$(basename "$program" .c)"
+  for level in -O0 -O2
+  do
+    cargo run -q -- --run $level "$program" 2> "$tmp/run.err"
+    expected="$?"
+    if grep -q "runtime error" "$tmp/run.err"
+    then
+      echo "SKIP: $name $level: runtime error in the interpreter"
+      continue
+    fi
+    cargo run -q -- -g $level "$program" > "$tmp/$name.s" || { status=1; continue; }
+    "$cc" -c -o "$tmp/$name.o" "$tmp/$name.s" || { status=1; continue; }
+    readelf --debug-dump=info "$tmp/$name.o" > "$tmp/info"
+    readelf --debug-dump=decodedline "$tmp/$name.o" > "$tmp/line"
+    if ! grep -q "DW_TAG_subprogram" "$tmp/info" || ! grep -q "DW_AT_name *: main" "$tmp/info"
+    then
+      echo "ERROR: $name $level: no DW_TAG_subprogram for main"
+      status=1
+      continue
+    fi
+    if ! grep -q "^$name.c  *[0-9]" "$tmp/line"
+    then
+      echo "ERROR: $name $level: empty line table"
+      status=1
+      continue
+    fi
+    "$cc" -o "$tmp/a.out" "$tmp/$name.o" 2> /dev/null || { status=1; continue; }
+    "$tmp/a.out"
+    actual="$?"
+    if [ "$expected" = "$actual" ]
+    then
+      echo "OK: $name debug $level"
+    else
+      echo "ERROR: $name $level: interpreter $expected, -g $actual"
+      status=1
+    fi
+  done
+done
+
+rm -r "$tmp"
+exit $status
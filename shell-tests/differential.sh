@@ -0,0 +1,47 @@
+# Compare exit codes of programs built by the x86_64 backend with the IR interpreter (--run).
+# Programs in textual IR (.fir) skip the optimizer without -O so that registers run out.
+# Programs that fail in the interpreter, like a division by zero, are skipped.
+cc="${CC:-cc}"
+status=0
+tmp="$(mktemp -d)"
+
+check() {
+  name="$1"
+  shift
+  cargo run -q -- --run "$@" 2> "$tmp/run.err"
+  expected="$?"
+  if grep -q "runtime error" "$tmp/run.err"
+  then
+    echo "SKIP: $name: runtime error in the interpreter"
+    return
+  fi
+  cargo run -q -- "$@" > "$tmp/$name.s" && "$cc" -o "$tmp/a.out" "$tmp/$name.s" || { status=1; return; }
+  "$tmp/a.out"
+  actual="$?"
+  if [ "$expected" = "$actual" ]
+  then
+    echo "OK: $name"
+  else
+    echo "ERROR: $name: interpreter $expected, x86_64 $actual"
+    status=1
+  fi
+}
+
+for program in ./examples/*.c ./shell-tests/programs/*.c
+do
+  for level in 0 1 2
+  do
+    check "$(basename "$program") -O$level" "-O$level" "$program"
+  done
+  check "$(basename "$program") -O2 -masm=att" -O2 -masm=att "$program"
+done
+
+for program in ./shell-tests/programs/*.fir
+do
+  check "$(basename "$program")" --from-ir "$program"
+  check "$(basename "$program") regalloc" --from-ir --passes= "$program"
+  check "$(basename "$program") regalloc -masm=att" --from-ir --passes= -masm=att "$program"
+done
+
+rm -r "$tmp"
+exit $status
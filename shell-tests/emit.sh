@@ -0,0 +1,25 @@
+# Compare --emit output of each example with shell-tests/golden/<example>.<kind>
+# Set UPDATE=1 to regenerate golden files.
+dir="$(cd $(dirname $0); pwd)"
+golden="$dir/golden"
+status=0
+
+for expected in "$golden"/*
+do
+  name="$(basename "$expected")"
+  kind="${name##*.}"
+  example="./examples/${name%.*}"
+  actual="$(cargo run -q -- --emit="$kind" "$example")"
+  if [ -n "$UPDATE" ]
+  then
+    echo "$actual" > "$expected"
+  elif [ "$actual" = "$(cat "$expected")" ]
+  then
+    echo "OK: $name"
+  else
+    echo "ERROR: $name differs from golden file"
+    status=1
+  fi
+done
+
+exit $status
@@ -0,0 +1,19 @@
+# Compiling C and compiling its emitted IR with --from-ir must give the same assembly.
+status=0
+tmp="$(mktemp -d)"
+
+for example in ./examples/*.c
+do
+  name="$(basename "$example")"
+  cargo run -q -- --emit=ir "$example" > "$tmp/$name.fir"
+  if [ "$(cargo run -q -- "$example")" = "$(cargo run -q -- --from-ir "$tmp/$name.fir")" ]
+  then
+    echo "OK: $name"
+  else
+    echo "ERROR: $name --from-ir output differs"
+    status=1
+  fi
+done
+
+rm -r "$tmp"
+exit $status
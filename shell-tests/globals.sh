@@ -0,0 +1,55 @@
+# Build the programs with global variables as assembly, as an object with -c,
+# as C and as LLVM IR, and compare the exit codes with the IR interpreter
+# (--run). Only x86_64 supports globals, so these aren't in programs/.
+cc="${CC:-cc}"
+status=0
+tmp="$(mktemp -d)"
+
+if ! command -v "$cc" > /dev/null
+then
+  echo "SKIP: globals need $cc"
+  rm -r "$tmp"
+  exit 0
+fi
+
+# check LABEL COMMAND... runs the command and compares its exit code.
+check() {
+  label="$1"
+  shift
+  "$@"
+  actual="$?"
+  if [ "$expected" = "$actual" ]
+  then
+    echo "OK: $label"
+  else
+    echo "ERROR: $label: interpreter $expected, got $actual"
+    status=1
+  fi
+}
+
+for program in ./shell-tests/globals/*.c
+do
+  name="$(basename "$program" .c)"
+  for level in -O0 -O1 -O2
+  do
+    cargo run -q -- --run $level "$program"
+    expected="$?"
+    cargo run -q -- $level "$program" > "$tmp/$name.s" &&
+      "$cc" -o "$tmp/a.out" "$tmp/$name.s" &&
+      check "$name asm $level" "$tmp/a.out" || status=1
+    cargo run -q -- -c $level -o "$tmp/$name.o" "$program" &&
+      "$cc" -o "$tmp/a.out" "$tmp/$name.o" &&
+      check "$name object $level" "$tmp/a.out" || status=1
+    cargo run -q -- --emit=c $level "$program" > "$tmp/$name.c" &&
+      "$cc" -std=c99 -Wall -Werror -o "$tmp/a.out" "$tmp/$name.c" &&
+      check "$name c $level" "$tmp/a.out" || status=1
+    if command -v lli > /dev/null
+    then
+      cargo run -q -- --emit=llvm $level "$program" > "$tmp/$name.ll" &&
+        check "$name llvm $level" lli "$tmp/$name.ll" || status=1
+    fi
+  done
+done
+
+rm -r "$tmp"
+exit $status
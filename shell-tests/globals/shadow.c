@@ -0,0 +1,10 @@
+int x = 1;
+long y;
+long *a = &y + 3, *b = &y - 1;
+char *s = "\tquote\"" + 2;
+{
+  static int x = 5;
+  x = x + 1;
+  { static long x; x = 7; y = a - b; y = y + x * 2; }
+  return x + y;
+}
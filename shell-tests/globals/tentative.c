@@ -0,0 +1,13 @@
+int t;
+int t = 3;
+extern int t;
+static long s;
+static long s;
+{
+  extern int t;
+  static int u = 4;
+  t = t + u;
+  { extern long s; s = 2; }
+}
+int t;
+return t * s;
@@ -0,0 +1,13 @@
+typedef long T;
+typedef T *P;
+T x = 3;
+P p = &x;
+T y = 2;
+{
+  static int T = 5;
+  T * y;
+  y = T * y + (char)300 + sizeof(P) + sizeof x + sizeof(char) + (y);
+}
+typedef char byte;
+static byte b = (byte)(1 + 2) * 10;
+return y + b + (int)(long)(p - p) + sizeof (T);
@@ -0,0 +1,10 @@
+int counter;
+static char small = 300;
+long big = 5000000000 / 1000000000 * 2;
+int *p = &counter + 2, *q = &counter;
+char *msg = "hi\n";
+long d;
+{ static int calls = 3; calls = calls + 1; counter = calls * 10; }
+small = small + 1000;
+d = p - q;
+return counter + small + big + d - 40;
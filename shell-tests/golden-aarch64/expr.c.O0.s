@@ -0,0 +1,17 @@
+.text
+.global main
+main:
+	stp x29, x30, [sp, #-16]!
+	mov x29, sp
+	movz x16, #0
+	add x9, x16, #1
+	add x9, x9, #2
+	movz x16, #3
+	add x9, x16, #5
+	movz x16, #1
+	sdiv x10, x16, xzr
+	mul x10, x10, xzr
+	sub x9, x9, x10
+	mov x0, x9
+	ldp x29, x30, [sp], #16
+	ret
@@ -0,0 +1,13 @@
+.text
+.global main
+main:
+	stp x29, x30, [sp, #-16]!
+	mov x29, sp
+	movz x16, #1
+	sdiv x9, x16, xzr
+	mul x9, x9, xzr
+	movz x16, #8
+	sub x9, x16, x9
+	mov x0, x9
+	ldp x29, x30, [sp], #16
+	ret
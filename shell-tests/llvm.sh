@@ -0,0 +1,45 @@
+# Run the --emit=llvm module of every program with lli at each level and
+# compare the exit code with the IR interpreter (--run).
+# Programs that fail in the interpreter, like a division by zero, are skipped.
+dir="$(cd $(dirname $0); pwd)"
+status=0
+tmp="$(mktemp -d)"
+
+if ! command -v lli > /dev/null
+then
+  echo "SKIP: LLVM IR execution needs lli"
+  rm -r "$tmp"
+  exit 0
+fi
+
+for program in ./examples/*.c ./shell-tests/programs/*.c ./shell-tests/programs/*.fir
+do
+  name="$(basename "$program")"
+  case "$program" in
+    *.fir) levels="--passes= --passes=mem2reg" ; base="--from-ir" ;;
+    *) levels="-O0 -O1 -O2 --passes=mem2reg" ; base="" ;;
+  esac
+  for level in $levels
+  do
+    cargo run -q -- --run $base $level "$program" 2> "$tmp/run.err"
+    expected="$?"
+    if grep -q "runtime error" "$tmp/run.err"
+    then
+      echo "SKIP: $name $level: runtime error in the interpreter"
+      continue
+    fi
+    cargo run -q -- --emit=llvm $base $level "$program" > "$tmp/$name.ll" || { status=1; continue; }
+    lli "$tmp/$name.ll"
+    actual="$?"
+    if [ "$expected" = "$actual" ]
+    then
+      echo "OK: $name llvm $level"
+    else
+      echo "ERROR: $name $level: interpreter $expected, lli $actual"
+      status=1
+    fi
+  done
+done
+
+rm -r "$tmp"
+exit $status
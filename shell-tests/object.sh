@@ -0,0 +1,56 @@
+# Write an object file with -c for every program, check it with readelf, link it
+# with the system compiler and compare the exit code with --run. Set CC to
+# choose the linker driver.
+# Programs that fail in the interpreter, like a division by zero, are skipped.
+dir="$(cd $(dirname $0); pwd)"
+cc="${CC:-cc}"
+status=0
+tmp="$(mktemp -d)"
+
+if ! command -v "$cc" > /dev/null || ! command -v readelf > /dev/null
+then
+  echo "SKIP: object files need $cc and readelf"
+  rm -r "$tmp"
+  exit 0
+fi
+
+for program in ./examples/*.c ./shell-tests/programs/*.c
+do
+  name="$(basename "$program" .c)"
+  for level in -O0 -O1 -O2
+  do
+    cargo run -q -- --run $level "$program" 2> "$tmp/run.err"
+    expected="$?"
+    if grep -q "runtime error" "$tmp/run.err"
+    then
+      echo "SKIP: $name $level: runtime error in the interpreter"
+      continue
+    fi
+    cargo run -q -- -c $level -o "$tmp/$name.o" "$program" || { status=1; continue; }
+    if ! readelf -h "$tmp/$name.o" | grep -q "REL (Relocatable file)"
+    then
+      echo "ERROR: $name $level: not a relocatable object"
+      status=1
+      continue
+    fi
+    "$cc" -o "$tmp/a.out" "$tmp/$name.o" 2> "$tmp/ld.err" || { cat "$tmp/ld.err"; status=1; continue; }
+    if [ -s "$tmp/ld.err" ]
+    then
+      echo "ERROR: $name $level: linker warnings"
+      cat "$tmp/ld.err"
+      status=1
+    fi
+    "$tmp/a.out"
+    actual="$?"
+    if [ "$expected" = "$actual" ]
+    then
+      echo "OK: $name object $level"
+    else
+      echo "ERROR: $name $level: interpreter $expected, object $actual"
+      status=1
+    fi
+  done
+done
+
+rm -r "$tmp"
+exit $status
@@ -0,0 +1,77 @@
+# Link position-independent output as a PIE and as a shared library. The
+# program reads `base`, an extern int that the driver defines as 30.
+dir="$(cd $(dirname $0); pwd)"
+cc="${CC:-cc}"
+status=0
+tmp="$(mktemp -d)"
+expected=36
+
+if ! command -v "$cc" > /dev/null
+then
+  echo "SKIP: position-independent code needs $cc"
+  rm -r "$tmp"
+  exit 0
+fi
+
+echo "int base = 30;" > "$tmp/base.c"
+cat > "$tmp/driver.c" <<END
+#include <dlfcn.h>
+#include <stdio.h>
+int base = 30;
+int main(int argc, char **argv) {
+  void *lib = dlopen(argv[1], RTLD_NOW);
+  if (!lib) {
+    fprintf(stderr, "%s\n", dlerror());
+    return 255;
+  }
+  return ((int (*)(void))dlsym(lib, "main"))();
+}
+END
+"$cc" -rdynamic -o "$tmp/driver" "$tmp/driver.c" -ldl || exit 1
+
+# check LABEL COMMAND... compares the exit code of the command with $expected.
+check() {
+  label="$1"
+  shift
+  "$@"
+  actual="$?"
+  if [ "$actual" = "$expected" ]
+  then
+    echo "OK: $label"
+  else
+    echo "ERROR: $label: expected $expected, got $actual"
+    status=1
+  fi
+}
+
+program="$dir/pic/counter.c"
+for level in -O0 -O2
+do
+  for model in -fPIE -fPIC
+  do
+    cargo run -q -- $model $level "$program" > "$tmp/pic.s" &&
+      "$cc" -pie -o "$tmp/a.out" "$tmp/pic.s" "$tmp/base.c" 2> /dev/null &&
+      check "pie asm $model $level" "$tmp/a.out" || status=1
+    cargo run -q -- -c $model $level -o "$tmp/pic.o" "$program" &&
+      "$cc" -pie -o "$tmp/a.out" "$tmp/pic.o" "$tmp/base.c" &&
+      check "pie object $model $level" "$tmp/a.out" || status=1
+  done
+  cargo run -q -- -c -fPIC $level -o "$tmp/pic.o" "$program" &&
+    "$cc" -shared -o "$tmp/libpic.so" "$tmp/pic.o" &&
+    check "shared object -fPIC $level" "$tmp/driver" "$tmp/libpic.so" || status=1
+  cargo run -q -- -fPIC $level "$program" > "$tmp/pic.s" &&
+    "$cc" -shared -o "$tmp/libpic.so" "$tmp/pic.s" 2> /dev/null &&
+    check "shared asm -fPIC $level" "$tmp/driver" "$tmp/libpic.so" || status=1
+  # Without -fPIC, the rip-relative access to preemptible globals can't link.
+  cargo run -q -- -c $level -o "$tmp/pic.o" "$program" || status=1
+  if "$cc" -shared -o "$tmp/libpic.so" "$tmp/pic.o" 2> /dev/null
+  then
+    echo "ERROR: shared object without -fPIC $level linked"
+    status=1
+  else
+    echo "OK: shared object without -fPIC $level"
+  fi
+done
+
+rm -r "$tmp"
+exit $status
@@ -0,0 +1,6 @@
+extern int base;
+int shared = 5;
+static char *name = "pic";
+int *self = &shared + 1;
+shared = shared + base;
+return shared + 1;
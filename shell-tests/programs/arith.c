@@ -0,0 +1,2 @@
+1 + 2 * 3 - 4 / 2;
+100 - 7 * 6 / 4;
@@ -0,0 +1,2 @@
+7 / 2 * 2;
+0 - 9 / 4 + 50;
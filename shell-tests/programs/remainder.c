@@ -0,0 +1,2 @@
+100 % 7 * 3;
+return 47 % 10 + 9 / 2 % 3;
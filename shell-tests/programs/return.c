@@ -0,0 +1,3 @@
+1 + 2;
+return 3 * 4 - 2;
+5;
@@ -0,0 +1,50 @@
+# Compare exit codes of --target=riscv64-linux-gnu programs run under
+# qemu-riscv64 with the IR interpreter (--run). Skipped without qemu-riscv64
+# and a cross compiler.
+# Programs that fail in the interpreter, like a division by zero, are skipped.
+cc="${RISCV64_CC:-riscv64-linux-gnu-gcc}"
+status=0
+
+if ! command -v qemu-riscv64 > /dev/null || ! command -v "$cc" > /dev/null
+then
+  echo "SKIP: riscv64 execution needs qemu-riscv64 and $cc"
+  exit $status
+fi
+
+tmp="$(mktemp -d)"
+for program in ./examples/*.c ./shell-tests/programs/*.c ./shell-tests/programs/*.fir
+do
+  name="$(basename "$program")"
+  case "$program" in
+    *.fir) levels="--passes=" ;;
+    *) levels="-O0 -O1 -O2" ;;
+  esac
+  for level in $levels
+  do
+    case "$program" in
+      *.fir) flags="--from-ir $level" ;;
+      *) flags="$level" ;;
+    esac
+    cargo run -q -- --run $flags "$program" 2> "$tmp/run.err"
+    expected="$?"
+    if grep -q "runtime error" "$tmp/run.err"
+    then
+      echo "SKIP: $name $level: runtime error in the interpreter"
+      continue
+    fi
+    cargo run -q -- --target=riscv64-linux-gnu $flags "$program" > "$tmp/$name.s" &&
+      "$cc" -static -o "$tmp/a.out" "$tmp/$name.s" || { status=1; continue; }
+    qemu-riscv64 "$tmp/a.out"
+    actual="$?"
+    if [ "$expected" = "$actual" ]
+    then
+      echo "OK: $name $level riscv64"
+    else
+      echo "ERROR: $name $level: interpreter $expected, riscv64 $actual"
+      status=1
+    fi
+  done
+done
+
+rm -r "$tmp"
+exit $status
@@ -0,0 +1,48 @@
+# Run the --emit=wasm module of every program with node at each level and
+# compare the result of main with the IR interpreter (--run, mod 256).
+# Programs that fail in the interpreter, like a division by zero, are skipped.
+dir="$(cd $(dirname $0); pwd)"
+status=0
+tmp="$(mktemp -d)"
+
+if ! command -v node > /dev/null
+then
+  echo "SKIP: wasm execution needs node"
+  rm -r "$tmp"
+  exit 0
+fi
+
+for program in ./examples/*.c ./shell-tests/programs/*.c ./shell-tests/programs/*.fir
+do
+  name="$(basename "$program")"
+  case "$program" in
+    *.fir) levels="--passes=" ; base="--from-ir" ;;
+    *) levels="-O0 -O1 -O2" ; base="" ;;
+  esac
+  for level in $levels
+  do
+    cargo run -q -- --run $base $level "$program" 2> "$tmp/run.err"
+    expected="$?"
+    if grep -q "runtime error" "$tmp/run.err"
+    then
+      echo "SKIP: $name $level: runtime error in the interpreter"
+      continue
+    fi
+    cargo run -q -- --target=wasm32 --emit=wasm $base $level "$program" > "$tmp/$name.wasm" || { status=1; continue; }
+    actual="$(node -e '
+      const bytes = require("fs").readFileSync(process.argv[1]);
+      if (!WebAssembly.validate(bytes)) { console.log("invalid"); process.exit(); }
+      WebAssembly.instantiate(bytes).then(m => console.log(Number(BigInt.asUintN(8, m.instance.exports.main()))));
+    ' "$tmp/$name.wasm")"
+    if [ "$expected" = "$actual" ]
+    then
+      echo "OK: $name wasm $level"
+    else
+      echo "ERROR: $name $level: interpreter $expected, wasm $actual"
+      status=1
+    fi
+  done
+done
+
+rm -r "$tmp"
+exit $status
@@ -0,0 +1,301 @@
+use std::fmt;
+use std::io::{self, Write};
+use std::str::FromStr;
+
+pub mod aarch64;
+pub mod dwarf;
+pub mod riscv64;
+pub mod x86_64;
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub enum Syntax {
+    #[default]
+    Intel,
+    Att,
+}
+
+impl FromStr for Syntax {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "intel" => Ok(Syntax::Intel),
+            "att" => Ok(Syntax::Att),
+            _ => Err(format!("Unknown assembler syntax: {}", s)),
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Hash, PartialOrd, Ord)]
+pub struct Label(pub String);
+
+impl Label {
+    pub fn new(name: impl Into<String>) -> Self {
+        Label(name.into())
+    }
+}
+
+impl fmt::Display for Label {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Section {
+    Text,
+    Data,
+    Rodata,
+    Bss,
+    // DWARF sections of -g, which take no code
+    DebugInfo,
+    DebugAbbrev,
+    DebugLine,
+}
+
+impl fmt::Display for Section {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Section::Text => write!(f, ".text"),
+            Section::Data => write!(f, ".data"),
+            Section::Rodata => write!(f, ".section .rodata"),
+            Section::Bss => write!(f, ".bss"),
+            Section::DebugInfo => write!(f, ".section .debug_info,\"\",@progbits"),
+            Section::DebugAbbrev => write!(f, ".section .debug_abbrev,\"\",@progbits"),
+            Section::DebugLine => write!(f, ".section .debug_line,\"\",@progbits"),
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum SymbolType {
+    Function,
+    Object,
+}
+
+// Operand of a data directive, resolved by the assembler or the linker
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum Value {
+    Imm(i64),
+    Label(Label),
+    // Address of a label plus a byte offset
+    Offset(Label, i64),
+    // Distance between two labels, a - b
+    Diff(Label, Label),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Imm(i) => write!(f, "{}", i),
+            Value::Label(l) => write!(f, "{}", l),
+            Value::Offset(l, offset) => write!(f, "{}{:+}", l, offset),
+            Value::Diff(a, b) => write!(f, "{}-{}", a, b),
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum Directive {
+    Section(Section),
+    Global(Label),
+    Type(Label, SymbolType),
+    // Size of the symbol from its label to here
+    Size(Label),
+    // Alignment in bytes
+    Align(u32),
+    Byte(u8),
+    Short(u16),
+    Long(Value),
+    Quad(Value),
+    Uleb128(u64),
+    Zero(usize),
+    Asciz(String),
+    // Source file number and name for Loc
+    File(u32, String),
+    // Line and column in a File of the code that follows
+    Loc(u32, usize, usize),
+}
+
+impl fmt::Display for Directive {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Directive::Section(s) => write!(f, "{}", s),
+            Directive::Global(l) => write!(f, ".global {}", l),
+            Directive::Type(l, SymbolType::Function) => write!(f, ".type {}, @function", l),
+            Directive::Type(l, SymbolType::Object) => write!(f, ".type {}, @object", l),
+            Directive::Size(l) => write!(f, ".size {0}, .-{0}", l),
+            Directive::Align(n) => write!(f, ".balign {}", n),
+            Directive::Byte(v) => write!(f, ".byte {}", v),
+            Directive::Short(v) => write!(f, ".short {}", v),
+            Directive::Long(v) => write!(f, ".long {}", v),
+            Directive::Quad(v) => write!(f, ".quad {}", v),
+            Directive::Uleb128(v) => write!(f, ".uleb128 {}", v),
+            Directive::Zero(n) => write!(f, ".zero {}", n),
+            Directive::Asciz(s) => write!(f, ".asciz \"{}\"", escape(s)),
+            Directive::File(n, name) => write!(f, ".file {} \"{}\"", n, name.escape_default()),
+            Directive::Loc(n, line, col) => write!(f, ".loc {} {} {}", n, line, col),
+        }
+    }
+}
+
+// String for gas: bytes other than printable ASCII are octal escapes.
+fn escape(s: &str) -> String {
+    let mut out = String::new();
+    for b in s.bytes() {
+        match b {
+            b'"' | b'\\' => out.extend(['\\', b as char]),
+            0x20..=0x7e => out.push(b as char),
+            _ => out += &format!("\\{:03o}", b),
+        }
+    }
+    out
+}
+
+// Lets Display implementations stream through the io::Write printers.
+pub struct FmtWriter<'a, 'b>(pub &'a mut fmt::Formatter<'b>);
+
+impl io::Write for FmtWriter<'_, '_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let s = std::str::from_utf8(buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        self.0.write_str(s).map_err(io::Error::other)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// A machine instruction of some target.
+pub trait Instruction {
+    fn write(&self, w: &mut dyn Write, syntax: Syntax) -> io::Result<()>;
+
+    // Printed first to select the syntax in the assembler
+    fn syntax_directive(_syntax: Syntax) -> Option<&'static str>
+    where
+        Self: Sized,
+    {
+        None
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum Item<I> {
+    Directive(Directive),
+    Label(Label),
+    Inst(I),
+}
+
+// An assembly file in order of output.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Program<I> {
+    pub items: Vec<Item<I>>,
+}
+
+impl<I> Default for Program<I> {
+    fn default() -> Self {
+        Self { items: vec![] }
+    }
+}
+
+impl<I: Instruction> Program<I> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn directive(&mut self, d: Directive) -> &mut Self {
+        self.items.push(Item::Directive(d));
+        self
+    }
+
+    pub fn label(&mut self, l: Label) -> &mut Self {
+        self.items.push(Item::Label(l));
+        self
+    }
+
+    pub fn inst(&mut self, inst: I) -> &mut Self {
+        self.items.push(Item::Inst(inst));
+        self
+    }
+
+    // Streams the program to w without building the whole text first.
+    pub fn write(&self, w: &mut dyn Write, syntax: Syntax) -> io::Result<()> {
+        if let Some(d) = I::syntax_directive(syntax) {
+            writeln!(w, "{}", d)?;
+        }
+        for item in &self.items {
+            match item {
+                Item::Directive(d) => writeln!(w, "{}", d)?,
+                Item::Label(l) => writeln!(w, "{}:", l)?,
+                Item::Inst(inst) => {
+                    write!(w, "\t")?;
+                    inst.write(w, syntax)?;
+                    writeln!(w)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn to_string(&self, syntax: Syntax) -> String {
+        let mut buf = vec![];
+        self.write(&mut buf, syntax).unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Nop;
+
+    impl Instruction for Nop {
+        fn write(&self, w: &mut dyn Write, _syntax: Syntax) -> io::Result<()> {
+            write!(w, "nop")
+        }
+    }
+
+    #[test]
+    fn test_write() {
+        let mut program = Program::new();
+        let main = Label::new("main");
+        program
+            .directive(Directive::Section(Section::Text))
+            .directive(Directive::Global(main.clone()))
+            .label(main.clone())
+            .inst(Nop)
+            .directive(Directive::Size(main))
+            .directive(Directive::Section(Section::Rodata))
+            .directive(Directive::Asciz("a\"b\n".to_string()))
+            .directive(Directive::Section(Section::DebugInfo))
+            .directive(Directive::Long(Value::Diff(Label::new(".Lb"), Label::new(".La"))))
+            .directive(Directive::Quad(Value::Label(Label::new("main"))))
+            .directive(Directive::Quad(Value::Offset(Label::new("x"), -8)))
+            .directive(Directive::Uleb128(300))
+            .directive(Directive::File(1, "t.c".to_string()))
+            .directive(Directive::Loc(1, 2, 3));
+        assert_eq!(program.to_string(Syntax::Att), "\
+            .text\n\
+            .global main\n\
+            main:\n\
+            \tnop\n\
+            .size main, .-main\n\
+            .section .rodata\n\
+            .asciz \"a\\\"b\\012\"\n\
+            .section .debug_info,\"\",@progbits\n\
+            .long .Lb-.La\n\
+            .quad main\n\
+            .quad x-8\n\
+            .uleb128 300\n\
+            .file 1 \"t.c\"\n\
+            .loc 1 2 3\n\
+        ");
+    }
+
+    #[test]
+    fn test_syntax_from_str() {
+        assert_eq!("att".parse(), Ok(Syntax::Att));
+        assert!("x".parse::<Syntax>().is_err());
+    }
+}
@@ -0,0 +1,131 @@
+use super::{Instruction, Label, Syntax};
+use std::fmt;
+use std::io::{self, Write};
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash)]
+pub enum Reg {
+    // x0 to x30. x29 is the frame pointer and x30 the link register.
+    X(u8),
+    Sp,
+    Xzr,
+}
+
+pub const FP: Reg = Reg::X(29);
+pub const LR: Reg = Reg::X(30);
+
+impl fmt::Display for Reg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Reg::X(n) => write!(f, "x{}", n),
+            Reg::Sp => write!(f, "sp"),
+            Reg::Xzr => write!(f, "xzr"),
+        }
+    }
+}
+
+// Second source operand of add and sub
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Operand {
+    Reg(Reg),
+    // Unsigned 12-bit immediate
+    Imm(u16),
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operand::Reg(r) => write!(f, "{}", r),
+            Operand::Imm(i) => write!(f, "#{}", i),
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Address {
+    // [base, #offset]
+    Offset(Reg, i64),
+    // [base, #offset]! updates base before the access.
+    PreIndex(Reg, i64),
+    // [base], #offset updates base after the access.
+    PostIndex(Reg, i64),
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Address::Offset(base, 0) => write!(f, "[{}]", base),
+            Address::Offset(base, offset) => write!(f, "[{}, #{}]", base, offset),
+            Address::PreIndex(base, offset) => write!(f, "[{}, #{}]!", base, offset),
+            Address::PostIndex(base, offset) => write!(f, "[{}], #{}", base, offset),
+        }
+    }
+}
+
+// AArch64 instructions in the subset the backend emits. Operands are in
+// assembler order, destination first.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum MInst {
+    Mov(Reg, Reg),
+    // 16-bit immediate shifted left by a multiple of 16. movz clears the other
+    // bits, movn inverts the result and movk keeps them.
+    Movz(Reg, u16, u8),
+    Movn(Reg, u16, u8),
+    Movk(Reg, u16, u8),
+    Add(Reg, Reg, Operand),
+    Sub(Reg, Reg, Operand),
+    Mul(Reg, Reg, Reg),
+    Sdiv(Reg, Reg, Reg),
+    // dst = a - n * m, written `msub dst, n, m, a`
+    Msub(Reg, Reg, Reg, Reg),
+    Ldr(Reg, Address),
+    Str(Reg, Address),
+    Ldp(Reg, Reg, Address),
+    Stp(Reg, Reg, Address),
+    B(Label),
+    Bl(Label),
+    Cbz(Reg, Label),
+    Cbnz(Reg, Label),
+    // Pseudo-instruction for a local label
+    Label(Label),
+    Ret,
+}
+
+impl Instruction for MInst {
+    // There is only one syntax for AArch64.
+    fn write(&self, w: &mut dyn Write, _syntax: Syntax) -> io::Result<()> {
+        use MInst::*;
+        let shifted = |w: &mut dyn Write, op: &str, r: &Reg, imm: &u16, shift: &u8| match shift {
+            0 => write!(w, "{} {}, #{}", op, r, imm),
+            _ => write!(w, "{} {}, #{}, lsl #{}", op, r, imm, shift),
+        };
+        match self {
+            Mov(dst, src) => write!(w, "mov {}, {}", dst, src),
+            Movz(r, imm, shift) => shifted(w, "movz", r, imm, shift),
+            Movn(r, imm, shift) => shifted(w, "movn", r, imm, shift),
+            Movk(r, imm, shift) => shifted(w, "movk", r, imm, shift),
+            Add(dst, lhs, rhs) => write!(w, "add {}, {}, {}", dst, lhs, rhs),
+            Sub(dst, lhs, rhs) => write!(w, "sub {}, {}, {}", dst, lhs, rhs),
+            Mul(dst, lhs, rhs) => write!(w, "mul {}, {}, {}", dst, lhs, rhs),
+            Sdiv(dst, lhs, rhs) => write!(w, "sdiv {}, {}, {}", dst, lhs, rhs),
+            Msub(dst, n, m, a) => write!(w, "msub {}, {}, {}, {}", dst, n, m, a),
+            Ldr(r, addr) => write!(w, "ldr {}, {}", r, addr),
+            Str(r, addr) => write!(w, "str {}, {}", r, addr),
+            Ldp(a, b, addr) => write!(w, "ldp {}, {}, {}", a, b, addr),
+            Stp(a, b, addr) => write!(w, "stp {}, {}, {}", a, b, addr),
+            B(l) => write!(w, "b {}", l),
+            Bl(l) => write!(w, "bl {}", l),
+            Cbz(r, l) => write!(w, "cbz {}, {}", r, l),
+            Cbnz(r, l) => write!(w, "cbnz {}, {}", r, l),
+            Label(l) => write!(w, "{}:", l),
+            Ret => write!(w, "ret"),
+        }
+    }
+}
+
+impl fmt::Display for MInst {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut buf = vec![];
+        self.write(&mut buf, Syntax::default()).map_err(|_| fmt::Error)?;
+        write!(f, "{}", String::from_utf8_lossy(&buf))
+    }
+}
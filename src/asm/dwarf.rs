@@ -0,0 +1,298 @@
+// DWARF 4 debug info for -g. The assembler builds the line table from .loc
+// directives, so only .debug_abbrev and .debug_info are written here.
+use super::{Directive, Item, Label, Program, Section, Value};
+
+const DW_TAG_BASE_TYPE: u64 = 0x24;
+const DW_TAG_COMPILE_UNIT: u64 = 0x11;
+const DW_TAG_SUBPROGRAM: u64 = 0x2e;
+const DW_TAG_VARIABLE: u64 = 0x34;
+
+const DW_AT_LOCATION: u64 = 0x02;
+const DW_AT_NAME: u64 = 0x03;
+const DW_AT_BYTE_SIZE: u64 = 0x0b;
+const DW_AT_STMT_LIST: u64 = 0x10;
+const DW_AT_LOW_PC: u64 = 0x11;
+const DW_AT_HIGH_PC: u64 = 0x12;
+const DW_AT_LANGUAGE: u64 = 0x13;
+const DW_AT_PRODUCER: u64 = 0x25;
+const DW_AT_DECL_FILE: u64 = 0x3a;
+const DW_AT_DECL_LINE: u64 = 0x3b;
+const DW_AT_ENCODING: u64 = 0x3e;
+const DW_AT_EXTERNAL: u64 = 0x3f;
+const DW_AT_TYPE: u64 = 0x49;
+
+const DW_FORM_ADDR: u64 = 0x01;
+const DW_FORM_DATA2: u64 = 0x05;
+const DW_FORM_DATA8: u64 = 0x07;
+const DW_FORM_STRING: u64 = 0x08;
+const DW_FORM_DATA1: u64 = 0x0b;
+const DW_FORM_UDATA: u64 = 0x0f;
+const DW_FORM_REF4: u64 = 0x13;
+const DW_FORM_SEC_OFFSET: u64 = 0x17;
+const DW_FORM_EXPRLOC: u64 = 0x18;
+const DW_FORM_FLAG_PRESENT: u64 = 0x19;
+
+const DW_LANG_C99: u16 = 0x0c;
+const DW_ATE_SIGNED: u8 = 0x05;
+const DW_ATE_SIGNED_CHAR: u8 = 0x06;
+const DW_OP_ADDR: u8 = 0x03;
+
+const COMPILE_UNIT: u64 = 1;
+const SUBPROGRAM: u64 = 2;
+const BASE_TYPE: u64 = 3;
+const VARIABLE: u64 = 4;
+const STATIC_VARIABLE: u64 = 5;
+
+// Abbreviation code, tag, whether it has children, then attributes and forms
+type Abbrev = (u64, u64, bool, &'static [(u64, u64)]);
+
+const ABBREVS: &[Abbrev] = &[
+    (COMPILE_UNIT, DW_TAG_COMPILE_UNIT, true, &[
+        (DW_AT_PRODUCER, DW_FORM_STRING),
+        (DW_AT_LANGUAGE, DW_FORM_DATA2),
+        (DW_AT_NAME, DW_FORM_STRING),
+        (DW_AT_LOW_PC, DW_FORM_ADDR),
+        (DW_AT_HIGH_PC, DW_FORM_DATA8),
+        (DW_AT_STMT_LIST, DW_FORM_SEC_OFFSET),
+    ]),
+    (SUBPROGRAM, DW_TAG_SUBPROGRAM, false, &[
+        (DW_AT_EXTERNAL, DW_FORM_FLAG_PRESENT),
+        (DW_AT_NAME, DW_FORM_STRING),
+        (DW_AT_DECL_FILE, DW_FORM_DATA1),
+        (DW_AT_DECL_LINE, DW_FORM_UDATA),
+        (DW_AT_TYPE, DW_FORM_REF4),
+        (DW_AT_LOW_PC, DW_FORM_ADDR),
+        (DW_AT_HIGH_PC, DW_FORM_DATA8),
+    ]),
+    (BASE_TYPE, DW_TAG_BASE_TYPE, false, &[
+        (DW_AT_ENCODING, DW_FORM_DATA1),
+        (DW_AT_BYTE_SIZE, DW_FORM_DATA1),
+        (DW_AT_NAME, DW_FORM_STRING),
+    ]),
+    (VARIABLE, DW_TAG_VARIABLE, false, &[
+        (DW_AT_EXTERNAL, DW_FORM_FLAG_PRESENT),
+        (DW_AT_NAME, DW_FORM_STRING),
+        (DW_AT_TYPE, DW_FORM_REF4),
+        (DW_AT_LOCATION, DW_FORM_EXPRLOC),
+    ]),
+    (STATIC_VARIABLE, DW_TAG_VARIABLE, false, &[
+        (DW_AT_NAME, DW_FORM_STRING),
+        (DW_AT_TYPE, DW_FORM_REF4),
+        (DW_AT_LOCATION, DW_FORM_EXPRLOC),
+    ]),
+];
+
+// A signed integer type of C
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct BaseType {
+    pub name: &'static str,
+    pub size: u8,
+}
+
+pub const CHAR: BaseType = BaseType { name: "char", size: 1 };
+pub const INT: BaseType = BaseType { name: "int", size: 4 };
+pub const LONG: BaseType = BaseType { name: "long", size: 8 };
+
+impl BaseType {
+    // The type of an object of `size` bytes. Pointers are described as long.
+    pub fn of_size(size: usize) -> Option<Self> {
+        match size {
+            1 => Some(CHAR),
+            4 => Some(INT),
+            8 => Some(LONG),
+            _ => None,
+        }
+    }
+
+    fn encoding(&self) -> u8 {
+        if self.size == 1 {
+            DW_ATE_SIGNED_CHAR
+        } else {
+            DW_ATE_SIGNED
+        }
+    }
+}
+
+// A global variable at the address of label. External ones are visible to
+// other units.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Variable {
+    pub name: String,
+    pub label: Label,
+    pub ty: BaseType,
+    pub external: bool,
+}
+
+fn abbrevs<I>(program: &mut Program<I>) {
+    program.items.push(Item::Directive(Directive::Section(Section::DebugAbbrev)));
+    program.items.push(Item::Label(Label::new(".Ldebug_abbrev0")));
+    for (code, tag, children, attributes) in ABBREVS {
+        let mut values = vec![*code, *tag, *children as u64];
+        for (attribute, form) in attributes.iter() {
+            values.extend([*attribute, *form]);
+        }
+        values.extend([0, 0]);
+        for v in values {
+            program.items.push(Item::Directive(Directive::Uleb128(v)));
+        }
+    }
+    program.items.push(Item::Directive(Directive::Byte(0)));
+}
+
+// Adds debug info for `function` and the global variables to a program with
+// one function in file, which starts on line and returns return_type. The
+// program must end in the code of the function, where its end label is
+// appended.
+pub fn emit<I>(
+    program: &mut Program<I>,
+    file: &str,
+    function: &Label,
+    line: usize,
+    return_type: BaseType,
+    variables: &[Variable],
+) {
+    let end = Label(format!(".L{}_end", function));
+    program.items.insert(0, Item::Directive(Directive::File(1, file.to_string())));
+    program.items.push(Item::Label(end.clone()));
+    abbrevs(program);
+
+    let (unit, start, finish) = (
+        Label::new(".Ldebug_info0"),
+        Label::new(".Ldebug_info_start"),
+        Label::new(".Ldebug_info_end"),
+    );
+    let mut types = vec![return_type];
+    for v in variables {
+        if !types.contains(&v.ty) {
+            types.push(v.ty);
+        }
+    }
+    // Offsets of references are from the start of the unit.
+    let reference = |ty: BaseType| {
+        let i = types.iter().position(|t| *t == ty).unwrap();
+        Directive::Long(Value::Diff(Label(format!(".Ldebug_type{}", i)), unit.clone()))
+    };
+    let pc = [
+        Directive::Quad(Value::Label(function.clone())),
+        Directive::Quad(Value::Diff(end, function.clone())),
+    ];
+    let mut info = vec![
+        Item::Directive(Directive::Section(Section::DebugInfo)),
+        Item::Label(unit.clone()),
+        Item::Directive(Directive::Long(Value::Diff(finish.clone(), start.clone()))),
+        Item::Label(start),
+        Item::Directive(Directive::Short(4)),
+        Item::Directive(Directive::Long(Value::Label(Label::new(".Ldebug_abbrev0")))),
+        Item::Directive(Directive::Byte(8)),
+        Item::Directive(Directive::Uleb128(COMPILE_UNIT)),
+        Item::Directive(Directive::Asciz("fenixcc".to_string())),
+        Item::Directive(Directive::Short(DW_LANG_C99)),
+        Item::Directive(Directive::Asciz(file.to_string())),
+    ];
+    info.extend(pc.iter().cloned().map(Item::Directive));
+    info.extend([
+        Item::Directive(Directive::Long(Value::Label(Label::new(".Ldebug_line0")))),
+        Item::Directive(Directive::Uleb128(SUBPROGRAM)),
+        Item::Directive(Directive::Asciz(function.0.clone())),
+        Item::Directive(Directive::Byte(1)),
+        Item::Directive(Directive::Uleb128(line as u64)),
+        Item::Directive(reference(return_type)),
+    ]);
+    info.extend(pc.iter().cloned().map(Item::Directive));
+    for v in variables {
+        let abbrev = if v.external { VARIABLE } else { STATIC_VARIABLE };
+        info.extend([
+            Item::Directive(Directive::Uleb128(abbrev)),
+            Item::Directive(Directive::Asciz(v.name.clone())),
+            Item::Directive(reference(v.ty)),
+            // The location is an expression of DW_OP_addr and the address.
+            Item::Directive(Directive::Uleb128(9)),
+            Item::Directive(Directive::Byte(DW_OP_ADDR)),
+            Item::Directive(Directive::Quad(Value::Label(v.label.clone()))),
+        ]);
+    }
+    for (i, ty) in types.iter().enumerate() {
+        info.extend([
+            Item::Label(Label(format!(".Ldebug_type{}", i))),
+            Item::Directive(Directive::Uleb128(BASE_TYPE)),
+            Item::Directive(Directive::Byte(ty.encoding())),
+            Item::Directive(Directive::Byte(ty.size)),
+            Item::Directive(Directive::Asciz(ty.name.to_string())),
+        ]);
+    }
+    info.extend([
+        // End of the children of the unit
+        Item::Directive(Directive::Byte(0)),
+        Item::Label(finish),
+        Item::Directive(Directive::Section(Section::DebugLine)),
+        Item::Label(Label::new(".Ldebug_line0")),
+    ]);
+    program.items.extend(info);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::{Instruction, Syntax};
+    use std::io::{self, Write};
+
+    struct Nop;
+
+    impl Instruction for Nop {
+        fn write(&self, w: &mut dyn Write, _syntax: Syntax) -> io::Result<()> {
+            write!(w, "nop")
+        }
+    }
+
+    #[test]
+    fn test_emit() {
+        let main = Label::new("main");
+        let mut program = Program::new();
+        program.directive(Directive::Section(Section::Text)).label(main.clone()).inst(Nop);
+        emit(&mut program, "a.c", &main, 3, INT, &[]);
+        let s = program.to_string(Syntax::Att);
+        assert!(s.starts_with(".file 1 \"a.c\"\n.text\nmain:\n\tnop\n.Lmain_end:\n.section .debug_abbrev,\"\",@progbits\n"));
+        assert!(s.contains("\
+            .section .debug_info,\"\",@progbits\n\
+            .Ldebug_info0:\n\
+            .long .Ldebug_info_end-.Ldebug_info_start\n\
+            .Ldebug_info_start:\n\
+            .short 4\n\
+            .long .Ldebug_abbrev0\n\
+            .byte 8\n\
+        "));
+        assert!(s.contains(".asciz \"main\"\n.byte 1\n.uleb128 3\n.long .Ldebug_type0-.Ldebug_info0\n.quad main\n.quad .Lmain_end-main\n"));
+        assert!(s.contains(".uleb128 3\n.byte 5\n.byte 4\n.asciz \"int\"\n.byte 0\n.Ldebug_info_end:\n"));
+        assert!(s.ends_with(".section .debug_line,\"\",@progbits\n.Ldebug_line0:\n"));
+
+        let mut program = Program::new();
+        program.directive(Directive::Section(Section::Text)).label(main.clone()).inst(Nop);
+        let variables = [
+            Variable { name: "x".to_string(), label: Label::new("x"), ty: INT, external: true },
+            Variable { name: "c".to_string(), label: Label::new("c.1"), ty: CHAR, external: false },
+        ];
+        emit(&mut program, "a.c", &main, 3, LONG, &variables);
+        let s = program.to_string(Syntax::Att);
+        assert!(s.contains("\
+            .uleb128 4\n\
+            .asciz \"x\"\n\
+            .long .Ldebug_type1-.Ldebug_info0\n\
+            .uleb128 9\n\
+            .byte 3\n\
+            .quad x\n\
+            .uleb128 5\n\
+            .asciz \"c\"\n\
+            .long .Ldebug_type2-.Ldebug_info0\n\
+            .uleb128 9\n\
+            .byte 3\n\
+            .quad c.1\n\
+            .Ldebug_type0:\n\
+            .uleb128 3\n\
+            .byte 5\n\
+            .byte 8\n\
+            .asciz \"long\"\n\
+            .Ldebug_type1:\n\
+        "));
+        assert!(s.contains(".Ldebug_type2:\n.uleb128 3\n.byte 6\n.byte 1\n.asciz \"char\"\n.byte 0\n"));
+    }
+}
@@ -0,0 +1,151 @@
+use super::{Instruction, Label, Syntax};
+use std::fmt;
+use std::io::{self, Write};
+use std::str::FromStr;
+
+// x0 to x31, printed with their psABI names
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, PartialOrd, Ord)]
+pub struct Reg(pub u8);
+
+pub const ZERO: Reg = Reg(0);
+pub const RA: Reg = Reg(1);
+pub const SP: Reg = Reg(2);
+// Frame pointer, also known as s0
+pub const FP: Reg = Reg(8);
+pub const A0: Reg = Reg(10);
+
+const NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5", "a6", "a7", "s2",
+    "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6",
+];
+
+impl Reg {
+    pub fn t(n: u8) -> Reg {
+        Reg(if n < 3 { 5 + n } else { 25 + n })
+    }
+
+    pub fn s(n: u8) -> Reg {
+        Reg(if n < 2 { 8 + n } else { 16 + n })
+    }
+
+    pub fn a(n: u8) -> Reg {
+        Reg(10 + n)
+    }
+}
+
+impl fmt::Display for Reg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", NAMES[self.0 as usize])
+    }
+}
+
+// Accepts psABI names, fp, and xN.
+impl FromStr for Reg {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(i) = NAMES.iter().position(|n| *n == s) {
+            return Ok(Reg(i as u8));
+        }
+        match s {
+            "fp" => Ok(FP),
+            _ => match s.strip_prefix('x').and_then(|n| n.parse().ok()) {
+                Some(n) if n < 32 => Ok(Reg(n)),
+                _ => Err(format!("Unknown register: {}", s)),
+            },
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Op {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Op::Add => "add",
+            Op::Sub => "sub",
+            Op::Mul => "mul",
+            Op::Div => "div",
+            Op::Rem => "rem",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+// RV64IM instructions in the subset the backend emits, with the pseudo-
+// instructions of the assembler manual. Immediates of addi, ld and sd are 12-bit
+// signed.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum MInst {
+    // Register-register operation: rd = rs1 op rs2
+    R(Op, Reg, Reg, Reg),
+    Addi(Reg, Reg, i64),
+    // Pseudo: loads any 64-bit immediate.
+    Li(Reg, i64),
+    // Pseudo: addi rd, rs, 0
+    Mv(Reg, Reg),
+    Ld(Reg, i64, Reg),
+    Sd(Reg, i64, Reg),
+    // Pseudo: jal zero, label
+    J(Label),
+    // Pseudo: beq/bne rs, zero, label
+    Beqz(Reg, Label),
+    Bnez(Reg, Label),
+    // Pseudo-instruction for a local label
+    Label(Label),
+    // Pseudo: jalr zero, 0(ra)
+    Ret,
+}
+
+impl Instruction for MInst {
+    fn write(&self, w: &mut dyn Write, _syntax: Syntax) -> io::Result<()> {
+        use MInst::*;
+        match self {
+            R(op, rd, rs1, rs2) => write!(w, "{} {}, {}, {}", op, rd, rs1, rs2),
+            Addi(rd, rs, imm) => write!(w, "addi {}, {}, {}", rd, rs, imm),
+            Li(rd, imm) => write!(w, "li {}, {}", rd, imm),
+            Mv(rd, rs) => write!(w, "mv {}, {}", rd, rs),
+            Ld(rd, offset, base) => write!(w, "ld {}, {}({})", rd, offset, base),
+            Sd(rs, offset, base) => write!(w, "sd {}, {}({})", rs, offset, base),
+            J(l) => write!(w, "j {}", l),
+            Beqz(rs, l) => write!(w, "beqz {}, {}", rs, l),
+            Bnez(rs, l) => write!(w, "bnez {}, {}", rs, l),
+            Label(l) => write!(w, "{}:", l),
+            Ret => write!(w, "ret"),
+        }
+    }
+}
+
+impl fmt::Display for MInst {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut buf = vec![];
+        self.write(&mut buf, Syntax::default()).map_err(|_| fmt::Error)?;
+        write!(f, "{}", String::from_utf8_lossy(&buf))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reg() {
+        assert_eq!(Reg::t(0).to_string(), "t0");
+        assert_eq!(Reg::t(3).to_string(), "t3");
+        assert_eq!(Reg::s(1).to_string(), "s1");
+        assert_eq!(Reg::s(2).to_string(), "s2");
+        assert_eq!(Reg::a(7).to_string(), "a7");
+        for i in 0..32 {
+            assert_eq!(Reg(i).to_string().parse(), Ok(Reg(i)));
+        }
+        assert_eq!("fp".parse(), Ok(FP));
+        assert_eq!("x31".parse(), Ok(Reg(31)));
+        assert!("x32".parse::<Reg>().is_err());
+    }
+}
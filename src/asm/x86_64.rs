@@ -0,0 +1,371 @@
+use super::{FmtWriter, Instruction, Label, Syntax};
+use std::fmt;
+use std::io::{self, Write};
+
+pub mod encode;
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash)]
+pub enum Reg {
+    Rax,
+    Rcx,
+    Rdx,
+    Rbx,
+    Rsp,
+    Rbp,
+    Rsi,
+    Rdi,
+    R8,
+    R9,
+    R10,
+    R11,
+    R12,
+    R13,
+    R14,
+    R15,
+}
+
+impl fmt::Display for Reg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Reg::Rax => "rax",
+            Reg::Rcx => "rcx",
+            Reg::Rdx => "rdx",
+            Reg::Rbx => "rbx",
+            Reg::Rsp => "rsp",
+            Reg::Rbp => "rbp",
+            Reg::Rsi => "rsi",
+            Reg::Rdi => "rdi",
+            Reg::R8 => "r8",
+            Reg::R9 => "r9",
+            Reg::R10 => "r10",
+            Reg::R11 => "r11",
+            Reg::R12 => "r12",
+            Reg::R13 => "r13",
+            Reg::R14 => "r14",
+            Reg::R15 => "r15",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl Reg {
+    // Name of the low `size` bytes: al, eax or rax
+    pub fn sized(&self, size: u8) -> String {
+        let name = self.to_string();
+        match (size, self) {
+            (8, _) => name,
+            (4, _) if name.starts_with('r') && name.len() == 3 && !name[1..].starts_with(char::is_numeric) => {
+                format!("e{}", &name[1..])
+            }
+            (4, _) => format!("{}d", name),
+            (1, Reg::Rax | Reg::Rcx | Reg::Rdx | Reg::Rbx) => format!("{}l", &name[1..2]),
+            (1, Reg::Rsp | Reg::Rbp | Reg::Rsi | Reg::Rdi) => format!("{}l", &name[1..]),
+            (1, _) => format!("{}b", name),
+            _ => unreachable!("register size {}", size),
+        }
+    }
+}
+
+// Operand of a machine instruction. Memory operands are 64 bits wide unless
+// the instruction says otherwise.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Arg {
+    Reg(Reg),
+    Mem { base: Reg, offset: i64 },
+    Imm(i64),
+}
+
+impl Arg {
+    // Registers read to evaluate the operand as a source
+    pub fn regs(&self) -> Vec<Reg> {
+        match self {
+            Arg::Reg(r) | Arg::Mem { base: r, .. } => vec![*r],
+            Arg::Imm(_) => vec![],
+        }
+    }
+
+    pub fn is_mem(&self) -> bool {
+        matches!(self, Arg::Mem { .. })
+    }
+}
+
+impl Arg {
+    fn write(&self, w: &mut dyn Write, syntax: Syntax) -> io::Result<()> {
+        match (self, syntax) {
+            (Arg::Reg(r), Syntax::Intel) => write!(w, "{}", r),
+            (Arg::Reg(r), Syntax::Att) => write!(w, "%{}", r),
+            (Arg::Imm(i), Syntax::Intel) => write!(w, "{}", i),
+            (Arg::Imm(i), Syntax::Att) => write!(w, "${}", i),
+            (Arg::Mem { .. }, _) => self.write_sized(w, syntax, 8),
+        }
+    }
+
+    // The operand as `size` bytes wide
+    fn write_sized(&self, w: &mut dyn Write, syntax: Syntax, size: u8) -> io::Result<()> {
+        match (self, syntax) {
+            (Arg::Reg(r), Syntax::Intel) => write!(w, "{}", r.sized(size)),
+            (Arg::Reg(r), Syntax::Att) => write!(w, "%{}", r.sized(size)),
+            (Arg::Mem { .. }, Syntax::Intel) => {
+                let ptr = match size {
+                    1 => "byte",
+                    4 => "dword",
+                    _ => "qword",
+                };
+                write!(w, "{} ptr ", ptr)?;
+                self.write_address(w, syntax)
+            }
+            // The mnemonic has the size.
+            (Arg::Mem { .. }, Syntax::Att) => self.write_address(w, syntax),
+            _ => self.write(w, syntax),
+        }
+    }
+
+    // Memory operand without size, as taken by lea
+    fn write_address(&self, w: &mut dyn Write, syntax: Syntax) -> io::Result<()> {
+        let (base, offset) = match self {
+            Arg::Mem { base, offset } => (*base, *offset),
+            _ => return self.write(w, syntax),
+        };
+        match (syntax, offset) {
+            (Syntax::Intel, 0) => write!(w, "[{}]", base),
+            (Syntax::Intel, _) if offset < 0 => write!(w, "[{} - {}]", base, -offset),
+            (Syntax::Intel, _) => write!(w, "[{} + {}]", base, offset),
+            (Syntax::Att, 0) => write!(w, "(%{})", base),
+            (Syntax::Att, _) => write!(w, "{}(%{})", offset, base),
+        }
+    }
+}
+
+// Intel syntax
+impl fmt::Display for Arg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Arg::Reg(r) => write!(f, "{}", r),
+            Arg::Imm(i) => write!(f, "{}", i),
+            Arg::Mem { base, offset: 0 } => write!(f, "qword ptr [{}]", base),
+            Arg::Mem { base, offset } if *offset < 0 => write!(f, "qword ptr [{} - {}]", base, -offset),
+            Arg::Mem { base, offset } => write!(f, "qword ptr [{} + {}]", base, offset),
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Cond {
+    E,
+    Ne,
+}
+
+// x86_64 instructions in the subset the backend emits. Two-operand forms are
+// (dst, src).
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum MInst {
+    Mov(Arg, Arg),
+    Add(Arg, Arg),
+    Sub(Arg, Arg),
+    Imul(Reg, Arg),
+    // dst = src * imm
+    Imul3(Reg, Arg, i64),
+    Shl(Arg, u8),
+    Cqo,
+    Idiv(Arg),
+    Test(Arg, Arg),
+    Cmp(Arg, Arg),
+    // dst = address of the memory operand
+    Lea(Reg, Arg),
+    // dst = address of the label relative to rip
+    LeaRip(Reg, Label),
+    // dst = address of the label, read from its GOT entry. For symbols that
+    // may be defined in another module of a position-independent program.
+    LoadGot(Reg, Label),
+    // Loads 1 or 4 bytes and sign-extends them to 64 bits.
+    Movsx(Reg, Arg, u8),
+    // Stores the low 1 or 4 bytes of the register.
+    MovNarrow(Arg, Reg, u8),
+    Push(Arg),
+    Pop(Arg),
+    Jmp(Label),
+    Jcc(Cond, Label),
+    // Pseudo-instruction for a local label, so passes see block boundaries
+    Label(Label),
+    // Pseudo-instruction for the source line and column of the code that
+    // follows, which becomes a .loc directive with -g
+    Loc(usize, usize),
+    Ret,
+}
+
+impl MInst {
+    // Registers whose value the instruction reads
+    pub fn reads(&self) -> Vec<Reg> {
+        use MInst::*;
+        let dst_base = |a: &Arg| match a {
+            Arg::Mem { base, .. } => vec![*base],
+            _ => vec![],
+        };
+        match self {
+            Mov(dst, src) => [dst_base(dst), src.regs()].concat(),
+            Add(dst, src) | Sub(dst, src) | Test(dst, src) | Cmp(dst, src) => [dst.regs(), src.regs()].concat(),
+            Imul(dst, src) => [vec![*dst], src.regs()].concat(),
+            Imul3(_, src, _) => src.regs(),
+            Shl(dst, _) => dst.regs(),
+            Cqo => vec![Reg::Rax],
+            Idiv(src) => [vec![Reg::Rax, Reg::Rdx], src.regs()].concat(),
+            Lea(_, src) | Movsx(_, src, _) => dst_base(src),
+            MovNarrow(dst, src, _) => [dst_base(dst), vec![*src]].concat(),
+            Push(src) => [vec![Reg::Rsp], src.regs()].concat(),
+            Pop(dst) => [vec![Reg::Rsp], dst_base(dst)].concat(),
+            Ret => vec![Reg::Rax, Reg::Rsp],
+            LeaRip(..) | LoadGot(..) | Jmp(_) | Jcc(..) | Label(_) | Loc(..) => vec![],
+        }
+    }
+
+    // Registers the instruction overwrites
+    pub fn writes(&self) -> Vec<Reg> {
+        use MInst::*;
+        let reg = |a: &Arg| match a {
+            Arg::Reg(r) => vec![*r],
+            _ => vec![],
+        };
+        match self {
+            Mov(dst, _) | Add(dst, _) | Sub(dst, _) | Shl(dst, _) => reg(dst),
+            Imul(dst, _) | Imul3(dst, ..) | Lea(dst, _) | LeaRip(dst, _) | LoadGot(dst, _) | Movsx(dst, ..) => vec![*dst],
+            Cqo => vec![Reg::Rdx],
+            Idiv(_) => vec![Reg::Rax, Reg::Rdx],
+            Push(_) => vec![Reg::Rsp],
+            Pop(dst) => [vec![Reg::Rsp], reg(dst)].concat(),
+            Ret => vec![Reg::Rsp],
+            Test(..) | Cmp(..) | MovNarrow(..) | Jmp(_) | Jcc(..) | Label(_) | Loc(..) => vec![],
+        }
+    }
+
+    pub fn writes_memory(&self) -> bool {
+        use MInst::*;
+        match self {
+            Mov(dst, _) | Add(dst, _) | Sub(dst, _) | Shl(dst, _) | Pop(dst) => dst.is_mem(),
+            Push(_) | MovNarrow(..) => true,
+            _ => false,
+        }
+    }
+
+    // Labels and jumps end a straight-line sequence.
+    pub fn is_control(&self) -> bool {
+        matches!(self, MInst::Jmp(_) | MInst::Jcc(..) | MInst::Label(_) | MInst::Ret)
+    }
+}
+
+impl MInst {
+    fn mnemonic(&self) -> &'static str {
+        use MInst::*;
+        match self {
+            Mov(..) => "mov",
+            Add(..) => "add",
+            Sub(..) => "sub",
+            Imul(..) | Imul3(..) => "imul",
+            Shl(..) => "shl",
+            Cqo => "cqo",
+            Idiv(_) => "idiv",
+            Test(..) => "test",
+            Cmp(..) => "cmp",
+            Lea(..) | LeaRip(..) => "lea",
+            Movsx(_, _, 4) => "movsxd",
+            Movsx(..) => "movsx",
+            MovNarrow(..) | LoadGot(..) => "mov",
+            Push(_) => "push",
+            Pop(_) => "pop",
+            Jmp(_) => "jmp",
+            Jcc(Cond::E, _) => "je",
+            Jcc(Cond::Ne, _) => "jne",
+            Label(_) | Loc(..) => "",
+            Ret => "ret",
+        }
+    }
+
+    // Operands in Intel order, destination first
+    fn operands(&self) -> Vec<Arg> {
+        use MInst::*;
+        match self {
+            Mov(a, b) | Add(a, b) | Sub(a, b) | Test(a, b) | Cmp(a, b) => vec![*a, *b],
+            Imul(a, b) | Lea(a, b) => vec![Arg::Reg(*a), *b],
+            Imul3(a, b, i) => vec![Arg::Reg(*a), *b, Arg::Imm(*i)],
+            Shl(a, i) => vec![*a, Arg::Imm(*i as i64)],
+            Idiv(a) | Push(a) | Pop(a) => vec![*a],
+            Movsx(a, b, _) => vec![Arg::Reg(*a), *b],
+            MovNarrow(a, b, _) => vec![*a, Arg::Reg(*b)],
+            Cqo | LeaRip(..) | LoadGot(..) | Jmp(_) | Jcc(..) | Label(_) | Loc(..) | Ret => vec![],
+        }
+    }
+}
+
+impl Instruction for MInst {
+    // AT&T reverses the operands and marks the operand size with a suffix.
+    fn write(&self, w: &mut dyn Write, syntax: Syntax) -> io::Result<()> {
+        let mut operands = self.operands();
+        match (self, syntax) {
+            (MInst::Jmp(l) | MInst::Jcc(_, l), _) => return write!(w, "{} {}", self.mnemonic(), l),
+            (MInst::Label(l), _) => return write!(w, "{}:", l),
+            (MInst::Loc(line, col), _) => return write!(w, ".loc 1 {} {}", line, col),
+            (MInst::Cqo, Syntax::Att) => return write!(w, "cqto"),
+            (MInst::LeaRip(r, l), Syntax::Intel) => return write!(w, "lea {}, [rip + {}]", r, l),
+            (MInst::LeaRip(r, l), Syntax::Att) => return write!(w, "leaq {}(%rip), %{}", l, r),
+            (MInst::LoadGot(r, l), Syntax::Intel) => return write!(w, "mov {}, qword ptr [rip + {}@GOTPCREL]", r, l),
+            (MInst::LoadGot(r, l), Syntax::Att) => return write!(w, "movq {}@GOTPCREL(%rip), %{}", l, r),
+            // movsbq and movslq name both operand sizes.
+            (MInst::Movsx(_, _, size), Syntax::Att) => {
+                write!(w, "movs{}q ", if *size == 1 { 'b' } else { 'l' })?;
+                operands.reverse();
+            }
+            (MInst::MovNarrow(_, _, size), Syntax::Att) => {
+                write!(w, "mov{} ", if *size == 1 { 'b' } else { 'l' })?;
+                operands.reverse();
+            }
+            (MInst::Cqo | MInst::Ret, _) => return write!(w, "{}", self.mnemonic()),
+            (_, Syntax::Intel) => write!(w, "{} ", self.mnemonic())?,
+            (_, Syntax::Att) => {
+                write!(w, "{}q ", self.mnemonic())?;
+                operands.reverse();
+            }
+        }
+        for (i, arg) in operands.iter().enumerate() {
+            if i > 0 {
+                write!(w, ", ")?;
+            }
+            match self {
+                MInst::Lea(..) => arg.write_address(w, syntax)?,
+                MInst::Movsx(_, _, size) if arg.is_mem() => arg.write_sized(w, syntax, *size)?,
+                MInst::MovNarrow(_, _, size) => arg.write_sized(w, syntax, *size)?,
+                _ => arg.write(w, syntax)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn syntax_directive(syntax: Syntax) -> Option<&'static str> {
+        Some(match syntax {
+            Syntax::Intel => ".intel_syntax noprefix",
+            Syntax::Att => ".att_syntax",
+        })
+    }
+}
+
+// Intel syntax
+impl fmt::Display for MInst {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write(&mut FmtWriter(f), Syntax::Intel).map_err(|_| fmt::Error)
+    }
+}
+
+#[test]
+fn test_display_arg() {
+    for arg in [Arg::Reg(Reg::R8), Arg::Imm(-1), Arg::Mem { base: Reg::Rbp, offset: -16 }, Arg::Mem { base: Reg::Rax, offset: 0 }] {
+        let mut buf = vec![];
+        arg.write(&mut buf, Syntax::Intel).unwrap();
+        assert_eq!(arg.to_string(), String::from_utf8(buf).unwrap());
+    }
+}
+
+#[test]
+fn test_sized() {
+    let names = |size| [Reg::Rax, Reg::Rsp, Reg::Rdi, Reg::R8].map(|r| r.sized(size));
+    assert_eq!(names(1), ["al", "spl", "dil", "r8b"]);
+    assert_eq!(names(4), ["eax", "esp", "edi", "r8d"]);
+    assert_eq!(names(8), ["rax", "rsp", "rdi", "r8"]);
+}
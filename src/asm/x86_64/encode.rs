@@ -0,0 +1,512 @@
+// Machine code for MInst and ELF objects for whole programs, so -c needs no
+// external assembler. Jumps always take a rel32, so one pass plus fixups
+// resolves them.
+use super::{Arg, Cond, MInst, Reg};
+use crate::asm::{Directive, Item, Label, Program, Section, SymbolType, Value};
+use crate::elf::{self, Object, Relocation, Symbol};
+use std::fmt;
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum EncodeError {
+    // Operands without an encoding, such as two memory operands or a 64-bit
+    // immediate in an arithmetic instruction
+    Operands(MInst),
+    DuplicateLabel(Label),
+    Directive(Directive, Section),
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EncodeError::Operands(inst) => write!(f, "cannot encode {}", inst),
+            EncodeError::DuplicateLabel(l) => write!(f, "label {} is already defined", l),
+            EncodeError::Directive(d, s) => write!(f, "cannot assemble {} in {}", d, elf::section_name(*s)),
+        }
+    }
+}
+
+fn is_imm8(i: i64) -> bool {
+    (i8::MIN as i64..=i8::MAX as i64).contains(&i)
+}
+
+fn is_imm32(i: i64) -> bool {
+    (i32::MIN as i64..=i32::MAX as i64).contains(&i)
+}
+
+fn uleb128(out: &mut Vec<u8>, mut v: u64) {
+    loop {
+        let byte = (v & 0x7f) as u8;
+        v >>= 7;
+        if v == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+// Register number. Reg is in hardware order.
+fn n(r: Reg) -> u8 {
+    r as u8
+}
+
+// ModRM with the register or opcode extension in reg, then SIB and
+// displacement for memory operands. rsp and r12 as base need a SIB byte, rbp
+// and r13 a displacement.
+fn modrm(out: &mut Vec<u8>, reg: u8, rm: Arg) {
+    match rm {
+        Arg::Reg(r) => out.push(0xc0 | (reg & 7) << 3 | (n(r) & 7)),
+        Arg::Mem { base, offset } => {
+            let b = n(base) & 7;
+            let mode = match offset {
+                0 if b != 5 => 0,
+                _ if is_imm8(offset) => 1,
+                _ => 2,
+            };
+            out.push(mode << 6 | (reg & 7) << 3 | b);
+            if b == 4 {
+                out.push(0x24);
+            }
+            match mode {
+                1 => out.push(offset as u8),
+                2 => out.extend((offset as i32).to_le_bytes()),
+                _ => {}
+            }
+        }
+        Arg::Imm(_) => unreachable!("immediate as r/m operand"),
+    }
+}
+
+// Optional REX prefix, opcode and ModRM. w selects 64-bit operands.
+fn op_rm(out: &mut Vec<u8>, w: bool, opcode: &[u8], reg: u8, rm: Arg) {
+    let base = match rm {
+        Arg::Reg(r) | Arg::Mem { base: r, .. } => n(r),
+        Arg::Imm(_) => 0,
+    };
+    let rex = 0x40 | (w as u8) << 3 | (reg >> 3) << 2 | base >> 3;
+    if rex != 0x40 {
+        out.push(rex);
+    }
+    out.extend(opcode);
+    modrm(out, reg, rm);
+}
+
+// add, sub and cmp, which differ in the opcode extension
+fn alu(out: &mut Vec<u8>, ext: u8, dst: Arg, src: Arg) -> bool {
+    match (dst, src) {
+        (Arg::Imm(_), _) => return false,
+        (_, Arg::Reg(s)) => op_rm(out, true, &[ext << 3 | 1], n(s), dst),
+        (Arg::Reg(d), Arg::Mem { .. }) => op_rm(out, true, &[ext << 3 | 3], n(d), src),
+        (_, Arg::Imm(i)) if is_imm8(i) => {
+            op_rm(out, true, &[0x83], ext, dst);
+            out.push(i as u8);
+        }
+        (_, Arg::Imm(i)) if is_imm32(i) => {
+            op_rm(out, true, &[0x81], ext, dst);
+            out.extend((i as i32).to_le_bytes());
+        }
+        _ => return false,
+    }
+    true
+}
+
+// Appends the machine code of inst. Returns the target of the rel32 the
+// encoding ends with and the relocation it takes if the caller can't resolve it.
+pub fn encode(inst: &MInst, out: &mut Vec<u8>) -> Result<Option<(Label, u32)>, EncodeError> {
+    use MInst::*;
+    let offsets_fit = inst.operands().iter().all(|a| match a {
+        Arg::Mem { offset, .. } => is_imm32(*offset),
+        _ => true,
+    });
+    let not_imm = |a: &Arg| !matches!(a, Arg::Imm(_));
+    let ok = offsets_fit
+        && match *inst {
+            Mov(dst, Arg::Reg(s)) if not_imm(&dst) => {
+                op_rm(out, true, &[0x89], n(s), dst);
+                true
+            }
+            Mov(Arg::Reg(d), src @ Arg::Mem { .. }) => {
+                op_rm(out, true, &[0x8b], n(d), src);
+                true
+            }
+            Mov(dst, Arg::Imm(i)) if not_imm(&dst) && is_imm32(i) => {
+                op_rm(out, true, &[0xc7], 0, dst);
+                out.extend((i as i32).to_le_bytes());
+                true
+            }
+            // movabs
+            Mov(Arg::Reg(d), Arg::Imm(i)) => {
+                out.extend([0x48 | n(d) >> 3, 0xb8 | (n(d) & 7)]);
+                out.extend(i.to_le_bytes());
+                true
+            }
+            Mov(..) => false,
+            Add(dst, src) => alu(out, 0, dst, src),
+            Sub(dst, src) => alu(out, 5, dst, src),
+            Cmp(dst, src) => alu(out, 7, dst, src),
+            Test(a, Arg::Reg(b)) if not_imm(&a) => {
+                op_rm(out, true, &[0x85], n(b), a);
+                true
+            }
+            Test(Arg::Reg(a), b @ Arg::Mem { .. }) => {
+                op_rm(out, true, &[0x85], n(a), b);
+                true
+            }
+            Test(a, Arg::Imm(i)) if not_imm(&a) && is_imm32(i) => {
+                op_rm(out, true, &[0xf7], 0, a);
+                out.extend((i as i32).to_le_bytes());
+                true
+            }
+            Test(..) => false,
+            Imul(d, Arg::Imm(i)) => return encode(&Imul3(d, Arg::Reg(d), i), out),
+            Imul(d, src) => {
+                op_rm(out, true, &[0x0f, 0xaf], n(d), src);
+                true
+            }
+            Imul3(d, src, i) if not_imm(&src) && is_imm8(i) => {
+                op_rm(out, true, &[0x6b], n(d), src);
+                out.push(i as u8);
+                true
+            }
+            Imul3(d, src, i) if not_imm(&src) && is_imm32(i) => {
+                op_rm(out, true, &[0x69], n(d), src);
+                out.extend((i as i32).to_le_bytes());
+                true
+            }
+            Imul3(..) => false,
+            Shl(a, 1) if not_imm(&a) => {
+                op_rm(out, true, &[0xd1], 4, a);
+                true
+            }
+            Shl(a, i) if not_imm(&a) => {
+                op_rm(out, true, &[0xc1], 4, a);
+                out.push(i);
+                true
+            }
+            Shl(..) => false,
+            Cqo => {
+                out.extend([0x48, 0x99]);
+                true
+            }
+            Idiv(a) if not_imm(&a) => {
+                op_rm(out, true, &[0xf7], 7, a);
+                true
+            }
+            Idiv(_) => false,
+            Lea(d, src @ Arg::Mem { .. }) => {
+                op_rm(out, true, &[0x8d], n(d), src);
+                true
+            }
+            Lea(..) => false,
+            LeaRip(d, ref l) => {
+                let rex = 0x48 | n(d) >> 3 << 2;
+                out.extend([rex, 0x8d, (n(d) & 7) << 3 | 5, 0, 0, 0, 0]);
+                return Ok(Some((l.clone(), elf::R_X86_64_PC32)));
+            }
+            // The linker may relax it to a lea when the symbol is local.
+            LoadGot(d, ref l) => {
+                let rex = 0x48 | n(d) >> 3 << 2;
+                out.extend([rex, 0x8b, (n(d) & 7) << 3 | 5, 0, 0, 0, 0]);
+                return Ok(Some((l.clone(), elf::R_X86_64_REX_GOTPCRELX)));
+            }
+            Movsx(d, src, 1) if not_imm(&src) => {
+                op_rm(out, true, &[0x0f, 0xbe], n(d), src);
+                true
+            }
+            Movsx(d, src, 4) if not_imm(&src) => {
+                op_rm(out, true, &[0x63], n(d), src);
+                true
+            }
+            Movsx(..) => false,
+            MovNarrow(dst, s, 4) if not_imm(&dst) => {
+                op_rm(out, false, &[0x89], n(s), dst);
+                true
+            }
+            MovNarrow(dst, s, 1) if not_imm(&dst) => {
+                let mut code = vec![];
+                op_rm(&mut code, false, &[0x88], n(s), dst);
+                // spl, bpl, sil and dil need a REX prefix to differ from ah,
+                // ch, dh and bh.
+                if (4..8).contains(&n(s)) && code[0] == 0x88 {
+                    out.push(0x40);
+                }
+                out.extend(code);
+                true
+            }
+            MovNarrow(..) => false,
+            // push and pop are 64-bit without REX.W.
+            Push(Arg::Reg(r)) | Pop(Arg::Reg(r)) => {
+                if n(r) >= 8 {
+                    out.push(0x41);
+                }
+                let opcode = if let Push(_) = inst { 0x50 } else { 0x58 };
+                out.push(opcode | (n(r) & 7));
+                true
+            }
+            Push(Arg::Imm(i)) if is_imm8(i) => {
+                out.extend([0x6a, i as u8]);
+                true
+            }
+            Push(Arg::Imm(i)) if is_imm32(i) => {
+                out.push(0x68);
+                out.extend((i as i32).to_le_bytes());
+                true
+            }
+            Push(src @ Arg::Mem { .. }) => {
+                op_rm(out, false, &[0xff], 6, src);
+                true
+            }
+            Pop(dst @ Arg::Mem { .. }) => {
+                op_rm(out, false, &[0x8f], 0, dst);
+                true
+            }
+            Push(_) | Pop(_) => false,
+            Jmp(ref l) | Jcc(_, ref l) => {
+                match inst {
+                    Jcc(Cond::E, _) => out.extend([0x0f, 0x84]),
+                    Jcc(Cond::Ne, _) => out.extend([0x0f, 0x85]),
+                    _ => out.push(0xe9),
+                }
+                out.extend([0; 4]);
+                return Ok(Some((l.clone(), elf::R_X86_64_PLT32)));
+            }
+            Label(_) | Loc(..) => true,
+            Ret => {
+                out.push(0xc3);
+                true
+            }
+        };
+    if ok {
+        Ok(None)
+    } else {
+        Err(EncodeError::Operands(inst.clone()))
+    }
+}
+
+// Assembles a program into a relocatable object. Labels starting with .L stay
+// out of the symbol table like in gas. Jumps within a section are resolved
+// here, others and addresses in data become relocations.
+pub fn object(program: &Program<MInst>) -> Result<Object, EncodeError> {
+    let mut object = Object::default();
+    let mut section = Section::Text;
+    let mut labels: Vec<(Label, Section, u64)> = vec![];
+    let mut globals = vec![];
+    let mut types = vec![];
+    let mut sizes = vec![];
+    // (section, offset of the rel32, target, relocation)
+    let mut fixups = vec![];
+    // (section, offset of the .quad, target, addend)
+    let mut addresses = vec![];
+    let offset = |object: &mut Object, section| match section {
+        Section::Bss => object.bss_size,
+        _ => object.contents(section).len() as u64,
+    };
+    for item in &program.items {
+        let label = match item {
+            Item::Label(l) | Item::Inst(MInst::Label(l)) => l,
+            Item::Inst(inst) if section == Section::Bss => return Err(EncodeError::Operands(inst.clone())),
+            Item::Inst(inst) => {
+                let contents = object.contents(section);
+                if let Some((target, kind)) = encode(inst, contents)? {
+                    fixups.push((section, contents.len() as u64 - 4, target, kind));
+                }
+                continue;
+            }
+            Item::Directive(d) => {
+                match (d, section) {
+                    // Debug info needs an external assembler for its line table.
+                    (Directive::Section(s), _) if !elf::SECTIONS.contains(s) => return Err(EncodeError::Directive(d.clone(), section)),
+                    (Directive::Section(s), _) => section = *s,
+                    (Directive::File(..) | Directive::Loc(..), _) => {}
+                    (Directive::Global(l), _) => globals.push(l.clone()),
+                    (Directive::Type(l, t), _) => types.push((l.clone(), *t)),
+                    (Directive::Size(l), _) => sizes.push((l.clone(), offset(&mut object, section))),
+                    (Directive::Align(n), Section::Bss) => object.bss_size = object.bss_size.div_ceil(*n as u64) * *n as u64,
+                    (Directive::Align(n), _) => {
+                        // Padding in code is executable, so it is nops.
+                        let fill = if section == Section::Text { 0x90 } else { 0 };
+                        let contents = object.contents(section);
+                        while !contents.len().is_multiple_of(*n as usize) {
+                            contents.push(fill);
+                        }
+                    }
+                    (Directive::Zero(n), Section::Bss) => object.bss_size += *n as u64,
+                    (_, Section::Bss) => return Err(EncodeError::Directive(d.clone(), section)),
+                    (Directive::Byte(v), _) => object.contents(section).push(*v),
+                    (Directive::Short(v), _) => object.contents(section).extend(v.to_le_bytes()),
+                    (Directive::Long(Value::Imm(v)), _) => object.contents(section).extend((*v as i32).to_le_bytes()),
+                    (Directive::Quad(Value::Imm(v)), _) => object.contents(section).extend(v.to_le_bytes()),
+                    (Directive::Quad(Value::Label(l)), _) | (Directive::Quad(Value::Offset(l, _)), _) => {
+                        let addend = if let Directive::Quad(Value::Offset(_, v)) = d { *v } else { 0 };
+                        let contents = object.contents(section);
+                        addresses.push((section, contents.len() as u64, l.clone(), addend));
+                        contents.extend([0; 8]);
+                    }
+                    (Directive::Uleb128(v), _) => uleb128(object.contents(section), *v),
+                    (Directive::Long(_) | Directive::Quad(_), _) => return Err(EncodeError::Directive(d.clone(), section)),
+                    (Directive::Zero(n), _) => object.contents(section).extend(vec![0; *n]),
+                    (Directive::Asciz(s), _) => {
+                        let contents = object.contents(section);
+                        contents.extend(s.as_bytes());
+                        contents.push(0);
+                    }
+                }
+                continue;
+            }
+        };
+        if labels.iter().any(|(l, ..)| l == label) {
+            return Err(EncodeError::DuplicateLabel(label.clone()));
+        }
+        labels.push((label.clone(), section, offset(&mut object, section)));
+    }
+
+    for (section, at, target, kind) in fixups {
+        let defined = labels.iter().find(|(l, ..)| *l == target);
+        let (symbol, kind, addend) = match defined {
+            Some((_, s, value)) if *s == section && kind != elf::R_X86_64_REX_GOTPCRELX => {
+                let rel = *value as i64 - (at as i64 + 4);
+                object.contents(section)[at as usize..at as usize + 4].copy_from_slice(&(rel as i32).to_le_bytes());
+                continue;
+            }
+            Some((l, s, value)) if l.0.starts_with(".L") => (elf::section_name(*s).to_string(), elf::R_X86_64_PC32, *value as i64 - 4),
+            _ => (target.0.clone(), kind, -4),
+        };
+        object.relocations.push(Relocation { section, offset: at, symbol, kind, addend });
+    }
+    for (section, at, target, addend) in addresses {
+        let (symbol, addend) = match labels.iter().find(|(l, ..)| *l == target) {
+            Some((l, s, value)) if l.0.starts_with(".L") => (elf::section_name(*s).to_string(), *value as i64 + addend),
+            _ => (target.0, addend),
+        };
+        object.relocations.push(Relocation { section, offset: at, symbol, kind: elf::R_X86_64_64, addend });
+    }
+
+    for (label, section, value) in &labels {
+        if label.0.starts_with(".L") {
+            continue;
+        }
+        let end = sizes.iter().find(|(l, _)| l == label).map(|(_, end)| *end);
+        object.symbols.push(Symbol {
+            name: label.0.clone(),
+            section: Some(*section),
+            value: *value,
+            size: end.map_or(0, |end| end - value),
+            global: globals.contains(label),
+            kind: types.iter().find(|(l, _)| l == label).map(|(_, t): &(Label, SymbolType)| *t),
+        });
+    }
+    for label in globals.iter().filter(|g| !labels.iter().any(|(l, ..)| l == *g)) {
+        object.symbols.push(Symbol { name: label.0.clone(), section: None, value: 0, size: 0, global: true, kind: None });
+    }
+    Ok(object)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bytes(inst: MInst) -> Vec<u8> {
+        let mut out = vec![];
+        encode(&inst, &mut out).unwrap();
+        out
+    }
+
+    // Expected bytes are from llvm-mc --show-encoding.
+    #[test]
+    fn test_encode() {
+        let (rax, r12) = (Arg::Reg(Reg::Rax), Arg::Reg(Reg::R12));
+        let mem = |base, offset| Arg::Mem { base, offset };
+        assert_eq!(bytes(MInst::Mov(rax, r12)), [0x4c, 0x89, 0xe0]);
+        assert_eq!(bytes(MInst::Mov(r12, mem(Reg::Rbp, -8))), [0x4c, 0x8b, 0x65, 0xf8]);
+        assert_eq!(bytes(MInst::Mov(mem(Reg::Rsp, 0), rax)), [0x48, 0x89, 0x04, 0x24]);
+        assert_eq!(bytes(MInst::Mov(mem(Reg::R13, 0), Arg::Imm(1))), [0x49, 0xc7, 0x45, 0x00, 0x01, 0x00, 0x00, 0x00]);
+        assert_eq!(bytes(MInst::Mov(r12, Arg::Imm(i64::MAX))), [0x49, 0xbc, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f]);
+        assert_eq!(bytes(MInst::Add(rax, Arg::Imm(1))), [0x48, 0x83, 0xc0, 0x01]);
+        assert_eq!(bytes(MInst::Sub(Arg::Reg(Reg::Rsp), Arg::Imm(1000))), [0x48, 0x81, 0xec, 0xe8, 0x03, 0x00, 0x00]);
+        assert_eq!(bytes(MInst::Cmp(r12, mem(Reg::Rbp, -1000))), [0x4c, 0x3b, 0xa5, 0x18, 0xfc, 0xff, 0xff]);
+        assert_eq!(bytes(MInst::Test(r12, r12)), [0x4d, 0x85, 0xe4]);
+        assert_eq!(bytes(MInst::Imul(Reg::Rax, Arg::Reg(Reg::Rdi))), [0x48, 0x0f, 0xaf, 0xc7]);
+        assert_eq!(bytes(MInst::Imul3(Reg::R8, mem(Reg::Rbp, -16), 3)), [0x4c, 0x6b, 0x45, 0xf0, 0x03]);
+        assert_eq!(bytes(MInst::Shl(rax, 3)), [0x48, 0xc1, 0xe0, 0x03]);
+        assert_eq!(bytes(MInst::Cqo), [0x48, 0x99]);
+        assert_eq!(bytes(MInst::Idiv(Arg::Reg(Reg::Rdi))), [0x48, 0xf7, 0xff]);
+        assert_eq!(bytes(MInst::Lea(Reg::Rsp, mem(Reg::Rbp, -16))), [0x48, 0x8d, 0x65, 0xf0]);
+        assert_eq!(bytes(MInst::Push(r12)), [0x41, 0x54]);
+        assert_eq!(bytes(MInst::Push(Arg::Imm(1000))), [0x68, 0xe8, 0x03, 0x00, 0x00]);
+        assert_eq!(bytes(MInst::Pop(rax)), [0x58]);
+        assert_eq!(bytes(MInst::Pop(mem(Reg::R12, 8))), [0x41, 0x8f, 0x44, 0x24, 0x08]);
+        assert_eq!(bytes(MInst::Ret), [0xc3]);
+        assert_eq!(bytes(MInst::LeaRip(Reg::R9, Label::new("x"))), [0x4c, 0x8d, 0x0d, 0, 0, 0, 0]);
+        assert_eq!(bytes(MInst::LoadGot(Reg::Rcx, Label::new("x"))), [0x48, 0x8b, 0x0d, 0, 0, 0, 0]);
+        assert_eq!(bytes(MInst::Movsx(Reg::Rax, mem(Reg::Rdi, 0), 1)), [0x48, 0x0f, 0xbe, 0x07]);
+        assert_eq!(bytes(MInst::Movsx(Reg::R12, mem(Reg::R11, 8), 4)), [0x4d, 0x63, 0x63, 0x08]);
+        assert_eq!(bytes(MInst::MovNarrow(mem(Reg::Rax, 0), Reg::Rdi, 1)), [0x40, 0x88, 0x38]);
+        assert_eq!(bytes(MInst::MovNarrow(mem(Reg::R11, 0), Reg::Rcx, 1)), [0x41, 0x88, 0x0b]);
+        assert_eq!(bytes(MInst::MovNarrow(mem(Reg::Rax, 0), Reg::R10, 4)), [0x44, 0x89, 0x10]);
+
+        let mut out = vec![];
+        assert_eq!(encode(&MInst::Add(rax, Arg::Imm(i64::MAX)), &mut out), Err(EncodeError::Operands(MInst::Add(rax, Arg::Imm(i64::MAX)))));
+        assert!(encode(&MInst::Mov(mem(Reg::Rax, 0), mem(Reg::Rax, 8)), &mut out).is_err());
+    }
+
+    #[test]
+    fn test_object() {
+        let (main, loop_) = (Label::new("main"), Label::new(".Lmain_1"));
+        let mut program = Program::new();
+        program
+            .directive(Directive::Section(Section::Text))
+            .directive(Directive::Global(main.clone()))
+            .directive(Directive::Type(main.clone(), SymbolType::Function))
+            .label(main.clone())
+            .label(loop_.clone())
+            .inst(MInst::Jcc(Cond::Ne, loop_))
+            .inst(MInst::Jmp(Label::new("exit")))
+            .directive(Directive::Size(main))
+            .directive(Directive::Section(Section::Bss))
+            .label(Label::new("counter"))
+            .directive(Directive::Zero(8))
+            .directive(Directive::Section(Section::Rodata))
+            .directive(Directive::Asciz("hi".to_string()))
+            .directive(Directive::Short(1))
+            .directive(Directive::Uleb128(300));
+        let object = object(&program).unwrap();
+        assert_eq!(object.text, [0x0f, 0x85, 0xfa, 0xff, 0xff, 0xff, 0xe9, 0, 0, 0, 0]);
+        assert_eq!(object.rodata, b"hi\0\x01\0\xac\x02");
+        assert_eq!(object.bss_size, 8);
+        let relocation = Relocation { section: Section::Text, offset: 7, symbol: "exit".to_string(), kind: elf::R_X86_64_PLT32, addend: -4 };
+        assert_eq!(object.relocations, [relocation]);
+        let main = object.symbol("main").unwrap();
+        assert_eq!((main.size, main.global, main.kind), (11, true, Some(SymbolType::Function)));
+        assert!(object.symbol(".Lmain_1").is_none());
+        assert_eq!(object.symbol("counter").unwrap().section, Some(Section::Bss));
+
+        let (x, s) = (Label::new("x"), Label::new(".L.str.0"));
+        let mut program = Program::new();
+        program
+            .inst(MInst::LeaRip(Reg::Rax, x.clone()))
+            .directive(Directive::Section(Section::Rodata))
+            .label(Label::new("pad"))
+            .directive(Directive::Byte(0))
+            .label(s.clone())
+            .directive(Directive::Section(Section::Data))
+            .label(x.clone())
+            .directive(Directive::Quad(Value::Offset(s, 1)))
+            .directive(Directive::Quad(Value::Label(Label::new("y"))));
+        let object = super::object(&program).unwrap();
+        assert_eq!(object.data, [0; 16]);
+        let relocations: Vec<_> = object.relocations.iter().map(|r| (r.section, r.offset, r.symbol.as_str(), r.kind, r.addend)).collect();
+        assert_eq!(relocations, [
+            (Section::Text, 3, "x", elf::R_X86_64_PC32, -4),
+            (Section::Data, 0, ".rodata", elf::R_X86_64_64, 2),
+            (Section::Data, 8, "y", elf::R_X86_64_64, 0),
+        ]);
+
+        let mut program = Program::new();
+        program.label(Label::new("a")).inst(MInst::Label(Label::new("a")));
+        assert_eq!(super::object(&program), Err(EncodeError::DuplicateLabel(Label::new("a"))));
+
+        let mut program = Program::<MInst>::new();
+        program.directive(Directive::Section(Section::DebugInfo));
+        assert!(super::object(&program).is_err());
+    }
+}
@@ -1,11 +1,12 @@
-use crate::{sym, Token, TokenKind};
-#[cfg(test)]
-use crate::{ast, ast_zero_literal, tok, head_tok, Loc};
+use crate::{sym, Span, Token, TokenKind, Type};
+use std::fmt;
 
 #[derive(PartialEq, Eq, Debug, Clone)]
 pub struct AST {
     pub token: Option<Token>,
     pub node: Node,
+    // Type of an expression, set by sema
+    pub ty: Option<Type>,
 }
 
 
@@ -23,15 +24,44 @@ macro_rules! define_node {
 define_node!{
     Block,
     ExprStatement,
+    Return,
+    Declaration,
+    Assignment,
+    AddressOf,
+    Cast,
+    SizeOf,
     Addition,
     Subtraction,
     Multiplication,
     Division,
+    Remainder,
+    ImplicitCast,
     IntLiteral,
+    StringLiteral,
+    Identifier,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum StorageClass {
+    Static,
+    Extern,
+    // Declares a type name instead of a variable
+    Typedef,
+}
+
+impl fmt::Display for StorageClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StorageClass::Static => write!(f, "static"),
+            StorageClass::Extern => write!(f, "extern"),
+            StorageClass::Typedef => write!(f, "typedef"),
+        }
+    }
 }
 
 pub mod nodes {
-    use super::AST;
+    use super::{StorageClass, AST};
+    use crate::Type;
     macro_rules! binary {
         ($name:ident) => {
             #[derive(Eq, Debug, Clone)]
@@ -63,13 +93,54 @@ pub mod nodes {
     pub struct ExprStatement {
         pub expr: Box<AST>,
     }
+    #[derive(PartialEq, Eq, Debug, Clone)]
+    pub struct Return {
+        pub expr: Box<AST>,
+    }
+
+    // One declarator; `int a, *b;` is two of these.
+    #[derive(PartialEq, Eq, Debug, Clone)]
+    pub struct Declaration {
+        pub name: String,
+        pub ty: Type,
+        pub storage: Option<StorageClass>,
+        pub init: Option<Box<AST>>,
+    }
+    #[derive(PartialEq, Eq, Debug, Clone)]
+    pub struct AddressOf {
+        pub operand: Box<AST>,
+    }
+    // `(ty)operand`
+    #[derive(PartialEq, Eq, Debug, Clone)]
+    pub struct Cast {
+        pub ty: Type,
+        pub operand: Box<AST>,
+    }
+    // `sizeof(ty)` or `sizeof operand`, whose type sema sets as ty
+    #[derive(PartialEq, Eq, Debug, Clone)]
+    pub struct SizeOf {
+        pub ty: Option<Type>,
+        pub operand: Option<Box<AST>>,
+    }
+    // A conversion of the operand to the type of this node
+    #[derive(PartialEq, Eq, Debug, Clone)]
+    pub struct ImplicitCast {
+        pub operand: Box<AST>,
+    }
+    #[derive(PartialEq, Eq, Debug, Clone)]
+    pub struct Identifier {
+        pub name: String,
+    }
 
+    binary!{Assignment}
     binary!{Addition}
     binary!{Subtraction}
     binary!{Division}
+    binary!{Remainder}
     binary!{Multiplication}
 
     value!{IntLiteral, i64}
+    value!{StringLiteral, String}
 
     #[cfg(test)]
     mod tests {
@@ -110,6 +181,7 @@ impl AST {
         Self {
             token,
             node,
+            ty: None,
         }
     }
 
@@ -130,6 +202,8 @@ impl AST {
                 sym!(Minus) => node!(Subtraction),
                 sym!(Asterisk) => node!(Multiplication),
                 sym!(Slash) => node!(Division),
+                sym!(Percent) => node!(Remainder),
+                sym!(Equal) => node!(Assignment),
                 _ => panic!("Invalid token"),
             },
         )
@@ -143,11 +217,66 @@ impl AST {
                 TokenKind::Int(value) => Node::IntLiteral(nodes::IntLiteral{
                     value
                 }),
+                TokenKind::Str(value) => Node::StringLiteral(nodes::StringLiteral{
+                    value
+                }),
                 _ => panic!("Invalid token"),
             },
         )
     }
 
+    pub fn new_identifier(token: Token) -> AST {
+        let name = match &token.kind {
+            TokenKind::Ident(name) => name.clone(),
+            _ => panic!("Invalid token"),
+        };
+        Self::new(Some(token), Node::Identifier(nodes::Identifier{ name }))
+    }
+
+    pub fn new_address_of(op: Token, operand: AST) -> Self {
+        Self::new(Some(op), Node::AddressOf(nodes::AddressOf{
+            operand: Box::new(operand)
+        }))
+    }
+
+    // `token` is the declared identifier.
+    pub fn new_declaration(token: Token, ty: Type, storage: Option<StorageClass>, init: Option<AST>) -> Self {
+        let name = match &token.kind {
+            TokenKind::Ident(name) => name.clone(),
+            _ => panic!("Invalid token"),
+        };
+        Self::new(Some(token), Node::Declaration(nodes::Declaration{
+            name,
+            ty,
+            storage,
+            init: init.map(Box::new),
+        }))
+    }
+
+    // `op` is the left parenthesis.
+    pub fn new_cast(op: Token, ty: Type, operand: AST) -> Self {
+        Self::new(Some(op), Node::Cast(nodes::Cast{
+            ty,
+            operand: Box::new(operand)
+        }))
+    }
+
+    pub fn new_size_of(op: Token, ty: Option<Type>, operand: Option<AST>) -> Self {
+        Self::new(Some(op), Node::SizeOf(nodes::SizeOf{
+            ty,
+            operand: operand.map(Box::new),
+        }))
+    }
+
+    // Converts ast to ty, at the location of ast.
+    pub fn new_implicit_cast(ast: AST, ty: Type) -> Self {
+        let mut cast = Self::new(ast.token.clone(), Node::ImplicitCast(nodes::ImplicitCast{
+            operand: Box::new(ast)
+        }));
+        cast.ty = Some(ty);
+        cast
+    }
+
     pub fn new_block(items: Vec<AST>) -> Self {
        Self::new(None, Node::Block(nodes::Block{ items}))
     }
@@ -157,12 +286,183 @@ impl AST {
             expr: Box::new(expr)
         }))
     }
+
+    pub fn new_return(token: Token, expr: AST) -> Self {
+        Self::new(Some(token), Node::Return(nodes::Return{
+            expr: Box::new(expr)
+        }))
+    }
+
+    fn children(&self) -> Vec<&AST> {
+        match &self.node {
+            Node::Block(v) => v.items.iter().collect(),
+            Node::ExprStatement(nodes::ExprStatement{ expr }) | Node::Return(nodes::Return{ expr }) => vec![expr],
+            Node::Declaration(v) => v.init.iter().map(|init| init.as_ref()).collect(),
+            Node::AddressOf(nodes::AddressOf{ operand })
+            | Node::Cast(nodes::Cast{ operand, .. })
+            | Node::ImplicitCast(nodes::ImplicitCast{ operand }) => vec![operand],
+            Node::SizeOf(v) => v.operand.iter().map(|operand| operand.as_ref()).collect(),
+            Node::Assignment(nodes::Assignment{ lhs, rhs })
+            | Node::Addition(nodes::Addition{ lhs, rhs })
+            | Node::Subtraction(nodes::Subtraction{ lhs, rhs })
+            | Node::Multiplication(nodes::Multiplication{ lhs, rhs })
+            | Node::Division(nodes::Division{ lhs, rhs })
+            | Node::Remainder(nodes::Remainder{ lhs, rhs }) => vec![lhs, rhs],
+            Node::IntLiteral(_) | Node::StringLiteral(_) | Node::Identifier(_) => vec![],
+        }
+    }
+
+    // The operands of +, -, *, / and %, which associate to the left
+    pub fn arithmetic_operands(&self) -> Option<(&AST, &AST)> {
+        match &self.node {
+            Node::Addition(nodes::Addition{ lhs, rhs })
+            | Node::Subtraction(nodes::Subtraction{ lhs, rhs })
+            | Node::Multiplication(nodes::Multiplication{ lhs, rhs })
+            | Node::Division(nodes::Division{ lhs, rhs })
+            | Node::Remainder(nodes::Remainder{ lhs, rhs }) => Some((lhs, rhs)),
+            _ => None,
+        }
+    }
+
+    pub fn arithmetic_operands_mut(&mut self) -> Option<(&mut AST, &mut AST)> {
+        match &mut self.node {
+            Node::Addition(nodes::Addition{ lhs, rhs })
+            | Node::Subtraction(nodes::Subtraction{ lhs, rhs })
+            | Node::Multiplication(nodes::Multiplication{ lhs, rhs })
+            | Node::Division(nodes::Division{ lhs, rhs })
+            | Node::Remainder(nodes::Remainder{ lhs, rhs }) => Some((lhs, rhs)),
+            _ => None,
+        }
+    }
+
+    // From the first to the last token of the node and its children
+    pub fn span(&self) -> Option<Span> {
+        let mut span: Option<Span> = None;
+        let mut stack = vec![self];
+        while let Some(ast) = stack.pop() {
+            if let Some(t) = &ast.token {
+                span = Some(match span {
+                    Some(a) => Span {
+                        start: if t.loc.offset < a.start.offset { t.loc } else { a.start },
+                        end: if t.loc.offset > a.end.offset { t.loc } else { a.end },
+                    },
+                    None => Span { start: t.loc, end: t.loc },
+                });
+            }
+            stack.extend(ast.children());
+        }
+        span
+    }
+
+    // Writes the line of the node and returns its children.
+    fn fmt_node(&self, f: &mut fmt::Formatter, depth: usize) -> Result<Vec<&AST>, fmt::Error> {
+        write!(f, "{:width$}", "", width = depth * 2)?;
+        let children: Vec<&AST> = match &self.node {
+            Node::Block(v) => {
+                write!(f, "Block")?;
+                v.items.iter().collect()
+            }
+            Node::ExprStatement(v) => {
+                write!(f, "ExprStatement")?;
+                vec![&v.expr]
+            }
+            Node::Return(v) => {
+                write!(f, "Return")?;
+                vec![&v.expr]
+            }
+            Node::Declaration(v) => {
+                write!(f, "Declaration")?;
+                if let Some(storage) = v.storage {
+                    write!(f, " {}", storage)?;
+                }
+                write!(f, " {} {}", v.ty, v.name)?;
+                v.init.iter().map(|init| init.as_ref()).collect()
+            }
+            Node::Assignment(v) => {
+                write!(f, "Assignment")?;
+                vec![&v.lhs, &v.rhs]
+            }
+            Node::AddressOf(v) => {
+                write!(f, "AddressOf")?;
+                vec![&v.operand]
+            }
+            Node::Cast(v) => {
+                write!(f, "Cast {}", v.ty)?;
+                vec![&v.operand]
+            }
+            Node::SizeOf(v) => {
+                write!(f, "SizeOf")?;
+                if let (Some(ty), None) = (&v.ty, &v.operand) {
+                    write!(f, " {}", ty)?;
+                }
+                v.operand.iter().map(|operand| operand.as_ref()).collect()
+            }
+            Node::Addition(v) => {
+                write!(f, "Addition")?;
+                vec![&v.lhs, &v.rhs]
+            }
+            Node::Subtraction(v) => {
+                write!(f, "Subtraction")?;
+                vec![&v.lhs, &v.rhs]
+            }
+            Node::Multiplication(v) => {
+                write!(f, "Multiplication")?;
+                vec![&v.lhs, &v.rhs]
+            }
+            Node::Division(v) => {
+                write!(f, "Division")?;
+                vec![&v.lhs, &v.rhs]
+            }
+            Node::Remainder(v) => {
+                write!(f, "Remainder")?;
+                vec![&v.lhs, &v.rhs]
+            }
+            Node::ImplicitCast(v) => {
+                write!(f, "ImplicitCast")?;
+                vec![&v.operand]
+            }
+            Node::IntLiteral(v) => {
+                write!(f, "IntLiteral {}", v.value)?;
+                vec![]
+            }
+            Node::StringLiteral(v) => {
+                write!(f, "StringLiteral {:?}", v.value)?;
+                vec![]
+            }
+            Node::Identifier(v) => {
+                write!(f, "Identifier {}", v.name)?;
+                vec![]
+            }
+        };
+        if let Some(ty) = &self.ty {
+            write!(f, " ({})", ty)?;
+        }
+        if let Some(tok) = &self.token {
+            write!(f, " <{}>", tok.loc)?;
+        }
+        writeln!(f)?;
+        Ok(children)
+    }
+}
+
+// Indented tree, one node per line.
+impl fmt::Display for AST {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut stack = vec![(self, 0)];
+        while let Some((ast, depth)) = stack.pop() {
+            let children = ast.fmt_node(f, depth)?;
+            stack.extend(children.into_iter().rev().map(|child| (child, depth + 1)));
+        }
+        Ok(())
+    }
 }
 
 
 #[cfg(test)]
+#[allow(clippy::items_after_test_module)]
 mod tests {
     use crate::{AST, Token, TokenKind, Loc, tok, ast_zero_literal};
+    use crate::{StorageClass, Type, ast, sym};
 
     #[test]
     #[should_panic]
@@ -179,21 +479,81 @@ mod tests {
             ast_zero_literal!(),
         );
     }
+
+    #[test]
+    fn test_display() {
+        let ast = ast!(new_block, vec![ast!(
+            new_expr_statement,
+            ast!(
+                new_binary_expr,
+                ast!(new_literal, tok!(new_int, 1, Loc::new(0, 1, 1))),
+                tok!(new, sym!(Plus), Loc::new(2, 1, 3)),
+                ast!(new_literal, tok!(new_int, 2, Loc::new(4, 1, 5))),
+            ),
+        )]);
+        assert_eq!(ast.to_string(), "\
+            Block\n  \
+              ExprStatement\n    \
+                Addition <1:3>\n      \
+                  IntLiteral 1 <1:1>\n      \
+                  IntLiteral 2 <1:5>\n\
+        ");
+    }
+
+    #[test]
+    fn test_display_declaration() {
+        let ast = ast!(new_block, vec![
+            ast!(
+                new_declaration,
+                tok!(new_ident, "p", Loc::new(12, 1, 13)),
+                Type::pointer_to(Type::Char),
+                Some(StorageClass::Static),
+                Some(ast!(new_literal, tok!(new_str, "a\n", Loc::new(16, 1, 17)))),
+            ),
+            ast!(new_expr_statement, ast!(
+                new_binary_expr,
+                ast!(new_identifier, tok!(new_ident, "p", Loc::new(23, 1, 24))),
+                tok!(new, sym!(Equal), Loc::new(25, 1, 26)),
+                ast!(
+                    new_address_of,
+                    tok!(new, sym!(Ampersand), Loc::new(27, 1, 28)),
+                    ast!(new_identifier, tok!(new_ident, "p", Loc::new(28, 1, 29))),
+                ),
+            )),
+        ]);
+        assert_eq!(ast.to_string(), "\
+            Block\n  \
+              Declaration static char * p <1:13>\n    \
+                StringLiteral \"a\\n\" <1:17>\n  \
+              ExprStatement\n    \
+                Assignment <1:26>\n      \
+                  Identifier p <1:24>\n      \
+                  AddressOf <1:28>\n        \
+                    Identifier p <1:29>\n\
+        ");
+    }
 }
 
 
+
 pub trait Visitor<R: Default, E> {
+    // The left operands of a chain like 1 + 2 + 3 are visited in a loop, so
+    // long expressions don't take stack space per operator.
     fn visit(&mut self, ast: &AST) -> Result<R, E> {
-        macro_rules! binary {
-            ($method:ident, $v:expr) => {{
-                let l = self.visit(&$v.lhs)?;
-                let r = self.visit(&$v.rhs)?;
-                self.$method(l, r)
-            }};
+        let mut chain = vec![];
+        let mut ast = ast;
+        while let Some((lhs, _)) = ast.arithmetic_operands() {
+            self.enter(ast);
+            chain.push(ast);
+            ast = lhs;
         }
-        match &ast.node {
+        self.enter(ast);
+        let v = match &ast.node {
             Node::Block(block) => {
                 for v in &block.items {
+                    if self.returned() {
+                        break;
+                    }
                     self.visit(v)?;
                 }
                 Ok(Default::default())
@@ -203,12 +563,56 @@ pub trait Visitor<R: Default, E> {
                 let v = self.visit(expr.expr.as_ref())?;
                 self.visit_expr_statement_right(v)
             }
+            Node::Return(ret) => {
+                let v = self.visit(ret.expr.as_ref())?;
+                self.visit_return(v)
+            }
+            Node::Declaration(decl) => self.visit_declaration(ast, decl),
+            Node::Assignment(assign) => self.visit_assignment(ast, assign),
+            Node::AddressOf(addr) => self.visit_address_of(ast, addr),
             Node::IntLiteral(lit) => self.visit_int_literal(lit),
-            Node::Addition(v) => binary!(visit_addition, v),
-            Node::Subtraction(v) => binary!(visit_subtraction, v),
-            Node::Multiplication(v) => binary!(visit_multiplication, v),
-            Node::Division(v) => binary!(visit_division, v),
+            Node::StringLiteral(lit) => self.visit_string_literal(ast, lit),
+            Node::Identifier(ident) => self.visit_identifier(ast, ident),
+            Node::Addition(_)
+            | Node::Subtraction(_)
+            | Node::Multiplication(_)
+            | Node::Division(_)
+            | Node::Remainder(_) => unreachable!("operator in the chain"),
+            Node::ImplicitCast(cast) => {
+                let v = self.visit(&cast.operand)?;
+                self.visit_implicit_cast(ast, v)
+            }
+            Node::Cast(cast) => {
+                let v = self.visit(&cast.operand)?;
+                self.visit_cast(cast, v)
+            }
+            // The operand is not evaluated.
+            Node::SizeOf(size_of) => self.visit_size_of(size_of),
+        };
+        self.leave(ast);
+        let mut v = v?;
+        for ast in chain.into_iter().rev() {
+            let (_, rhs) = ast.arithmetic_operands().unwrap();
+            let r = self.visit(rhs)?;
+            let result = match &ast.node {
+                Node::Addition(_) => self.visit_addition(v, r),
+                Node::Subtraction(_) => self.visit_subtraction(v, r),
+                Node::Multiplication(_) => self.visit_multiplication(v, r),
+                Node::Division(_) => self.visit_division(v, r),
+                _ => self.visit_remainder(v, r),
+            };
+            self.leave(ast);
+            v = result?;
         }
+        Ok(v)
+    }
+    // Called before and after visiting each node including its children.
+    fn enter(&mut self, _ast: &AST) {}
+    fn leave(&mut self, _ast: &AST) {}
+    // Whether the rest of the enclosing blocks is skipped, as after a return
+    // that was executed.
+    fn returned(&self) -> bool {
+        false
     }
     fn visit_expr_statement_left(&mut self) -> Result<R, E> {
         Ok(Default::default())
@@ -216,9 +620,39 @@ pub trait Visitor<R: Default, E> {
     fn visit_expr_statement_right(&mut self, _item: R) -> Result<R, E> {
         Ok(Default::default())
     }
+    fn visit_return(&mut self, _value: R) -> Result<R, E> {
+        Ok(Default::default())
+    }
+    // Declarations, assignments and `&` decide themselves which of their
+    // children to visit, since those may not be evaluated as values.
+    fn visit_declaration(&mut self, _ast: &AST, _decl: &Declaration) -> Result<R, E> {
+        Ok(Default::default())
+    }
+    fn visit_assignment(&mut self, _ast: &AST, _assign: &Assignment) -> Result<R, E> {
+        Ok(Default::default())
+    }
+    fn visit_address_of(&mut self, _ast: &AST, _addr: &AddressOf) -> Result<R, E> {
+        Ok(Default::default())
+    }
+    // The converted value of the operand, to the type of ast
+    fn visit_implicit_cast(&mut self, _ast: &AST, operand: R) -> Result<R, E> {
+        Ok(operand)
+    }
+    fn visit_cast(&mut self, _cast: &Cast, operand: R) -> Result<R, E> {
+        Ok(operand)
+    }
+    fn visit_size_of(&mut self, _size_of: &SizeOf) -> Result<R, E> {
+        Ok(Default::default())
+    }
     fn visit_int_literal(&mut self, _i: &IntLiteral) -> Result<R, E> {
         Ok(Default::default())
     }
+    fn visit_string_literal(&mut self, _ast: &AST, _s: &StringLiteral) -> Result<R, E> {
+        Ok(Default::default())
+    }
+    fn visit_identifier(&mut self, _ast: &AST, _ident: &Identifier) -> Result<R, E> {
+        Ok(Default::default())
+    }
     fn visit_addition(&mut self, _lhs: R, _rhs: R) -> Result<R, E> {
         Ok(Default::default())
     }
@@ -231,4 +665,7 @@ pub trait Visitor<R: Default, E> {
     fn visit_division(&mut self, _lhs: R, _rhs: R) -> Result<R, E> {
         Ok(Default::default())
     }
+    fn visit_remainder(&mut self, _lhs: R, _rhs: R) -> Result<R, E> {
+        Ok(Default::default())
+    }
 }
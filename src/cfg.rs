@@ -0,0 +1,1014 @@
+use crate::Instruction::*;
+use crate::{ir, Loc, IR};
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+// Virtual register. Printed as %n.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, PartialOrd, Ord)]
+pub struct VReg(pub usize);
+
+// Index of a block in Function::blocks. Printed as bbn.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, PartialOrd, Ord)]
+pub struct BlockId(pub usize);
+
+// Stack slot of a local variable. Printed as $n.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, PartialOrd, Ord)]
+pub struct Slot(pub usize);
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Operand {
+    Reg(VReg),
+    Imm(i64),
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum Inst {
+    Copy {
+        dst: VReg,
+        src: Operand,
+    },
+    Binary {
+        op: BinOp,
+        dst: VReg,
+        lhs: Operand,
+        rhs: Operand,
+    },
+    Load {
+        dst: VReg,
+        slot: Slot,
+    },
+    Store {
+        slot: Slot,
+        src: Operand,
+    },
+    // Address of a global
+    Addr {
+        dst: VReg,
+        global: String,
+    },
+    // Loads `size` bytes from the address in addr and sign-extends them.
+    LoadMem {
+        dst: VReg,
+        addr: Operand,
+        size: u8,
+    },
+    // Stores the low `size` bytes of src.
+    StoreMem {
+        addr: Operand,
+        src: Operand,
+        size: u8,
+    },
+    // Only at the start of a block, with one argument per predecessor.
+    Phi {
+        dst: VReg,
+        args: Vec<(BlockId, Operand)>,
+    },
+    // Source line and column of the following instructions, for -g
+    Loc {
+        line: usize,
+        col: usize,
+    },
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum Terminator {
+    Jump(BlockId),
+    // Jumps to `then` if cond is not zero.
+    Branch {
+        cond: Operand,
+        then: BlockId,
+        els: BlockId,
+    },
+    Return(Operand),
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Block {
+    pub insts: Vec<Inst>,
+    pub term: Terminator,
+}
+
+// blocks[0] is the entry block.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Function {
+    pub name: String,
+    pub blocks: Vec<Block>,
+    pub vreg_count: usize,
+    pub slot_count: usize,
+    // Size of the C type of each register, indexed by VReg. Values are kept
+    // sign-extended to 64 bits, so only typed backends like LLVM need it.
+    pub widths: Vec<u8>,
+    // Size of the return type
+    pub ret_width: u8,
+}
+
+impl Operand {
+    pub fn reg(&self) -> Option<VReg> {
+        match self {
+            Operand::Reg(r) => Some(*r),
+            Operand::Imm(_) => None,
+        }
+    }
+}
+
+impl Inst {
+    pub fn def(&self) -> Option<VReg> {
+        match self {
+            Inst::Copy { dst, .. }
+            | Inst::Binary { dst, .. }
+            | Inst::Load { dst, .. }
+            | Inst::Addr { dst, .. }
+            | Inst::LoadMem { dst, .. }
+            | Inst::Phi { dst, .. } => Some(*dst),
+            Inst::Store { .. } | Inst::StoreMem { .. } | Inst::Loc { .. } => None,
+        }
+    }
+
+    pub fn operands(&self) -> Vec<Operand> {
+        match self {
+            Inst::Copy { src, .. } | Inst::Store { src, .. } | Inst::LoadMem { addr: src, .. } => vec![*src],
+            Inst::Binary { lhs, rhs, .. } | Inst::StoreMem { addr: lhs, src: rhs, .. } => vec![*lhs, *rhs],
+            Inst::Load { .. } | Inst::Addr { .. } | Inst::Loc { .. } => vec![],
+            Inst::Phi { args, .. } => args.iter().map(|(_, v)| *v).collect(),
+        }
+    }
+
+    pub fn operands_mut(&mut self) -> Vec<&mut Operand> {
+        match self {
+            Inst::Copy { src, .. } | Inst::Store { src, .. } | Inst::LoadMem { addr: src, .. } => vec![src],
+            Inst::Binary { lhs, rhs, .. } | Inst::StoreMem { addr: lhs, src: rhs, .. } => vec![lhs, rhs],
+            Inst::Load { .. } | Inst::Addr { .. } | Inst::Loc { .. } => vec![],
+            Inst::Phi { args, .. } => args.iter_mut().map(|(_, v)| v).collect(),
+        }
+    }
+
+    pub fn is_phi(&self) -> bool {
+        matches!(self, Inst::Phi { .. })
+    }
+
+    pub fn uses(&self) -> Vec<VReg> {
+        self.operands().iter().filter_map(Operand::reg).collect()
+    }
+}
+
+impl Terminator {
+    pub fn successors(&self) -> Vec<BlockId> {
+        match self {
+            Terminator::Jump(b) => vec![*b],
+            Terminator::Branch { then, els, .. } => vec![*then, *els],
+            Terminator::Return(_) => vec![],
+        }
+    }
+
+    pub fn operands_mut(&mut self) -> Vec<&mut Operand> {
+        match self {
+            Terminator::Jump(_) => vec![],
+            Terminator::Branch { cond: v, .. } | Terminator::Return(v) => vec![v],
+        }
+    }
+
+    pub fn successors_mut(&mut self) -> Vec<&mut BlockId> {
+        match self {
+            Terminator::Jump(b) => vec![b],
+            Terminator::Branch { then, els, .. } => vec![then, els],
+            Terminator::Return(_) => vec![],
+        }
+    }
+
+    pub fn uses(&self) -> Vec<VReg> {
+        match self {
+            Terminator::Jump(_) => vec![],
+            Terminator::Branch { cond: v, .. } | Terminator::Return(v) => v.reg().into_iter().collect(),
+        }
+    }
+}
+
+impl Function {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            blocks: vec![],
+            vreg_count: 0,
+            slot_count: 0,
+            widths: vec![],
+            ret_width: 8,
+        }
+    }
+
+    pub fn new_vreg(&mut self) -> VReg {
+        self.new_typed_vreg(8)
+    }
+
+    pub fn new_typed_vreg(&mut self, width: u8) -> VReg {
+        self.vreg_count += 1;
+        self.widths.push(width);
+        VReg(self.vreg_count - 1)
+    }
+
+    pub fn width(&self, r: VReg) -> u8 {
+        self.widths.get(r.0).copied().unwrap_or(8)
+    }
+
+    pub fn new_slot(&mut self) -> Slot {
+        self.slot_count += 1;
+        Slot(self.slot_count - 1)
+    }
+
+    pub fn push_block(&mut self, block: Block) -> BlockId {
+        self.blocks.push(block);
+        BlockId(self.blocks.len() - 1)
+    }
+
+    pub fn block(&self, id: BlockId) -> &Block {
+        &self.blocks[id.0]
+    }
+
+    pub fn block_mut(&mut self, id: BlockId) -> &mut Block {
+        &mut self.blocks[id.0]
+    }
+
+    pub fn block_ids(&self) -> impl Iterator<Item = BlockId> {
+        (0..self.blocks.len()).map(BlockId)
+    }
+
+    pub fn successors(&self, id: BlockId) -> Vec<BlockId> {
+        self.block(id).term.successors()
+    }
+
+    // Predecessors of every block, indexed by BlockId.
+    pub fn predecessors(&self) -> Vec<Vec<BlockId>> {
+        let mut preds = vec![vec![]; self.blocks.len()];
+        for id in self.block_ids() {
+            for succ in self.successors(id) {
+                if !preds[succ.0].contains(&id) {
+                    preds[succ.0].push(id);
+                }
+            }
+        }
+        preds
+    }
+
+    // Blocks reachable from the entry in reverse postorder.
+    pub fn reverse_postorder(&self) -> Vec<BlockId> {
+        let mut order = vec![];
+        if self.blocks.is_empty() {
+            return order;
+        }
+        let mut visited = vec![false; self.blocks.len()];
+        // (block, index of the next successor to visit)
+        let mut stack = vec![(BlockId(0), 0)];
+        visited[0] = true;
+        while let Some((id, i)) = stack.pop() {
+            let succs = self.successors(id);
+            if let Some(&succ) = succs.get(i) {
+                stack.push((id, i + 1));
+                if !visited[succ.0] {
+                    visited[succ.0] = true;
+                    stack.push((succ, 0));
+                }
+            } else {
+                order.push(id);
+            }
+        }
+        order.reverse();
+        order
+    }
+
+    // Rewrites every operand of instructions and terminators.
+    pub fn map_operands<F: FnMut(Operand) -> Operand>(&mut self, mut f: F) {
+        for block in &mut self.blocks {
+            let operands = block.insts.iter_mut().flat_map(Inst::operands_mut);
+            for v in operands.chain(block.term.operands_mut()) {
+                *v = f(*v);
+            }
+        }
+    }
+
+    pub fn verify(&self) -> Result<(), VerifyError> {
+        verify(self)
+    }
+}
+
+// Runs f and returns its result, or None on division by zero, on memory access
+// or after `limit` blocks.
+// Uninitialized registers and slots read as 0.
+#[cfg(test)]
+pub(crate) fn execute(f: &Function, limit: usize) -> Option<i64> {
+    let mut regs = vec![0i64; f.vreg_count];
+    let mut slots = vec![0i64; f.slot_count];
+    let (mut prev, mut id) = (None, BlockId(0));
+    for _ in 0..limit {
+        let block = f.block(id);
+        let value = |regs: &Vec<i64>, v: &Operand| match v {
+            Operand::Reg(r) => regs[r.0],
+            Operand::Imm(i) => *i,
+        };
+        let phis: Vec<(VReg, i64)> = block.insts.iter().filter_map(|inst| match inst {
+            Inst::Phi { dst, args } => {
+                let (_, v) = args.iter().find(|(b, _)| Some(*b) == prev)?;
+                Some((*dst, value(&regs, v)))
+            }
+            _ => None,
+        }).collect();
+        for (dst, v) in phis {
+            regs[dst.0] = v;
+        }
+        for inst in &block.insts {
+            match inst {
+                Inst::Copy { dst, src } => regs[dst.0] = value(&regs, src),
+                Inst::Binary { op, dst, lhs, rhs } => {
+                    let (l, r) = (value(&regs, lhs), value(&regs, rhs));
+                    regs[dst.0] = match op {
+                        BinOp::Add => l.wrapping_add(r),
+                        BinOp::Sub => l.wrapping_sub(r),
+                        BinOp::Mul => l.wrapping_mul(r),
+                        BinOp::Div => l.checked_div(r)?,
+                        BinOp::Rem => l.checked_rem(r)?,
+                    };
+                }
+                Inst::Load { dst, slot } => regs[dst.0] = slots[slot.0],
+                Inst::Store { slot, src } => slots[slot.0] = value(&regs, src),
+                Inst::Addr { .. } | Inst::LoadMem { .. } | Inst::StoreMem { .. } => return None,
+                Inst::Phi { .. } | Inst::Loc { .. } => {}
+            }
+        }
+        prev = Some(id);
+        id = match &block.term {
+            Terminator::Jump(b) => *b,
+            Terminator::Branch { cond, then, els } => if value(&regs, cond) != 0 { *then } else { *els },
+            Terminator::Return(v) => return Some(value(&regs, v)),
+        };
+    }
+    None
+}
+
+impl TryFrom<&IR> for Function {
+    type Error = ir::VerifyError;
+
+    fn try_from(ir: &IR) -> Result<Self, Self::Error> {
+        Function::from_ir(ir, false)
+    }
+}
+
+impl Function {
+    // Simulates the operand stack so that every stack value gets a virtual register.
+    // main returns the value of the last popi like x86_64::compile. Code after ret
+    // goes into a new block without predecessors. Globals stay in the IR. With
+    // `lines`, a Loc precedes the code of every source line like in
+    // x86_64::lower_debug. pushi and popi have no code of their own, but the
+    // implicit return of main is on the line of the last expression.
+    pub fn from_ir(ir: &IR, lines: bool) -> Result<Self, ir::VerifyError> {
+        ir::verify(ir)?;
+        let mut f = Function::new("main");
+        f.ret_width = crate::RETURN_TYPE.size() as u8;
+        let mut insts = vec![];
+        let mut stack = vec![];
+        let mut last = Operand::Imm(0);
+        let mut line = None;
+        let mut last_loc = None;
+        for (pc, inst) in ir.instructions.iter().enumerate() {
+            let code = lines && !matches!(inst, PushI(_) | PopI);
+            last_loc = ir.loc(pc).or(last_loc);
+            if let Some(loc) = ir.loc(pc).filter(|loc| code && Some(loc.line) != line) {
+                line = Some(loc.line);
+                insts.push(Inst::Loc { line: loc.line, col: loc.col });
+            }
+            let op = match inst {
+                PushI(i) => {
+                    stack.push(Operand::Imm(*i));
+                    continue;
+                }
+                PushG(global) => {
+                    let dst = f.new_typed_vreg(ir.width(pc));
+                    insts.push(Inst::Addr { dst, global: global.clone() });
+                    stack.push(Operand::Reg(dst));
+                    continue;
+                }
+                Load(size) => {
+                    let addr = pop(&mut stack, pc)?;
+                    let dst = f.new_typed_vreg(ir.width(pc));
+                    insts.push(Inst::LoadMem { dst, addr, size: *size });
+                    stack.push(Operand::Reg(dst));
+                    continue;
+                }
+                // The stored value is reloaded for its truncation unless that
+                // is known.
+                Store(size) => {
+                    let src = pop(&mut stack, pc)?;
+                    let addr = pop(&mut stack, pc)?;
+                    insts.push(Inst::StoreMem { addr, src, size: *size });
+                    stack.push(match src {
+                        _ if *size == 8 => src,
+                        Operand::Imm(v) => Operand::Imm(ir::truncate(v, *size)),
+                        Operand::Reg(_) => {
+                            let dst = f.new_typed_vreg(ir.width(pc));
+                            insts.push(Inst::LoadMem { dst, addr, size: *size });
+                            Operand::Reg(dst)
+                        }
+                    });
+                    continue;
+                }
+                PopI => {
+                    last = pop(&mut stack, pc)?;
+                    continue;
+                }
+                AddI => BinOp::Add,
+                SubI => BinOp::Sub,
+                MulI => BinOp::Mul,
+                DivI => BinOp::Div,
+                RemI => BinOp::Rem,
+                Ret => {
+                    let v = pop(&mut stack, pc)?;
+                    f.push_block(Block {
+                        insts: std::mem::take(&mut insts),
+                        term: Terminator::Return(v),
+                    });
+                    continue;
+                }
+            };
+            let rhs = pop(&mut stack, pc)?;
+            let lhs = pop(&mut stack, pc)?;
+            let dst = f.new_typed_vreg(ir.width(pc));
+            insts.push(Inst::Binary { op, dst, lhs, rhs });
+            stack.push(Operand::Reg(dst));
+        }
+        if let Some(loc) = last_loc.filter(|loc| lines && Some(loc.line) != line) {
+            insts.push(Inst::Loc { line: loc.line, col: loc.col });
+        }
+        f.push_block(Block {
+            insts,
+            term: Terminator::Return(last),
+        });
+        Ok(f)
+    }
+}
+
+fn pop(stack: &mut Vec<Operand>, pc: usize) -> Result<Operand, ir::VerifyError> {
+    stack.pop().ok_or(ir::VerifyError::StackUnderflow { pc })
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum VerifyError {
+    NoBlocks,
+    InvalidBlock { block: BlockId, target: BlockId },
+    InvalidVReg { block: BlockId, vreg: VReg },
+    InvalidSlot { block: BlockId, slot: Slot },
+    UndefinedVReg { block: BlockId, vreg: VReg },
+    MisplacedPhi { block: BlockId, vreg: VReg },
+    PhiPredecessors { block: BlockId, vreg: VReg },
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VerifyError::NoBlocks => write!(f, "function has no blocks"),
+            VerifyError::InvalidBlock { block, target } => write!(f, "{}: jump to unknown block {}", block, target),
+            VerifyError::InvalidVReg { block, vreg } => write!(f, "{}: {} is out of range", block, vreg),
+            VerifyError::InvalidSlot { block, slot } => write!(f, "{}: {} is out of range", block, slot),
+            VerifyError::UndefinedVReg { block, vreg } => write!(f, "{}: {} is used but never defined", block, vreg),
+            VerifyError::MisplacedPhi { block, vreg } => write!(f, "{}: phi {} follows a non-phi instruction", block, vreg),
+            VerifyError::PhiPredecessors { block, vreg } => {
+                write!(f, "{}: arguments of phi {} do not match predecessors", block, vreg)
+            }
+        }
+    }
+}
+
+// Checks that every jump target, register and slot exists, every used register
+// has a definition and phis are well-formed.
+pub fn verify(f: &Function) -> Result<(), VerifyError> {
+    if f.blocks.is_empty() {
+        return Err(VerifyError::NoBlocks);
+    }
+    for id in f.block_ids() {
+        for target in f.block(id).term.successors() {
+            if target.0 >= f.blocks.len() {
+                return Err(VerifyError::InvalidBlock { block: id, target });
+            }
+        }
+    }
+    let preds = f.predecessors();
+    let mut defined = vec![false; f.vreg_count];
+    for id in f.block_ids() {
+        for inst in &f.block(id).insts {
+            if let Some(dst) = inst.def() {
+                if dst.0 >= f.vreg_count {
+                    return Err(VerifyError::InvalidVReg { block: id, vreg: dst });
+                }
+                defined[dst.0] = true;
+            }
+        }
+    }
+    for id in f.block_ids() {
+        let block = f.block(id);
+        let uses = block.insts.iter().flat_map(Inst::uses).chain(block.term.uses());
+        for vreg in uses {
+            if vreg.0 >= f.vreg_count {
+                return Err(VerifyError::InvalidVReg { block: id, vreg });
+            }
+            if !defined[vreg.0] {
+                return Err(VerifyError::UndefinedVReg { block: id, vreg });
+            }
+        }
+        let mut phi_allowed = true;
+        for inst in &block.insts {
+            match inst {
+                Inst::Load { slot, .. } | Inst::Store { slot, .. } if slot.0 >= f.slot_count => {
+                    return Err(VerifyError::InvalidSlot { block: id, slot: *slot });
+                }
+                Inst::Loc { .. } => {}
+                Inst::Phi { dst, .. } if !phi_allowed => {
+                    return Err(VerifyError::MisplacedPhi { block: id, vreg: *dst });
+                }
+                Inst::Phi { dst, args } => {
+                    let mut blocks: Vec<BlockId> = args.iter().map(|(b, _)| *b).collect();
+                    let mut expected = preds[id.0].clone();
+                    blocks.sort();
+                    expected.sort();
+                    if blocks != expected {
+                        return Err(VerifyError::PhiPredecessors { block: id, vreg: *dst });
+                    }
+                }
+                _ => phi_allowed = false,
+            }
+        }
+    }
+    Ok(())
+}
+
+impl fmt::Display for VReg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "%{}", self.0)
+    }
+}
+
+impl fmt::Display for BlockId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "bb{}", self.0)
+    }
+}
+
+impl fmt::Display for Slot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "${}", self.0)
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operand::Reg(r) => write!(f, "{}", r),
+            Operand::Imm(i) => write!(f, "{}", i),
+        }
+    }
+}
+
+impl fmt::Display for BinOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            BinOp::Add => "add",
+            BinOp::Sub => "sub",
+            BinOp::Mul => "mul",
+            BinOp::Div => "div",
+            BinOp::Rem => "rem",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl fmt::Display for Inst {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Inst::Copy { dst, src } => write!(f, "{} = copy {}", dst, src),
+            Inst::Binary { op, dst, lhs, rhs } => write!(f, "{} = {} {}, {}", dst, op, lhs, rhs),
+            Inst::Load { dst, slot } => write!(f, "{} = load {}", dst, slot),
+            Inst::Store { slot, src } => write!(f, "store {}, {}", slot, src),
+            Inst::Addr { dst, global } => write!(f, "{} = addr @{}", dst, global),
+            Inst::LoadMem { dst, addr, size } => write!(f, "{} = load.{} {}", dst, size, addr),
+            Inst::StoreMem { addr, src, size } => write!(f, "store.{} {}, {}", size, addr, src),
+            Inst::Phi { dst, args } => {
+                write!(f, "{} = phi", dst)?;
+                for (i, (block, v)) in args.iter().enumerate() {
+                    write!(f, "{} [{}, {}]", if i == 0 { "" } else { "," }, block, v)?;
+                }
+                Ok(())
+            }
+            Inst::Loc { line, col } => write!(f, "loc {}:{}", line, col),
+        }
+    }
+}
+
+impl fmt::Display for Terminator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Terminator::Jump(b) => write!(f, "jmp {}", b),
+            Terminator::Branch { cond, then, els } => write!(f, "br {}, {}, {}", cond, then, els),
+            Terminator::Return(v) => write!(f, "ret {}", v),
+        }
+    }
+}
+
+// Registers and return values are i64 unless their type is given, as in
+// `%0:i32 = add %1, 2` or `fn main -> i32 {`.
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.ret_width {
+            8 => writeln!(f, "fn {} {{", self.name)?,
+            w => writeln!(f, "fn {} -> {} {{", self.name, ir::width_name(w))?,
+        }
+        for id in self.block_ids() {
+            writeln!(f, "{}:", id)?;
+            let block = self.block(id);
+            for inst in &block.insts {
+                match inst.def().map(|r| (r, self.width(r))) {
+                    Some((r, w)) if w != 8 => {
+                        let text = inst.to_string().replacen(&format!("{} =", r), &format!("{}:{} =", r, ir::width_name(w)), 1);
+                        writeln!(f, "  {}", text)?
+                    }
+                    _ => writeln!(f, "  {}", inst)?,
+                }
+            }
+            writeln!(f, "  {}", block.term)?;
+        }
+        writeln!(f, "}}")
+    }
+}
+
+// Parses the format printed by Display. Blocks must be listed in order from bb0.
+impl FromStr for Function {
+    type Err = ir::ParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut f = Function::new("");
+        let mut insts = vec![];
+        let mut in_block = false;
+        let mut widths = vec![];
+        let mut offset = 0;
+        for (i, line) in s.lines().enumerate() {
+            let loc = Loc::new(offset, i + 1, 1);
+            let error = |message: String| ir::ParseError { loc, message };
+            offset += line.chars().count() + 1;
+            let code = line.split('#').next().unwrap();
+            let cleaned: String = code.chars().map(|c| if ",[]".contains(c) { ' ' } else { c }).collect();
+            let mut words: Vec<&str> = cleaned.split_whitespace().collect();
+            if let [def, "=", ..] = words.as_slice() {
+                if let Some((reg, ty)) = def.split_once(':') {
+                    let width = ir::parse_width(ty).ok_or_else(|| error(format!("Invalid type: {}", ty)))?;
+                    widths.push((parse_vreg(reg).map_err(error)?, width));
+                    words[0] = reg;
+                }
+            }
+            match words.as_slice() {
+                [] | ["}"] => {}
+                ["fn", name, "{"] => f.name = name.to_string(),
+                ["fn", name, "->", ty, "{"] => {
+                    f.name = name.to_string();
+                    f.ret_width = ir::parse_width(ty).ok_or_else(|| error(format!("Invalid type: {}", ty)))?;
+                }
+                [label] if label.ends_with(':') => {
+                    let id = parse_block_id(label.trim_end_matches(':')).map_err(error)?;
+                    if in_block || id.0 != f.blocks.len() {
+                        return Err(error(format!("Unexpected block {}", id)));
+                    }
+                    in_block = true;
+                }
+                _ if !in_block => return Err(error(format!("Expected block label: {}", code.trim()))),
+                ["jmp", _] | ["br", _, _, _] | ["ret", _] => {
+                    let term = parse_terminator(&words).map_err(error)?;
+                    f.push_block(Block { insts: std::mem::take(&mut insts), term });
+                    in_block = false;
+                }
+                _ => insts.push(parse_inst(&words).map_err(error)?),
+            }
+        }
+        if in_block {
+            return Err(ir::ParseError {
+                loc: Loc::new(offset, s.lines().count(), 1),
+                message: "Block has no terminator".to_string(),
+            });
+        }
+        let mut vregs = 0;
+        let mut slots = 0;
+        for block in &f.blocks {
+            for inst in &block.insts {
+                let regs = inst.uses().into_iter().chain(inst.def());
+                vregs = regs.map(|r| r.0 + 1).fold(vregs, usize::max);
+                if let Inst::Load { slot, .. } | Inst::Store { slot, .. } = inst {
+                    slots = slots.max(slot.0 + 1);
+                }
+            }
+            vregs = block.term.uses().iter().map(|r| r.0 + 1).fold(vregs, usize::max);
+        }
+        f.vreg_count = vregs;
+        f.slot_count = slots;
+        f.widths = vec![8; vregs];
+        for (r, width) in widths {
+            f.widths[r.0] = width;
+        }
+        Ok(f)
+    }
+}
+
+fn parse_index(s: &str, prefix: &str) -> Result<usize, String> {
+    s.strip_prefix(prefix)
+        .and_then(|n| n.parse().ok())
+        .ok_or_else(|| format!("Expected {}n: {}", prefix, s))
+}
+
+fn parse_block_id(s: &str) -> Result<BlockId, String> {
+    parse_index(s, "bb").map(BlockId)
+}
+
+fn parse_vreg(s: &str) -> Result<VReg, String> {
+    parse_index(s, "%").map(VReg)
+}
+
+fn parse_operand(s: &str) -> Result<Operand, String> {
+    if s.starts_with('%') {
+        return parse_vreg(s).map(Operand::Reg);
+    }
+    s.parse().map(Operand::Imm).map_err(|_| format!("Invalid operand: {}", s))
+}
+
+fn parse_size(s: &str) -> Result<u8, String> {
+    s.parse().ok().filter(|n| ir::SIZES.contains(n)).ok_or_else(|| format!("Invalid size: {}", s))
+}
+
+fn parse_inst(words: &[&str]) -> Result<Inst, String> {
+    let binary = |op| -> Result<Inst, String> {
+        Ok(Inst::Binary {
+            op,
+            dst: parse_vreg(words[0])?,
+            lhs: parse_operand(words[3])?,
+            rhs: parse_operand(words[4])?,
+        })
+    };
+    Ok(match words {
+        [dst, "=", "copy", src] => Inst::Copy { dst: parse_vreg(dst)?, src: parse_operand(src)? },
+        [_, "=", "add", _, _] => binary(BinOp::Add)?,
+        [_, "=", "sub", _, _] => binary(BinOp::Sub)?,
+        [_, "=", "mul", _, _] => binary(BinOp::Mul)?,
+        [_, "=", "div", _, _] => binary(BinOp::Div)?,
+        [_, "=", "rem", _, _] => binary(BinOp::Rem)?,
+        [dst, "=", "load", slot] => Inst::Load { dst: parse_vreg(dst)?, slot: Slot(parse_index(slot, "$")?) },
+        ["store", slot, src] => Inst::Store { slot: Slot(parse_index(slot, "$")?), src: parse_operand(src)? },
+        [dst, "=", "addr", global] => Inst::Addr {
+            dst: parse_vreg(dst)?,
+            global: global.strip_prefix('@').ok_or_else(|| format!("Expected @name: {}", global))?.to_string(),
+        },
+        [dst, "=", load, addr] if load.starts_with("load.") => Inst::LoadMem {
+            dst: parse_vreg(dst)?,
+            addr: parse_operand(addr)?,
+            size: parse_size(&load[5..])?,
+        },
+        [store, addr, src] if store.starts_with("store.") => Inst::StoreMem {
+            addr: parse_operand(addr)?,
+            src: parse_operand(src)?,
+            size: parse_size(&store[6..])?,
+        },
+        [dst, "=", "phi", args @ ..] if args.len() % 2 == 0 => Inst::Phi {
+            dst: parse_vreg(dst)?,
+            args: args
+                .chunks(2)
+                .map(|a| Ok((parse_block_id(a[0])?, parse_operand(a[1])?)))
+                .collect::<Result<_, String>>()?,
+        },
+        ["loc", loc] => {
+            let (line, col) = loc.split_once(':').ok_or_else(|| format!("Expected line:col: {}", loc))?;
+            let number = |s: &str| s.parse().map_err(|_| format!("Invalid location: {}", loc));
+            Inst::Loc { line: number(line)?, col: number(col)? }
+        }
+        _ => return Err(format!("Invalid instruction: {}", words.join(" "))),
+    })
+}
+
+fn parse_terminator(words: &[&str]) -> Result<Terminator, String> {
+    Ok(match words {
+        ["jmp", b] => Terminator::Jump(parse_block_id(b)?),
+        ["br", cond, then, els] => Terminator::Branch {
+            cond: parse_operand(cond)?,
+            then: parse_block_id(then)?,
+            els: parse_block_id(els)?,
+        },
+        ["ret", v] => Terminator::Return(parse_operand(v)?),
+        _ => unreachable!(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{fold, IRTranslator, Compiler, Source};
+
+    fn reg(i: usize) -> Operand {
+        Operand::Reg(VReg(i))
+    }
+
+    // bb0 -> bb1 -> bb3, bb0 -> bb2 -> bb3, bb4 is unreachable
+    fn diamond() -> Function {
+        let mut f = Function::new("f");
+        let v0 = f.new_vreg();
+        let v1 = f.new_vreg();
+        let blocks = vec![
+            (vec![Inst::Copy { dst: v0, src: Operand::Imm(1) }],
+             Terminator::Branch { cond: reg(0), then: BlockId(1), els: BlockId(2) }),
+            (vec![], Terminator::Jump(BlockId(3))),
+            (vec![], Terminator::Jump(BlockId(3))),
+            (vec![Inst::Binary { op: BinOp::Add, dst: v1, lhs: reg(0), rhs: Operand::Imm(2) }],
+             Terminator::Return(reg(1))),
+            (vec![], Terminator::Jump(BlockId(3))),
+        ];
+        for (insts, term) in blocks {
+            f.push_block(Block { insts, term });
+        }
+        f
+    }
+
+    #[test]
+    fn test_from_ir() {
+        let output = Compiler::default().compile(&Source::inline("1 + 2 * 3; 4;"));
+        let f = Function::try_from(output.ir.as_ref().unwrap()).unwrap();
+        assert_eq!(f.blocks.len(), 1);
+        assert_eq!(f.vreg_count, 2);
+        assert_eq!(f.to_string(), "\
+            fn main -> i32 {\n\
+            bb0:\n  \
+              %0:i32 = mul 2, 3\n  \
+              %1:i32 = add 1, %0\n  \
+              ret 4\n\
+            }\n\
+        ");
+        assert_eq!(f.verify(), Ok(()));
+    }
+
+    #[test]
+    fn test_from_ir_lines() {
+        let output = Compiler::default().compile(&Source::inline("1 +\n2; 3;\nreturn 4;"));
+        let f = Function::from_ir(output.ir.as_ref().unwrap(), true).unwrap();
+        assert_eq!(f.to_string(), "\
+            fn main -> i32 {\n\
+            bb0:\n  \
+              loc 1:3\n  \
+              %0:i32 = add 1, 2\n  \
+              loc 3:1\n  \
+              ret 4\n\
+            bb1:\n  \
+              ret 3\n\
+            }\n\
+        ");
+        assert_eq!(f.to_string().parse(), Ok(f));
+
+        let mut ir = Compiler::default().compile(&Source::inline("1;\n2 + 3;")).ir.unwrap();
+        fold::fold(&mut ir);
+        let f = Function::from_ir(&ir, true).unwrap();
+        assert_eq!(f.to_string(), "fn main -> i32 {\nbb0:\n  loc 2:3\n  ret 5\n}\n");
+    }
+
+    #[test]
+    fn test_from_invalid_ir() {
+        let ir: IR = vec![PushI(1), AddI, Ret].into();
+        assert_eq!(Function::try_from(&ir), Err(ir::VerifyError::StackUnderflow { pc: 1 }));
+    }
+
+    #[test]
+    fn test_translate_function() {
+        let output = Compiler::default().compile(&Source::inline("1 - 2;"));
+        let f = IRTranslator::new().translate_function(output.ast.as_ref().unwrap()).unwrap();
+        assert_eq!(f.block(BlockId(0)).term, Terminator::Return(reg(0)));
+    }
+
+    #[test]
+    fn test_parse() {
+        let mut f = diamond();
+        f.new_slot();
+        f.block_mut(BlockId(1)).insts.push(Inst::Store { slot: Slot(0), src: reg(0) });
+        let phi = Inst::Phi { dst: VReg(0), args: vec![(BlockId(1), reg(1)), (BlockId(2), Operand::Imm(-3))] };
+        f.block_mut(BlockId(2)).insts.insert(0, phi);
+        assert_eq!(f.to_string().parse::<Function>(), Ok(f.clone()));
+        f.widths[1] = 1;
+        f.ret_width = 4;
+        assert!(f.to_string().starts_with("fn f -> i32 {\n"));
+        assert!(f.to_string().contains("  %1:i8 = add %0, 2\n"));
+        assert_eq!(f.to_string().parse::<Function>(), Ok(f));
+
+        let err = "fn f {\nbb0:\n  %0 = neg 1\n".parse::<Function>().unwrap_err();
+        assert_eq!(err.loc.line, 3);
+        assert_eq!(err.message, "Invalid instruction: %0 = neg 1");
+        assert!("fn f {\nbb1:\n  ret 0\n}".parse::<Function>().is_err());
+        assert!("fn f {\nbb0:\n  %0 = copy 1\n}".parse::<Function>().is_err());
+        assert!("fn f {\n  ret 0\n}".parse::<Function>().is_err());
+        assert!("fn f -> i16 {\nbb0:\n  ret 0\n}".parse::<Function>().is_err());
+        assert!("fn f {\nbb0:\n  %0:i1 = copy 1\n  ret 0\n}".parse::<Function>().is_err());
+    }
+
+    #[test]
+    fn test_execute() {
+        let output = Compiler::default().compile(&Source::inline("1 + 2 * 3; 4 - 10 / 3;"));
+        let f = Function::try_from(output.ir.as_ref().unwrap()).unwrap();
+        assert_eq!(execute(&f, 10), Some(1));
+        assert_eq!(execute(&diamond(), 10), Some(3));
+    }
+
+    #[test]
+    fn test_map_operands() {
+        let mut f = diamond();
+        f.map_operands(|v| if v == reg(0) { Operand::Imm(5) } else { v });
+        assert_eq!(f.block(BlockId(0)).term, Terminator::Branch { cond: Operand::Imm(5), then: BlockId(1), els: BlockId(2) });
+        assert_eq!(execute(&f, 10), Some(7));
+    }
+
+    #[test]
+    fn test_successors_predecessors() {
+        let f = diamond();
+        assert_eq!(f.successors(BlockId(0)), vec![BlockId(1), BlockId(2)]);
+        assert_eq!(f.successors(BlockId(3)), vec![]);
+        let preds = f.predecessors();
+        assert_eq!(preds[0], vec![]);
+        assert_eq!(preds[3], vec![BlockId(1), BlockId(2), BlockId(4)]);
+    }
+
+    #[test]
+    fn test_reverse_postorder() {
+        let f = diamond();
+        assert_eq!(
+            f.reverse_postorder(),
+            vec![BlockId(0), BlockId(2), BlockId(1), BlockId(3)]
+        );
+    }
+
+    #[test]
+    fn test_verify() {
+        let mut f = diamond();
+        assert_eq!(f.verify(), Ok(()));
+
+        f.block_mut(BlockId(1)).term = Terminator::Jump(BlockId(9));
+        assert_eq!(f.verify(), Err(VerifyError::InvalidBlock { block: BlockId(1), target: BlockId(9) }));
+
+        let mut f = diamond();
+        f.block_mut(BlockId(2)).term = Terminator::Return(reg(7));
+        assert_eq!(f.verify(), Err(VerifyError::InvalidVReg { block: BlockId(2), vreg: VReg(7) }));
+
+        let mut f = diamond();
+        f.block_mut(BlockId(0)).insts.clear();
+        assert_eq!(f.verify(), Err(VerifyError::UndefinedVReg { block: BlockId(0), vreg: VReg(0) }));
+
+        let mut f = diamond();
+        f.block_mut(BlockId(1)).insts.push(Inst::Store { slot: Slot(0), src: reg(0) });
+        assert_eq!(f.verify(), Err(VerifyError::InvalidSlot { block: BlockId(1), slot: Slot(0) }));
+
+        assert_eq!(Function::new("f").verify(), Err(VerifyError::NoBlocks));
+    }
+
+    #[test]
+    fn test_verify_phi() {
+        let mut f = diamond();
+        let v2 = f.new_vreg();
+        let phi = Inst::Phi { dst: v2, args: vec![(BlockId(1), reg(0)), (BlockId(2), Operand::Imm(3))] };
+        f.block_mut(BlockId(3)).insts.push(phi.clone());
+        assert_eq!(f.verify(), Err(VerifyError::MisplacedPhi { block: BlockId(3), vreg: v2 }));
+
+        f.block_mut(BlockId(3)).insts.pop();
+        f.block_mut(BlockId(3)).insts.insert(0, phi);
+        assert_eq!(f.verify(), Err(VerifyError::PhiPredecessors { block: BlockId(3), vreg: v2 }));
+
+        f.blocks.pop();
+        assert_eq!(f.verify(), Ok(()));
+        assert_eq!(f.block(BlockId(3)).insts[0].to_string(), "%2 = phi [bb1, %0], [bb2, 3]");
+        assert_eq!(f.block(BlockId(3)).insts[0].uses(), vec![VReg(0)]);
+    }
+
+    #[test]
+    fn test_display() {
+        let mut f = diamond();
+        let slot = f.new_slot();
+        f.block_mut(BlockId(1)).insts.push(Inst::Store { slot, src: reg(0) });
+        f.block_mut(BlockId(2)).insts.push(Inst::Load { dst: VReg(0), slot });
+        assert_eq!(f.to_string(), "\
+            fn f {\n\
+            bb0:\n  \
+              %0 = copy 1\n  \
+              br %0, bb1, bb2\n\
+            bb1:\n  \
+              store $0, %0\n  \
+              jmp bb3\n\
+            bb2:\n  \
+              %0 = load $0\n  \
+              jmp bb3\n\
+            bb3:\n  \
+              %1 = add %0, 2\n  \
+              ret %1\n\
+            bb4:\n  \
+              jmp bb3\n\
+            }\n\
+        ");
+    }
+}
@@ -0,0 +1,453 @@
+use crate::asm::aarch64::{Address, MInst, Operand as Op, Reg, FP, LR};
+use crate::asm::{Directive, Item, Label, Program, Section, Syntax};
+use crate::cfg::{BinOp, BlockId, Function, Inst, Operand, Slot, Terminator, VReg};
+use crate::regalloc::{allocate, Allocation, Location, RegisterInfo};
+use crate::{ir, ssa, IR};
+use std::convert::TryFrom;
+
+// Assembly file with code as a global function.
+pub fn assemble(name: &str, code: Vec<MInst>) -> Program<MInst> {
+    let name = Label::new(name);
+    let mut program = Program::new();
+    program
+        .directive(Directive::Section(Section::Text))
+        .directive(Directive::Global(name.clone()))
+        .label(name);
+    for inst in code {
+        match inst {
+            MInst::Label(l) => program.items.push(Item::Label(l)),
+            _ => program.items.push(Item::Inst(inst)),
+        }
+    }
+    program
+}
+
+pub fn compile(ir: &IR) -> Result<String, ir::VerifyError> {
+    Ok(compile_function(&Function::try_from(ir)?))
+}
+
+// Registers for the allocator following AAPCS64, caller-saved first. x16 and
+// x17 (the intra-procedure-call scratch registers) hold operands loaded from
+// spill slots or immediates. x8 is free since nothing returns a struct, so it
+// holds the quotient of a remainder. x18 is the platform register.
+pub const REGISTERS: &[RegisterInfo] = &[
+    RegisterInfo { name: "x9", callee_saved: false },
+    RegisterInfo { name: "x10", callee_saved: false },
+    RegisterInfo { name: "x11", callee_saved: false },
+    RegisterInfo { name: "x12", callee_saved: false },
+    RegisterInfo { name: "x13", callee_saved: false },
+    RegisterInfo { name: "x14", callee_saved: false },
+    RegisterInfo { name: "x15", callee_saved: false },
+    RegisterInfo { name: "x19", callee_saved: true },
+    RegisterInfo { name: "x20", callee_saved: true },
+    RegisterInfo { name: "x21", callee_saved: true },
+    RegisterInfo { name: "x22", callee_saved: true },
+    RegisterInfo { name: "x23", callee_saved: true },
+    RegisterInfo { name: "x24", callee_saved: true },
+    RegisterInfo { name: "x25", callee_saved: true },
+    RegisterInfo { name: "x26", callee_saved: true },
+    RegisterInfo { name: "x27", callee_saved: true },
+    RegisterInfo { name: "x28", callee_saved: true },
+];
+
+// Same order as REGISTERS
+const ALLOCATABLE: &[Reg] = &[
+    Reg::X(9),
+    Reg::X(10),
+    Reg::X(11),
+    Reg::X(12),
+    Reg::X(13),
+    Reg::X(14),
+    Reg::X(15),
+    Reg::X(19),
+    Reg::X(20),
+    Reg::X(21),
+    Reg::X(22),
+    Reg::X(23),
+    Reg::X(24),
+    Reg::X(25),
+    Reg::X(26),
+    Reg::X(27),
+    Reg::X(28),
+];
+
+const X0: Reg = Reg::X(0);
+const X8: Reg = Reg::X(8);
+const IP0: Reg = Reg::X(16);
+const IP1: Reg = Reg::X(17);
+
+// Immediate of add and sub
+fn imm12(i: i64) -> Option<Op> {
+    (0..4096).contains(&i).then_some(Op::Imm(i as u16))
+}
+
+// movz or movn for the first 16 bits, then movk for the rest. movn is used when
+// more halves are all ones than all zeros, which is the case for small negative
+// values.
+pub fn mov_imm(dst: Reg, i: i64) -> Vec<MInst> {
+    let halves: Vec<u16> = (0..4).map(|k| (i >> (16 * k)) as u16).collect();
+    let ones = halves.iter().filter(|h| **h == 0xffff).count();
+    let zeros = halves.iter().filter(|h| **h == 0).count();
+    let fill = if ones > zeros { 0xffff } else { 0 };
+    let mut code = vec![];
+    for (k, half) in halves.iter().enumerate() {
+        let shift = 16 * k as u8;
+        if *half == fill {
+            continue;
+        }
+        code.push(match code.is_empty() {
+            true if fill == 0 => MInst::Movz(dst, *half, shift),
+            true => MInst::Movn(dst, !*half, shift),
+            false => MInst::Movk(dst, *half, shift),
+        });
+    }
+    if code.is_empty() {
+        code.push(if fill == 0 { MInst::Movz(dst, 0, 0) } else { MInst::Movn(dst, 0, 0) });
+    }
+    code
+}
+
+struct FunctionCompiler<'a> {
+    f: &'a Function,
+    allocation: Allocation,
+    code: Vec<MInst>,
+}
+
+impl FunctionCompiler<'_> {
+    fn emit(&mut self, inst: MInst) {
+        self.code.push(inst);
+    }
+
+    fn label(&self, b: BlockId) -> Label {
+        Label(format!(".L{}_{}", self.f.name, b))
+    }
+
+    // Frame below the frame record, from sp up: callee-saved registers, spill
+    // slots, then stack slots. sp stays 16-byte aligned.
+    fn frame_offset(&self, i: usize) -> i64 {
+        8 * (self.allocation.callee_saved.len() + i) as i64
+    }
+
+    fn frame_size(&self) -> i64 {
+        let size = self.frame_offset(self.allocation.spill_count + self.f.slot_count);
+        (size + 15) & !15
+    }
+
+    fn spill(&self, i: usize) -> Address {
+        Address::Offset(Reg::Sp, self.frame_offset(i))
+    }
+
+    fn slot(&self, slot: Slot) -> Address {
+        Address::Offset(Reg::Sp, self.frame_offset(self.allocation.spill_count + slot.0))
+    }
+
+    // A register holding v, loaded into scratch unless v is in a register.
+    fn reg(&mut self, v: Operand, scratch: Reg) -> Reg {
+        match v {
+            Operand::Imm(0) => Reg::Xzr,
+            Operand::Imm(i) => {
+                self.code.extend(mov_imm(scratch, i));
+                scratch
+            }
+            Operand::Reg(r) => match self.allocation.location(r) {
+                Location::Reg(i) => ALLOCATABLE[i],
+                Location::Spill(i) => {
+                    self.emit(MInst::Ldr(scratch, self.spill(i)));
+                    scratch
+                }
+            },
+        }
+    }
+
+    // The register to compute r in. Spilled values go through ip0 and are stored
+    // by def_done.
+    fn def(&self, r: VReg) -> Reg {
+        match self.allocation.location(r) {
+            Location::Reg(i) => ALLOCATABLE[i],
+            Location::Spill(_) => IP0,
+        }
+    }
+
+    fn def_done(&mut self, r: VReg) {
+        if let Location::Spill(i) = self.allocation.location(r) {
+            self.emit(MInst::Str(IP0, self.spill(i)));
+        }
+    }
+
+    fn move_into(&mut self, dst: Reg, v: Operand) {
+        if let Operand::Imm(i) = v {
+            self.code.extend(mov_imm(dst, i));
+            return;
+        }
+        let src = self.reg(v, dst);
+        if src != dst {
+            self.emit(MInst::Mov(dst, src));
+        }
+    }
+
+    fn binary(&mut self, op: BinOp, dst: VReg, lhs: Operand, rhs: Operand) {
+        // add and sub take 12-bit immediates. A negative one flips the operation.
+        let imm = match (op, rhs) {
+            (BinOp::Add | BinOp::Sub, Operand::Imm(i)) => match (imm12(i), i.checked_neg().and_then(imm12)) {
+                (Some(v), _) => Some((op == BinOp::Add, v)),
+                (None, Some(v)) => Some((op == BinOp::Sub, v)),
+                _ => None,
+            },
+            _ => None,
+        };
+        let lhs = match self.reg(lhs, IP0) {
+            // Register 31 is sp in the immediate form.
+            Reg::Xzr if imm.is_some() => {
+                self.emit(MInst::Movz(IP0, 0, 0));
+                IP0
+            }
+            r => r,
+        };
+        let d = self.def(dst);
+        if let Some((add, v)) = imm {
+            self.emit(if add { MInst::Add(d, lhs, v) } else { MInst::Sub(d, lhs, v) });
+        } else {
+            let rhs = self.reg(rhs, IP1);
+            let inst = match op {
+                BinOp::Add => MInst::Add(d, lhs, Op::Reg(rhs)),
+                BinOp::Sub => MInst::Sub(d, lhs, Op::Reg(rhs)),
+                BinOp::Mul => MInst::Mul(d, lhs, rhs),
+                BinOp::Div => MInst::Sdiv(d, lhs, rhs),
+                // lhs - lhs / rhs * rhs
+                BinOp::Rem => {
+                    self.emit(MInst::Sdiv(X8, lhs, rhs));
+                    MInst::Msub(d, X8, rhs, lhs)
+                }
+            };
+            self.emit(inst);
+        }
+        self.def_done(dst);
+    }
+
+    fn inst(&mut self, inst: &Inst) {
+        match inst {
+            Inst::Copy { dst, src } => {
+                let d = self.def(*dst);
+                self.move_into(d, *src);
+                self.def_done(*dst);
+            }
+            Inst::Binary { op, dst, lhs, rhs } => self.binary(*op, *dst, *lhs, *rhs),
+            Inst::Load { dst, slot } => {
+                let d = self.def(*dst);
+                self.emit(MInst::Ldr(d, self.slot(*slot)));
+                self.def_done(*dst);
+            }
+            Inst::Store { slot, src } => {
+                let src = self.reg(*src, IP0);
+                self.emit(MInst::Str(src, self.slot(*slot)));
+            }
+            // Compiler::codegen rejects globals for --target=aarch64.
+            Inst::Addr { .. } | Inst::LoadMem { .. } | Inst::StoreMem { .. } => {
+                unreachable!("global variable in aarch64 codegen")
+            }
+            Inst::Phi { .. } => unreachable!("phi in codegen"),
+            // Only x86_64 has debug info.
+            Inst::Loc { .. } => {}
+        }
+    }
+
+    fn terminator(&mut self, term: &Terminator, next: Option<BlockId>) {
+        let (cond, then, els) = match term {
+            Terminator::Return(v) => {
+                self.move_into(X0, *v);
+                self.epilogue();
+                return;
+            }
+            Terminator::Jump(b) => (None, *b, *b),
+            Terminator::Branch { cond: Operand::Imm(c), then, els } => (None, *then, if *c != 0 { *then } else { *els }),
+            Terminator::Branch { cond, then, els } => (Some(self.reg(*cond, IP0)), *then, *els),
+        };
+        match cond {
+            None if Some(els) == next => {}
+            None => self.emit(MInst::B(self.label(els))),
+            Some(cond) if Some(then) == next => self.emit(MInst::Cbz(cond, self.label(els))),
+            Some(cond) => {
+                self.emit(MInst::Cbnz(cond, self.label(then)));
+                if Some(els) != next {
+                    self.emit(MInst::B(self.label(els)));
+                }
+            }
+        }
+    }
+
+    // Callee-saved registers in pairs where possible
+    fn save_area(&self) -> Vec<(i64, Vec<Reg>)> {
+        let regs: Vec<Reg> = self.allocation.callee_saved.iter().map(|r| ALLOCATABLE[*r]).collect();
+        regs.chunks(2).enumerate().map(|(i, pair)| (16 * i as i64, pair.to_vec())).collect()
+    }
+
+    // Pushes the frame record (fp and lr) so the frame chain and the return
+    // address survive calls, then allocates the frame.
+    fn prologue(&mut self) {
+        self.emit(MInst::Stp(FP, LR, Address::PreIndex(Reg::Sp, -16)));
+        self.emit(MInst::Mov(FP, Reg::Sp));
+        let size = self.frame_size();
+        if size > 0 {
+            match imm12(size) {
+                Some(v) => self.emit(MInst::Sub(Reg::Sp, Reg::Sp, v)),
+                None => {
+                    self.code.extend(mov_imm(IP0, size));
+                    self.emit(MInst::Sub(Reg::Sp, Reg::Sp, Op::Reg(IP0)));
+                }
+            }
+        }
+        for (offset, regs) in self.save_area() {
+            self.emit(match regs[..] {
+                [a, b] => MInst::Stp(a, b, Address::Offset(Reg::Sp, offset)),
+                _ => MInst::Str(regs[0], Address::Offset(Reg::Sp, offset)),
+            });
+        }
+    }
+
+    fn epilogue(&mut self) {
+        for (offset, regs) in self.save_area() {
+            self.emit(match regs[..] {
+                [a, b] => MInst::Ldp(a, b, Address::Offset(Reg::Sp, offset)),
+                _ => MInst::Ldr(regs[0], Address::Offset(Reg::Sp, offset)),
+            });
+        }
+        if self.frame_size() > 0 {
+            self.emit(MInst::Mov(Reg::Sp, FP));
+        }
+        self.emit(MInst::Ldp(FP, LR, Address::PostIndex(Reg::Sp, 16)));
+        self.emit(MInst::Ret);
+    }
+}
+
+// Lowers the CFG with registers from the linear scan allocator.
+pub fn lower_function(f: &Function) -> Vec<MInst> {
+    let mut f = f.clone();
+    if f.blocks.iter().any(|b| b.insts.iter().any(Inst::is_phi)) {
+        ssa::destruct(&mut f);
+    }
+    let allocation = allocate(&f, REGISTERS);
+    let mut compiler = FunctionCompiler { f: &f, allocation, code: vec![] };
+    compiler.prologue();
+    let order = compiler.allocation.order.clone();
+    for (i, id) in order.iter().enumerate() {
+        if i > 0 {
+            compiler.emit(MInst::Label(compiler.label(*id)));
+        }
+        let block = f.block(*id);
+        for inst in &block.insts {
+            compiler.inst(inst);
+        }
+        compiler.terminator(&block.term, order.get(i + 1).copied());
+    }
+    compiler.code
+}
+
+pub fn compile_function(f: &Function) -> String {
+    assemble(&f.name, lower_function(f)).to_string(Syntax::default())
+}
+
+#[test]
+fn test_mov_imm() {
+    let x = Reg::X(9);
+    assert_eq!(mov_imm(x, 0), vec![MInst::Movz(x, 0, 0)]);
+    assert_eq!(mov_imm(x, 7), vec![MInst::Movz(x, 7, 0)]);
+    assert_eq!(mov_imm(x, -1), vec![MInst::Movn(x, 0, 0)]);
+    assert_eq!(mov_imm(x, -2), vec![MInst::Movn(x, 1, 0)]);
+    assert_eq!(mov_imm(x, 0x1_0000), vec![MInst::Movz(x, 1, 16)]);
+    assert_eq!(mov_imm(x, i64::MAX), vec![
+        MInst::Movn(x, 0x8000, 48),
+    ]);
+    assert_eq!(mov_imm(x, 0x1234_0000_5678), vec![MInst::Movz(x, 0x5678, 0), MInst::Movk(x, 0x1234, 32)]);
+    assert_eq!(MInst::Movk(x, 0x1234, 32).to_string(), "movk x9, #4660, lsl #32");
+}
+
+#[test]
+fn test_allocatable() {
+    let names: Vec<String> = ALLOCATABLE.iter().map(Reg::to_string).collect();
+    assert_eq!(names, REGISTERS.iter().map(|r| r.name).collect::<Vec<_>>());
+}
+
+#[cfg(test)]
+fn compile_source(code: &str) -> String {
+    let f: Function = code.parse().unwrap();
+    compile_function(&f)
+}
+
+#[test]
+fn test_compile_function() {
+    let s = compile_source("
+        fn main {
+        bb0:
+          %0 = copy 7
+          %1 = div %0, 2
+          %2 = rem %1, 5000
+          %3 = add %2, 1
+          %4 = sub %3, -2
+          %5 = mul %4, %4
+          ret %5
+        }
+    ");
+    assert_eq!(s, "\
+        .text\n\
+        .global main\n\
+        main:\n\
+        \tstp x29, x30, [sp, #-16]!\n\
+        \tmov x29, sp\n\
+        \tmovz x9, #7\n\
+        \tmovz x17, #2\n\
+        \tsdiv x9, x9, x17\n\
+        \tmovz x17, #5000\n\
+        \tsdiv x8, x9, x17\n\
+        \tmsub x9, x8, x17, x9\n\
+        \tadd x9, x9, #1\n\
+        \tadd x9, x9, #2\n\
+        \tmul x9, x9, x9\n\
+        \tmov x0, x9\n\
+        \tldp x29, x30, [sp], #16\n\
+        \tret\n\
+    ");
+}
+
+#[test]
+fn test_compile_function_branch() {
+    let s = compile_source("
+        fn main {
+        bb0:
+          store $0, 3
+          jmp bb1
+        bb1:
+          %0 = load $0
+          %1 = sub %0, 1
+          store $0, %1
+          br %1, bb1, bb2
+        bb2:
+          ret %0
+        }
+    ");
+    assert!(s.contains("\tsub sp, sp, #16\n"));
+    assert!(s.contains("\tstr x16, [sp]\n"));
+    assert!(s.contains(".Lmain_bb1:\n"));
+    assert!(s.contains("\tcbnz x10, .Lmain_bb1\n"));
+    assert!(s.contains("\tmov sp, x29\n"));
+    assert!(!s.contains("\tb "));
+}
+
+#[test]
+fn test_compile_function_spill() {
+    // 18 values are live at once but only 17 registers are available.
+    let mut code = String::from("fn main {\nbb0:\n");
+    for i in 0..18 {
+        code += &format!("%{} = copy {}\n", i, i + 1);
+    }
+    code += "%18 = add %0, %1\n";
+    for i in 2..18 {
+        code += &format!("%{} = add %{}, %{}\n", i + 17, i + 16, i);
+    }
+    code += "ret %33\n}\n";
+    let s = compile_source(&code);
+    // Ten callee-saved registers in five pairs, then the spill slot.
+    assert!(s.contains("\tsub sp, sp, #96\n"));
+    assert!(s.contains("\tstp x19, x20, [sp]\n"));
+    assert!(s.contains("\tldp x27, x28, [sp, #64]\n"));
+    assert!(s.contains("[sp, #80]"));
+}
@@ -0,0 +1,265 @@
+use crate::cfg::{BinOp, BlockId, Function, Inst, Operand, Terminator};
+use crate::{ir, ssa, Global, Init, IR};
+use std::convert::TryFrom;
+use std::fmt::Write;
+
+pub fn compile(ir: &IR) -> Result<String, ir::VerifyError> {
+    Ok(compile_function(&Function::try_from(ir)?, &ir.globals))
+}
+
+// Names that aren't C identifiers, like n.1 of a static local or .str.0 of a
+// string literal, become reserved ones.
+fn mangle(name: &str) -> String {
+    if name.contains('.') {
+        format!("__{}", name.replace('.', "_"))
+    } else {
+        name.to_string()
+    }
+}
+
+fn int_type(size: usize) -> String {
+    format!("int{}_t", size * 8)
+}
+
+// `static const uint8_t __str_0[3]`
+fn declarator(g: &Global) -> String {
+    let storage = if g.local { "static " } else { "extern " };
+    let qualifier = if g.readonly { "const " } else { "" };
+    match g.init {
+        Init::Bytes(_) => format!("{}{}uint8_t {}[{}]", storage, qualifier, mangle(&g.name), g.size),
+        _ => format!("{}{}{} {}", storage, qualifier, int_type(g.size), mangle(&g.name)),
+    }
+}
+
+// Declarations of all globals first, so initializers can take the address
+// of any of them.
+fn globals(out: &mut String, globals: &[Global]) {
+    for g in globals {
+        writeln!(out, "{};", declarator(g)).unwrap();
+    }
+    for g in globals.iter().filter(|g| !g.external) {
+        let decl = declarator(g);
+        let decl = decl.strip_prefix("extern ").unwrap_or(&decl);
+        match &g.init {
+            Init::Zero => writeln!(out, "{};", decl),
+            Init::Int(i64::MIN) => writeln!(out, "{} = INT64_MIN;", decl),
+            Init::Int(v) => writeln!(out, "{} = {};", decl, v),
+            Init::Addr(name, offset) => {
+                writeln!(out, "{} = (int64_t)((char *)&{} + {});", decl, mangle(name), offset)
+            }
+            Init::Bytes(bytes) => {
+                let bytes: Vec<_> = bytes.iter().map(u8::to_string).collect();
+                writeln!(out, "{} = {{{}}};", decl, bytes.join(", "))
+            }
+        }
+        .unwrap();
+    }
+    if !globals.is_empty() {
+        out.push('\n');
+    }
+}
+
+fn operand(v: Operand) -> String {
+    match v {
+        // -9223372036854775808 would negate an out-of-range constant.
+        Operand::Imm(i64::MIN) => "INT64_MIN".to_string(),
+        Operand::Imm(i) => i.to_string(),
+        Operand::Reg(r) => format!("v{}", r.0),
+    }
+}
+
+fn binary(op: BinOp, lhs: Operand, rhs: Operand) -> String {
+    let (lhs, rhs) = (operand(lhs), operand(rhs));
+    match op {
+        // Signed overflow is undefined, so wrap in unsigned like the interpreter.
+        BinOp::Add | BinOp::Sub | BinOp::Mul => {
+            let op = match op {
+                BinOp::Add => "+",
+                BinOp::Sub => "-",
+                _ => "*",
+            };
+            format!("(int64_t)((uint64_t){} {} (uint64_t){})", lhs, op, rhs)
+        }
+        BinOp::Div => format!("{} / {}", lhs, rhs),
+        BinOp::Rem => format!("{} % {}", lhs, rhs),
+    }
+}
+
+// Portable C in three-address form: one int64_t per virtual register and stack
+// slot, one statement per instruction and gotos between blocks. Blocks are in
+// reverse postorder and only jump targets get a label. Memory is accessed
+// through casts of the int64_t addresses to pointers of the global's type.
+pub fn compile_function(f: &Function, globals: &[Global]) -> String {
+    let mut f = f.clone();
+    if f.blocks.iter().any(|b| b.insts.iter().any(Inst::is_phi)) {
+        ssa::destruct(&mut f);
+    }
+    let order = f.reverse_postorder();
+    let next = |i: usize| order.get(i + 1).copied();
+    let mut targets = vec![false; f.blocks.len()];
+    for (i, id) in order.iter().enumerate() {
+        for s in f.successors(*id) {
+            if Some(s) != next(i) || matches!(f.block(*id).term, Terminator::Branch { .. }) {
+                targets[s.0] = true;
+            }
+        }
+    }
+
+    // Values of expression statements are never used. They become (void)
+    // statements, which keeps -Wall quiet.
+    let mut used = vec![false; f.vreg_count];
+    for block in order.iter().map(|id| f.block(*id)) {
+        for r in block.insts.iter().flat_map(Inst::uses).chain(block.term.uses()) {
+            used[r.0] = true;
+        }
+    }
+
+    let main = f.name == "main";
+    let mut out = String::from("#include <stdint.h>\n\n");
+    self::globals(&mut out, globals);
+    writeln!(out, "{} {}(void) {{", if main { "int" } else { "int64_t" }, f.name).unwrap();
+    // Statics the function doesn't refer to would be unused.
+    let referenced: Vec<_> = f.blocks.iter().flat_map(|b| &b.insts).filter_map(|inst| match inst {
+        Inst::Addr { global, .. } => Some(global),
+        _ => None,
+    }).collect();
+    for g in globals.iter().filter(|g| g.local && !referenced.contains(&&g.name)) {
+        writeln!(out, "  (void)&{};", mangle(&g.name)).unwrap();
+    }
+    for i in (0..f.vreg_count).filter(|i| used[*i]) {
+        writeln!(out, "  int64_t v{};", i).unwrap();
+    }
+    for i in 0..f.slot_count {
+        writeln!(out, "  int64_t s{};", i).unwrap();
+    }
+    let goto = |b: BlockId| format!("goto {};", b);
+    for (i, id) in order.iter().enumerate() {
+        if targets[id.0] {
+            writeln!(out, "{}:", id).unwrap();
+        }
+        let block = f.block(*id);
+        for inst in &block.insts {
+            let value = match inst {
+                Inst::Copy { src, .. } => operand(*src),
+                Inst::Binary { op, lhs, rhs, .. } => binary(*op, *lhs, *rhs),
+                Inst::Load { slot, .. } => format!("s{}", slot.0),
+                Inst::Store { slot, src } => {
+                    writeln!(out, "  s{} = {};", slot.0, operand(*src)).unwrap();
+                    continue;
+                }
+                Inst::Addr { global, .. } => format!("(int64_t)&{}", mangle(global)),
+                Inst::LoadMem { addr, size, .. } => {
+                    format!("*({} *){}", int_type(*size as usize), operand(*addr))
+                }
+                Inst::StoreMem { addr, src, size } => {
+                    let ty = int_type(*size as usize);
+                    writeln!(out, "  *({} *){} = ({}){};", ty, operand(*addr), ty, operand(*src)).unwrap();
+                    continue;
+                }
+                Inst::Phi { .. } => unreachable!("phi in codegen"),
+                Inst::Loc { .. } => continue,
+            };
+            match inst.def() {
+                Some(dst) if used[dst.0] => writeln!(out, "  v{} = {};", dst.0, value).unwrap(),
+                _ => writeln!(out, "  (void)({});", value).unwrap(),
+            }
+        }
+        let line = match &block.term {
+            Terminator::Return(v) if main => format!("return (int){};", operand(*v)),
+            Terminator::Return(v) => format!("return {};", operand(*v)),
+            Terminator::Jump(b) if Some(*b) == next(i) => continue,
+            Terminator::Jump(b) => goto(*b),
+            Terminator::Branch { cond, then, els } => {
+                format!("if ({}) {} else {}", operand(*cond), goto(*then), goto(*els))
+            }
+        };
+        writeln!(out, "  {}", line).unwrap();
+    }
+    out.push_str("}\n");
+    out
+}
+
+#[test]
+fn test_compile() {
+    use crate::Instruction::*;
+    let ir: IR = vec![PushI(7), PushI(2), RemI, PopI, PushI(i64::MIN), PushI(4), MulI, Ret].into();
+    let c = compile(&ir).unwrap();
+    assert!(c.starts_with("#include <stdint.h>\n\nint main(void) {\n  int64_t v1;\n  (void)(7 % 2);\n"));
+    assert!(c.contains(" = (int64_t)((uint64_t)INT64_MIN * (uint64_t)4);\n"));
+    assert!(c.contains("  return (int)v"));
+    assert!(!c.contains("bb0:"));
+}
+
+#[test]
+fn test_compile_function() {
+    let f: Function = "
+        fn count {
+        bb0:
+          store $0, 3
+          jmp bb1
+        bb1:
+          %0 = load $0
+          %1 = sub %0, 1
+          store $0, %1
+          br %1, bb1, bb2
+        bb2:
+          ret %0
+        bb3:
+          ret 5
+        }
+    ".parse().unwrap();
+    assert_eq!(compile_function(&f, &[]), "\
+        #include <stdint.h>\n\
+        \n\
+        int64_t count(void) {\n  \
+          int64_t v0;\n  \
+          int64_t v1;\n  \
+          int64_t s0;\n  \
+          s0 = 3;\n\
+        bb1:\n  \
+          v0 = s0;\n  \
+          v1 = (int64_t)((uint64_t)v0 - (uint64_t)1);\n  \
+          s0 = v1;\n  \
+          if (v1) goto bb1; else goto bb2;\n\
+        bb2:\n  \
+          return v0;\n\
+        }\n\
+    ");
+}
+
+#[test]
+fn test_compile_globals() {
+    let ir: IR = "\
+        global x 4 = -1\n\
+        static c 1\n\
+        global p 8 = &x+4\n\
+        static const .str.0 3 = \"h\\x0a\\x00\"\n\
+        extern e 8\n\
+        pushg x\n\
+        pushg c\n\
+        load 1\n\
+        store 4\n\
+        ret\n"
+        .parse()
+        .unwrap();
+    let c = compile(&ir).unwrap();
+    assert!(c.starts_with("\
+        #include <stdint.h>\n\
+        \n\
+        extern int32_t x;\n\
+        static int8_t c;\n\
+        extern int64_t p;\n\
+        static const uint8_t ___str_0[3];\n\
+        extern int64_t e;\n\
+        int32_t x = -1;\n\
+        static int8_t c;\n\
+        int64_t p = (int64_t)((char *)&x + 4);\n\
+        static const uint8_t ___str_0[3] = {104, 10, 0};\n\
+        \n\
+        int main(void) {\n\
+    "));
+    assert!(c.contains("  (void)&___str_0;\n"));
+    assert!(c.contains(" = (int64_t)&x;\n"));
+    assert!(c.contains(" = *(int8_t *)v"));
+    assert!(c.contains("  *(int32_t *)v0 = (int32_t)v"));
+}
@@ -0,0 +1,484 @@
+use crate::cfg::{BinOp, BlockId, Function, Inst, Operand, Terminator, VReg};
+use crate::{ir, ssa, Global, Init, IR};
+use std::cmp::Ordering;
+use std::convert::TryFrom;
+use std::fmt::Write;
+
+pub fn compile(ir: &IR, triple: &str) -> Result<String, ir::VerifyError> {
+    Ok(compile_function(&Function::try_from(ir)?, &ir.globals, triple))
+}
+
+fn int_type(size: usize) -> String {
+    format!("i{}", size * 8)
+}
+
+// The LLVM type of a global: an integer of its size, or an array of bytes
+fn global_type(g: &Global) -> String {
+    match g.init {
+        Init::Bytes(_) => format!("[{} x i8]", g.size),
+        _ => int_type(g.size),
+    }
+}
+
+fn global(globals: &[Global], name: &str) -> String {
+    let g = globals.iter().find(|g| g.name == name).unwrap();
+    format!("{}* @{}", global_type(g), g.name)
+}
+
+fn define_global(g: &Global, globals: &[Global]) -> String {
+    let linkage = match g.name.starts_with('.') {
+        _ if !g.local => "",
+        true => "private unnamed_addr ",
+        false => "internal ",
+    };
+    let kind = if g.readonly { "constant" } else { "global" };
+    if g.external {
+        return format!("@{} = external {} {}, align {}\n", g.name, kind, global_type(g), g.align());
+    }
+    let init = match &g.init {
+        Init::Zero => "0".to_string(),
+        Init::Int(v) => v.to_string(),
+        Init::Addr(name, 0) => format!("ptrtoint ({} to i64)", global(globals, name)),
+        Init::Addr(name, offset) => {
+            format!("add (i64 ptrtoint ({} to i64), i64 {})", global(globals, name), offset)
+        }
+        Init::Bytes(bytes) => {
+            let mut s = String::from("c\"");
+            for b in bytes {
+                match b {
+                    0x20..=0x7e if *b != b'"' && *b != b'\\' => s.push(*b as char),
+                    _ => write!(s, "\\{:02X}", b).unwrap(),
+                }
+            }
+            s + "\""
+        }
+    };
+    format!("@{} = {}{} {} {}, align {}\n", g.name, linkage, kind, global_type(g), init, g.align())
+}
+
+struct FunctionCompiler<'a> {
+    f: &'a Function,
+    globals: &'a [Global],
+    // Virtual registers defined more than once live in allocas, the others
+    // are SSA values.
+    in_memory: Vec<bool>,
+    // Blocks reachable from the entry. Others aren't emitted.
+    reachable: Vec<bool>,
+    temp_count: usize,
+    out: String,
+}
+
+impl FunctionCompiler<'_> {
+    fn emit(&mut self, line: impl AsRef<str>) {
+        self.out.push_str("  ");
+        self.out.push_str(line.as_ref());
+        self.out.push('\n');
+    }
+
+    fn temp(&mut self) -> String {
+        self.temp_count += 1;
+        format!("%t{}", self.temp_count - 1)
+    }
+
+    // The value of v as an integer of the given width, loaded into a
+    // temporary if v is in memory and converted if v has another width.
+    fn operand(&mut self, v: Operand, width: u8) -> String {
+        let r = match v {
+            Operand::Imm(i) => return ir::truncate(i, width).to_string(),
+            Operand::Reg(r) => r,
+        };
+        let ty = int_type(self.f.width(r) as usize);
+        let value = if self.in_memory[r.0] {
+            let t = self.temp();
+            self.emit(format!("{} = load {1}, {1}* %v{2}.addr", t, ty, r.0));
+            t
+        } else {
+            format!("%v{}", r.0)
+        };
+        match convert(&value, self.f.width(r), width) {
+            Some(conversion) => {
+                let t = self.temp();
+                self.emit(format!("{} = {}", t, conversion));
+                t
+            }
+            None => value,
+        }
+    }
+
+    // Emits `value` as the definition of r.
+    fn define(&mut self, r: VReg, value: String) {
+        if self.in_memory[r.0] {
+            let t = self.temp();
+            self.emit(format!("{} = {}", t, value));
+            let ty = int_type(self.f.width(r) as usize);
+            self.emit(format!("store {0} {1}, {0}* %v{2}.addr", ty, t, r.0));
+        } else {
+            self.emit(format!("%v{} = {}", r.0, value));
+        }
+    }
+
+    // Emits `value`, an integer of the given width, as the definition of r
+    // converted to the width of r.
+    fn define_converted(&mut self, r: VReg, value: String, width: u8) {
+        if width == self.f.width(r) {
+            return self.define(r, value);
+        }
+        let t = self.temp();
+        self.emit(format!("{} = {}", t, value));
+        let conversion = convert(&t, width, self.f.width(r)).unwrap();
+        self.define(r, conversion);
+    }
+
+    fn inst(&mut self, inst: &Inst) {
+        match inst {
+            // There is no copy instruction. A cast to the same type is one.
+            Inst::Copy { dst, src } => {
+                let ty = int_type(self.f.width(*dst) as usize);
+                let src = self.operand(*src, self.f.width(*dst));
+                self.define(*dst, format!("bitcast {0} {1} to {0}", ty, src));
+            }
+            // Wrapping like the interpreter, so no nsw.
+            Inst::Binary { op, dst, lhs, rhs } => {
+                let width = self.f.width(*dst);
+                let (lhs, rhs) = (self.operand(*lhs, width), self.operand(*rhs, width));
+                let op = match op {
+                    BinOp::Add => "add",
+                    BinOp::Sub => "sub",
+                    BinOp::Mul => "mul",
+                    BinOp::Div => "sdiv",
+                    BinOp::Rem => "srem",
+                };
+                let ty = int_type(width as usize);
+                self.define(*dst, format!("{} {} {}, {}", op, ty, lhs, rhs));
+            }
+            Inst::Load { dst, slot } => self.define_converted(*dst, format!("load i64, i64* %s{}", slot.0), 8),
+            Inst::Store { slot, src } => {
+                let src = self.operand(*src, 8);
+                self.emit(format!("store i64 {}, i64* %s{}", src, slot.0));
+            }
+            Inst::Addr { dst, global: name } => {
+                let g = global(self.globals, name);
+                self.define_converted(*dst, format!("ptrtoint {} to i64", g), 8);
+            }
+            Inst::LoadMem { dst, addr, size } => {
+                let ty = int_type(*size as usize);
+                let addr = self.operand(*addr, 8);
+                let p = self.temp();
+                self.emit(format!("{} = inttoptr i64 {} to {}*", p, addr, ty));
+                self.define_converted(*dst, format!("load {0}, {0}* {1}", ty, p), *size);
+            }
+            Inst::StoreMem { addr, src, size } => {
+                let ty = int_type(*size as usize);
+                let (addr, src) = (self.operand(*addr, 8), self.operand(*src, *size));
+                let p = self.temp();
+                self.emit(format!("{} = inttoptr i64 {} to {}*", p, addr, ty));
+                self.emit(format!("store {0} {1}, {0}* {2}", ty, src, p));
+            }
+            // Only in SSA form, where no register is in memory. The arguments
+            // have the width of dst, see compile_function.
+            Inst::Phi { dst, args } => {
+                let width = self.f.width(*dst);
+                let mut incoming = vec![];
+                for (b, v) in args {
+                    if self.reachable[b.0] {
+                        incoming.push(format!("[ {}, %{} ]", self.operand(*v, width), b));
+                    }
+                }
+                self.define(*dst, format!("phi {} {}", int_type(width as usize), incoming.join(", ")));
+            }
+            // There is no debug metadata.
+            Inst::Loc { .. } => {}
+        }
+    }
+
+    fn terminator(&mut self, term: &Terminator) {
+        match term {
+            Terminator::Return(v) => {
+                let v = self.operand(*v, self.f.ret_width);
+                self.emit(format!("ret {} {}", int_type(self.f.ret_width as usize), v));
+            }
+            Terminator::Jump(b) => self.emit(format!("br label %{}", b)),
+            Terminator::Branch { cond: Operand::Imm(c), then, els } => {
+                self.emit(format!("br label %{}", if *c != 0 { then } else { els }));
+            }
+            Terminator::Branch { cond, then, els } => {
+                let width = match cond {
+                    Operand::Reg(r) => self.f.width(*r),
+                    Operand::Imm(_) => 8,
+                };
+                let ty = int_type(width as usize);
+                let cond = self.operand(*cond, width);
+                let t = self.temp();
+                self.emit(format!("{} = icmp ne {} {}, 0", t, ty, cond));
+                self.emit(format!("br i1 {}, label %{}, label %{}", t, then, els));
+            }
+        }
+    }
+
+    fn block(&mut self, id: BlockId) {
+        writeln!(self.out, "{}:", id).unwrap();
+        let block = self.f.block(id);
+        for inst in &block.insts {
+            self.inst(inst);
+        }
+        self.terminator(&block.term);
+    }
+}
+
+// The conversion of value from an integer of one width to another, which
+// sign-extends like the IR
+fn convert(value: &str, from: u8, to: u8) -> Option<String> {
+    let op = match from.cmp(&to) {
+        Ordering::Equal => return None,
+        Ordering::Less => "sext",
+        Ordering::Greater => "trunc",
+    };
+    Some(format!("{} {} {} to {}", op, int_type(from as usize), value, int_type(to as usize)))
+}
+
+// Whether a phi has an argument of another width than its result, which
+// would need a conversion in the predecessor
+fn has_mixed_phi(f: &Function) -> bool {
+    f.blocks.iter().flat_map(|b| &b.insts).any(|inst| match inst {
+        Inst::Phi { dst, args } => args.iter().any(|(_, v)| match v {
+            Operand::Reg(r) => f.width(*r) != f.width(*dst),
+            Operand::Imm(_) => false,
+        }),
+        _ => false,
+    })
+}
+
+// Number of definitions of each virtual register
+fn def_counts(f: &Function) -> Vec<usize> {
+    let mut defs = vec![0; f.vreg_count];
+    for inst in f.blocks.iter().flat_map(|b| &b.insts) {
+        if let Some(r) = inst.def() {
+            defs[r.0] += 1;
+        }
+    }
+    defs
+}
+
+// Textual LLVM IR in the style of clang -O0. Registers are integers of their
+// width in the CFG, which is the size of their C type, and operands of another
+// width are sign-extended or truncated at their use like clang's implicit
+// casts. Stack slots are i64 allocas in an entry block, which also gives bb0 a
+// predecessor-free block in front. Registers that aren't in SSA form become
+// allocas too, so mem2reg cleans up both. Globals are integers of their size,
+// and addresses are ptrtoint'ed to i64 since pointers are integers in the CFG.
+pub fn compile_function(f: &Function, globals: &[Global], triple: &str) -> String {
+    let mut f = f.clone();
+    let mut defs = def_counts(&f);
+    let has_phis = f.blocks.iter().any(|b| b.insts.iter().any(Inst::is_phi));
+    if has_phis && (defs.iter().any(|n| *n > 1) || has_mixed_phi(&f)) {
+        ssa::destruct(&mut f);
+        defs = def_counts(&f);
+    }
+    let order = f.reverse_postorder();
+    let mut reachable = vec![false; f.blocks.len()];
+    for id in &order {
+        reachable[id.0] = true;
+    }
+    let in_memory = defs.iter().map(|n| *n > 1).collect();
+    let mut compiler = FunctionCompiler { f: &f, globals, in_memory, reachable, temp_count: 0, out: String::new() };
+
+    let ret = int_type(f.ret_width as usize);
+    let mut out = format!("target triple = \"{}\"\n\n", triple);
+    for g in globals {
+        out += &define_global(g, globals);
+    }
+    if !globals.is_empty() {
+        out.push('\n');
+    }
+    writeln!(out, "define {} @{}() {{\nentry:", ret, f.name).unwrap();
+    for i in 0..f.slot_count {
+        writeln!(out, "  %s{} = alloca i64", i).unwrap();
+    }
+    for (i, _) in defs.iter().enumerate().filter(|(_, n)| **n > 1) {
+        writeln!(out, "  %v{}.addr = alloca {}", i, int_type(f.width(VReg(i)) as usize)).unwrap();
+    }
+    writeln!(out, "  br label %{}", BlockId(0)).unwrap();
+    for id in order {
+        compiler.block(id);
+    }
+    out.push_str(&compiler.out);
+    out.push_str("}\n");
+    out
+}
+
+#[test]
+fn test_compile() {
+    use crate::Instruction::*;
+    let ir: IR = vec![PushI(7), PushI(2), RemI, PopI, PushI(3), PushI(4), MulI, Ret].into();
+    let ll = compile(&ir, "x86_64-unknown-linux-gnu").unwrap();
+    assert!(ll.starts_with("target triple = \"x86_64-unknown-linux-gnu\"\n\ndefine i32 @main() {\nentry:\n  br label %bb0\nbb0:\n"));
+    assert!(ll.contains(" = srem i64 7, 2\n"));
+    assert!(ll.contains(" = mul i64 3, 4\n"));
+    assert!(ll.contains("  %t0 = trunc i64 %v"));
+    assert!(ll.contains("  ret i32 %t0\n"));
+}
+
+#[test]
+fn test_compile_function() {
+    let f: Function = "
+        fn count {
+        bb0:
+          store $0, 3
+          jmp bb1
+        bb1:
+          %0 = load $0
+          %1 = sub %0, 1
+          store $0, %1
+          br %1, bb1, bb2
+        bb2:
+          ret %0
+        bb3:
+          ret 5
+        }
+    ".parse().unwrap();
+    assert_eq!(compile_function(&f, &[], "riscv64-unknown-linux-gnu"), "\
+        target triple = \"riscv64-unknown-linux-gnu\"\n\
+        \n\
+        define i64 @count() {\n\
+        entry:\n  \
+          %s0 = alloca i64\n  \
+          br label %bb0\n\
+        bb0:\n  \
+          store i64 3, i64* %s0\n  \
+          br label %bb1\n\
+        bb1:\n  \
+          %v0 = load i64, i64* %s0\n  \
+          %v1 = sub i64 %v0, 1\n  \
+          store i64 %v1, i64* %s0\n  \
+          %t0 = icmp ne i64 %v1, 0\n  \
+          br i1 %t0, label %bb1, label %bb2\n\
+        bb2:\n  \
+          ret i64 %v0\n\
+        }\n\
+    ");
+}
+
+#[test]
+fn test_compile_function_phi() {
+    let f: Function = "
+        fn main {
+        bb0:
+          br 1, bb1, bb2
+        bb1:
+          jmp bb2
+        bb2:
+          %0 = phi [bb0, 1], [bb1, 2]
+          ret %0
+        }
+    ".parse().unwrap();
+    let ll = compile_function(&f, &[], "x86_64-unknown-linux-gnu");
+    assert!(ll.contains("bb0:\n  br label %bb1\n"));
+    assert!(ll.contains("  %v0 = phi i64 [ 1, %bb0 ], [ 2, %bb1 ]\n"));
+
+    // Registers with more than one definition are in memory.
+    let f: Function = "
+        fn main {
+        bb0:
+          %0 = copy 1
+          %0 = add %0, 2
+          ret %0
+        }
+    ".parse().unwrap();
+    let ll = compile_function(&f, &[], "x86_64-unknown-linux-gnu");
+    assert!(ll.contains("entry:\n  %v0.addr = alloca i64\n  br label %bb0\n"));
+    assert!(ll.contains("\
+        bb0:\n  \
+          %t0 = bitcast i64 1 to i64\n  \
+          store i64 %t0, i64* %v0.addr\n  \
+          %t1 = load i64, i64* %v0.addr\n  \
+          %t2 = add i64 %t1, 2\n  \
+          store i64 %t2, i64* %v0.addr\n\
+    "));
+}
+
+#[test]
+fn test_compile_globals() {
+    let ir: IR = "\
+        global x 4 = -1\n\
+        static c 1\n\
+        global p 8 = &x+4\n\
+        static const .str.0 3 = \"h\\x22\\x00\"\n\
+        extern e 8\n\
+        pushg x\n\
+        pushg c\n\
+        load 1\n\
+        store 4\n\
+        ret\n"
+        .parse()
+        .unwrap();
+    let ll = compile(&ir, "x86_64-unknown-linux-gnu").unwrap();
+    assert!(ll.contains("\n\n\
+        @x = global i32 -1, align 4\n\
+        @c = internal global i8 0, align 1\n\
+        @p = global i64 add (i64 ptrtoint (i32* @x to i64), i64 4), align 8\n\
+        @.str.0 = private unnamed_addr constant [3 x i8] c\"h\\22\\00\", align 1\n\
+        @e = external global i64, align 8\n\
+        \n\
+        define i32 @main() {\n\
+    "));
+    assert!(ll.contains(" = ptrtoint i32* @x to i64\n"));
+    assert!(ll.contains(" = load i8, i8* %t"));
+    assert!(ll.contains(" = sext i8 %t"));
+    assert!(ll.contains(" = trunc i64 %v"));
+    assert!(ll.contains("  store i32 %t"));
+}
+
+// The conversions and arithmetic of each instruction, like `sext i8 to i32` or
+// `mul i32`, ignoring nsw, which only clang adds.
+#[cfg(test)]
+fn operations(ll: &str) -> Vec<String> {
+    ll.lines()
+        .filter_map(|line| line.split_once(" = ").map(|(_, inst)| inst.replace("nsw ", "")))
+        .filter_map(|inst| {
+            let words: Vec<&str> = inst.split_whitespace().collect();
+            match words.as_slice() {
+                [op @ ("sext" | "zext" | "trunc"), from, _, "to", to] => Some(format!("{} {} to {}", op, from, to)),
+                [op @ ("add" | "sub" | "mul" | "sdiv" | "srem"), ty, ..] => Some(format!("{} {}", op, ty)),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+#[test]
+fn test_compile_types() {
+    use crate::{Compiler, Source};
+    let code = "char c = 100; int x = 3; long l; l = c * x + 1; c = l / x; return c % 7;";
+    let ll = compile(&Compiler::default().compile(&Source::inline(code)).ir.unwrap(), "x86_64-unknown-linux-gnu").unwrap();
+    // clang -S -emit-llvm -O0 of the same statements in main
+    let clang = "
+        define dso_local i32 @main() #0 {
+          %1 = alloca i32, align 4
+          store i32 0, i32* %1, align 4
+          %2 = load i8, i8* @c, align 1
+          %3 = sext i8 %2 to i32
+          %4 = load i32, i32* @x, align 4
+          %5 = mul nsw i32 %3, %4
+          %6 = add nsw i32 %5, 1
+          %7 = sext i32 %6 to i64
+          store i64 %7, i64* @l, align 8
+          %8 = load i64, i64* @l, align 8
+          %9 = load i32, i32* @x, align 4
+          %10 = sext i32 %9 to i64
+          %11 = sdiv i64 %8, %10
+          %12 = trunc i64 %11 to i8
+          store i8 %12, i8* @c, align 1
+          %13 = load i8, i8* @c, align 1
+          %14 = sext i8 %13 to i32
+          %15 = srem i32 %14, 7
+          ret i32 %15
+        }
+    ";
+    assert_eq!(operations(&ll), operations(clang));
+    assert!(ll.contains("define i32 @main() {\n"));
+    assert!(ll.contains("  ret i32 %v"));
+
+    // The final difference of a cast is narrow.
+    let ll = compile(&Compiler::default().compile(&Source::inline("long l; return (char)l;")).ir.unwrap(), "x86_64-unknown-linux-gnu").unwrap();
+    assert_eq!(operations(&ll), vec!["srem i64", "add i64", "srem i64", "trunc i64 to i8", "sub i8", "sext i8 to i32"]);
+}
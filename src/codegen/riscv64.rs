@@ -0,0 +1,425 @@
+use crate::asm::riscv64::{MInst, Op, Reg, A0, FP, RA, SP, ZERO};
+use crate::asm::{Directive, Item, Label, Program, Section, Syntax};
+use crate::cfg::{BinOp, BlockId, Function, Inst, Operand, Slot, Terminator, VReg};
+use crate::regalloc::{allocate, Allocation, Location, RegisterInfo};
+use crate::{ir, ssa, IR};
+use std::convert::TryFrom;
+
+#[cfg(test)]
+mod assembler;
+#[cfg(test)]
+mod sim;
+
+// Assembly file with code as a global function.
+pub fn assemble(name: &str, code: Vec<MInst>) -> Program<MInst> {
+    let name = Label::new(name);
+    let mut program = Program::new();
+    program
+        .directive(Directive::Section(Section::Text))
+        .directive(Directive::Global(name.clone()))
+        .label(name);
+    for inst in code {
+        match inst {
+            MInst::Label(l) => program.items.push(Item::Label(l)),
+            _ => program.items.push(Item::Inst(inst)),
+        }
+    }
+    program
+}
+
+pub fn compile(ir: &IR) -> Result<String, ir::VerifyError> {
+    Ok(compile_function(&Function::try_from(ir)?))
+}
+
+// Registers for the allocator following the psABI, caller-saved first. t0 and
+// t1 hold operands loaded from spill slots or immediates. a0 is the return
+// value and s0 the frame pointer.
+pub const REGISTERS: &[RegisterInfo] = &[
+    RegisterInfo { name: "t2", callee_saved: false },
+    RegisterInfo { name: "t3", callee_saved: false },
+    RegisterInfo { name: "t4", callee_saved: false },
+    RegisterInfo { name: "t5", callee_saved: false },
+    RegisterInfo { name: "t6", callee_saved: false },
+    RegisterInfo { name: "a1", callee_saved: false },
+    RegisterInfo { name: "a2", callee_saved: false },
+    RegisterInfo { name: "a3", callee_saved: false },
+    RegisterInfo { name: "a4", callee_saved: false },
+    RegisterInfo { name: "a5", callee_saved: false },
+    RegisterInfo { name: "a6", callee_saved: false },
+    RegisterInfo { name: "a7", callee_saved: false },
+    RegisterInfo { name: "s1", callee_saved: true },
+    RegisterInfo { name: "s2", callee_saved: true },
+    RegisterInfo { name: "s3", callee_saved: true },
+    RegisterInfo { name: "s4", callee_saved: true },
+    RegisterInfo { name: "s5", callee_saved: true },
+    RegisterInfo { name: "s6", callee_saved: true },
+    RegisterInfo { name: "s7", callee_saved: true },
+    RegisterInfo { name: "s8", callee_saved: true },
+    RegisterInfo { name: "s9", callee_saved: true },
+    RegisterInfo { name: "s10", callee_saved: true },
+    RegisterInfo { name: "s11", callee_saved: true },
+];
+
+// Same order as REGISTERS
+fn allocatable(i: usize) -> Reg {
+    match i {
+        0..=4 => Reg::t(i as u8 + 2),
+        5..=11 => Reg::a(i as u8 - 4),
+        _ => Reg::s(i as u8 - 11),
+    }
+}
+
+const T0: Reg = Reg(5);
+const T1: Reg = Reg(6);
+
+fn is_imm12(i: i64) -> bool {
+    (-2048..2048).contains(&i)
+}
+
+struct FunctionCompiler<'a> {
+    f: &'a Function,
+    allocation: Allocation,
+    code: Vec<MInst>,
+}
+
+impl FunctionCompiler<'_> {
+    fn emit(&mut self, inst: MInst) {
+        self.code.push(inst);
+    }
+
+    fn label(&self, b: BlockId) -> Label {
+        Label(format!(".L{}_{}", self.f.name, b))
+    }
+
+    // Frame below the frame record, from sp up: callee-saved registers, spill
+    // slots, then stack slots. sp stays 16-byte aligned. Offsets must fit in 12
+    // bits, which limits the frame to 255 values.
+    fn frame_offset(&self, i: usize) -> i64 {
+        8 * (self.allocation.callee_saved.len() + i) as i64
+    }
+
+    fn frame_size(&self) -> i64 {
+        let size = self.frame_offset(self.allocation.spill_count + self.f.slot_count);
+        (size + 15) & !15
+    }
+
+    fn spill(&self, i: usize) -> i64 {
+        self.frame_offset(i)
+    }
+
+    fn slot(&self, slot: Slot) -> i64 {
+        self.frame_offset(self.allocation.spill_count + slot.0)
+    }
+
+    // A register holding v, loaded into scratch unless v is in a register.
+    fn reg(&mut self, v: Operand, scratch: Reg) -> Reg {
+        match v {
+            Operand::Imm(0) => ZERO,
+            Operand::Imm(i) => {
+                self.emit(MInst::Li(scratch, i));
+                scratch
+            }
+            Operand::Reg(r) => match self.allocation.location(r) {
+                Location::Reg(i) => allocatable(i),
+                Location::Spill(i) => {
+                    self.emit(MInst::Ld(scratch, self.spill(i), SP));
+                    scratch
+                }
+            },
+        }
+    }
+
+    // The register to compute r in. Spilled values go through t0 and are stored
+    // by def_done.
+    fn def(&self, r: VReg) -> Reg {
+        match self.allocation.location(r) {
+            Location::Reg(i) => allocatable(i),
+            Location::Spill(_) => T0,
+        }
+    }
+
+    fn def_done(&mut self, r: VReg) {
+        if let Location::Spill(i) = self.allocation.location(r) {
+            self.emit(MInst::Sd(T0, self.spill(i), SP));
+        }
+    }
+
+    fn move_into(&mut self, dst: Reg, v: Operand) {
+        if let Operand::Imm(i) = v {
+            self.emit(MInst::Li(dst, i));
+            return;
+        }
+        let src = self.reg(v, dst);
+        if src != dst {
+            self.emit(MInst::Mv(dst, src));
+        }
+    }
+
+    // sp += imm
+    fn adjust_sp(&mut self, imm: i64) {
+        if is_imm12(imm) {
+            self.emit(MInst::Addi(SP, SP, imm));
+        } else {
+            self.emit(MInst::Li(T0, imm));
+            self.emit(MInst::R(Op::Add, SP, SP, T0));
+        }
+    }
+
+    fn binary(&mut self, op: BinOp, dst: VReg, lhs: Operand, rhs: Operand) {
+        let lhs = self.reg(lhs, T0);
+        let d = self.def(dst);
+        // addi takes a 12-bit immediate. Subtraction adds the negated one.
+        let imm = match (op, rhs) {
+            (BinOp::Add, Operand::Imm(i)) => Some(i),
+            (BinOp::Sub, Operand::Imm(i)) => i.checked_neg(),
+            _ => None,
+        };
+        match imm {
+            Some(i) if is_imm12(i) => self.emit(MInst::Addi(d, lhs, i)),
+            _ => {
+                let rhs = self.reg(rhs, T1);
+                let op = match op {
+                    BinOp::Add => Op::Add,
+                    BinOp::Sub => Op::Sub,
+                    BinOp::Mul => Op::Mul,
+                    BinOp::Div => Op::Div,
+                    BinOp::Rem => Op::Rem,
+                };
+                self.emit(MInst::R(op, d, lhs, rhs));
+            }
+        }
+        self.def_done(dst);
+    }
+
+    fn inst(&mut self, inst: &Inst) {
+        match inst {
+            Inst::Copy { dst, src } => {
+                let d = self.def(*dst);
+                self.move_into(d, *src);
+                self.def_done(*dst);
+            }
+            Inst::Binary { op, dst, lhs, rhs } => self.binary(*op, *dst, *lhs, *rhs),
+            Inst::Load { dst, slot } => {
+                let d = self.def(*dst);
+                self.emit(MInst::Ld(d, self.slot(*slot), SP));
+                self.def_done(*dst);
+            }
+            Inst::Store { slot, src } => {
+                let src = self.reg(*src, T0);
+                self.emit(MInst::Sd(src, self.slot(*slot), SP));
+            }
+            // Compiler::codegen rejects globals for --target=riscv64.
+            Inst::Addr { .. } | Inst::LoadMem { .. } | Inst::StoreMem { .. } => {
+                unreachable!("global variable in riscv64 codegen")
+            }
+            Inst::Phi { .. } => unreachable!("phi in codegen"),
+            // Only x86_64 has debug info.
+            Inst::Loc { .. } => {}
+        }
+    }
+
+    fn terminator(&mut self, term: &Terminator, next: Option<BlockId>) {
+        let (cond, then, els) = match term {
+            Terminator::Return(v) => {
+                self.move_into(A0, *v);
+                self.epilogue();
+                return;
+            }
+            Terminator::Jump(b) => (None, *b, *b),
+            Terminator::Branch { cond: Operand::Imm(c), then, els } => (None, *then, if *c != 0 { *then } else { *els }),
+            Terminator::Branch { cond, then, els } => (Some(self.reg(*cond, T0)), *then, *els),
+        };
+        match cond {
+            None if Some(els) == next => {}
+            None => self.emit(MInst::J(self.label(els))),
+            Some(cond) if Some(then) == next => self.emit(MInst::Beqz(cond, self.label(els))),
+            Some(cond) => {
+                self.emit(MInst::Bnez(cond, self.label(then)));
+                if Some(els) != next {
+                    self.emit(MInst::J(self.label(els)));
+                }
+            }
+        }
+    }
+
+    fn callee_saved(&self) -> Vec<(i64, Reg)> {
+        let regs = self.allocation.callee_saved.iter().map(|r| allocatable(*r));
+        regs.enumerate().map(|(i, r)| (8 * i as i64, r)).collect()
+    }
+
+    // Saves ra and the caller's fp in a 16-byte frame record and points fp at
+    // the caller's sp, then allocates the frame.
+    fn prologue(&mut self) {
+        self.emit(MInst::Addi(SP, SP, -16));
+        self.emit(MInst::Sd(RA, 8, SP));
+        self.emit(MInst::Sd(FP, 0, SP));
+        self.emit(MInst::Addi(FP, SP, 16));
+        let size = self.frame_size();
+        if size > 0 {
+            self.adjust_sp(-size);
+        }
+        for (offset, r) in self.callee_saved() {
+            self.emit(MInst::Sd(r, offset, SP));
+        }
+    }
+
+    fn epilogue(&mut self) {
+        for (offset, r) in self.callee_saved() {
+            self.emit(MInst::Ld(r, offset, SP));
+        }
+        if self.frame_size() > 0 {
+            self.emit(MInst::Addi(SP, FP, -16));
+        }
+        self.emit(MInst::Ld(RA, 8, SP));
+        self.emit(MInst::Ld(FP, 0, SP));
+        self.emit(MInst::Addi(SP, SP, 16));
+        self.emit(MInst::Ret);
+    }
+}
+
+// Lowers the CFG with registers from the linear scan allocator.
+pub fn lower_function(f: &Function) -> Vec<MInst> {
+    let mut f = f.clone();
+    if f.blocks.iter().any(|b| b.insts.iter().any(Inst::is_phi)) {
+        ssa::destruct(&mut f);
+    }
+    let allocation = allocate(&f, REGISTERS);
+    let mut compiler = FunctionCompiler { f: &f, allocation, code: vec![] };
+    compiler.prologue();
+    let order = compiler.allocation.order.clone();
+    for (i, id) in order.iter().enumerate() {
+        if i > 0 {
+            compiler.emit(MInst::Label(compiler.label(*id)));
+        }
+        let block = f.block(*id);
+        for inst in &block.insts {
+            compiler.inst(inst);
+        }
+        compiler.terminator(&block.term, order.get(i + 1).copied());
+    }
+    compiler.code
+}
+
+pub fn compile_function(f: &Function) -> String {
+    assemble(&f.name, lower_function(f)).to_string(Syntax::default())
+}
+
+#[test]
+fn test_allocatable() {
+    let names: Vec<String> = (0..REGISTERS.len()).map(|i| allocatable(i).to_string()).collect();
+    assert_eq!(names, REGISTERS.iter().map(|r| r.name).collect::<Vec<_>>());
+}
+
+#[cfg(test)]
+fn compile_source(code: &str) -> String {
+    let f: Function = code.parse().unwrap();
+    compile_function(&f)
+}
+
+#[test]
+fn test_compile_function() {
+    let s = compile_source("
+        fn main {
+        bb0:
+          %0 = copy 7
+          %1 = div %0, 2
+          %2 = rem %1, 5000
+          %3 = sub %2, -2
+          %4 = mul %3, %3
+          ret %4
+        }
+    ");
+    assert_eq!(s, "\
+        .text\n\
+        .global main\n\
+        main:\n\
+        \taddi sp, sp, -16\n\
+        \tsd ra, 8(sp)\n\
+        \tsd s0, 0(sp)\n\
+        \taddi s0, sp, 16\n\
+        \tli t2, 7\n\
+        \tli t1, 2\n\
+        \tdiv t2, t2, t1\n\
+        \tli t1, 5000\n\
+        \trem t2, t2, t1\n\
+        \taddi t2, t2, 2\n\
+        \tmul t2, t2, t2\n\
+        \tmv a0, t2\n\
+        \tld ra, 8(sp)\n\
+        \tld s0, 0(sp)\n\
+        \taddi sp, sp, 16\n\
+        \tret\n\
+    ");
+}
+
+// Compiles with the full pipeline and runs the result in the simulator.
+#[cfg(test)]
+fn run(code: &str, opt_level: crate::OptLevel) -> i64 {
+    use crate::{Compiler, Options, Source, Target};
+    let options = Options { target: Target::Riscv64, opt_level, ..Default::default() };
+    let output = Compiler::new(options).compile(&Source::inline(code));
+    let words = assembler::assemble(&output.asm.unwrap().to_string()).unwrap();
+    sim::Machine::new(&words).run(100_000).unwrap()
+}
+
+#[test]
+fn test_execute() {
+    use crate::{interpret, Compiler, OptLevel, Source};
+    let programs = [
+        include_str!("../../shell-tests/programs/arith.c"),
+        include_str!("../../shell-tests/programs/return.c"),
+        include_str!("../../shell-tests/programs/remainder.c"),
+        "0 - 7 / 2 - 7 % 2;",
+        "return 9223372036854775807 - 2 * 2147483648 + 4096;",
+    ];
+    for code in programs {
+        let ir = Compiler::default().compile(&Source::inline(code)).ir.unwrap();
+        let expected = interpret(&ir).unwrap().result;
+        for level in [OptLevel::O0, OptLevel::O1, OptLevel::O2] {
+            assert_eq!(run(code, level), expected, "{} at {:?}", code, level);
+        }
+    }
+}
+
+#[test]
+fn test_execute_spill() {
+    // 30 values are live at once but only 23 registers are available.
+    let mut code = String::from("fn main {\nbb0:\n");
+    for i in 0..30 {
+        code += &format!("%{} = copy {}\n", i, i * 1000);
+    }
+    code += "%30 = add %0, %1\n";
+    for i in 2..30 {
+        code += &format!("%{} = sub %{}, %{}\n", i + 29, i + 28, i);
+    }
+    code += "ret %58\n}\n";
+    let s = compile_source(&code);
+    assert!(s.contains("\tsd s11, 80(sp)\n"));
+    let words = assembler::assemble(&s).unwrap();
+    let expected = 1000 - (2..30).map(|i| i * 1000).sum::<i64>();
+    assert_eq!(sim::Machine::new(&words).run(10_000), Ok(expected));
+}
+
+#[test]
+fn test_execute_branch() {
+    let s = compile_source("
+        fn main {
+        bb0:
+          store $0, 10
+          store $1, 0
+          jmp bb1
+        bb1:
+          %0 = load $0
+          %1 = load $1
+          %2 = add %1, %0
+          store $1, %2
+          %3 = sub %0, 1
+          store $0, %3
+          br %3, bb1, bb2
+        bb2:
+          ret %2
+        }
+    ");
+    let words = assembler::assemble(&s).unwrap();
+    assert_eq!(sim::Machine::new(&words).run(10_000), Ok(55));
+}
@@ -0,0 +1,212 @@
+// Minimal RV64IM assembler for the output of the backend. Directives are
+// ignored and code starts at address 0.
+use crate::asm::riscv64::{Reg, RA, ZERO};
+use std::collections::HashMap;
+
+// An instruction whose encoding may depend on a label address
+enum Pending {
+    Word(u32),
+    Branch { funct3: u32, rs1: Reg, rs2: Reg, label: String },
+    Jal { rd: Reg, label: String },
+}
+
+fn r(funct7: u32, rs2: Reg, rs1: Reg, funct3: u32, rd: Reg, opcode: u32) -> u32 {
+    funct7 << 25 | (rs2.0 as u32) << 20 | (rs1.0 as u32) << 15 | funct3 << 12 | (rd.0 as u32) << 7 | opcode
+}
+
+fn i(imm: i64, rs1: Reg, funct3: u32, rd: Reg, opcode: u32) -> u32 {
+    ((imm as u32) & 0xfff) << 20 | (rs1.0 as u32) << 15 | funct3 << 12 | (rd.0 as u32) << 7 | opcode
+}
+
+fn s(imm: i64, rs2: Reg, rs1: Reg, funct3: u32, opcode: u32) -> u32 {
+    let imm = imm as u32;
+    (imm >> 5 & 0x7f) << 25 | (rs2.0 as u32) << 20 | (rs1.0 as u32) << 15 | funct3 << 12 | (imm & 0x1f) << 7 | opcode
+}
+
+fn b(imm: i64, rs2: Reg, rs1: Reg, funct3: u32) -> u32 {
+    let imm = imm as u32;
+    (imm >> 12 & 1) << 31
+        | (imm >> 5 & 0x3f) << 25
+        | (rs2.0 as u32) << 20
+        | (rs1.0 as u32) << 15
+        | funct3 << 12
+        | (imm >> 1 & 0xf) << 8
+        | (imm >> 11 & 1) << 7
+        | 0x63
+}
+
+fn j(imm: i64, rd: Reg) -> u32 {
+    let imm = imm as u32;
+    (imm >> 20 & 1) << 31 | (imm >> 1 & 0x3ff) << 21 | (imm >> 11 & 1) << 20 | (imm >> 12 & 0xff) << 12 | (rd.0 as u32) << 7 | 0x6f
+}
+
+fn sign_extend(v: u64, bits: u32) -> i64 {
+    ((v << (64 - bits)) as i64) >> (64 - bits)
+}
+
+fn imm12(v: i64) -> Result<i64, String> {
+    match v {
+        -2048..=2047 => Ok(v),
+        _ => Err(format!("Immediate out of range: {}", v)),
+    }
+}
+
+// The sequence of the assembler manual for li: lui and addiw for 32-bit
+// values, otherwise the upper bits recursively, then slli and addi.
+fn li(rd: Reg, v: i64) -> Vec<u32> {
+    let lo12 = sign_extend(v as u64, 12);
+    if v == v as i32 as i64 {
+        let hi20 = ((v + 0x800) >> 12) & 0xfffff;
+        let mut code = vec![];
+        if hi20 != 0 {
+            code.push((hi20 as u32) << 12 | (rd.0 as u32) << 7 | 0x37);
+        }
+        if lo12 != 0 || hi20 == 0 {
+            let (rs1, opcode) = if hi20 != 0 { (rd, 0x1b) } else { (ZERO, 0x13) };
+            code.push(i(lo12, rs1, 0, rd, opcode));
+        }
+        return code;
+    }
+    let hi52 = (v as u64).wrapping_add(0x800) >> 12;
+    let shift = 12 + hi52.trailing_zeros();
+    let hi = sign_extend(hi52 >> (shift - 12), 64 - shift);
+    let mut code = li(rd, hi);
+    code.push(i(shift as i64, rd, 1, rd, 0x13));
+    if lo12 != 0 {
+        code.push(i(lo12, rd, 0, rd, 0x13));
+    }
+    code
+}
+
+fn reg(s: &str) -> Result<Reg, String> {
+    s.parse()
+}
+
+fn int(s: &str) -> Result<i64, String> {
+    s.parse().map_err(|_| format!("Invalid integer: {}", s))
+}
+
+// offset(base)
+fn mem(s: &str) -> Result<(i64, Reg), String> {
+    let (offset, base) = s
+        .strip_suffix(')')
+        .and_then(|s| s.split_once('('))
+        .ok_or_else(|| format!("Invalid memory operand: {}", s))?;
+    Ok((imm12(int(offset)?)?, reg(base)?))
+}
+
+fn instruction(mnemonic: &str, ops: &[&str]) -> Result<Vec<Pending>, String> {
+    use Pending::*;
+    let arith = |funct7, funct3| -> Result<Vec<Pending>, String> {
+        match ops {
+            [rd, rs1, rs2] => Ok(vec![Word(r(funct7, reg(rs2)?, reg(rs1)?, funct3, reg(rd)?, 0x33))]),
+            _ => Err(format!("Expected 3 operands for {}", mnemonic)),
+        }
+    };
+    let branch = |funct3, rs1: &str, label: &str| -> Result<Vec<Pending>, String> {
+        Ok(vec![Branch { funct3, rs1: reg(rs1)?, rs2: ZERO, label: label.to_string() }])
+    };
+    match (mnemonic, ops) {
+        ("add", _) => arith(0, 0),
+        ("sub", _) => arith(0x20, 0),
+        ("mul", _) => arith(1, 0),
+        ("div", _) => arith(1, 4),
+        ("rem", _) => arith(1, 6),
+        ("addi", [rd, rs1, imm]) => Ok(vec![Word(i(imm12(int(imm)?)?, reg(rs1)?, 0, reg(rd)?, 0x13))]),
+        ("ld", [rd, m]) => {
+            let (offset, base) = mem(m)?;
+            Ok(vec![Word(i(offset, base, 3, reg(rd)?, 0x03))])
+        }
+        ("sd", [rs, m]) => {
+            let (offset, base) = mem(m)?;
+            Ok(vec![Word(s(offset, reg(rs)?, base, 3, 0x23))])
+        }
+        ("li", [rd, imm]) => Ok(li(reg(rd)?, int(imm)?).into_iter().map(Word).collect()),
+        ("mv", [rd, rs]) => Ok(vec![Word(i(0, reg(rs)?, 0, reg(rd)?, 0x13))]),
+        ("j", [label]) => Ok(vec![Jal { rd: ZERO, label: label.to_string() }]),
+        ("beqz", [rs, label]) => branch(0, rs, label),
+        ("bnez", [rs, label]) => branch(1, rs, label),
+        ("ret", []) => Ok(vec![Word(i(0, RA, 0, ZERO, 0x67))]),
+        _ => Err(format!("Unknown instruction: {} {}", mnemonic, ops.join(", "))),
+    }
+}
+
+// Machine code of the text section, one word per instruction.
+pub fn assemble(text: &str) -> Result<Vec<u32>, String> {
+    let mut labels = HashMap::new();
+    let mut pending = vec![];
+    for line in text.lines().map(str::trim).filter(|l| !l.is_empty()) {
+        if let Some(label) = line.strip_suffix(':') {
+            labels.insert(label.to_string(), 4 * pending.len() as i64);
+            continue;
+        }
+        if line.starts_with('.') {
+            continue;
+        }
+        let (mnemonic, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let ops: Vec<&str> = rest.split(',').map(str::trim).filter(|s| !s.is_empty()).collect();
+        pending.extend(instruction(mnemonic, &ops).map_err(|e| format!("{}: {}", line, e))?);
+    }
+    let target = |label: &str, pc: i64, bits: u32| -> Result<i64, String> {
+        let offset = labels.get(label).ok_or_else(|| format!("Undefined label: {}", label))? - pc;
+        match offset {
+            _ if offset == sign_extend(offset as u64, bits) => Ok(offset),
+            _ => Err(format!("Branch out of range: {}", label)),
+        }
+    };
+    let mut code = vec![];
+    for (n, p) in pending.iter().enumerate() {
+        let pc = 4 * n as i64;
+        code.push(match p {
+            Pending::Word(w) => *w,
+            Pending::Branch { funct3, rs1, rs2, label } => b(target(label, pc, 13)?, *rs2, *rs1, *funct3),
+            Pending::Jal { rd, label } => j(target(label, pc, 21)?, *rd),
+        });
+    }
+    Ok(code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Encodings from the GNU assembler
+    #[test]
+    fn test_encode() {
+        let code = assemble("
+            .text
+            main:
+            \taddi sp, sp, -16
+            \tsd ra, 8(sp)
+            \tld s0, 0(sp)
+            \trem a0, a1, a2
+            \tsub t2, t2, t1
+            \tmv a0, t2
+            .L1:
+            \tbnez a0, .L1
+            \tj main
+            \tret
+        ").unwrap();
+        assert_eq!(code, vec![
+            0xff010113, 0x00113423, 0x00013403, 0x02c5e533, 0x406383b3, 0x00038513, 0x00051063, 0xfe5ff06f, 0x00008067,
+        ]);
+    }
+
+    #[test]
+    fn test_li() {
+        let x = Reg(10);
+        assert_eq!(li(x, 0), vec![0x00000513]);
+        assert_eq!(li(x, -1), vec![0xfff00513]);
+        // lui a0, 1; addiw a0, a0, -2048
+        assert_eq!(li(x, 2048), vec![0x00001537, 0x8005051b]);
+        // lui a0, 1044897; addiw a0, a0, -1225; slli a0, a0, 13; addi a0, a0, 1516
+        assert_eq!(li(x, -123456789012), vec![0xff1a1537, 0xb375051b, 0x00d51513, 0x5ec50513]);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(assemble("addi a0, a0, 2048").is_err());
+        assert!(assemble("j .L9").is_err());
+        assert!(assemble("foo a0").is_err());
+    }
+}
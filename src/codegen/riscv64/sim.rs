@@ -0,0 +1,184 @@
+// RV64IM simulator for testing the backend without a cross toolchain. Code is
+// at address 0 and main is called with ra set to RETURN, so its ret ends the run.
+use std::convert::TryInto;
+use std::fmt;
+
+const STACK_SIZE: usize = 64 * 1024;
+const STACK_TOP: u64 = 0x8000_0000;
+const RETURN: u64 = 0x4000_0000;
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum SimError {
+    StepLimit,
+    Fetch { pc: u64 },
+    Access { pc: u64, addr: u64 },
+    Illegal { pc: u64, word: u32 },
+}
+
+impl fmt::Display for SimError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SimError::StepLimit => write!(f, "step limit exceeded"),
+            SimError::Fetch { pc } => write!(f, "{:#x}: fetch outside code", pc),
+            SimError::Access { pc, addr } => write!(f, "{:#x}: access outside stack at {:#x}", pc, addr),
+            SimError::Illegal { pc, word } => write!(f, "{:#x}: illegal instruction {:#010x}", pc, word),
+        }
+    }
+}
+
+pub struct Machine<'a> {
+    code: &'a [u32],
+    regs: [i64; 32],
+    pc: u64,
+    stack: Vec<u8>,
+}
+
+fn bits(w: u32, hi: u32, lo: u32) -> u32 {
+    (w >> lo) & ((1 << (hi - lo + 1)) - 1)
+}
+
+fn sign_extend(v: u32, bits: u32) -> i64 {
+    ((v << (32 - bits)) as i32 >> (32 - bits)) as i64
+}
+
+impl<'a> Machine<'a> {
+    pub fn new(code: &'a [u32]) -> Self {
+        let mut regs = [0; 32];
+        regs[1] = RETURN as i64;
+        regs[2] = STACK_TOP as i64;
+        Machine { code, regs, pc: 0, stack: vec![0; STACK_SIZE] }
+    }
+
+    fn set(&mut self, rd: u32, v: i64) {
+        if rd != 0 {
+            self.regs[rd as usize] = v;
+        }
+    }
+
+    fn stack_index(&self, addr: u64) -> Result<usize, SimError> {
+        match STACK_TOP.checked_sub(addr) {
+            Some(d) if d >= 8 && d as usize <= STACK_SIZE => Ok(STACK_SIZE - d as usize),
+            _ => Err(SimError::Access { pc: self.pc, addr }),
+        }
+    }
+
+    fn load(&self, addr: u64) -> Result<i64, SimError> {
+        let i = self.stack_index(addr)?;
+        Ok(i64::from_le_bytes(self.stack[i..i + 8].try_into().unwrap()))
+    }
+
+    fn store(&mut self, addr: u64, v: i64) -> Result<(), SimError> {
+        let i = self.stack_index(addr)?;
+        self.stack[i..i + 8].copy_from_slice(&v.to_le_bytes());
+        Ok(())
+    }
+
+    // Executes one instruction.
+    fn step(&mut self) -> Result<(), SimError> {
+        let pc = self.pc;
+        let word = *self.code.get((pc / 4) as usize).filter(|_| pc.is_multiple_of(4)).ok_or(SimError::Fetch { pc })?;
+        let illegal = Err(SimError::Illegal { pc, word });
+        let (rd, funct3, funct7) = (bits(word, 11, 7), bits(word, 14, 12), bits(word, 31, 25));
+        let rs1 = self.regs[bits(word, 19, 15) as usize];
+        let rs2 = self.regs[bits(word, 24, 20) as usize];
+        let imm_i = sign_extend(bits(word, 31, 20), 12);
+        let mut next = pc.wrapping_add(4);
+        match bits(word, 6, 0) {
+            0x33 => {
+                let v = match (funct7, funct3) {
+                    (0, 0) => rs1.wrapping_add(rs2),
+                    (0x20, 0) => rs1.wrapping_sub(rs2),
+                    (1, 0) => rs1.wrapping_mul(rs2),
+                    // Division by zero and overflow don't trap.
+                    (1, 4) if rs2 == 0 => -1,
+                    (1, 4) => rs1.wrapping_div(rs2),
+                    (1, 6) if rs2 == 0 => rs1,
+                    (1, 6) => rs1.wrapping_rem(rs2),
+                    _ => return illegal,
+                };
+                self.set(rd, v);
+            }
+            0x13 => match funct3 {
+                0 => self.set(rd, rs1.wrapping_add(imm_i)),
+                1 if funct7 >> 1 == 0 => self.set(rd, rs1 << bits(word, 25, 20)),
+                _ => return illegal,
+            },
+            0x1b if funct3 == 0 => self.set(rd, rs1.wrapping_add(imm_i) as i32 as i64),
+            0x37 => self.set(rd, (word & 0xffff_f000) as i32 as i64),
+            0x03 if funct3 == 3 => {
+                let v = self.load(rs1.wrapping_add(imm_i) as u64)?;
+                self.set(rd, v);
+            }
+            0x23 if funct3 == 3 => {
+                let imm = sign_extend(funct7 << 5 | rd, 12);
+                self.store(rs1.wrapping_add(imm) as u64, rs2)?;
+            }
+            0x63 => {
+                let imm = bits(word, 31, 31) << 12 | bits(word, 7, 7) << 11 | bits(word, 30, 25) << 5 | bits(word, 11, 8) << 1;
+                let taken = match funct3 {
+                    0 => rs1 == rs2,
+                    1 => rs1 != rs2,
+                    _ => return illegal,
+                };
+                if taken {
+                    next = pc.wrapping_add(sign_extend(imm, 13) as u64);
+                }
+            }
+            0x6f => {
+                let imm = bits(word, 31, 31) << 20 | bits(word, 19, 12) << 12 | bits(word, 20, 20) << 11 | bits(word, 30, 21) << 1;
+                self.set(rd, next as i64);
+                next = pc.wrapping_add(sign_extend(imm, 21) as u64);
+            }
+            0x67 if funct3 == 0 => {
+                self.set(rd, next as i64);
+                next = (rs1.wrapping_add(imm_i) & !1) as u64;
+            }
+            _ => return illegal,
+        }
+        self.pc = next;
+        Ok(())
+    }
+
+    // Runs main and returns a0 when it returns.
+    pub fn run(&mut self, limit: usize) -> Result<i64, SimError> {
+        for _ in 0..limit {
+            if self.pc == RETURN {
+                return Ok(self.regs[10]);
+            }
+            self.step()?;
+        }
+        Err(SimError::StepLimit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::assembler::assemble;
+    use super::*;
+
+    fn run(code: &str) -> Result<i64, SimError> {
+        Machine::new(&assemble(code).unwrap()).run(1000)
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        assert_eq!(run("li a0, -123456789012\nret"), Ok(-123456789012));
+        assert_eq!(run("li a0, 9223372036854775807\nli t0, 2\nmul a0, a0, t0\nret"), Ok(-2));
+        assert_eq!(run("li a0, -7\nli t0, 2\nrem a0, a0, t0\nret"), Ok(-1));
+        assert_eq!(run("li a0, -7\ndiv a0, a0, zero\nret"), Ok(-1));
+    }
+
+    #[test]
+    fn test_memory() {
+        assert_eq!(run("addi sp, sp, -16\nli t0, 42\nsd t0, 8(sp)\nld a0, 8(sp)\naddi sp, sp, 16\nret"), Ok(42));
+        assert_eq!(run("sd t0, 0(sp)\nret"), Err(SimError::Access { pc: 0, addr: STACK_TOP }));
+    }
+
+    #[test]
+    fn test_control() {
+        assert_eq!(run("li a0, 3\nli t0, 0\n.L1:\nadd t0, t0, a0\naddi a0, a0, -1\nbnez a0, .L1\nmv a0, t0\nret"), Ok(6));
+        assert_eq!(run(".L1:\nj .L1"), Err(SimError::StepLimit));
+        assert_eq!(run("li ra, 0\nret"), Err(SimError::StepLimit));
+        assert_eq!(Machine::new(&[0]).run(1), Err(SimError::Illegal { pc: 0, word: 0 }));
+    }
+}
@@ -0,0 +1,377 @@
+use crate::cfg::{BinOp, BlockId, Function, Inst, Operand, Terminator};
+use crate::Instruction::*;
+use crate::{ssa, IR};
+use std::fmt;
+
+pub mod encode;
+pub use encode::encode;
+#[cfg(test)]
+mod interp;
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum ValType {
+    I32,
+    I64,
+}
+
+impl fmt::Display for ValType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ValType::I32 => write!(f, "i32"),
+            ValType::I64 => write!(f, "i64"),
+        }
+    }
+}
+
+// WebAssembly instructions in the subset the backend emits. Blocks have no
+// results, and loads and stores take a byte offset.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum WInst {
+    Unreachable,
+    Block,
+    Loop,
+    End,
+    Br(u32),
+    // Labels, then the default label
+    BrTable(Vec<u32>, u32),
+    Return,
+    // Picks the first operand if the i32 condition is not zero.
+    Select,
+    LocalGet(u32),
+    LocalSet(u32),
+    LocalTee(u32),
+    GlobalGet(u32),
+    GlobalSet(u32),
+    I64Load(u32),
+    I64Store(u32),
+    I32Const(i32),
+    I64Const(i64),
+    I64Eqz,
+    I32Add,
+    I32Sub,
+    I64Add,
+    I64Sub,
+    I64Mul,
+    I64DivS,
+    I64RemS,
+}
+
+impl fmt::Display for WInst {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use WInst::*;
+        match self {
+            Unreachable => write!(f, "unreachable"),
+            Block => write!(f, "block"),
+            Loop => write!(f, "loop"),
+            End => write!(f, "end"),
+            Br(l) => write!(f, "br {}", l),
+            BrTable(labels, default) => {
+                write!(f, "br_table")?;
+                for l in labels {
+                    write!(f, " {}", l)?;
+                }
+                write!(f, " {}", default)
+            }
+            Return => write!(f, "return"),
+            Select => write!(f, "select"),
+            LocalGet(i) => write!(f, "local.get {}", i),
+            LocalSet(i) => write!(f, "local.set {}", i),
+            LocalTee(i) => write!(f, "local.tee {}", i),
+            GlobalGet(i) => write!(f, "global.get {}", i),
+            GlobalSet(i) => write!(f, "global.set {}", i),
+            I64Load(0) => write!(f, "i64.load"),
+            I64Load(offset) => write!(f, "i64.load offset={}", offset),
+            I64Store(0) => write!(f, "i64.store"),
+            I64Store(offset) => write!(f, "i64.store offset={}", offset),
+            I32Const(i) => write!(f, "i32.const {}", i),
+            I64Const(i) => write!(f, "i64.const {}", i),
+            I64Eqz => write!(f, "i64.eqz"),
+            I32Add => write!(f, "i32.add"),
+            I32Sub => write!(f, "i32.sub"),
+            I64Add => write!(f, "i64.add"),
+            I64Sub => write!(f, "i64.sub"),
+            I64Mul => write!(f, "i64.mul"),
+            I64DivS => write!(f, "i64.div_s"),
+            I64RemS => write!(f, "i64.rem_s"),
+        }
+    }
+}
+
+// A function without parameters returning i64, exported under its name.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Func {
+    pub name: String,
+    pub locals: Vec<ValType>,
+    pub body: Vec<WInst>,
+}
+
+// Global 0 is the stack pointer of the linear-memory stack, which grows down
+// from the end of the first page.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Module {
+    pub funcs: Vec<Func>,
+    pub memory_pages: u32,
+    pub stack_pointer: i32,
+}
+
+pub const PAGE_SIZE: u32 = 65536;
+const STACK_POINTER: u32 = 0;
+
+impl Module {
+    pub fn new(funcs: Vec<Func>) -> Self {
+        Module { funcs, memory_pages: 1, stack_pointer: PAGE_SIZE as i32 }
+    }
+}
+
+// The text format with one instruction per line
+impl fmt::Display for Module {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "(module")?;
+        writeln!(f, "  (memory (export \"memory\") {})", self.memory_pages)?;
+        writeln!(f, "  (global $__stack_pointer (mut i32) (i32.const {}))", self.stack_pointer)?;
+        for func in &self.funcs {
+            write!(f, "  (func ${0} (export \"{0}\") (result i64)", func.name)?;
+            if !func.locals.is_empty() {
+                write!(f, "\n    (local")?;
+                for t in &func.locals {
+                    write!(f, " {}", t)?;
+                }
+                write!(f, ")")?;
+            }
+            let mut depth = 2;
+            for inst in &func.body {
+                if *inst == WInst::End {
+                    depth -= 1;
+                }
+                write!(f, "\n{:width$}{}", "", inst, width = 2 * depth)?;
+                if matches!(inst, WInst::Block | WInst::Loop) {
+                    depth += 1;
+                }
+            }
+            writeln!(f, ")")?;
+        }
+        writeln!(f, ")")
+    }
+}
+
+fn binary(op: BinOp) -> WInst {
+    match op {
+        BinOp::Add => WInst::I64Add,
+        BinOp::Sub => WInst::I64Sub,
+        BinOp::Mul => WInst::I64Mul,
+        BinOp::Div => WInst::I64DivS,
+        BinOp::Rem => WInst::I64RemS,
+    }
+}
+
+// Stack-machine lowering: the IR operand stack is the wasm operand stack. Local
+// 0 holds the value of the last popi, which main returns.
+pub fn lower(ir: &IR) -> Module {
+    let mut body = vec![];
+    for inst in &ir.instructions {
+        body.push(match inst {
+            PushI(i) => WInst::I64Const(*i),
+            PopI => WInst::LocalSet(0),
+            AddI => binary(BinOp::Add),
+            SubI => binary(BinOp::Sub),
+            MulI => binary(BinOp::Mul),
+            DivI => binary(BinOp::Div),
+            RemI => binary(BinOp::Rem),
+            Ret => WInst::Return,
+            PushG(_) | Load(_) | Store(_) => unreachable!("global variable in wasm32 codegen"),
+        });
+    }
+    body.push(WInst::LocalGet(0));
+    Module::new(vec![Func { name: "main".to_string(), locals: vec![ValType::I64], body }])
+}
+
+struct FunctionCompiler<'a> {
+    f: &'a Function,
+    order: Vec<BlockId>,
+    body: Vec<WInst>,
+    // Locals after the virtual registers
+    pc: u32,
+    fp: u32,
+}
+
+impl FunctionCompiler<'_> {
+    fn emit(&mut self, inst: WInst) {
+        self.body.push(inst);
+    }
+
+    fn frame_size(&self) -> i32 {
+        (8 * self.f.slot_count as i32 + 15) & !15
+    }
+
+    fn operand(&mut self, v: Operand) {
+        self.emit(match v {
+            Operand::Reg(r) => WInst::LocalGet(r.0 as u32),
+            Operand::Imm(i) => WInst::I64Const(i),
+        });
+    }
+
+    fn inst(&mut self, inst: &Inst) {
+        match inst {
+            Inst::Copy { dst, src } => {
+                self.operand(*src);
+                self.emit(WInst::LocalSet(dst.0 as u32));
+            }
+            Inst::Binary { op, dst, lhs, rhs } => {
+                self.operand(*lhs);
+                self.operand(*rhs);
+                self.emit(binary(*op));
+                self.emit(WInst::LocalSet(dst.0 as u32));
+            }
+            Inst::Load { dst, slot } => {
+                self.emit(WInst::LocalGet(self.fp));
+                self.emit(WInst::I64Load(8 * slot.0 as u32));
+                self.emit(WInst::LocalSet(dst.0 as u32));
+            }
+            Inst::Store { slot, src } => {
+                self.emit(WInst::LocalGet(self.fp));
+                self.operand(*src);
+                self.emit(WInst::I64Store(8 * slot.0 as u32));
+            }
+            // Compiler::codegen rejects globals for --target=wasm32.
+            Inst::Addr { .. } | Inst::LoadMem { .. } | Inst::StoreMem { .. } => {
+                unreachable!("global variable in wasm32 codegen")
+            }
+            Inst::Phi { .. } => unreachable!("phi in codegen"),
+            // Only x86_64 has debug info.
+            Inst::Loc { .. } => {}
+        }
+    }
+
+    // Sets the block to run next and continues the dispatch loop, unless it's
+    // the next one in order.
+    fn jump(&mut self, i: usize, target: BlockId) {
+        let n = self.order.len();
+        let j = self.order.iter().position(|b| *b == target).unwrap();
+        if j != i + 1 {
+            self.emit(WInst::I32Const(j as i32));
+            self.emit(WInst::LocalSet(self.pc));
+            self.emit(WInst::Br((n - 1 - i) as u32));
+        }
+    }
+
+    fn terminator(&mut self, i: usize, term: &Terminator) {
+        match term {
+            Terminator::Return(v) => {
+                self.operand(*v);
+                if self.f.slot_count > 0 {
+                    self.emit(WInst::LocalGet(self.fp));
+                    self.emit(WInst::I32Const(self.frame_size()));
+                    self.emit(WInst::I32Add);
+                    self.emit(WInst::GlobalSet(STACK_POINTER));
+                }
+                self.emit(WInst::Return);
+            }
+            Terminator::Jump(b) => self.jump(i, *b),
+            Terminator::Branch { cond, then, els } => {
+                let index = |b: &BlockId| self.order.iter().position(|o| o == b).unwrap() as i32;
+                let (then, els) = (index(then), index(els));
+                self.emit(WInst::I32Const(els));
+                self.emit(WInst::I32Const(then));
+                self.operand(*cond);
+                self.emit(WInst::I64Eqz);
+                self.emit(WInst::Select);
+                self.emit(WInst::LocalSet(self.pc));
+                self.emit(WInst::Br((self.order.len() - 1 - i) as u32));
+            }
+        }
+    }
+
+    fn prologue(&mut self) {
+        if self.f.slot_count > 0 {
+            self.emit(WInst::GlobalGet(STACK_POINTER));
+            self.emit(WInst::I32Const(self.frame_size()));
+            self.emit(WInst::I32Sub);
+            self.emit(WInst::LocalTee(self.fp));
+            self.emit(WInst::GlobalSet(STACK_POINTER));
+        }
+    }
+}
+
+// Lowers the CFG with one local per virtual register. Stack slots are in a frame
+// on the linear-memory stack. With more than one block, control flow goes
+// through a loop around nested blocks, where block i of the layout follows the
+// end of the i-th innermost one and br_table dispatches on the pc local.
+pub fn lower_function(f: &Function) -> Module {
+    let mut f = f.clone();
+    if f.blocks.iter().any(|b| b.insts.iter().any(Inst::is_phi)) {
+        ssa::destruct(&mut f);
+    }
+    let order = f.reverse_postorder();
+    let n = order.len();
+    let pc = f.vreg_count as u32;
+    let mut compiler = FunctionCompiler { f: &f, order: order.clone(), body: vec![], pc, fp: pc + 1 };
+    compiler.prologue();
+    if n > 1 {
+        compiler.emit(WInst::Loop);
+        for _ in 0..n {
+            compiler.emit(WInst::Block);
+        }
+        compiler.emit(WInst::LocalGet(pc));
+        compiler.emit(WInst::BrTable((0..n as u32 - 1).collect(), n as u32 - 1));
+    }
+    for (i, id) in order.iter().enumerate() {
+        if n > 1 {
+            compiler.emit(WInst::End);
+        }
+        for inst in &f.block(*id).insts {
+            compiler.inst(inst);
+        }
+        compiler.terminator(i, &f.block(*id).term);
+    }
+    if n > 1 {
+        compiler.emit(WInst::End);
+        compiler.emit(WInst::Unreachable);
+    }
+    let mut locals = vec![ValType::I64; f.vreg_count];
+    locals.extend([ValType::I32, ValType::I32]);
+    Module::new(vec![Func { name: f.name.clone(), locals, body: compiler.body }])
+}
+
+#[test]
+fn test_lower() {
+    let ir: IR = vec![PushI(1), PushI(2), AddI, PopI, PushI(3), Ret].into();
+    assert_eq!(lower(&ir).to_string(), "\
+        (module\n  \
+          (memory (export \"memory\") 1)\n  \
+          (global $__stack_pointer (mut i32) (i32.const 65536))\n  \
+          (func $main (export \"main\") (result i64)\n    \
+            (local i64)\n    \
+            i64.const 1\n    \
+            i64.const 2\n    \
+            i64.add\n    \
+            local.set 0\n    \
+            i64.const 3\n    \
+            return\n    \
+            local.get 0)\n\
+        )\n\
+    ");
+}
+
+#[test]
+fn test_lower_function() {
+    let f: Function = "
+        fn main {
+        bb0:
+          store $0, 3
+          jmp bb1
+        bb1:
+          %0 = load $0
+          %1 = sub %0, 1
+          store $0, %1
+          br %1, bb1, bb2
+        bb2:
+          ret %0
+        }
+    ".parse().unwrap();
+    let wat = lower_function(&f).to_string();
+    assert!(wat.contains("\n    (local i64 i64 i32 i32)\n"));
+    assert!(wat.contains("\n    loop\n      block\n        block\n          block\n            local.get 2\n            br_table 0 1 2\n          end\n"));
+    assert!(wat.contains("\n        i64.store\n"));
+    assert!(wat.contains("\n        i64.eqz\n        select\n        local.set 2\n        br 1\n"));
+    assert!(wat.contains("\n    end\n    unreachable)\n"));
+}
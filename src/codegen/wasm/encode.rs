@@ -0,0 +1,196 @@
+// Binary format of a Module, version 1.
+use super::{Module, ValType, WInst};
+
+pub const MAGIC: &[u8] = b"\0asm";
+pub const VERSION: &[u8] = &[1, 0, 0, 0];
+
+// Section ids
+pub const TYPE: u8 = 1;
+pub const FUNCTION: u8 = 3;
+pub const MEMORY: u8 = 5;
+pub const GLOBAL: u8 = 6;
+pub const EXPORT: u8 = 7;
+pub const CODE: u8 = 10;
+
+pub fn uleb128(out: &mut Vec<u8>, mut v: u64) {
+    loop {
+        let byte = (v & 0x7f) as u8;
+        v >>= 7;
+        if v == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+pub fn sleb128(out: &mut Vec<u8>, mut v: i64) {
+    loop {
+        let byte = (v & 0x7f) as u8;
+        v >>= 7;
+        if (v == 0 && byte & 0x40 == 0) || (v == -1 && byte & 0x40 != 0) {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+pub fn val_type(t: ValType) -> u8 {
+    match t {
+        ValType::I32 => 0x7f,
+        ValType::I64 => 0x7e,
+    }
+}
+
+fn name(out: &mut Vec<u8>, s: &str) {
+    uleb128(out, s.len() as u64);
+    out.extend(s.as_bytes());
+}
+
+fn section(out: &mut Vec<u8>, id: u8, count: usize, content: Vec<u8>) {
+    let mut body = vec![];
+    uleb128(&mut body, count as u64);
+    body.extend(content);
+    out.push(id);
+    uleb128(out, body.len() as u64);
+    out.extend(body);
+}
+
+fn inst(out: &mut Vec<u8>, inst: &WInst) {
+    use WInst::*;
+    match inst {
+        Unreachable => out.push(0x00),
+        // Empty block type
+        Block => out.extend([0x02, 0x40]),
+        Loop => out.extend([0x03, 0x40]),
+        End => out.push(0x0b),
+        Br(l) => {
+            out.push(0x0c);
+            uleb128(out, *l as u64);
+        }
+        BrTable(labels, default) => {
+            out.push(0x0e);
+            uleb128(out, labels.len() as u64);
+            for l in labels {
+                uleb128(out, *l as u64);
+            }
+            uleb128(out, *default as u64);
+        }
+        Return => out.push(0x0f),
+        Select => out.push(0x1b),
+        LocalGet(i) | LocalSet(i) | LocalTee(i) | GlobalGet(i) | GlobalSet(i) => {
+            out.push(match inst {
+                LocalGet(_) => 0x20,
+                LocalSet(_) => 0x21,
+                LocalTee(_) => 0x22,
+                GlobalGet(_) => 0x23,
+                _ => 0x24,
+            });
+            uleb128(out, *i as u64);
+        }
+        // Alignment 2^3, then the offset
+        I64Load(offset) | I64Store(offset) => {
+            out.push(if let I64Load(_) = inst { 0x29 } else { 0x37 });
+            out.push(3);
+            uleb128(out, *offset as u64);
+        }
+        I32Const(i) => {
+            out.push(0x41);
+            sleb128(out, *i as i64);
+        }
+        I64Const(i) => {
+            out.push(0x42);
+            sleb128(out, *i);
+        }
+        I64Eqz => out.push(0x50),
+        I32Add => out.push(0x6a),
+        I32Sub => out.push(0x6b),
+        I64Add => out.push(0x7c),
+        I64Sub => out.push(0x7d),
+        I64Mul => out.push(0x7e),
+        I64DivS => out.push(0x7f),
+        I64RemS => out.push(0x81),
+    }
+}
+
+// Every function has type 0, [] -> [i64]. The memory is exported as "memory".
+pub fn encode(module: &Module) -> Vec<u8> {
+    let mut out = [MAGIC, VERSION].concat();
+    let n = module.funcs.len();
+
+    section(&mut out, TYPE, 1, vec![0x60, 0, 1, val_type(ValType::I64)]);
+    section(&mut out, FUNCTION, n, vec![0; n]);
+
+    let mut memory = vec![0];
+    uleb128(&mut memory, module.memory_pages as u64);
+    section(&mut out, MEMORY, 1, memory);
+
+    let mut global = vec![val_type(ValType::I32), 1];
+    inst(&mut global, &WInst::I32Const(module.stack_pointer));
+    global.push(0x0b);
+    section(&mut out, GLOBAL, 1, global);
+
+    let mut exports = vec![];
+    for (i, func) in module.funcs.iter().enumerate() {
+        name(&mut exports, &func.name);
+        exports.push(0);
+        uleb128(&mut exports, i as u64);
+    }
+    name(&mut exports, "memory");
+    exports.extend([2, 0]);
+    section(&mut out, EXPORT, n + 1, exports);
+
+    let mut code = vec![];
+    for func in &module.funcs {
+        // Runs of locals of the same type
+        let mut runs: Vec<(u32, ValType)> = vec![];
+        for t in &func.locals {
+            match runs.last_mut() {
+                Some((count, last)) if last == t => *count += 1,
+                _ => runs.push((1, *t)),
+            }
+        }
+        let mut body = vec![];
+        uleb128(&mut body, runs.len() as u64);
+        for (count, t) in runs {
+            uleb128(&mut body, count as u64);
+            body.push(val_type(t));
+        }
+        for i in &func.body {
+            inst(&mut body, i);
+        }
+        body.push(0x0b);
+        uleb128(&mut code, body.len() as u64);
+        code.extend(body);
+    }
+    section(&mut out, CODE, n, code);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::Func;
+    use super::*;
+
+    #[test]
+    fn test_leb128() {
+        let mut out = vec![];
+        uleb128(&mut out, 624485);
+        assert_eq!(out, [0xe5, 0x8e, 0x26]);
+        out.clear();
+        sleb128(&mut out, -123456);
+        assert_eq!(out, [0xc0, 0xbb, 0x78]);
+        out.clear();
+        sleb128(&mut out, 64);
+        assert_eq!(out, [0xc0, 0x00]);
+    }
+
+    #[test]
+    fn test_encode() {
+        let func = Func { name: "f".to_string(), locals: vec![], body: vec![WInst::I64Const(-1)] };
+        let bytes = encode(&Module::new(vec![func]));
+        assert_eq!(&bytes[..8], b"\0asm\x01\0\0\0");
+        assert!(bytes.ends_with(&[CODE, 6, 1, 4, 0, 0x42, 0x7f, 0x0b]));
+    }
+}
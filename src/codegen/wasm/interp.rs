@@ -0,0 +1,451 @@
+// Decoder, validator and interpreter for the binary modules of the backend, so
+// tests don't need an engine. Only the sections and instructions encode emits
+// are supported.
+use super::encode::{CODE, EXPORT, GLOBAL, MAGIC, MEMORY, VERSION};
+use super::{ValType, WInst, PAGE_SIZE};
+use std::convert::TryInto;
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn byte(&mut self) -> Result<u8, String> {
+        let b = *self.bytes.get(self.pos).ok_or("unexpected end")?;
+        self.pos += 1;
+        Ok(b)
+    }
+
+    fn leb(&mut self, signed: bool) -> Result<i64, String> {
+        let (mut v, mut shift) = (0i64, 0);
+        loop {
+            let b = self.byte()?;
+            v |= ((b & 0x7f) as i64) << shift;
+            shift += 7;
+            if b & 0x80 == 0 {
+                if signed && shift < 64 && b & 0x40 != 0 {
+                    v |= -1 << shift;
+                }
+                return Ok(v);
+            }
+        }
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(self.leb(false)? as u32)
+    }
+
+    fn val_type(&mut self) -> Result<ValType, String> {
+        match self.byte()? {
+            0x7f => Ok(ValType::I32),
+            0x7e => Ok(ValType::I64),
+            b => Err(format!("unknown value type {:#x}", b)),
+        }
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        let n = self.u32()? as usize;
+        let s = self.bytes.get(self.pos..self.pos + n).ok_or("unexpected end")?;
+        self.pos += n;
+        String::from_utf8(s.to_vec()).map_err(|e| e.to_string())
+    }
+
+    fn inst(&mut self) -> Result<WInst, String> {
+        use WInst::*;
+        Ok(match self.byte()? {
+            0x00 => Unreachable,
+            0x02 | 0x03 if self.byte()? != 0x40 => return Err("block with result".to_string()),
+            0x02 => Block,
+            0x03 => Loop,
+            0x0b => End,
+            0x0c => Br(self.u32()?),
+            0x0e => {
+                let labels = (0..self.u32()?).map(|_| self.u32()).collect::<Result<_, _>>()?;
+                BrTable(labels, self.u32()?)
+            }
+            0x0f => Return,
+            0x1b => Select,
+            0x20 => LocalGet(self.u32()?),
+            0x21 => LocalSet(self.u32()?),
+            0x22 => LocalTee(self.u32()?),
+            0x23 => GlobalGet(self.u32()?),
+            0x24 => GlobalSet(self.u32()?),
+            op @ (0x29 | 0x37) => {
+                self.u32()?;
+                let offset = self.u32()?;
+                if op == 0x29 { I64Load(offset) } else { I64Store(offset) }
+            }
+            0x41 => I32Const(self.leb(true)? as i32),
+            0x42 => I64Const(self.leb(true)?),
+            0x50 => I64Eqz,
+            0x6a => I32Add,
+            0x6b => I32Sub,
+            0x7c => I64Add,
+            0x7d => I64Sub,
+            0x7e => I64Mul,
+            0x7f => I64DivS,
+            0x81 => I64RemS,
+            b => return Err(format!("unknown opcode {:#x}", b)),
+        })
+    }
+}
+
+pub struct Func {
+    locals: Vec<ValType>,
+    body: Vec<WInst>,
+}
+
+pub struct Instance {
+    funcs: Vec<Func>,
+    exports: Vec<(String, u32)>,
+    memory: Vec<u8>,
+    stack_pointer: i32,
+}
+
+// Pops an operand of type t. Below the current frame only an unreachable frame
+// has operands, of any type.
+fn pop_operand(stack: &mut Vec<ValType>, height: usize, unreachable: bool, t: ValType) -> Result<(), String> {
+    if stack.len() == height {
+        return if unreachable { Ok(()) } else { Err("operand stack underflow".to_string()) };
+    }
+    match stack.pop() {
+        Some(v) if v == t => Ok(()),
+        v => Err(format!("expected {}, found {:?}", t, v)),
+    }
+}
+
+// Type checks a body with type [] -> [i64].
+fn validate(func: &Func) -> Result<(), String> {
+    use ValType::*;
+    use WInst::*;
+    // (height, unreachable) of each open block, the function body first
+    let mut frames = vec![(0, false)];
+    let mut stack = vec![];
+    let local = |i: &u32| func.locals.get(*i as usize).copied().ok_or(format!("unknown local {}", i));
+    for inst in &func.body {
+        let (height, unreachable) = *frames.last().ok_or("instruction after the end")?;
+        let pop = |stack: &mut Vec<ValType>, t| pop_operand(stack, height, unreachable, t);
+        // Number of results of the label, only the function body has one.
+        let arity = |l: &u32| match frames.len().checked_sub(*l as usize + 1) {
+            Some(0) => Ok(1),
+            Some(_) => Ok(0),
+            None => Err(format!("unknown label {}", l)),
+        };
+        let (pops, push): (Vec<ValType>, Option<ValType>) = match inst {
+            Unreachable | Return | Br(_) | BrTable(..) => {
+                match inst {
+                    Return => pop(&mut stack, I64)?,
+                    Br(l) if arity(l)? == 1 => pop(&mut stack, I64)?,
+                    BrTable(labels, default) => {
+                        pop(&mut stack, I32)?;
+                        let n = arity(default)?;
+                        for l in labels {
+                            if arity(l)? != n {
+                                return Err("br_table labels with different arities".to_string());
+                            }
+                        }
+                        if n == 1 {
+                            pop(&mut stack, I64)?;
+                        }
+                    }
+                    _ => {}
+                }
+                stack.truncate(height);
+                frames.last_mut().unwrap().1 = true;
+                continue;
+            }
+            Block | Loop => {
+                frames.push((stack.len(), false));
+                continue;
+            }
+            End => {
+                let result = if frames.len() == 1 { 1 } else { 0 };
+                if result == 1 {
+                    pop(&mut stack, I64)?;
+                }
+                if stack.len() != height {
+                    return Err("values left on the operand stack".to_string());
+                }
+                frames.pop();
+                continue;
+            }
+            Select => {
+                pop(&mut stack, I32)?;
+                let t = stack.last().copied().unwrap_or(I64);
+                (vec![t, t], Some(t))
+            }
+            LocalGet(i) => (vec![], Some(local(i)?)),
+            LocalSet(i) => (vec![local(i)?], None),
+            LocalTee(i) => (vec![local(i)?], Some(local(i)?)),
+            GlobalGet(0) => (vec![], Some(I32)),
+            GlobalSet(0) => (vec![I32], None),
+            GlobalGet(i) | GlobalSet(i) => return Err(format!("unknown global {}", i)),
+            I64Load(_) => (vec![I32], Some(I64)),
+            I64Store(_) => (vec![I64, I32], None),
+            I32Const(_) => (vec![], Some(I32)),
+            I64Const(_) => (vec![], Some(I64)),
+            I64Eqz => (vec![I64], Some(I32)),
+            I32Add | I32Sub => (vec![I32, I32], Some(I32)),
+            I64Add | I64Sub | I64Mul | I64DivS | I64RemS => (vec![I64, I64], Some(I64)),
+        };
+        for t in pops {
+            pop(&mut stack, t)?;
+        }
+        stack.extend(push);
+    }
+    match frames.len() {
+        0 => Ok(()),
+        _ => Err("missing end".to_string()),
+    }
+}
+
+// Decodes and validates a module.
+pub fn instantiate(bytes: &[u8]) -> Result<Instance, String> {
+    if !bytes.starts_with(&[MAGIC, VERSION].concat()) {
+        return Err("bad header".to_string());
+    }
+    let mut r = Reader { bytes, pos: 8 };
+    let mut instance = Instance { funcs: vec![], exports: vec![], memory: vec![], stack_pointer: 0 };
+    while r.pos < bytes.len() {
+        let id = r.byte()?;
+        let size = r.u32()? as usize;
+        let end = r.pos + size;
+        match id {
+            MEMORY => {
+                r.u32()?;
+                r.byte()?;
+                instance.memory = vec![0; r.u32()? as usize * PAGE_SIZE as usize];
+            }
+            GLOBAL => {
+                r.u32()?;
+                r.val_type()?;
+                r.byte()?;
+                instance.stack_pointer = match r.inst()? {
+                    WInst::I32Const(i) => i,
+                    _ => return Err("unsupported global initializer".to_string()),
+                };
+            }
+            EXPORT => {
+                for _ in 0..r.u32()? {
+                    let name = r.name()?;
+                    let kind = r.byte()?;
+                    let index = r.u32()?;
+                    if kind == 0 {
+                        instance.exports.push((name, index));
+                    }
+                }
+            }
+            CODE => {
+                for _ in 0..r.u32()? {
+                    let body_end = r.u32()? as usize + r.pos;
+                    let mut locals = vec![];
+                    for _ in 0..r.u32()? {
+                        let count = r.u32()? as usize;
+                        locals.extend(vec![r.val_type()?; count]);
+                    }
+                    let mut body = vec![];
+                    while r.pos < body_end {
+                        body.push(r.inst()?);
+                    }
+                    let func = Func { locals, body };
+                    validate(&func)?;
+                    instance.funcs.push(func);
+                }
+            }
+            // The type and function sections only hold [] -> [i64].
+            _ => {}
+        }
+        r.pos = end;
+    }
+    Ok(instance)
+}
+
+impl Instance {
+    fn memory(&mut self, addr: i32, offset: u32) -> Result<&mut [u8], String> {
+        let start = addr as u32 as usize + offset as usize;
+        self.memory.get_mut(start..start + 8).ok_or("out of bounds memory access".to_string())
+    }
+
+    // Calls an exported function. Traps are errors.
+    pub fn invoke(&mut self, name: &str) -> Result<i64, String> {
+        let index = self.exports.iter().find(|(n, _)| n == name).ok_or("unknown export")?.1;
+        let func = &self.funcs[index as usize];
+        let body = func.body.clone();
+        let mut locals: Vec<i64> = vec![0; func.locals.len()];
+        let mut stack: Vec<i64> = vec![];
+        // (pc of the block start, is loop) of each open block
+        let mut blocks: Vec<(usize, bool)> = vec![];
+        let mut pc = 0;
+        while pc < body.len() {
+            let inst = &body[pc];
+            pc += 1;
+            let mut pop = || stack.pop().unwrap();
+            let label = match inst {
+                WInst::Br(l) => Some(*l),
+                WInst::BrTable(labels, default) => Some(*labels.get(pop() as usize).unwrap_or(default)),
+                _ => None,
+            };
+            if let Some(l) = label {
+                // The function body
+                if l as usize == blocks.len() {
+                    break;
+                }
+                let depth = blocks.len() - 1 - l as usize;
+                let (start, is_loop) = blocks[depth];
+                if is_loop {
+                    blocks.truncate(depth + 1);
+                    pc = start;
+                } else {
+                    blocks.truncate(depth);
+                    pc = matching_end(&body, start) + 1;
+                }
+                continue;
+            }
+            use WInst::*;
+            let binary = |stack: &mut Vec<i64>, f: fn(i64, i64) -> Option<i64>| -> Result<(), String> {
+                let (r, l) = (stack.pop().unwrap(), stack.pop().unwrap());
+                stack.push(f(l, r).ok_or("integer divide by zero or overflow")?);
+                Ok(())
+            };
+            match inst {
+                Unreachable => return Err("unreachable".to_string()),
+                Block | Loop => blocks.push((pc, *inst == Loop)),
+                End => {
+                    blocks.pop();
+                }
+                Br(_) | BrTable(..) => unreachable!(),
+                Return => break,
+                Select => {
+                    let (c, b, a) = (pop(), pop(), pop());
+                    stack.push(if c as i32 != 0 { a } else { b });
+                }
+                LocalGet(i) => stack.push(locals[*i as usize]),
+                LocalSet(i) => locals[*i as usize] = pop(),
+                LocalTee(i) => locals[*i as usize] = *stack.last().unwrap(),
+                GlobalGet(_) => stack.push(self.stack_pointer as i64),
+                GlobalSet(_) => self.stack_pointer = pop() as i32,
+                I64Load(offset) => {
+                    let addr = pop() as i32;
+                    stack.push(i64::from_le_bytes(self.memory(addr, *offset)?.try_into().unwrap()));
+                }
+                I64Store(offset) => {
+                    let (v, addr) = (pop(), pop() as i32);
+                    self.memory(addr, *offset)?.copy_from_slice(&v.to_le_bytes());
+                }
+                I32Const(i) => stack.push(*i as i64),
+                I64Const(i) => stack.push(*i),
+                I64Eqz => {
+                    let v = pop();
+                    stack.push((v == 0) as i64);
+                }
+                I32Add => binary(&mut stack, |l, r| Some((l as i32).wrapping_add(r as i32) as i64))?,
+                I32Sub => binary(&mut stack, |l, r| Some((l as i32).wrapping_sub(r as i32) as i64))?,
+                I64Add => binary(&mut stack, |l, r| Some(l.wrapping_add(r)))?,
+                I64Sub => binary(&mut stack, |l, r| Some(l.wrapping_sub(r)))?,
+                I64Mul => binary(&mut stack, |l, r| Some(l.wrapping_mul(r)))?,
+                I64DivS => binary(&mut stack, i64::checked_div)?,
+                // i64::MIN % -1 is 0 in wasm, but only division by zero traps.
+                I64RemS => binary(&mut stack, |l, r| if r == 0 { None } else { Some(l.wrapping_rem(r)) })?,
+            }
+        }
+        Ok(stack.pop().unwrap())
+    }
+}
+
+// Index of the end of the block starting at start.
+fn matching_end(body: &[WInst], start: usize) -> usize {
+    let mut depth = 0;
+    for (i, inst) in body.iter().enumerate().skip(start) {
+        match inst {
+            WInst::Block | WInst::Loop => depth += 1,
+            WInst::End if depth == 0 => return i,
+            WInst::End => depth -= 1,
+            _ => {}
+        }
+    }
+    body.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{encode, lower, lower_function, Func as WFunc, Module};
+    use super::*;
+    use crate::cfg::Function;
+    use crate::{interpret, Compiler, OptLevel, Options, Source, Target};
+
+    fn run(module: &Module) -> Result<i64, String> {
+        instantiate(&encode(module))?.invoke("main")
+    }
+
+    #[test]
+    fn test_validate() {
+        use WInst::*;
+        let module = |body| Module::new(vec![WFunc { name: "main".to_string(), locals: vec![ValType::I32], body }]);
+        assert!(run(&module(vec![I64Const(1), I64Const(2)])).is_err());
+        assert!(run(&module(vec![LocalGet(0)])).is_err());
+        assert!(run(&module(vec![Block, I64Const(1), End, I64Const(1)])).is_err());
+        assert!(run(&module(vec![Block, Br(0), End, I64Const(1)])).is_ok());
+        assert!(run(&module(vec![Block, Br(1), End, I64Const(1)])).is_err());
+        assert!(run(&module(vec![Block, Br(2), End, I64Const(1)])).is_err());
+        assert_eq!(run(&module(vec![Block, I64Const(3), Br(1), End, I64Const(1)])), Ok(3));
+        // Anything goes after return.
+        assert_eq!(run(&module(vec![I64Const(4), Return, I64Add])), Ok(4));
+    }
+
+    #[test]
+    fn test_traps() {
+        let ir = Compiler::default().compile(&Source::inline("1 / 0;")).ir.unwrap();
+        assert!(run(&lower(&ir)).is_err());
+    }
+
+    #[test]
+    fn test_execute() {
+        let programs = [
+            include_str!("../../../shell-tests/programs/arith.c"),
+            include_str!("../../../shell-tests/programs/return.c"),
+            include_str!("../../../shell-tests/programs/remainder.c"),
+            "0 - 7 / 2 - 7 % 2;",
+            "return 1;\n2;",
+        ];
+        for code in programs {
+            let source = Source::inline(code);
+            let ir = Compiler::default().compile(&source).ir.unwrap();
+            let expected = interpret(&ir).unwrap().result;
+            assert_eq!(run(&lower(&ir)), Ok(expected), "{}", code);
+            for opt_level in [OptLevel::O0, OptLevel::O1, OptLevel::O2] {
+                let options = Options { target: Target::Wasm32, opt_level, ..Default::default() };
+                let output = Compiler::new(options).compile(&source);
+                let result = instantiate(&output.wasm.unwrap()).unwrap().invoke("main");
+                assert_eq!(result, Ok(expected), "{} at {:?}", code, opt_level);
+            }
+        }
+    }
+
+    #[test]
+    fn test_execute_function() {
+        let f: Function = "
+            fn main {
+            bb0:
+              store $0, 10
+              store $1, 0
+              jmp bb1
+            bb1:
+              %0 = load $0
+              %1 = load $1
+              %2 = add %1, %0
+              store $1, %2
+              %3 = sub %0, 1
+              store $0, %3
+              br %3, bb1, bb2
+            bb2:
+              ret %2
+            }
+        ".parse().unwrap();
+        let module = lower_function(&f);
+        let mut instance = instantiate(&encode(&module)).unwrap();
+        assert_eq!(instance.invoke("main"), Ok(55));
+        // The frame is popped again.
+        assert_eq!(instance.stack_pointer, PAGE_SIZE as i32);
+    }
+}
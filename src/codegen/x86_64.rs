@@ -0,0 +1,690 @@
+use crate::cfg::{BinOp, BlockId, Function, Inst, Operand, Slot, Terminator};
+use crate::regalloc::{allocate, Allocation, Location, RegisterInfo};
+use crate::Instruction::*;
+use crate::asm::x86_64::{Arg, Cond, MInst, Reg};
+use crate::asm::{Directive, Item, Label, Program, Section, Syntax, SymbolType, Value};
+use crate::{ir, ssa, Global, Init, Instruction, IR};
+
+pub mod peephole;
+pub use peephole::peephole;
+
+// Assembly file with code as a global function.
+pub fn assemble(name: &str, code: Vec<MInst>) -> Program<MInst> {
+    let name = Label::new(name);
+    let mut program = Program::new();
+    program
+        .directive(Directive::Section(Section::Text))
+        .directive(Directive::Global(name.clone()))
+        .label(name);
+    for inst in code {
+        match inst {
+            MInst::Label(l) => program.items.push(Item::Label(l)),
+            MInst::Loc(line, col) => program.items.push(Item::Directive(Directive::Loc(1, line, col))),
+            _ => program.items.push(Item::Inst(inst)),
+        }
+    }
+    program
+}
+
+// Label of a global. Names that no C identifier can clash with, like .str.0
+// of string literals, become .L labels outside of the symbol table.
+pub fn global_label(name: &str) -> Label {
+    if name.starts_with('.') {
+        Label(format!(".L{}", name))
+    } else {
+        Label::new(name)
+    }
+}
+
+// Appends a definition in .data, .bss or .rodata for each global but the
+// extern ones.
+pub fn data(program: &mut Program<MInst>, globals: &[Global]) {
+    for g in globals.iter().filter(|g| !g.external) {
+        let label = global_label(&g.name);
+        let section = match g.init {
+            _ if g.readonly => Section::Rodata,
+            Init::Zero => Section::Bss,
+            _ => Section::Data,
+        };
+        program.directive(Directive::Section(section)).directive(Directive::Align(g.align() as u32));
+        if !g.local {
+            program.directive(Directive::Global(label.clone()));
+        }
+        program.directive(Directive::Type(label.clone(), SymbolType::Object)).label(label.clone());
+        match &g.init {
+            Init::Zero => {
+                program.directive(Directive::Zero(g.size));
+            }
+            Init::Int(v) => {
+                program.directive(match g.size {
+                    1 => Directive::Byte(*v as u8),
+                    4 => Directive::Long(Value::Imm(ir::truncate(*v, 4))),
+                    _ => Directive::Quad(Value::Imm(*v)),
+                });
+            }
+            Init::Addr(name, 0) => {
+                program.directive(Directive::Quad(Value::Label(global_label(name))));
+            }
+            Init::Addr(name, offset) => {
+                program.directive(Directive::Quad(Value::Offset(global_label(name), *offset)));
+            }
+            Init::Bytes(bytes) => match bytes.split_last() {
+                Some((0, s)) if std::str::from_utf8(s).is_ok() => {
+                    program.directive(Directive::Asciz(String::from_utf8(s.to_vec()).unwrap()));
+                }
+                _ => {
+                    for b in bytes {
+                        program.directive(Directive::Byte(*b));
+                    }
+                }
+            },
+        }
+        program.directive(Directive::Size(label));
+    }
+}
+
+// Reads the addresses of the globals from the GOT instead of computing them
+// relative to rip, for symbols that position-independent code can't assume
+// to be in the same module.
+pub fn use_got(code: &mut [MInst], globals: &[&Global]) {
+    let labels: Vec<Label> = globals.iter().map(|g| global_label(&g.name)).collect();
+    for inst in code {
+        if let MInst::LeaRip(r, l) = inst {
+            if labels.contains(l) {
+                *inst = MInst::LoadGot(*r, l.clone());
+            }
+        }
+    }
+}
+
+fn is_imm32(i: i64) -> bool {
+    (i32::MIN as i64..=i32::MAX as i64).contains(&i)
+}
+
+const RAX: Arg = Arg::Reg(Reg::Rax);
+const RDI: Arg = Arg::Reg(Reg::Rdi);
+const RDX: Arg = Arg::Reg(Reg::Rdx);
+const R11: Arg = Arg::Reg(Reg::R11);
+
+fn compile_binary_operation(op: fn(Arg, Arg) -> MInst) -> Vec<MInst> {
+    vec![MInst::Pop(RDI), MInst::Pop(RAX), op(RAX, RDI), MInst::Push(RAX)]
+}
+
+// idiv leaves the quotient in rax and the remainder in rdx.
+fn compile_division(result: Arg) -> Vec<MInst> {
+    vec![MInst::Pop(RDI), MInst::Pop(RAX), MInst::Cqo, MInst::Idiv(RDI), MInst::Push(result)]
+}
+
+fn compile_instruction(inst: &Instruction) -> Vec<MInst> {
+    match inst {
+        PushI(i) if is_imm32(*i) => vec![MInst::Push(Arg::Imm(*i))],
+        // push takes only a sign-extended 32-bit immediate.
+        PushI(i) => vec![MInst::Mov(RAX, Arg::Imm(*i)), MInst::Push(RAX)],
+        PopI => vec![MInst::Pop(RAX)],
+        AddI => compile_binary_operation(MInst::Add),
+        SubI => compile_binary_operation(MInst::Sub),
+        MulI => compile_binary_operation(|_, src| MInst::Imul(Reg::Rax, src)),
+        DivI => compile_division(RAX),
+        RemI => compile_division(RDX),
+        PushG(name) => vec![MInst::LeaRip(Reg::Rax, global_label(name)), MInst::Push(RAX)],
+        Load(size) => vec![MInst::Pop(RAX), load(Reg::Rax, Reg::Rax, *size), MInst::Push(RAX)],
+        // The value of the assignment is read back for its truncation.
+        Store(size) => vec![
+            MInst::Pop(RDI),
+            MInst::Pop(RAX),
+            store(Reg::Rax, Reg::Rdi, *size),
+            load(Reg::Rdi, Reg::Rax, *size),
+            MInst::Push(RDI),
+        ],
+        Ret => vec![MInst::Pop(RAX), MInst::Ret],
+    }
+}
+
+// dst = the sign-extended `size` bytes at [addr]
+fn load(dst: Reg, addr: Reg, size: u8) -> MInst {
+    let mem = Arg::Mem { base: addr, offset: 0 };
+    match size {
+        8 => MInst::Mov(Arg::Reg(dst), mem),
+        _ => MInst::Movsx(dst, mem, size),
+    }
+}
+
+fn store(addr: Reg, src: Reg, size: u8) -> MInst {
+    let mem = Arg::Mem { base: addr, offset: 0 };
+    match size {
+        8 => MInst::Mov(mem, Arg::Reg(src)),
+        _ => MInst::MovNarrow(mem, src, size),
+    }
+}
+
+// Stack-machine lowering: every value goes through the machine stack.
+pub fn lower(ir: &IR) -> Vec<MInst> {
+    lower_ir(ir, false)
+}
+
+// Like lower, with a Loc wherever the source line changes, for -g
+pub fn lower_debug(ir: &IR) -> Vec<MInst> {
+    lower_ir(ir, true)
+}
+
+fn lower_ir(ir: &IR, lines: bool) -> Vec<MInst> {
+    let mut code = vec![];
+    let mut line = None;
+    for (i, inst) in ir.instructions.iter().enumerate() {
+        if let Some(loc) = ir.loc(i).filter(|loc| lines && Some(loc.line) != line) {
+            line = Some(loc.line);
+            code.push(MInst::Loc(loc.line, loc.col));
+        }
+        code.extend(compile_instruction(inst));
+    }
+    if ir.instructions.last() != Some(&Ret) {
+        code.push(MInst::Ret);
+    }
+    code
+}
+
+pub fn compile(ir: &IR) -> String {
+    let mut program = assemble("main", lower(ir));
+    data(&mut program, &ir.globals);
+    program.to_string(Syntax::Intel)
+}
+
+// Registers for the allocator, caller-saved first. rax and rdx are used by idiv
+// and r11 holds 64-bit immediates, so they are scratch registers. rbp is the
+// frame pointer.
+pub const REGISTERS: &[RegisterInfo] = &[
+    RegisterInfo { name: "rcx", callee_saved: false },
+    RegisterInfo { name: "rsi", callee_saved: false },
+    RegisterInfo { name: "rdi", callee_saved: false },
+    RegisterInfo { name: "r8", callee_saved: false },
+    RegisterInfo { name: "r9", callee_saved: false },
+    RegisterInfo { name: "r10", callee_saved: false },
+    RegisterInfo { name: "rbx", callee_saved: true },
+    RegisterInfo { name: "r12", callee_saved: true },
+    RegisterInfo { name: "r13", callee_saved: true },
+    RegisterInfo { name: "r14", callee_saved: true },
+    RegisterInfo { name: "r15", callee_saved: true },
+];
+
+// Same order as REGISTERS
+const ALLOCATABLE: &[Reg] = &[
+    Reg::Rcx,
+    Reg::Rsi,
+    Reg::Rdi,
+    Reg::R8,
+    Reg::R9,
+    Reg::R10,
+    Reg::Rbx,
+    Reg::R12,
+    Reg::R13,
+    Reg::R14,
+    Reg::R15,
+];
+
+struct FunctionCompiler<'a> {
+    f: &'a Function,
+    allocation: Allocation,
+    code: Vec<MInst>,
+}
+
+impl FunctionCompiler<'_> {
+    fn emit(&mut self, inst: MInst) {
+        self.code.push(inst);
+    }
+
+    fn label(&self, b: BlockId) -> Label {
+        Label(format!(".L{}_{}", self.f.name, b))
+    }
+
+    // Frame: saved rbp, callee-saved registers, spill slots, then stack slots.
+    fn frame_offset(&self, i: usize) -> i64 {
+        -8 * (self.allocation.callee_saved.len() + 1 + i) as i64
+    }
+
+    fn frame_size(&self) -> usize {
+        let size = 8 * (self.allocation.spill_count + self.f.slot_count);
+        let pushed = 8 * self.allocation.callee_saved.len();
+        size + (size + pushed) % 16
+    }
+
+    fn location(&self, loc: Location) -> Arg {
+        match loc {
+            Location::Reg(r) => Arg::Reg(ALLOCATABLE[r]),
+            Location::Spill(i) => Arg::Mem { base: Reg::Rbp, offset: self.frame_offset(i) },
+        }
+    }
+
+    fn slot(&self, slot: Slot) -> Arg {
+        Arg::Mem { base: Reg::Rbp, offset: self.frame_offset(self.allocation.spill_count + slot.0) }
+    }
+
+    fn arg(&self, v: Operand) -> Arg {
+        match v {
+            Operand::Reg(r) => self.location(self.allocation.location(r)),
+            Operand::Imm(i) => Arg::Imm(i),
+        }
+    }
+
+    // An operand for instructions that take at most a 32-bit immediate
+    fn arg32(&mut self, v: Operand) -> Arg {
+        match self.arg(v) {
+            Arg::Imm(i) if !is_imm32(i) => {
+                self.emit(MInst::Mov(R11, Arg::Imm(i)));
+                R11
+            }
+            a => a,
+        }
+    }
+
+    // A register holding the operand, r11 unless it is in one
+    fn base(&mut self, v: Operand) -> Reg {
+        match self.arg(v) {
+            Arg::Reg(r) => r,
+            a => {
+                self.mov(R11, a);
+                Reg::R11
+            }
+        }
+    }
+
+    fn mov(&mut self, dst: Arg, src: Arg) {
+        match (dst, src) {
+            _ if dst == src => {}
+            (Arg::Mem { .. }, Arg::Mem { .. }) => {
+                self.emit(MInst::Mov(RAX, src));
+                self.emit(MInst::Mov(dst, RAX));
+            }
+            (Arg::Mem { .. }, Arg::Imm(i)) if !is_imm32(i) => {
+                self.emit(MInst::Mov(RAX, src));
+                self.emit(MInst::Mov(dst, RAX));
+            }
+            _ => self.emit(MInst::Mov(dst, src)),
+        }
+    }
+
+    fn binary(&mut self, op: BinOp, dst: Arg, lhs: Operand, rhs: Operand) {
+        let lhs = self.arg(lhs);
+        let rhs = self.arg32(rhs);
+        if matches!(op, BinOp::Div | BinOp::Rem) {
+            let rhs = match rhs {
+                Arg::Imm(_) => {
+                    self.mov(R11, rhs);
+                    R11
+                }
+                _ => rhs,
+            };
+            self.mov(RAX, lhs);
+            self.emit(MInst::Cqo);
+            self.emit(MInst::Idiv(rhs));
+            self.mov(dst, if op == BinOp::Div { RAX } else { RDX });
+            return;
+        }
+        // Compute in place unless that would overwrite rhs before it's read.
+        let work = match dst {
+            Arg::Reg(r) if dst != rhs => r,
+            _ => Reg::Rax,
+        };
+        self.mov(Arg::Reg(work), lhs);
+        self.emit(match (op, rhs) {
+            (BinOp::Mul, Arg::Imm(i)) => MInst::Imul3(work, Arg::Reg(work), i),
+            (BinOp::Mul, _) => MInst::Imul(work, rhs),
+            (BinOp::Add, _) => MInst::Add(Arg::Reg(work), rhs),
+            (BinOp::Sub, _) => MInst::Sub(Arg::Reg(work), rhs),
+            (BinOp::Div | BinOp::Rem, _) => unreachable!(),
+        });
+        self.mov(dst, Arg::Reg(work));
+    }
+
+    fn inst(&mut self, inst: &Inst) {
+        match inst {
+            Inst::Copy { dst, src } => {
+                let dst = self.arg(Operand::Reg(*dst));
+                let src = self.arg(*src);
+                self.mov(dst, src)
+            }
+            Inst::Binary { op, dst, lhs, rhs } => {
+                let dst = self.arg(Operand::Reg(*dst));
+                self.binary(*op, dst, *lhs, *rhs)
+            }
+            Inst::Load { dst, slot } => {
+                let dst = self.arg(Operand::Reg(*dst));
+                self.mov(dst, self.slot(*slot))
+            }
+            Inst::Store { slot, src } => {
+                let src = self.arg(*src);
+                self.mov(self.slot(*slot), src)
+            }
+            Inst::Addr { dst, global } => {
+                let dst = self.arg(Operand::Reg(*dst));
+                let work = if let Arg::Reg(r) = dst { r } else { Reg::Rax };
+                self.emit(MInst::LeaRip(work, global_label(global)));
+                self.mov(dst, Arg::Reg(work))
+            }
+            Inst::LoadMem { dst, addr, size } => {
+                let addr = self.base(*addr);
+                let dst = self.arg(Operand::Reg(*dst));
+                let work = if let Arg::Reg(r) = dst { r } else { Reg::Rax };
+                self.emit(load(work, addr, *size));
+                self.mov(dst, Arg::Reg(work))
+            }
+            Inst::StoreMem { addr, src, size } => {
+                let addr = self.base(*addr);
+                let src = match self.arg(*src) {
+                    Arg::Reg(r) => r,
+                    a => {
+                        self.mov(RAX, a);
+                        Reg::Rax
+                    }
+                };
+                self.emit(store(addr, src, *size))
+            }
+            Inst::Phi { .. } => unreachable!("phi in codegen"),
+            // The prologue may have the first one already.
+            Inst::Loc { line, col } => {
+                let loc = MInst::Loc(*line, *col);
+                if self.code.iter().rfind(|inst| matches!(inst, MInst::Loc(..))) != Some(&loc) {
+                    self.emit(loc)
+                }
+            }
+        }
+    }
+
+    fn terminator(&mut self, term: &Terminator, next: Option<BlockId>) {
+        let (cond, then, els) = match term {
+            Terminator::Return(v) => {
+                let v = self.arg(*v);
+                self.mov(RAX, v);
+                self.epilogue();
+                return;
+            }
+            Terminator::Jump(b) => (None, *b, *b),
+            Terminator::Branch { cond: Operand::Imm(c), then, els } => (None, *then, if *c != 0 { *then } else { *els }),
+            Terminator::Branch { cond, then, els } => (Some(self.arg(*cond)), *then, *els),
+        };
+        match cond {
+            None if Some(els) == next => {}
+            None => self.emit(MInst::Jmp(self.label(els))),
+            Some(cond) => {
+                match cond {
+                    Arg::Reg(_) => self.emit(MInst::Test(cond, cond)),
+                    _ => self.emit(MInst::Cmp(cond, Arg::Imm(0))),
+                }
+                if Some(then) == next {
+                    self.emit(MInst::Jcc(Cond::E, self.label(els)));
+                } else {
+                    self.emit(MInst::Jcc(Cond::Ne, self.label(then)));
+                    if Some(els) != next {
+                        self.emit(MInst::Jmp(self.label(els)));
+                    }
+                }
+            }
+        }
+    }
+
+    fn prologue(&mut self) {
+        self.emit(MInst::Push(Arg::Reg(Reg::Rbp)));
+        self.emit(MInst::Mov(Arg::Reg(Reg::Rbp), Arg::Reg(Reg::Rsp)));
+        for r in self.allocation.callee_saved.clone() {
+            self.emit(MInst::Push(Arg::Reg(ALLOCATABLE[r])));
+        }
+        if self.frame_size() > 0 {
+            self.emit(MInst::Sub(Arg::Reg(Reg::Rsp), Arg::Imm(self.frame_size() as i64)));
+        }
+    }
+
+    fn epilogue(&mut self) {
+        let saved = 8 * self.allocation.callee_saved.len() as i64;
+        match (self.frame_size(), saved) {
+            (0, _) => {}
+            (_, 0) => self.emit(MInst::Mov(Arg::Reg(Reg::Rsp), Arg::Reg(Reg::Rbp))),
+            _ => self.emit(MInst::Lea(Reg::Rsp, Arg::Mem { base: Reg::Rbp, offset: -saved })),
+        }
+        for r in self.allocation.callee_saved.clone().into_iter().rev() {
+            self.emit(MInst::Pop(Arg::Reg(ALLOCATABLE[r])));
+        }
+        self.emit(MInst::Pop(Arg::Reg(Reg::Rbp)));
+        self.emit(MInst::Ret);
+    }
+}
+
+// Lowers the CFG with registers from the linear scan allocator.
+pub fn lower_function(f: &Function) -> Vec<MInst> {
+    let mut f = f.clone();
+    if f.blocks.iter().any(|b| b.insts.iter().any(Inst::is_phi)) {
+        ssa::destruct(&mut f);
+    }
+    let allocation = allocate(&f, REGISTERS);
+    let mut compiler = FunctionCompiler { f: &f, allocation, code: vec![] };
+    // The prologue is on the first line.
+    if let Some(loc) = f.blocks.iter().flat_map(|b| &b.insts).find(|inst| matches!(inst, Inst::Loc { .. })) {
+        compiler.inst(loc);
+    }
+    compiler.prologue();
+    let order = compiler.allocation.order.clone();
+    for (i, id) in order.iter().enumerate() {
+        if i > 0 {
+            compiler.emit(MInst::Label(compiler.label(*id)));
+        }
+        let block = f.block(*id);
+        for inst in &block.insts {
+            compiler.inst(inst);
+        }
+        compiler.terminator(&block.term, order.get(i + 1).copied());
+    }
+    compiler.code
+}
+
+pub fn compile_function(f: &Function) -> String {
+    assemble(&f.name, lower_function(f)).to_string(Syntax::Intel)
+}
+
+#[test]
+fn test_pushi() {
+    assert_eq!(compile_instruction(&PushI(9)), vec![MInst::Push(Arg::Imm(9))]);
+    assert_eq!(
+        compile_instruction(&PushI(i64::MAX)),
+        vec![MInst::Mov(RAX, Arg::Imm(i64::MAX)), MInst::Push(RAX)],
+    );
+}
+
+#[test]
+fn test_popi() {
+    assert_eq!(compile_instruction(&PopI), vec![MInst::Pop(RAX)]);
+}
+
+#[test]
+fn test_binary() {
+    assert_eq!(compile_instruction(&AddI)[2], MInst::Add(RAX, RDI));
+    assert_eq!(compile_instruction(&SubI)[2], MInst::Sub(RAX, RDI));
+    assert_eq!(compile_instruction(&MulI)[2], MInst::Imul(Reg::Rax, RDI));
+    for inst in [AddI, SubI, MulI] {
+        let code = compile_instruction(&inst);
+        assert_eq!(code.len(), 4);
+        assert_eq!(code[3], MInst::Push(RAX));
+    }
+}
+
+#[test]
+fn test_divi() {
+    let code = compile_instruction(&DivI);
+    assert_eq!(code.len(), 5);
+    assert_eq!(code[2..4], [MInst::Cqo, MInst::Idiv(RDI)]);
+    assert_eq!(compile_instruction(&RemI)[4], MInst::Push(RDX));
+}
+
+#[test]
+fn test_display() {
+    let mem = Arg::Mem { base: Reg::Rbp, offset: -16 };
+    assert_eq!(MInst::Mov(mem, Arg::Imm(1)).to_string(), "mov qword ptr [rbp - 16], 1");
+    assert_eq!(MInst::Imul3(Reg::R8, mem, 3).to_string(), "imul r8, qword ptr [rbp - 16], 3");
+    assert_eq!(MInst::Lea(Reg::Rsp, mem).to_string(), "lea rsp, [rbp - 16]");
+    assert_eq!(MInst::Jcc(Cond::Ne, Label::new(".L1")).to_string(), "jne .L1");
+    assert_eq!(MInst::Label(Label::new(".L1")).to_string(), ".L1:");
+    let program = assemble("main", vec![MInst::Label(Label::new(".L1")), MInst::Ret]);
+    assert_eq!(program.to_string(Syntax::Intel), ".intel_syntax noprefix\n.text\n.global main\nmain:\n.L1:\n\tret\n");
+}
+
+#[test]
+fn test_att() {
+    let mem = Arg::Mem { base: Reg::Rbp, offset: -16 };
+    let att = |inst: MInst| {
+        let program = assemble("main", vec![inst]);
+        program.to_string(Syntax::Att).lines().last().unwrap().to_string()
+    };
+    assert_eq!(att(MInst::Mov(mem, Arg::Imm(1))), "\tmovq $1, -16(%rbp)");
+    assert_eq!(att(MInst::Add(Arg::Reg(Reg::Rax), Arg::Reg(Reg::Rdi))), "\taddq %rdi, %rax");
+    assert_eq!(att(MInst::Imul3(Reg::R8, mem, 3)), "\timulq $3, -16(%rbp), %r8");
+    assert_eq!(att(MInst::Shl(Arg::Reg(Reg::Rax), 3)), "\tshlq $3, %rax");
+    assert_eq!(att(MInst::Lea(Reg::Rsp, Arg::Mem { base: Reg::Rbp, offset: 0 })), "\tleaq (%rbp), %rsp");
+    assert_eq!(att(MInst::Cqo), "\tcqto");
+    assert_eq!(att(MInst::Jmp(Label::new(".L1"))), "\tjmp .L1");
+    assert!(assemble("main", vec![]).to_string(Syntax::Att).starts_with(".att_syntax\n"));
+}
+
+#[test]
+fn test_lower_debug() {
+    use crate::{Compiler, Source};
+    let ir = Compiler::default().compile(&Source::inline("1 +\n2;\nreturn 3;")).ir.unwrap();
+    let code = lower_debug(&ir);
+    let locs: Vec<&MInst> = code.iter().filter(|inst| matches!(inst, MInst::Loc(..))).collect();
+    assert_eq!(locs, [&MInst::Loc(1, 1), &MInst::Loc(2, 1), &MInst::Loc(1, 3), &MInst::Loc(3, 8)]);
+    assert_eq!(code.iter().filter(|inst| !matches!(inst, MInst::Loc(..))).cloned().collect::<Vec<_>>(), lower(&ir));
+    let s = assemble("main", code).to_string(Syntax::Att);
+    assert!(s.contains("main:
+.loc 1 1 1
+	pushq $1
+.loc 1 2 1
+"));
+}
+
+#[test]
+fn test_globals() {
+    let ir: IR = "\
+        global x 4 = -1\n\
+        static c 1\n\
+        global p 8 = &x+4\n\
+        static const .str.0 3 = \"h\\x0a\\x00\"\n\
+        pushg x\n\
+        pushg c\n\
+        load 1\n\
+        store 4\n\
+        ret\n"
+        .parse()
+        .unwrap();
+    let s = compile(&ir);
+    assert!(s.contains("\tlea rax, [rip + x]\n\tpush rax\n"));
+    assert!(s.contains("\tpop rax\n\tmovsx rax, byte ptr [rax]\n"));
+    assert!(s.contains("\tmov dword ptr [rax], edi\n\tmovsxd rdi, dword ptr [rax]\n"));
+    assert!(s.contains(".data\n.balign 4\n.global x\n.type x, @object\nx:\n.long -1\n.size x, .-x\n"));
+    assert!(s.contains(".bss\n.balign 1\n.type c, @object\nc:\n.zero 1\n"));
+    assert!(s.contains("p:\n.quad x+4\n"));
+    assert!(s.contains(".section .rodata\n.balign 1\n.type .L.str.0, @object\n.L.str.0:\n.asciz \"h\\012\"\n"));
+
+    let f: Function = "fn main {\nbb0:\n  %0 = addr @x\n  store.1 %0, 300\n  %1 = load.4 %0\n  ret %1\n}".parse().unwrap();
+    let s = compile_function(&f);
+    assert!(s.contains("\tlea rcx, [rip + x]\n\tmov rax, 300\n\tmov byte ptr [rcx], al\n\tmovsxd rcx, dword ptr [rcx]\n"));
+}
+
+#[test]
+fn test_use_got() {
+    let ir: IR = "extern x 4\nglobal y 4\npushg x\nload 4\npushg y\nload 4\naddi\nret".parse().unwrap();
+    let mut code = lower(&ir);
+    use_got(&mut code, &[&ir.globals[0]]);
+    let mut program = assemble("main", code);
+    data(&mut program, &ir.globals);
+    let s = program.to_string(Syntax::Intel);
+    assert!(s.contains("\tmov rax, qword ptr [rip + x@GOTPCREL]\n"));
+    assert!(s.contains("\tlea rax, [rip + y]\n"));
+    assert!(!s.contains("x:"));
+    assert!(program.to_string(Syntax::Att).contains("\tmovq x@GOTPCREL(%rip), %rax\n"));
+}
+
+#[test]
+fn test_allocatable() {
+    let names: Vec<String> = ALLOCATABLE.iter().map(Reg::to_string).collect();
+    assert_eq!(names, REGISTERS.iter().map(|r| r.name).collect::<Vec<_>>());
+}
+
+#[cfg(test)]
+fn compile_source(code: &str) -> String {
+    let f: Function = code.parse().unwrap();
+    compile_function(&f)
+}
+
+#[test]
+fn test_compile_function() {
+    let s = compile_source("
+        fn main {
+        bb0:
+          %0 = copy 7
+          %1 = div %0, 2
+          %2 = add %1, 1
+          %3 = mul %2, 9223372036854775807
+          ret %3
+        }
+    ");
+    assert_eq!(s, "\
+        .intel_syntax noprefix\n\
+        .text\n\
+        .global main\n\
+        main:\n\
+        \tpush rbp\n\
+        \tmov rbp, rsp\n\
+        \tmov rcx, 7\n\
+        \tmov r11, 2\n\
+        \tmov rax, rcx\n\
+        \tcqo\n\
+        \tidiv r11\n\
+        \tmov rcx, rax\n\
+        \tadd rcx, 1\n\
+        \tmov r11, 9223372036854775807\n\
+        \timul rcx, r11\n\
+        \tmov rax, rcx\n\
+        \tpop rbp\n\
+        \tret\n\
+    ");
+}
+
+#[test]
+fn test_compile_function_branch() {
+    let s = compile_source("
+        fn main {
+        bb0:
+          store $0, 3
+          jmp bb1
+        bb1:
+          %0 = load $0
+          %1 = sub %0, 1
+          store $0, %1
+          br %1, bb1, bb2
+        bb2:
+          ret %0
+        }
+    ");
+    assert!(s.contains("\tsub rsp, 16\n"));
+    assert!(s.contains("\tmov qword ptr [rbp - 8], 3\n"));
+    assert!(s.contains(".Lmain_bb1:\n"));
+    assert!(s.contains("\tjne .Lmain_bb1\n"));
+    assert!(s.contains("\tmov rsp, rbp\n"));
+    assert!(!s.contains("jmp"));
+}
+
+#[test]
+fn test_compile_function_spill() {
+    // 12 values are live at once but only 11 registers are available.
+    let mut code = String::from("fn main {\nbb0:\n");
+    for i in 0..12 {
+        code += &format!("%{} = copy {}\n", i, i);
+    }
+    code += "%12 = add %0, %1\n";
+    for i in 2..12 {
+        code += &format!("%{} = add %{}, %{}\n", i + 11, i + 10, i);
+    }
+    code += "ret %22\n}\n";
+    let s = compile_source(&code);
+    assert!(s.contains("qword ptr [rbp - 48]"));
+    for r in ["rbx", "r12", "r13", "r14", "r15"] {
+        assert!(s.contains(&format!("\tpush {}\n", r)));
+        assert!(s.contains(&format!("\tpop {}\n", r)));
+    }
+}
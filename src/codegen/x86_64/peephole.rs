@@ -0,0 +1,205 @@
+use super::is_imm32;
+use crate::asm::x86_64::{Arg, MInst, Reg};
+
+// Whether the value of r after code[i] is never read.
+fn is_dead(code: &[MInst], i: usize, r: Reg) -> bool {
+    for inst in &code[i + 1..] {
+        if inst.reads().contains(&r) {
+            return false;
+        }
+        if *inst == MInst::Ret {
+            return true;
+        }
+        // The value may be read at the target.
+        if inst.is_control() {
+            return false;
+        }
+        if inst.writes().contains(&r) {
+            return true;
+        }
+    }
+    true
+}
+
+// mov r, r
+fn remove_self_moves(code: &mut Vec<MInst>) -> bool {
+    let n = code.len();
+    code.retain(|inst| !matches!(inst, MInst::Mov(dst, src) if dst == src));
+    code.len() != n
+}
+
+// `push x; ...; pop y` becomes `...; mov y, x` if the instructions in between
+// don't use the stack or change x.
+fn forward_push(code: &mut Vec<MInst>) -> bool {
+    for i in 0..code.len() {
+        let src = match code[i] {
+            MInst::Push(src) => src,
+            _ => continue,
+        };
+        for j in i + 1..code.len() {
+            let inst = &code[j];
+            if let MInst::Pop(dst) = *inst {
+                if dst.is_mem() && src.is_mem() {
+                    break;
+                }
+                code[j] = MInst::Mov(dst, src);
+                code.remove(i);
+                return true;
+            }
+            let uses_stack = inst.reads().contains(&Reg::Rsp) || inst.writes().contains(&Reg::Rsp);
+            let clobbers = src.regs().iter().any(|r| inst.writes().contains(r)) || (src.is_mem() && inst.writes_memory());
+            if inst.is_control() || uses_stack || clobbers {
+                break;
+            }
+        }
+    }
+    false
+}
+
+// inst with its source operand r replaced by imm
+fn with_immediate(inst: &MInst, r: Reg, imm: i64) -> Option<MInst> {
+    let reg = Arg::Reg(r);
+    let imm = Arg::Imm(imm);
+    match *inst {
+        MInst::Mov(dst, src) if src == reg && dst != reg => Some(MInst::Mov(dst, imm)),
+        MInst::Add(dst, src) if src == reg && dst != reg => Some(MInst::Add(dst, imm)),
+        MInst::Sub(dst, src) if src == reg && dst != reg => Some(MInst::Sub(dst, imm)),
+        MInst::Cmp(dst, src) if src == reg && dst != reg => Some(MInst::Cmp(dst, imm)),
+        MInst::Imul(dst, src) if src == reg && dst != r => match imm {
+            Arg::Imm(i) => Some(MInst::Imul3(dst, Arg::Reg(dst), i)),
+            _ => None,
+        },
+        MInst::Push(src) if src == reg => Some(MInst::Push(imm)),
+        _ => None,
+    }
+}
+
+// `mov r, imm; ...; op x, r` becomes `...; op x, imm` if r is dead after op.
+fn fold_immediates(code: &mut Vec<MInst>) -> bool {
+    for i in 0..code.len() {
+        let (r, imm) = match code[i] {
+            MInst::Mov(Arg::Reg(r), Arg::Imm(imm)) if is_imm32(imm) => (r, imm),
+            _ => continue,
+        };
+        for j in i + 1..code.len() {
+            let inst = &code[j];
+            if inst.reads().contains(&r) {
+                if let Some(folded) = with_immediate(inst, r, imm) {
+                    if is_dead(code, j, r) {
+                        code[j] = folded;
+                        code.remove(i);
+                        return true;
+                    }
+                }
+                break;
+            }
+            if inst.is_control() || inst.writes().contains(&r) {
+                break;
+            }
+        }
+    }
+    false
+}
+
+// imul by a power of two becomes shl.
+fn strength_reduce(code: &mut Vec<MInst>) -> bool {
+    for i in 0..code.len() {
+        let (dst, src, imm) = match code[i] {
+            MInst::Imul3(dst, src, imm) if imm > 0 && (imm as u64).is_power_of_two() => (dst, src, imm),
+            _ => continue,
+        };
+        let shift = imm.trailing_zeros() as u8;
+        let mut replacement = vec![MInst::Mov(Arg::Reg(dst), src)];
+        if shift > 0 {
+            replacement.push(MInst::Shl(Arg::Reg(dst), shift));
+        }
+        code.splice(i..=i, replacement);
+        return true;
+    }
+    false
+}
+
+// Runs every rule until none applies.
+pub fn peephole(code: &mut Vec<MInst>) {
+    while remove_self_moves(code) | forward_push(code) | fold_immediates(code) | strength_reduce(code) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{assemble, lower};
+    use crate::asm::Syntax;
+    use super::*;
+    use crate::{Compiler, Source};
+
+    fn optimize(code: &str) -> String {
+        let ir = Compiler::default().compile(&Source::inline(code)).ir.unwrap();
+        let mut code = lower(&ir);
+        peephole(&mut code);
+        assemble("main", code).to_string(Syntax::Intel)
+    }
+
+    #[test]
+    fn test_peephole() {
+        assert_eq!(optimize("1 + 2 * 8;"), "\
+            .intel_syntax noprefix\n\
+            .text\n\
+            .global main\n\
+            main:\n\
+            \tmov rax, 2\n\
+            \tshl rax, 3\n\
+            \tmov rdi, rax\n\
+            \tmov rax, 1\n\
+            \tadd rax, rdi\n\
+            \tret\n\
+        ");
+        assert_eq!(optimize("return 5 - 3;"), "\
+            .intel_syntax noprefix\n\
+            .text\n\
+            .global main\n\
+            main:\n\
+            \tmov rax, 5\n\
+            \tsub rax, 3\n\
+            \tret\n\
+        ");
+    }
+
+    #[test]
+    fn test_forward_push() {
+        let rax = Arg::Reg(Reg::Rax);
+        let rdi = Arg::Reg(Reg::Rdi);
+        let mut code = vec![MInst::Push(rax), MInst::Pop(rax), MInst::Ret];
+        peephole(&mut code);
+        assert_eq!(code, vec![MInst::Ret]);
+
+        // rax changes before the pop.
+        let mut code = vec![MInst::Push(rax), MInst::Mov(rax, Arg::Imm(1)), MInst::Pop(rdi), MInst::Ret];
+        peephole(&mut code);
+        assert_eq!(code.len(), 4);
+    }
+
+    #[test]
+    fn test_strength_reduce() {
+        let rcx = Arg::Reg(Reg::Rcx);
+        let mut code = vec![MInst::Imul3(Reg::Rax, rcx, 1), MInst::Imul3(Reg::Rcx, rcx, 4), MInst::Imul3(Reg::Rax, rcx, -4)];
+        peephole(&mut code);
+        assert_eq!(code, vec![
+            MInst::Mov(Arg::Reg(Reg::Rax), rcx),
+            MInst::Shl(rcx, 2),
+            MInst::Imul3(Reg::Rax, rcx, -4),
+        ]);
+    }
+
+    #[test]
+    fn test_fold_immediates() {
+        let rax = Arg::Reg(Reg::Rax);
+        let rdi = Arg::Reg(Reg::Rdi);
+        // rdi is read again, so it must keep its value.
+        let mut code = vec![MInst::Mov(rdi, Arg::Imm(3)), MInst::Add(rax, rdi), MInst::Sub(rax, rdi), MInst::Ret];
+        peephole(&mut code);
+        assert_eq!(code[1], MInst::Add(rax, rdi));
+
+        let mut code = vec![MInst::Mov(rdi, Arg::Imm(i64::MAX)), MInst::Add(rax, rdi), MInst::Ret];
+        peephole(&mut code);
+        assert_eq!(code.len(), 3);
+    }
+}
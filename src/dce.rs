@@ -0,0 +1,258 @@
+use crate::cfg::{BlockId, Function, Inst, Slot};
+use crate::Instruction::*;
+use crate::{Diagnostic, IR};
+
+pub const UNREACHABLE_CODE: &str = "code will never be executed [-Wunreachable-code]";
+
+// Removes code after the first ret and expression statements whose value is
+// discarded. Every instruction but store is pure: division by zero is
+// undefined, so a discarded division may go too. The last popi of a function
+// without ret is its result and stays. Returns a warning at the first
+// unreachable instruction.
+pub fn dce(ir: &mut IR) -> Vec<Diagnostic> {
+    let mut warnings = vec![];
+    if let Some(ret) = ir.instructions.iter().position(|inst| *inst == Ret) {
+        if ret + 1 < ir.len() {
+            let loc = (ret + 1..ir.len()).find_map(|i| ir.loc(i));
+            warnings.push(Diagnostic::warning(UNREACHABLE_CODE, loc));
+            ir.truncate(ret + 1);
+        }
+    }
+
+    let statements = match discarded_statements(ir) {
+        Some(v) => v,
+        None => return warnings,
+    };
+    let returns = ir.instructions.last() == Some(&Ret);
+    let dead = match statements.split_last() {
+        Some((_, rest)) if !returns => rest,
+        _ => &statements[..],
+    };
+    let stores = |(start, end): &&(usize, usize)| {
+        ir.instructions[*start..=*end].iter().any(|inst| matches!(inst, Store(_)))
+    };
+    let mut out = IR::new();
+    let mut dead = dead.iter().filter(|s| !stores(s)).peekable();
+    for (i, inst) in ir.instructions.iter().enumerate() {
+        while dead.next_if(|(_, end)| *end < i).is_some() {}
+        if !matches!(dead.peek(), Some((start, _)) if *start <= i) {
+            out.push_typed(inst.clone(), ir.loc(i), ir.width(i));
+        }
+    }
+    out.globals = std::mem::take(&mut ir.globals);
+    *ir = out;
+    warnings
+}
+
+// Ranges of instructions from an empty stack to the popi that empties it again.
+// None if the stack underflows.
+fn discarded_statements(ir: &IR) -> Option<Vec<(usize, usize)>> {
+    let mut statements = vec![];
+    let mut depth = 0usize;
+    let mut start = 0;
+    for (i, inst) in ir.instructions.iter().enumerate() {
+        if depth == 0 {
+            start = i;
+        }
+        depth = match inst {
+            PushI(_) | PushG(_) => depth + 1,
+            Load(_) => depth.checked_sub(1)? + 1,
+            PopI | Ret | AddI | SubI | MulI | DivI | RemI | Store(_) => depth.checked_sub(1)?,
+        };
+        if matches!(inst, AddI | SubI | MulI | DivI | RemI | Load(_) | Store(_)) && depth == 0 {
+            return None;
+        }
+        if *inst == PopI && depth == 0 {
+            statements.push((start, i));
+        }
+    }
+    Some(statements)
+}
+
+// Removes blocks unreachable from the entry, pure instructions whose result is
+// never used and slots that are never loaded. Works in and out of SSA form.
+pub fn dce_function(f: &mut Function) {
+    remove_unreachable_blocks(f);
+    remove_unused_slots(f);
+    remove_dead_insts(f);
+}
+
+pub fn remove_unreachable_blocks(f: &mut Function) {
+    let mut reachable = vec![false; f.blocks.len()];
+    for id in f.reverse_postorder() {
+        reachable[id.0] = true;
+    }
+    if reachable.iter().all(|r| *r) {
+        return;
+    }
+    let mut ids = vec![None; f.blocks.len()];
+    let mut n = 0;
+    for (i, r) in reachable.iter().enumerate() {
+        if *r {
+            ids[i] = Some(BlockId(n));
+            n += 1;
+        }
+    }
+    let blocks = std::mem::take(&mut f.blocks);
+    for (mut block, id) in blocks.into_iter().zip(&ids) {
+        if id.is_none() {
+            continue;
+        }
+        for inst in block.insts.iter_mut() {
+            if let Inst::Phi { args, .. } = inst {
+                args.retain(|(p, _)| reachable[p.0]);
+                for (p, _) in args.iter_mut() {
+                    *p = ids[p.0].unwrap();
+                }
+            }
+        }
+        for succ in block.term.successors_mut() {
+            *succ = ids[succ.0].unwrap();
+        }
+        f.blocks.push(block);
+    }
+}
+
+pub fn remove_unused_slots(f: &mut Function) {
+    let mut loaded = vec![false; f.slot_count];
+    for inst in f.blocks.iter().flat_map(|b| &b.insts) {
+        if let Inst::Load { slot, .. } = inst {
+            loaded[slot.0] = true;
+        }
+    }
+    let mut slots = vec![None; f.slot_count];
+    let mut n = 0;
+    for (i, l) in loaded.iter().enumerate() {
+        if *l {
+            slots[i] = Some(Slot(n));
+            n += 1;
+        }
+    }
+    for block in &mut f.blocks {
+        block.insts.retain(|inst| !matches!(inst, Inst::Store { slot, .. } if !loaded[slot.0]));
+        for inst in block.insts.iter_mut() {
+            if let Inst::Load { slot, .. } | Inst::Store { slot, .. } = inst {
+                *slot = slots[slot.0].unwrap();
+            }
+        }
+    }
+    f.slot_count = n;
+}
+
+// Mark and sweep from the operands of stores and terminators.
+pub fn remove_dead_insts(f: &mut Function) {
+    let mut live = vec![false; f.vreg_count];
+    let mut work = vec![];
+    for block in &f.blocks {
+        let stores = block.insts.iter().filter(|inst| inst.def().is_none());
+        work.extend(stores.flat_map(Inst::uses).chain(block.term.uses()));
+    }
+    // Out of SSA form a register may have several definitions.
+    let mut defs = vec![vec![]; f.vreg_count];
+    for inst in f.blocks.iter().flat_map(|b| &b.insts) {
+        if let Some(r) = inst.def() {
+            defs[r.0].push(inst);
+        }
+    }
+    while let Some(r) = work.pop() {
+        if !live[r.0] {
+            live[r.0] = true;
+            work.extend(defs[r.0].iter().flat_map(|inst| inst.uses()));
+        }
+    }
+    for block in &mut f.blocks {
+        block.insts.retain(|inst| inst.def().is_none_or(|r| live[r.0]));
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cfg::execute;
+    use crate::ssa::{mem2reg, verify_ssa};
+    use crate::{Compiler, Loc, Source};
+    use std::convert::TryFrom;
+
+    fn dce_source(code: &str) -> (IR, Vec<Diagnostic>) {
+        let mut ir = Compiler::default().compile(&Source::inline(code)).ir.unwrap();
+        let warnings = dce(&mut ir);
+        (ir, warnings)
+    }
+
+    #[test]
+    fn test_dce() {
+        let (ir, warnings) = dce_source("1 + 2; 3 / 0; 4 * 5;");
+        assert_eq!(ir.instructions, vec![PushI(4), PushI(5), MulI, PopI]);
+        assert!(warnings.is_empty());
+
+        let (ir, _) = dce_source("");
+        assert!(ir.is_empty());
+
+        // Unbalanced IR is left alone.
+        let mut ir: IR = vec![PushI(1), PushI(2), PopI, PopI, AddI].into();
+        dce(&mut ir);
+        assert_eq!(ir.len(), 5);
+    }
+
+    #[test]
+    fn test_dce_unreachable() {
+        let (ir, warnings) = dce_source("1;\nreturn 2;\n3 + 4;\nreturn 5;");
+        assert_eq!(ir.instructions, vec![PushI(2), Ret]);
+        assert_eq!(warnings, vec![Diagnostic::warning(UNREACHABLE_CODE, Some(Loc::new(13, 3, 1)))]);
+
+        let (ir, warnings) = dce_source("return 1;");
+        assert_eq!(ir.instructions, vec![PushI(1), Ret]);
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn test_dce_function() {
+        // bb1 is unreachable, $1 is never loaded and %3 is never used.
+        let mut f: Function = "
+            fn f {
+            bb0:
+              store $0, 1
+              store $1, 2
+              jmp bb2
+            bb1:
+              jmp bb2
+            bb2:
+              %1 = phi [bb0, 3], [bb1, 4]
+              %0 = load $0
+              %2 = add %0, %1
+              %3 = mul %2, 2
+              ret %2
+            }
+        ".parse().unwrap();
+        let expected = execute(&f, 100);
+        dce_function(&mut f);
+        assert_eq!(f.to_string(), "\
+            fn f {\n\
+            bb0:\n  \
+              store $0, 1\n  \
+              jmp bb1\n\
+            bb1:\n  \
+              %1 = phi [bb0, 3]\n  \
+              %0 = load $0\n  \
+              %2 = add %0, %1\n  \
+              ret %2\n\
+            }\n\
+        ");
+        assert_eq!(f.slot_count, 1);
+        assert_eq!(execute(&f, 100), expected);
+        assert_eq!(f.verify(), Ok(()));
+    }
+
+    #[test]
+    fn test_dce_after_ret() {
+        let ir = Compiler::default().compile(&Source::inline("1; return 2 + 3; 4 * 5;")).ir.unwrap();
+        let mut f = Function::try_from(&ir).unwrap();
+        assert_eq!(f.blocks.len(), 2);
+        mem2reg(&mut f);
+        dce_function(&mut f);
+        assert_eq!(f.blocks.len(), 1);
+        assert_eq!(execute(&f, 100), Some(5));
+        assert_eq!(verify_ssa(&f), Ok(()));
+    }
+}
@@ -0,0 +1,138 @@
+use crate::{ir, parser, EvalError, Loc, Source, Span, TokenKind};
+use std::fmt;
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Level {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Level::Error => write!(f, "error"),
+            Level::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Diagnostic {
+    pub level: Level,
+    pub message: String,
+    pub loc: Option<Loc>,
+    // Operands the message is about
+    pub spans: Vec<Span>,
+}
+
+impl Diagnostic {
+    pub fn new(level: Level, message: impl Into<String>, loc: Option<Loc>) -> Self {
+        Self {
+            level,
+            message: message.into(),
+            loc,
+            spans: vec![],
+        }
+    }
+
+    pub fn with_spans(mut self, spans: impl IntoIterator<Item = Span>) -> Self {
+        self.spans.extend(spans);
+        self
+    }
+
+    pub fn error(message: impl Into<String>, loc: Option<Loc>) -> Self {
+        Self::new(Level::Error, message, loc)
+    }
+
+    pub fn warning(message: impl Into<String>, loc: Option<Loc>) -> Self {
+        Self::new(Level::Warning, message, loc)
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.level == Level::Error
+    }
+
+    // The source lines of the spans with the spans underlined:
+    //
+    //   p + q;
+    //   ^   ^
+    pub fn excerpts(&self, source: &Source) -> String {
+        let code: String = source.code.iter().collect();
+        let mut out = String::new();
+        for span in &self.spans {
+            let line = code.lines().nth(span.start.line - 1).unwrap_or("");
+            let end = if span.end.line == span.start.line { span.end.col } else { line.chars().count() };
+            let width = end.saturating_sub(span.start.col);
+            out += &format!("{}\n{:indent$}^{}\n", line, "", "~".repeat(width), indent = span.start.col - 1);
+        }
+        out
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(loc) = self.loc {
+            write!(f, "{}: ", loc)?;
+        }
+        write!(f, "{}: {}", self.level, self.message)
+    }
+}
+
+impl From<parser::Error> for Diagnostic {
+    fn from(err: parser::Error) -> Self {
+        match err {
+            parser::Error::Message(tok, message) => match tok.kind {
+                TokenKind::Error(e) => Self::error(format!("{}: {:?}", message, e), Some(tok.loc)),
+                TokenKind::EOF => Self::error(format!("{}: end of file", message), Some(tok.loc)),
+                _ => Self::error(message, Some(tok.loc)),
+            },
+            parser::Error::EOF => Self::error("Unexpected end of file", None),
+        }
+    }
+}
+
+impl From<ir::ParseError> for Diagnostic {
+    fn from(err: ir::ParseError) -> Self {
+        Self::error(err.message, Some(err.loc))
+    }
+}
+
+impl From<EvalError> for Diagnostic {
+    fn from(err: EvalError) -> Self {
+        let loc = err.loc;
+        let message = EvalError { loc: None, ..err }.to_string();
+        Self::error(message, loc)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Diagnostic;
+    use crate::{head_tok, parser, sym, Loc, Source, Span};
+
+    #[test]
+    fn test_display() {
+        let d = Diagnostic::error("Expected semicolon", Some(Loc::new(3, 1, 4)));
+        assert_eq!(d.to_string(), "1:4: error: Expected semicolon");
+        let d = Diagnostic::warning("division by zero", None);
+        assert_eq!(d.to_string(), "warning: division by zero");
+    }
+
+    #[test]
+    fn test_excerpts() {
+        let span = |a: usize, b: usize| Span { start: Loc::new(a - 1, 2, a), end: Loc::new(b - 1, 2, b) };
+        let d = Diagnostic::error("invalid operands", Some(Loc::new(7, 2, 5))).with_spans([span(1, 3), span(7, 7)]);
+        let source = Source::inline("int x;\nx + 1 + y;");
+        assert_eq!(d.excerpts(&source), "x + 1 + y;\n^~~\nx + 1 + y;\n      ^\n");
+        assert_eq!(d.spans[0].to_string(), "2:1-2:3");
+    }
+
+    #[test]
+    fn test_from_parser_error() {
+        let err = parser::Error::Message(head_tok!(new, sym!(Plus)), "Unexpected Token".to_string());
+        let d: Diagnostic = err.into();
+        assert!(d.is_error());
+        assert_eq!(d.loc, Some(Loc::head()));
+        assert_eq!(d.message, "Unexpected Token");
+    }
+}
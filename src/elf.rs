@@ -0,0 +1,346 @@
+// ELF64 relocatable objects for x86_64 Linux, as read by ld and readelf.
+use crate::asm::{Section, SymbolType};
+
+pub const R_X86_64_64: u32 = 1;
+pub const R_X86_64_PC32: u32 = 2;
+pub const R_X86_64_PLT32: u32 = 4;
+pub const R_X86_64_REX_GOTPCRELX: u32 = 42;
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_RELA: u32 = 4;
+const SHT_NOBITS: u32 = 8;
+
+const SHF_WRITE: u64 = 1;
+const SHF_ALLOC: u64 = 2;
+const SHF_EXECINSTR: u64 = 4;
+const SHF_INFO_LINK: u64 = 0x40;
+
+const STB_LOCAL: u8 = 0;
+const STB_GLOBAL: u8 = 1;
+const STT_NOTYPE: u8 = 0;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+const STT_SECTION: u8 = 3;
+
+// Sections in the order of their headers after the null one
+pub const SECTIONS: [Section; 4] = [Section::Text, Section::Data, Section::Bss, Section::Rodata];
+
+fn section_index(section: Section) -> usize {
+    SECTIONS.iter().position(|s| *s == section).unwrap()
+}
+
+pub fn section_name(section: Section) -> &'static str {
+    match section {
+        Section::Text => ".text",
+        Section::Data => ".data",
+        Section::Bss => ".bss",
+        Section::Rodata => ".rodata",
+        Section::DebugInfo => ".debug_info",
+        Section::DebugAbbrev => ".debug_abbrev",
+        Section::DebugLine => ".debug_line",
+    }
+}
+
+fn section_by_name(name: &str) -> Option<Section> {
+    SECTIONS.iter().copied().find(|s| section_name(*s) == name)
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Symbol {
+    pub name: String,
+    // None if undefined
+    pub section: Option<Section>,
+    pub value: u64,
+    pub size: u64,
+    pub global: bool,
+    pub kind: Option<SymbolType>,
+}
+
+// A relocation in `section` against `symbol`, or against the start of the
+// section of that name if no such symbol exists.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Relocation {
+    pub section: Section,
+    pub offset: u64,
+    pub symbol: String,
+    pub kind: u32,
+    pub addend: i64,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct Object {
+    pub text: Vec<u8>,
+    pub data: Vec<u8>,
+    pub rodata: Vec<u8>,
+    pub bss_size: u64,
+    pub symbols: Vec<Symbol>,
+    pub relocations: Vec<Relocation>,
+}
+
+struct Header {
+    name: u32,
+    kind: u32,
+    flags: u64,
+    offset: u64,
+    size: u64,
+    link: u32,
+    info: u32,
+    align: u64,
+    entsize: u64,
+}
+
+// Names are added once and referenced by offset.
+#[derive(Default)]
+struct StringTable {
+    bytes: Vec<u8>,
+}
+
+impl StringTable {
+    fn add(&mut self, s: &str) -> u32 {
+        if self.bytes.is_empty() {
+            self.bytes.push(0);
+        }
+        if s.is_empty() {
+            return 0;
+        }
+        let offset = self.bytes.len() as u32;
+        self.bytes.extend(s.as_bytes());
+        self.bytes.push(0);
+        offset
+    }
+}
+
+fn align(out: &mut Vec<u8>, n: usize) {
+    while !out.len().is_multiple_of(n) {
+        out.push(0);
+    }
+}
+
+impl Object {
+    pub fn contents(&mut self, section: Section) -> &mut Vec<u8> {
+        match section {
+            Section::Text => &mut self.text,
+            Section::Data => &mut self.data,
+            Section::Rodata => &mut self.rodata,
+            Section::Bss => panic!(".bss has no contents"),
+            _ => panic!("{} is not written", section_name(section)),
+        }
+    }
+
+    pub fn symbol(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|s| s.name == name)
+    }
+
+    // Symbol table with local symbols first, as ELF requires: the null symbol,
+    // one per section, local symbols, then global and undefined ones.
+    fn symbol_table(&self) -> (Vec<Symbol>, usize) {
+        let mut symbols: Vec<Symbol> = SECTIONS
+            .iter()
+            .map(|s| Symbol { name: String::new(), section: Some(*s), value: 0, size: 0, global: false, kind: None })
+            .collect();
+        symbols.extend(self.symbols.iter().filter(|s| !s.global).cloned());
+        let first_global = symbols.len() + 1;
+        symbols.extend(self.symbols.iter().filter(|s| s.global).cloned());
+        for r in &self.relocations {
+            if section_by_name(&r.symbol).is_none() && !symbols.iter().any(|s| s.name == r.symbol) {
+                let undefined = Symbol { name: r.symbol.clone(), section: None, value: 0, size: 0, global: true, kind: None };
+                symbols.push(undefined);
+            }
+        }
+        (symbols, first_global)
+    }
+
+    pub fn write(&self) -> Vec<u8> {
+        let mut out = vec![0; 64];
+        let mut names = StringTable::default();
+        let mut headers = vec![Header { name: 0, kind: 0, flags: 0, offset: 0, size: 0, link: 0, info: 0, align: 0, entsize: 0 }];
+        let (symbols, first_global) = self.symbol_table();
+        // Index of the symbol table among the headers, after the relocations
+        let rela_sections: Vec<Section> = SECTIONS
+            .iter()
+            .copied()
+            .filter(|s| self.relocations.iter().any(|r| r.section == *s))
+            .collect();
+        let symtab_index = 1 + SECTIONS.len() + rela_sections.len();
+
+        for section in SECTIONS {
+            let (kind, flags, contents): (u32, u64, &[u8]) = match section {
+                Section::Text => (SHT_PROGBITS, SHF_ALLOC | SHF_EXECINSTR, &self.text),
+                Section::Data => (SHT_PROGBITS, SHF_ALLOC | SHF_WRITE, &self.data),
+                Section::Bss => (SHT_NOBITS, SHF_ALLOC | SHF_WRITE, &[]),
+                Section::Rodata => (SHT_PROGBITS, SHF_ALLOC, &self.rodata),
+                _ => unreachable!(),
+            };
+            let section_align = if section == Section::Text { 16 } else { 8 };
+            align(&mut out, section_align);
+            let size = if section == Section::Bss { self.bss_size } else { contents.len() as u64 };
+            let name = names.add(section_name(section));
+            headers.push(Header {
+                name,
+                kind,
+                flags,
+                offset: out.len() as u64,
+                size,
+                link: 0,
+                info: 0,
+                align: section_align as u64,
+                entsize: 0,
+            });
+            out.extend(contents);
+        }
+
+        for section in &rela_sections {
+            align(&mut out, 8);
+            let offset = out.len() as u64;
+            for r in self.relocations.iter().filter(|r| r.section == *section) {
+                let index = match symbols.iter().position(|s| s.name == r.symbol) {
+                    Some(i) => i + 1,
+                    None => 1 + section_index(section_by_name(&r.symbol).unwrap()),
+                };
+                out.extend(r.offset.to_le_bytes());
+                out.extend(((index as u64) << 32 | r.kind as u64).to_le_bytes());
+                out.extend(r.addend.to_le_bytes());
+            }
+            let name = format!(".rela{}", section_name(*section));
+            headers.push(Header {
+                name: names.add(&name),
+                kind: SHT_RELA,
+                flags: SHF_INFO_LINK,
+                offset,
+                size: out.len() as u64 - offset,
+                link: symtab_index as u32,
+                info: 1 + section_index(*section) as u32,
+                align: 8,
+                entsize: 24,
+            });
+        }
+
+        let mut strings = StringTable::default();
+        align(&mut out, 8);
+        let offset = out.len() as u64;
+        out.extend([0; 24]);
+        for s in &symbols {
+            let kind = match s.kind {
+                _ if s.name.is_empty() => STT_SECTION,
+                Some(SymbolType::Function) => STT_FUNC,
+                Some(SymbolType::Object) => STT_OBJECT,
+                None => STT_NOTYPE,
+            };
+            let bind = if s.global { STB_GLOBAL } else { STB_LOCAL };
+            out.extend(strings.add(&s.name).to_le_bytes());
+            out.push(bind << 4 | kind);
+            out.push(0);
+            let shndx = s.section.map_or(0, |section| 1 + section_index(section) as u16);
+            out.extend(shndx.to_le_bytes());
+            out.extend(s.value.to_le_bytes());
+            out.extend(s.size.to_le_bytes());
+        }
+        headers.push(Header {
+            name: names.add(".symtab"),
+            kind: SHT_SYMTAB,
+            flags: 0,
+            offset,
+            size: out.len() as u64 - offset,
+            link: symtab_index as u32 + 1,
+            info: first_global as u32,
+            align: 8,
+            entsize: 24,
+        });
+
+        let strtab = names.add(".strtab");
+        let shstrtab = names.add(".shstrtab");
+        // An empty .note.GNU-stack asks for a non-executable stack.
+        let note = names.add(".note.GNU-stack");
+        for (name, bytes) in [(strtab, strings.bytes), (shstrtab, names.bytes)] {
+            headers.push(Header {
+                name,
+                kind: SHT_STRTAB,
+                flags: 0,
+                offset: out.len() as u64,
+                size: bytes.len() as u64,
+                link: 0,
+                info: 0,
+                align: 1,
+                entsize: 0,
+            });
+            out.extend(bytes);
+        }
+        headers.push(Header {
+            name: note,
+            kind: SHT_PROGBITS,
+            flags: 0,
+            offset: out.len() as u64,
+            size: 0,
+            link: 0,
+            info: 0,
+            align: 1,
+            entsize: 0,
+        });
+
+        align(&mut out, 8);
+        let shoff = out.len() as u64;
+        for h in &headers {
+            out.extend(h.name.to_le_bytes());
+            out.extend(h.kind.to_le_bytes());
+            out.extend(h.flags.to_le_bytes());
+            out.extend(0u64.to_le_bytes());
+            out.extend(h.offset.to_le_bytes());
+            out.extend(h.size.to_le_bytes());
+            out.extend(h.link.to_le_bytes());
+            out.extend(h.info.to_le_bytes());
+            out.extend(h.align.to_le_bytes());
+            out.extend(h.entsize.to_le_bytes());
+        }
+
+        let mut header = vec![0x7f, b'E', b'L', b'F', 2, 1, 1, 0];
+        header.extend([0; 8]);
+        // ET_REL, EM_X86_64, EV_CURRENT
+        header.extend(1u16.to_le_bytes());
+        header.extend(62u16.to_le_bytes());
+        header.extend(1u32.to_le_bytes());
+        // Entry point, program headers and section headers
+        header.extend(0u64.to_le_bytes());
+        header.extend(0u64.to_le_bytes());
+        header.extend(shoff.to_le_bytes());
+        header.extend(0u32.to_le_bytes());
+        header.extend(64u16.to_le_bytes());
+        header.extend(0u16.to_le_bytes());
+        header.extend(0u16.to_le_bytes());
+        header.extend(64u16.to_le_bytes());
+        header.extend((headers.len() as u16).to_le_bytes());
+        header.extend((symtab_index as u16 + 2).to_le_bytes());
+        out[..64].copy_from_slice(&header);
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u16_at(bytes: &[u8], at: usize) -> u16 {
+        u16::from_le_bytes([bytes[at], bytes[at + 1]])
+    }
+
+    #[test]
+    fn test_write() {
+        let main = Symbol { name: "main".to_string(), section: Some(Section::Text), value: 0, size: 5, global: true, kind: Some(SymbolType::Function) };
+        let exit = Relocation { section: Section::Text, offset: 1, symbol: "exit".to_string(), kind: R_X86_64_PLT32, addend: -4 };
+        let object = Object { text: vec![0xe9, 0, 0, 0, 0], symbols: vec![main], relocations: vec![exit], ..Default::default() };
+        let (symbols, first_global) = object.symbol_table();
+        assert_eq!(symbols.iter().map(|s| s.name.as_str()).collect::<Vec<_>>(), ["", "", "", "", "main", "exit"]);
+        assert_eq!(first_global, 5);
+        assert_eq!(symbols[5].section, None);
+
+        let bytes = object.write();
+        assert_eq!(&bytes[..4], b"\x7fELF");
+        // Sections, .rela.text, .symtab, .strtab, .shstrtab and .note.GNU-stack
+        assert_eq!(u16_at(&bytes, 60), 10);
+        assert_eq!(u16_at(&bytes, 62), 8);
+        let names = String::from_utf8_lossy(&bytes);
+        assert!(names.contains("\0.rela.text\0"));
+        assert!(names.contains("\0main\0exit\0"));
+    }
+}
@@ -0,0 +1,370 @@
+use crate::nodes::*;
+use crate::{ir, Loc, Node, StorageClass, Type, Visitor, AST};
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum EvalErrorKind {
+    DivisionByZero,
+    // Signed overflow is undefined in C, so it is never a constant.
+    Overflow,
+    // Variables, assignments and addresses
+    NotConstant,
+    // Addresses and string literals, which the interpreter has no memory for
+    Unsupported,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct EvalError {
+    pub kind: EvalErrorKind,
+    pub loc: Option<Loc>,
+}
+
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let message = match self.kind {
+            EvalErrorKind::DivisionByZero => "division by zero in constant expression",
+            EvalErrorKind::Overflow => "integer overflow in constant expression",
+            EvalErrorKind::NotConstant => "not a constant expression",
+            EvalErrorKind::Unsupported => "expression not supported by the evaluator",
+        };
+        match self.loc {
+            Some(loc) => write!(f, "{}: {}", loc, message),
+            None => write!(f, "{}", message),
+        }
+    }
+}
+
+// A declared variable and its current value
+#[derive(Debug, Clone, Copy)]
+struct Variable {
+    value: i64,
+    size: u8,
+}
+
+// Evaluates integer expressions with C semantics. A block evaluates to the value
+// of its first return or else its last expression statement, like the result of
+// main. In constant mode, for initializers, variables are not constant. The
+// interpreter mode runs declarations and assignments of integer variables.
+#[derive(Default)]
+pub struct Evaluator {
+    interpreter: bool,
+    locs: Vec<Option<Loc>>,
+    // The sema types of the nodes being visited, innermost last
+    tys: Vec<Option<Type>>,
+    // One per enclosing block, mapping names to indexes into variables
+    scopes: Vec<HashMap<String, usize>>,
+    // One per declaration in the order executed
+    variables: Vec<Variable>,
+    last: i64,
+    returned: bool,
+}
+
+impl Evaluator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn interpreter() -> Self {
+        Self {
+            interpreter: true,
+            ..Self::default()
+        }
+    }
+
+    pub fn evaluate(&mut self, ast: &AST) -> Result<i64, EvalError> {
+        self.locs.clear();
+        self.tys.clear();
+        self.scopes.clear();
+        self.variables.clear();
+        self.last = 0;
+        self.returned = false;
+        let v = self.visit(ast)?;
+        Ok(match ast.node {
+            Node::Block(_) => self.last,
+            _ => v,
+        })
+    }
+
+    fn unsupported(&self) -> EvalError {
+        self.error(if self.interpreter { EvalErrorKind::Unsupported } else { EvalErrorKind::NotConstant })
+    }
+
+    fn error(&self, kind: EvalErrorKind) -> EvalError {
+        EvalError {
+            kind,
+            loc: self.locs.last().copied().flatten(),
+        }
+    }
+
+    // v if it is in the range of the type of the current node. Without sema
+    // types values are only checked against i64.
+    fn check(&self, v: Option<i64>) -> Result<i64, EvalError> {
+        match (v, self.tys.last()) {
+            (Some(v), Some(Some(ty))) if ir::truncate(v, ty.size() as u8) != v => Err(self.error(EvalErrorKind::Overflow)),
+            (Some(v), _) => Ok(v),
+            (None, _) => Err(self.error(EvalErrorKind::Overflow)),
+        }
+    }
+
+    // The variable declared as name in the innermost scope that has one
+    fn lookup(&self, name: &str) -> Result<usize, EvalError> {
+        match self.scopes.iter().rev().find_map(|scope| scope.get(name)) {
+            Some(i) if self.interpreter => Ok(*i),
+            _ => Err(self.error(EvalErrorKind::NotConstant)),
+        }
+    }
+
+    fn assign(&mut self, i: usize, v: i64) -> i64 {
+        let var = &mut self.variables[i];
+        var.value = ir::truncate(v, var.size);
+        var.value
+    }
+}
+
+pub fn eval_const(ast: &AST) -> Result<i64, EvalError> {
+    Evaluator::new().evaluate(ast)
+}
+
+// Runs a program with local variables, as a reference for the compiled code.
+pub fn eval_program(ast: &AST) -> Result<i64, EvalError> {
+    Evaluator::interpreter().evaluate(ast)
+}
+
+macro_rules! fn_eval_binary {
+    ($method:ident, $f:ident) => {
+        fn $method(&mut self, lhs: i64, rhs: i64) -> Result<i64, EvalError> {
+            self.check(lhs.$f(rhs))
+        }
+    };
+}
+
+impl Visitor<i64, EvalError> for Evaluator {
+    fn enter(&mut self, ast: &AST) {
+        self.locs.push(ast.token.as_ref().map(|t| t.loc));
+        self.tys.push(ast.ty.clone());
+        if let Node::Block(_) = ast.node {
+            self.scopes.push(HashMap::new());
+        }
+    }
+    fn leave(&mut self, ast: &AST) {
+        self.locs.pop();
+        self.tys.pop();
+        if let Node::Block(_) = ast.node {
+            self.scopes.pop();
+        }
+    }
+    fn returned(&self) -> bool {
+        self.returned
+    }
+
+    fn visit_expr_statement_right(&mut self, v: i64) -> Result<i64, EvalError> {
+        self.last = v;
+        Ok(v)
+    }
+    fn visit_return(&mut self, v: i64) -> Result<i64, EvalError> {
+        self.last = v;
+        self.returned = true;
+        Ok(v)
+    }
+    fn visit_int_literal(&mut self, i: &IntLiteral) -> Result<i64, EvalError> {
+        Ok(i.value)
+    }
+    fn visit_implicit_cast(&mut self, ast: &AST, v: i64) -> Result<i64, EvalError> {
+        match &ast.ty {
+            Some(ty) => Ok(ir::truncate(v, ty.size() as u8)),
+            None => Ok(v),
+        }
+    }
+    fn visit_cast(&mut self, cast: &Cast, v: i64) -> Result<i64, EvalError> {
+        Ok(ir::truncate(v, cast.ty.size() as u8))
+    }
+    // The type of an expression operand is known after sema.
+    fn visit_size_of(&mut self, size_of: &SizeOf) -> Result<i64, EvalError> {
+        match &size_of.ty {
+            Some(ty) => Ok(ty.size() as i64),
+            None => Err(self.error(EvalErrorKind::NotConstant)),
+        }
+    }
+    // A redeclaration in the same scope, or an extern one of a visible
+    // variable, refers to the same variable. Its scope starts before the
+    // initializer.
+    fn visit_declaration(&mut self, _ast: &AST, decl: &Declaration) -> Result<i64, EvalError> {
+        if !self.interpreter {
+            return Err(self.error(EvalErrorKind::NotConstant));
+        }
+        if decl.storage == Some(StorageClass::Typedef) {
+            return Ok(0);
+        }
+        let prior = match decl.storage {
+            Some(StorageClass::Extern) => self.lookup(&decl.name).ok(),
+            _ => self.scopes.last().and_then(|scope| scope.get(&decl.name)).copied(),
+        };
+        let i = prior.unwrap_or_else(|| {
+            self.variables.push(Variable { value: 0, size: decl.ty.size() as u8 });
+            self.variables.len() - 1
+        });
+        if self.scopes.is_empty() {
+            self.scopes.push(HashMap::new());
+        }
+        self.scopes.last_mut().unwrap().insert(decl.name.clone(), i);
+        match &decl.init {
+            Some(init) => {
+                let v = self.visit(init)?;
+                Ok(self.assign(i, v))
+            }
+            None => Ok(self.variables[i].value),
+        }
+    }
+    fn visit_assignment(&mut self, _ast: &AST, assign: &Assignment) -> Result<i64, EvalError> {
+        let i = match &assign.lhs.node {
+            Node::Identifier(ident) => self.lookup(&ident.name)?,
+            _ => return Err(self.error(EvalErrorKind::NotConstant)),
+        };
+        let v = self.visit(&assign.rhs)?;
+        Ok(self.assign(i, v))
+    }
+    fn visit_address_of(&mut self, _ast: &AST, _addr: &AddressOf) -> Result<i64, EvalError> {
+        Err(self.unsupported())
+    }
+    fn visit_string_literal(&mut self, _ast: &AST, _s: &StringLiteral) -> Result<i64, EvalError> {
+        Err(self.unsupported())
+    }
+    fn visit_identifier(&mut self, _ast: &AST, ident: &Identifier) -> Result<i64, EvalError> {
+        let i = self.lookup(&ident.name)?;
+        Ok(self.variables[i].value)
+    }
+
+    fn_eval_binary!(visit_addition, checked_add);
+    fn_eval_binary!(visit_subtraction, checked_sub);
+    fn_eval_binary!(visit_multiplication, checked_mul);
+
+    // Rust's checked_div truncates toward zero as C does and fails on MIN / -1.
+    fn visit_division(&mut self, lhs: i64, rhs: i64) -> Result<i64, EvalError> {
+        if rhs == 0 {
+            return Err(self.error(EvalErrorKind::DivisionByZero));
+        }
+        self.check(lhs.checked_div(rhs))
+    }
+
+    // The sign of the remainder follows the dividend as in C.
+    fn visit_remainder(&mut self, lhs: i64, rhs: i64) -> Result<i64, EvalError> {
+        if rhs == 0 {
+            return Err(self.error(EvalErrorKind::DivisionByZero));
+        }
+        self.check(lhs.checked_rem(rhs))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ast, Compiler, Source};
+
+    fn eval(code: &str) -> Result<i64, EvalError> {
+        let output = Compiler::default().compile(&Source::inline(code));
+        eval_const(&output.ast.unwrap())
+    }
+
+    #[test]
+    fn test_evaluate() {
+        assert_eq!(eval("1 + 2 * 3 - 8 / 4;"), Ok(5));
+        assert_eq!(eval("1; 2; 3 * 3;"), Ok(9));
+        assert_eq!(eval(""), Ok(0));
+        assert_eq!(eval("1; return 2; return 3; 4;"), Ok(2));
+        assert_eq!(eval("{ 1; { return 2; } 1 / 0; } 3 / 0;"), Ok(2));
+    }
+
+    fn run(code: &str) -> Result<i64, EvalError> {
+        let output = Compiler::default().compile(&Source::inline(code));
+        eval_program(&output.ast.unwrap())
+    }
+
+    #[test]
+    fn test_interpreter() {
+        assert_eq!(run("int x = 2; long y; y = x * 3; return y + x;"), Ok(8));
+        assert_eq!(run("char c = 300; int x = c = c + 255; return x;"), Ok(43));
+        assert_eq!(run("int x = 1; { int x = 2; x = x + 1; } return x;"), Ok(1));
+        assert_eq!(run("static int x; { extern int x; x = 4; } x * 2;"), Ok(8));
+        assert_eq!(run("int x; int x = 5; typedef int T; T y = sizeof(T) + x; y;"), Ok(9));
+        assert_eq!(run("long x = sizeof x + 1; return x;"), Ok(9));
+        assert_eq!(run("int x = 2147483647; x + 1;").unwrap_err().kind, EvalErrorKind::Overflow);
+        let err = run("int x;\nint *p = &x;").unwrap_err();
+        assert_eq!(err.to_string(), "2:10: expression not supported by the evaluator");
+        assert_eq!(eval("int x = 1; x;").unwrap_err().kind, EvalErrorKind::NotConstant);
+    }
+
+    #[test]
+    fn test_evaluate_expr() {
+        let output = Compiler::default().compile(&Source::inline("6 / 4;"));
+        let block = output.ast.unwrap();
+        let stmt = match &block.node {
+            Node::Block(b) => match &b.items[0].node {
+                Node::ExprStatement(s) => s.expr.clone(),
+                _ => unreachable!(),
+            },
+            _ => unreachable!(),
+        };
+        assert_eq!(eval_const(&stmt), Ok(1));
+
+        let output = Compiler::default().compile(&Source::inline("char c = 300;"));
+        let init = match &output.ast.unwrap().node {
+            Node::Block(b) => match &b.items[0].node {
+                Node::Declaration(d) => d.init.clone().unwrap(),
+                _ => unreachable!(),
+            },
+            _ => unreachable!(),
+        };
+        assert_eq!(eval_const(&init), Ok(44));
+        assert_eq!(eval_const(&ast!(new_block, vec![])), Ok(0));
+    }
+
+    #[test]
+    fn test_c_semantics() {
+        assert_eq!(eval("0 - 7 / 2;"), Ok(-3));
+        assert_eq!(eval("7 % 3 * 2;"), Ok(2));
+        assert_eq!(eval("9223372036854775807 - 1 - 9223372036854775807;"), Ok(-1));
+        assert_eq!(eval("(char)(100 + 200) + (int)4294967297;"), Ok(45));
+        assert_eq!(eval("sizeof(long *) + sizeof (char);"), Ok(9));
+    }
+
+    #[test]
+    fn test_errors() {
+        let err = eval("1;\n4 / 0 + 1;").unwrap_err();
+        assert_eq!(err.kind, EvalErrorKind::DivisionByZero);
+        assert_eq!(err.loc, Some(Loc::new(5, 2, 3)));
+        assert_eq!(err.to_string(), "2:3: division by zero in constant expression");
+
+        let err = eval("1 + 9223372036854775807 * 2;").unwrap_err();
+        assert_eq!(err.kind, EvalErrorKind::Overflow);
+        assert_eq!(err.loc, Some(Loc::new(24, 1, 25)));
+
+        let err = eval("0 - 9223372036854775807 - 1 - 1;").unwrap_err();
+        assert_eq!(err, EvalError { kind: EvalErrorKind::Overflow, loc: Some(Loc::new(28, 1, 29)) });
+
+        let err = eval("0 + 2147483647 + 1;").unwrap_err();
+        assert_eq!(err, EvalError { kind: EvalErrorKind::Overflow, loc: Some(Loc::new(15, 1, 16)) });
+        assert_eq!(eval("(long)1 + 2147483647 + 1;"), Ok(2147483649));
+        let err = eval("(0 - 2147483647 - 1) / (0 - 1);").unwrap_err();
+        assert_eq!(err.kind, EvalErrorKind::Overflow);
+
+        let err = eval("int x;\n1 + x;").unwrap_err();
+        assert_eq!(err.kind, EvalErrorKind::NotConstant);
+        assert_eq!(err.to_string(), "1:5: not a constant expression");
+    }
+
+    #[test]
+    fn test_not_constant() {
+        let output = Compiler::default().compile(&Source::inline("int x;\n1 + x;\nx = 2;\n&x;"));
+        let items = match output.ast.unwrap().node {
+            Node::Block(b) => b.items,
+            _ => unreachable!(),
+        };
+        let errors: Vec<_> = items[1..].iter().map(|item| eval_const(item).unwrap_err().to_string()).collect();
+        assert_eq!(errors, vec![
+            "2:5: not a constant expression",
+            "3:3: not a constant expression",
+            "4:1: not a constant expression",
+        ]);
+    }
+}
@@ -0,0 +1,328 @@
+use crate::cfg::{BinOp, BlockId, Function, Inst, Operand, Terminator, VReg};
+use crate::Instruction::{self, *};
+use crate::{ir, Diagnostic, IR};
+use std::collections::HashSet;
+
+// Folds `lhs op rhs` as C does for signed integers. Overflow is undefined and
+// division by zero traps at runtime, so neither is folded.
+pub fn fold_binary(op: BinOp, lhs: i64, rhs: i64) -> Option<i64> {
+    match op {
+        BinOp::Add => lhs.checked_add(rhs),
+        BinOp::Sub => lhs.checked_sub(rhs),
+        BinOp::Mul => lhs.checked_mul(rhs),
+        // Truncates toward zero.
+        BinOp::Div => lhs.checked_div(rhs),
+        BinOp::Rem => lhs.checked_rem(rhs),
+    }
+}
+
+fn binop(inst: &Instruction) -> Option<BinOp> {
+    match inst {
+        AddI => Some(BinOp::Add),
+        SubI => Some(BinOp::Sub),
+        MulI => Some(BinOp::Mul),
+        DivI => Some(BinOp::Div),
+        RemI => Some(BinOp::Rem),
+        PushI(_) | PushG(_) | Load(_) | Store(_) | PopI | Ret => None,
+    }
+}
+
+// Replaces `pushi a; pushi b; op` by `pushi (a op b)`. Folded values are pushed
+// again, so whole constant expressions collapse. A result out of the range of
+// the type of op overflows and isn't folded. Returns warnings for constant
+// division by zero.
+pub fn fold(ir: &mut IR) -> Vec<Diagnostic> {
+    let mut warnings = vec![];
+    let mut out = IR::new();
+    for (i, inst) in ir.instructions.iter().enumerate() {
+        let (loc, width) = (ir.loc(i), ir.width(i));
+        let n = out.len();
+        if let (Some(op), [.., PushI(lhs), PushI(rhs)]) = (binop(inst), out.instructions.as_slice()) {
+            if matches!(op, BinOp::Div | BinOp::Rem) && *rhs == 0 {
+                warnings.push(Diagnostic::warning("division by zero", loc));
+            } else if let Some(v) = fold_binary(op, *lhs, *rhs).filter(|v| ir::truncate(*v, width) == *v) {
+                out.truncate(n - 2);
+                out.push_typed(PushI(v), loc, width);
+                continue;
+            }
+        }
+        out.push_typed(inst.clone(), loc, width);
+    }
+    out.globals = std::mem::take(&mut ir.globals);
+    *ir = out;
+    warnings
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+enum Value {
+    // Not known yet
+    Top,
+    Const(i64),
+    // Not a constant
+    Bottom,
+}
+
+impl Value {
+    fn meet(self, other: Value) -> Value {
+        match (self, other) {
+            (Value::Top, v) | (v, Value::Top) => v,
+            (Value::Const(a), Value::Const(b)) if a == b => Value::Const(a),
+            _ => Value::Bottom,
+        }
+    }
+}
+
+struct Sccp<'a> {
+    f: &'a Function,
+    values: Vec<Value>,
+    edges: HashSet<(BlockId, BlockId)>,
+    executable: Vec<bool>,
+    // Blocks that use each register
+    users: Vec<Vec<BlockId>>,
+    work: Vec<BlockId>,
+}
+
+impl<'a> Sccp<'a> {
+    fn new(f: &'a Function) -> Self {
+        let mut users = vec![vec![]; f.vreg_count];
+        for id in f.block_ids() {
+            let block = f.block(id);
+            for r in block.insts.iter().flat_map(Inst::uses).chain(block.term.uses()) {
+                if !users[r.0].contains(&id) {
+                    users[r.0].push(id);
+                }
+            }
+        }
+        Self {
+            f,
+            values: vec![Value::Top; f.vreg_count],
+            edges: HashSet::new(),
+            executable: vec![false; f.blocks.len()],
+            users,
+            work: vec![BlockId(0)],
+        }
+    }
+
+    fn value(&self, v: &Operand) -> Value {
+        match v {
+            Operand::Reg(r) => self.values[r.0],
+            Operand::Imm(i) => Value::Const(*i),
+        }
+    }
+
+    fn set(&mut self, r: VReg, v: Value) {
+        let new = self.values[r.0].meet(v);
+        if new != self.values[r.0] {
+            self.values[r.0] = new;
+            let executable = &self.executable;
+            let users = self.users[r.0].iter().filter(|b| executable[b.0]);
+            self.work.extend(users);
+        }
+    }
+
+    fn mark_edge(&mut self, from: BlockId, to: BlockId) {
+        if self.edges.insert((from, to)) {
+            self.work.push(to);
+        }
+    }
+
+    fn run(&mut self) {
+        while let Some(id) = self.work.pop() {
+            self.executable[id.0] = true;
+            let block = self.f.block(id);
+            for inst in &block.insts {
+                let v = match inst {
+                    Inst::Copy { src, .. } => self.value(src),
+                    Inst::Binary { op, dst, lhs, rhs } => match (self.value(lhs), self.value(rhs)) {
+                        (Value::Const(l), Value::Const(r)) => fold_binary(*op, l, r)
+                            .filter(|v| ir::truncate(*v, self.f.width(*dst)) == *v)
+                            .map_or(Value::Bottom, Value::Const),
+                        (Value::Bottom, _) | (_, Value::Bottom) => Value::Bottom,
+                        _ => Value::Top,
+                    },
+                    Inst::Phi { args, .. } => args
+                        .iter()
+                        .filter(|(p, _)| self.edges.contains(&(*p, id)))
+                        .fold(Value::Top, |acc, (_, v)| acc.meet(self.value(v))),
+                    Inst::Load { .. } | Inst::Addr { .. } | Inst::LoadMem { .. } => Value::Bottom,
+                    Inst::Store { .. } | Inst::StoreMem { .. } | Inst::Loc { .. } => continue,
+                };
+                self.set(inst.def().unwrap(), v);
+            }
+            match &block.term {
+                Terminator::Jump(to) => self.mark_edge(id, *to),
+                Terminator::Branch { cond, then, els } => match self.value(cond) {
+                    Value::Top => {}
+                    Value::Const(c) => self.mark_edge(id, if c != 0 { *then } else { *els }),
+                    Value::Bottom => {
+                        self.mark_edge(id, *then);
+                        self.mark_edge(id, *els);
+                    }
+                },
+                Terminator::Return(_) => {}
+            }
+        }
+    }
+}
+
+// Sparse conditional constant propagation (Wegman and Zadeck). f must be in SSA form.
+// Constant registers are replaced by immediates and branches on constants become
+// jumps. Blocks that became unreachable are left for DCE.
+pub fn sccp(f: &mut Function) {
+    if f.blocks.is_empty() {
+        return;
+    }
+    let mut sccp = Sccp::new(f);
+    sccp.run();
+    let values = sccp.values;
+
+    f.map_operands(|v| match v {
+        Operand::Reg(r) => match values[r.0] {
+            Value::Const(c) => Operand::Imm(c),
+            _ => v,
+        },
+        v => v,
+    });
+    for id in f.block_ids().collect::<Vec<_>>() {
+        let block = f.block_mut(id);
+        block.insts.retain(|inst| match inst.def() {
+            Some(r) => !matches!(values[r.0], Value::Const(_)),
+            None => true,
+        });
+        if let Terminator::Branch { cond: Operand::Imm(c), then, els } = block.term {
+            let (taken, dropped) = if c != 0 { (then, els) } else { (els, then) };
+            block.term = Terminator::Jump(taken);
+            if taken != dropped {
+                remove_phi_args(f, dropped, id);
+            }
+        }
+    }
+}
+
+fn remove_phi_args(f: &mut Function, block: BlockId, pred: BlockId) {
+    for inst in f.block_mut(block).insts.iter_mut() {
+        if let Inst::Phi { args, .. } = inst {
+            args.retain(|(p, _)| *p != pred);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cfg::execute;
+    use crate::ssa::verify_ssa;
+    use crate::{Compiler, Loc, Source};
+
+    fn fold_source(code: &str) -> (IR, Vec<Diagnostic>) {
+        let mut ir = Compiler::default().compile(&Source::inline(code)).ir.unwrap();
+        let warnings = fold(&mut ir);
+        (ir, warnings)
+    }
+
+    #[test]
+    fn test_fold() {
+        let (ir, warnings) = fold_source("0 + 1 + 2;\n3 + 5 - 1 / 1 * 1;");
+        assert_eq!(ir.instructions, vec![PushI(3), PopI, PushI(7), PopI]);
+        assert_eq!(ir.locs[2], Some(Loc::new(17, 2, 7)));
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn test_fold_c_semantics() {
+        let (ir, _) = fold_source("0 - 7 / 2;");
+        assert_eq!(ir.instructions, vec![PushI(-3), PopI]);
+
+        let mut ir: IR = vec![PushI(-7), PushI(2), DivI, PushI(7), PushI(-2), DivI].into();
+        fold(&mut ir);
+        assert_eq!(ir.instructions, vec![PushI(-3), PushI(-3)]);
+
+        let (ir, _) = fold_source("0 - 7 % 2;");
+        assert_eq!(ir.instructions, vec![PushI(-1), PopI]);
+
+        // Signed overflow is left to runtime.
+        let (ir, warnings) = fold_source("9223372036854775807 + 1;");
+        assert_eq!(ir.instructions, vec![PushI(i64::MAX), PushI(1), AddI, PopI]);
+        assert!(warnings.is_empty());
+
+        let mut ir: IR = vec![PushI(i64::MIN), PushI(-1), DivI].into();
+        fold(&mut ir);
+        assert_eq!(ir.len(), 3);
+    }
+
+    #[test]
+    fn test_fold_division_by_zero() {
+        let (ir, warnings) = fold_source("1 + 4 / 0 * 2;");
+        assert_eq!(ir.instructions, vec![PushI(1), PushI(4), PushI(0), DivI, PushI(2), MulI, AddI, PopI]);
+        assert_eq!(warnings, vec![Diagnostic::warning("division by zero", Some(Loc::new(6, 1, 7)))]);
+    }
+
+    #[test]
+    fn test_sccp() {
+        // %1 is 2 because bb2 is never executed, so the return value is constant.
+        let mut f: Function = "
+            fn f {
+            bb0:
+              %0 = copy 1
+              br %0, bb1, bb2
+            bb1:
+              jmp bb3
+            bb2:
+              jmp bb3
+            bb3:
+              %1 = phi [bb1, 2], [bb2, 3]
+              %2 = add %1, 1
+              ret %2
+            }
+        ".parse().unwrap();
+        sccp(&mut f);
+        assert_eq!(f.to_string(), "\
+            fn f {\n\
+            bb0:\n  \
+              jmp bb1\n\
+            bb1:\n  \
+              jmp bb3\n\
+            bb2:\n  \
+              jmp bb3\n\
+            bb3:\n  \
+              ret 3\n\
+            }\n\
+        ");
+        assert_eq!(verify_ssa(&f), Ok(()));
+    }
+
+    #[test]
+    fn test_sccp_loop() {
+        // %1 is always 5 but %2 changes in every iteration.
+        let src = "
+            fn f {
+            bb0:
+              jmp bb1
+            bb1:
+              %0 = phi [bb0, 5], [bb2, %1]
+              %2 = phi [bb0, 3], [bb2, %3]
+              br %2, bb2, bb3
+            bb2:
+              %1 = mul %0, 1
+              %3 = sub %2, 1
+              jmp bb1
+            bb3:
+              %4 = div %0, 0
+              %5 = add %4, %0
+              ret %5
+            }
+        ";
+        let mut f: Function = src.parse().unwrap();
+        sccp(&mut f);
+        assert_eq!(f.block(BlockId(1)).insts.len(), 1);
+        assert_eq!(f.block(BlockId(3)).insts[0].to_string(), "%4 = div 5, 0");
+        assert_eq!(f.block(BlockId(3)).insts[1].to_string(), "%5 = add %4, 5");
+        assert_eq!(verify_ssa(&f), Ok(()));
+
+        let mut f: Function = src.replace("div %0, 0", "div %0, 2").parse().unwrap();
+        let expected = execute(&f, 100);
+        sccp(&mut f);
+        assert_eq!(execute(&f, 100), expected);
+        assert_eq!(f.block(BlockId(3)).term, Terminator::Return(Operand::Imm(7)));
+    }
+}
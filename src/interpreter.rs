@@ -0,0 +1,305 @@
+use crate::Instruction::*;
+use crate::{ir, Init, IR};
+use std::collections::HashMap;
+use std::fmt;
+
+// Address of the first global in the interpreter's memory
+pub const DATA_BASE: i64 = 0x1000;
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum RuntimeError {
+    DivisionByZero { pc: usize },
+    // i64::MIN / -1 traps on x86_64 like division by zero.
+    DivisionOverflow { pc: usize },
+    StackUnderflow { pc: usize },
+    StackOverflow { pc: usize },
+    // Load or store outside of the globals
+    InvalidAddress { pc: usize },
+    // Unverified IR may refer to globals it doesn't define.
+    UnknownGlobal(String),
+    // The initializer doesn't fit the size of the global.
+    InvalidInit(String),
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RuntimeError::DivisionByZero { pc } => write!(f, "{}: division by zero", pc),
+            RuntimeError::DivisionOverflow { pc } => write!(f, "{}: division overflow", pc),
+            RuntimeError::StackUnderflow { pc } => write!(f, "{}: stack underflow", pc),
+            RuntimeError::StackOverflow { pc } => write!(f, "{}: stack overflow", pc),
+            RuntimeError::InvalidAddress { pc } => write!(f, "{}: invalid address", pc),
+            RuntimeError::UnknownGlobal(name) => write!(f, "unknown global {}", name),
+            RuntimeError::InvalidInit(name) => write!(f, "invalid initializer of global {}", name),
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct Execution {
+    // Value returned from main by ret, or else the last value popped as in
+    // x86_64::compile.
+    pub result: i64,
+    // Values discarded by each popi in order.
+    pub popped: Vec<i64>,
+    // Contents of each global when main returned, the side effects of stores
+    pub globals: HashMap<String, Vec<u8>>,
+}
+
+pub struct Interpreter {
+    stack: Vec<i64>,
+    stack_limit: usize,
+    // Globals laid out from DATA_BASE in order
+    memory: Vec<u8>,
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Interpreter {
+    pub fn new() -> Self {
+        Self::with_stack_limit(1 << 16)
+    }
+
+    pub fn with_stack_limit(stack_limit: usize) -> Self {
+        Self {
+            stack: vec![],
+            stack_limit,
+            memory: vec![],
+        }
+    }
+
+    // Returns the address of each global. There is no other object to define
+    // extern globals, so they are zero like a .bss variable.
+    fn layout(&mut self, ir: &IR) -> Result<HashMap<String, i64>, RuntimeError> {
+        let mut addresses = HashMap::new();
+        self.memory.clear();
+        for g in &ir.globals {
+            let align = g.align().max(1);
+            let offset = self.memory.len().div_ceil(align) * align;
+            self.memory.resize(offset + g.size, 0);
+            addresses.insert(g.name.clone(), DATA_BASE + offset as i64);
+        }
+        for g in &ir.globals {
+            let offset = (addresses[&g.name] - DATA_BASE) as usize;
+            let bytes = match &g.init {
+                Init::Zero => continue,
+                Init::Int(v) if g.size <= 8 => v.to_le_bytes()[..g.size].to_vec(),
+                Init::Addr(name, v) if g.size == 8 => (global_address(&addresses, name)? + v).to_le_bytes().to_vec(),
+                Init::Bytes(bytes) if bytes.len() == g.size => bytes.clone(),
+                _ => return Err(RuntimeError::InvalidInit(g.name.clone())),
+            };
+            self.memory[offset..offset + g.size].copy_from_slice(&bytes);
+        }
+        Ok(addresses)
+    }
+
+    fn memory_range(&self, pc: usize, address: i64, size: u8) -> Result<std::ops::Range<usize>, RuntimeError> {
+        let start = address.checked_sub(DATA_BASE).filter(|v| *v >= 0).map(|v| v as usize);
+        match start {
+            Some(start) if start + size as usize <= self.memory.len() => Ok(start..start + size as usize),
+            _ => Err(RuntimeError::InvalidAddress { pc }),
+        }
+    }
+
+    fn load(&self, pc: usize, address: i64, size: u8) -> Result<i64, RuntimeError> {
+        let mut bytes = [0; 8];
+        bytes[..size as usize].copy_from_slice(&self.memory[self.memory_range(pc, address, size)?]);
+        Ok(ir::truncate(i64::from_le_bytes(bytes), size))
+    }
+
+    fn store(&mut self, pc: usize, address: i64, size: u8, v: i64) -> Result<i64, RuntimeError> {
+        let range = self.memory_range(pc, address, size)?;
+        self.memory[range].copy_from_slice(&v.to_le_bytes()[..size as usize]);
+        Ok(ir::truncate(v, size))
+    }
+
+    fn push(&mut self, pc: usize, v: i64) -> Result<(), RuntimeError> {
+        if self.stack.len() >= self.stack_limit {
+            return Err(RuntimeError::StackOverflow { pc });
+        }
+        self.stack.push(v);
+        Ok(())
+    }
+
+    fn pop(&mut self, pc: usize) -> Result<i64, RuntimeError> {
+        self.stack.pop().ok_or(RuntimeError::StackUnderflow { pc })
+    }
+
+    pub fn run(&mut self, ir: &IR) -> Result<Execution, RuntimeError> {
+        macro_rules! binary {
+            ($pc:expr, $f:expr) => {{
+                let rhs = self.pop($pc)?;
+                let lhs = self.pop($pc)?;
+                self.push($pc, $f(lhs, rhs)?)?
+            }};
+        }
+
+        self.stack.clear();
+        let addresses = self.layout(ir)?;
+        let mut execution = Execution::default();
+        for (pc, inst) in ir.instructions.iter().enumerate() {
+            match inst {
+                PushI(i) => self.push(pc, *i)?,
+                PushG(name) => self.push(pc, global_address(&addresses, name)?)?,
+                Load(size) => {
+                    let address = self.pop(pc)?;
+                    let v = self.load(pc, address, *size)?;
+                    self.push(pc, v)?
+                }
+                Store(size) => {
+                    let v = self.pop(pc)?;
+                    let address = self.pop(pc)?;
+                    let v = self.store(pc, address, *size, v)?;
+                    self.push(pc, v)?
+                }
+                PopI => {
+                    let v = self.pop(pc)?;
+                    execution.result = v;
+                    execution.popped.push(v);
+                }
+                AddI => binary!(pc, |l: i64, r| Ok(l.wrapping_add(r))),
+                SubI => binary!(pc, |l: i64, r| Ok(l.wrapping_sub(r))),
+                MulI => binary!(pc, |l: i64, r| Ok(l.wrapping_mul(r))),
+                DivI => binary!(pc, |l: i64, r: i64| match r {
+                    0 => Err(RuntimeError::DivisionByZero { pc }),
+                    -1 if l == i64::MIN => Err(RuntimeError::DivisionOverflow { pc }),
+                    _ => Ok(l / r),
+                }),
+                RemI => binary!(pc, |l: i64, r: i64| match r {
+                    0 => Err(RuntimeError::DivisionByZero { pc }),
+                    -1 if l == i64::MIN => Err(RuntimeError::DivisionOverflow { pc }),
+                    _ => Ok(l % r),
+                }),
+                Ret => {
+                    execution.result = self.pop(pc)?;
+                    break;
+                }
+            }
+        }
+        for g in &ir.globals {
+            let offset = (addresses[&g.name] - DATA_BASE) as usize;
+            execution.globals.insert(g.name.clone(), self.memory[offset..offset + g.size].to_vec());
+        }
+        Ok(execution)
+    }
+}
+
+fn global_address(addresses: &HashMap<String, i64>, name: &str) -> Result<i64, RuntimeError> {
+    addresses.get(name).copied().ok_or_else(|| RuntimeError::UnknownGlobal(name.to_string()))
+}
+
+pub fn interpret(ir: &IR) -> Result<Execution, RuntimeError> {
+    Interpreter::new().run(ir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Compiler, Source};
+
+    fn run(code: &str) -> Result<Execution, RuntimeError> {
+        let output = Compiler::default().compile(&Source::inline(code));
+        interpret(&output.ir.unwrap())
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        let e = run("1 + 2 * 3; 7 - 8 / 3;").unwrap();
+        assert_eq!(e.popped, vec![7, 5]);
+        assert_eq!(e.result, 5);
+        assert_eq!(run("").unwrap().result, 0);
+
+        let e = run("1; return 2 + 3; 4;").unwrap();
+        assert_eq!(e.popped, vec![1]);
+        assert_eq!(e.result, 5);
+    }
+
+    #[test]
+    fn test_c_semantics() {
+        let ir: IR = vec![PushI(-7), PushI(2), DivI, PopI, PushI(7), PushI(-2), DivI, PopI].into();
+        assert_eq!(interpret(&ir).unwrap().popped, vec![-3, -3]);
+        let ir: IR = vec![PushI(-7), PushI(2), RemI, PopI, PushI(7), PushI(-2), RemI, PopI].into();
+        assert_eq!(interpret(&ir).unwrap().popped, vec![-1, 1]);
+        assert_eq!(run("9223372036854775807 + 1;").unwrap().result, i64::MIN);
+    }
+
+    #[test]
+    fn test_division_errors() {
+        assert_eq!(run("1; 1 / 0;"), Err(RuntimeError::DivisionByZero { pc: 4 }));
+        let ir: IR = vec![PushI(i64::MIN), PushI(-1), DivI, PopI].into();
+        assert_eq!(interpret(&ir), Err(RuntimeError::DivisionOverflow { pc: 2 }));
+        assert_eq!(run("7 % 0;"), Err(RuntimeError::DivisionByZero { pc: 2 }));
+    }
+
+    #[test]
+    fn test_stack_errors() {
+        let ir: IR = vec![PushI(1), AddI].into();
+        assert_eq!(interpret(&ir), Err(RuntimeError::StackUnderflow { pc: 1 }));
+        let ir: IR = vec![PopI].into();
+        assert_eq!(interpret(&ir), Err(RuntimeError::StackUnderflow { pc: 0 }));
+        let ir: IR = vec![PushI(1), PushI(2), PushI(3)].into();
+        let mut interpreter = Interpreter::with_stack_limit(2);
+        assert_eq!(interpreter.run(&ir), Err(RuntimeError::StackOverflow { pc: 2 }));
+    }
+
+    #[test]
+    fn test_memory() {
+        let ir: IR = "\
+            global c 1 = -1\n\
+            global x 4\n\
+            global p 8 = &x+2\n\
+            pushg x\n\
+            pushi 4294967297\n\
+            store 4\n\
+            popi\n\
+            pushg c\n\
+            load 1\n\
+            popi\n\
+            pushg p\n\
+            load 8\n\
+            pushg x\n\
+            subi\n\
+            popi\n\
+            pushg x\n\
+            load 4\n\
+            ret\n"
+            .parse()
+            .unwrap();
+        let e = interpret(&ir).unwrap();
+        assert_eq!(e.popped, vec![1, -1, 2]);
+        assert_eq!(e.result, 1);
+        assert_eq!(e.globals["x"], vec![1, 0, 0, 0]);
+        assert_eq!(e.globals["c"], vec![0xff]);
+        assert_eq!(e.globals.len(), 3);
+        let ir: IR = vec![PushI(DATA_BASE - 1), Load(1)].into();
+        assert_eq!(interpret(&ir), Err(RuntimeError::InvalidAddress { pc: 1 }));
+        let ir: IR = "global c 1\npushg c\npushi 1\nstore 4".parse().unwrap();
+        assert_eq!(interpret(&ir), Err(RuntimeError::InvalidAddress { pc: 2 }));
+    }
+
+    #[test]
+    fn test_side_effects() {
+        let e = run("static int x; char c = 2; x = 300; c = x; x = x + 1; return 0;").unwrap();
+        assert_eq!(e.result, 0);
+        assert_eq!(e.globals["x"], 301i32.to_le_bytes());
+        assert_eq!(e.globals["c"], vec![44]);
+    }
+
+    #[test]
+    fn test_invalid_globals() {
+        let ir: IR = "pushg x\nret".parse().unwrap();
+        assert_eq!(interpret(&ir), Err(RuntimeError::UnknownGlobal("x".to_string())));
+        let ir: IR = "global p 8 = &y".parse().unwrap();
+        assert_eq!(interpret(&ir), Err(RuntimeError::UnknownGlobal("y".to_string())));
+        let ir: IR = "global x 16 = 1".parse().unwrap();
+        assert_eq!(interpret(&ir).unwrap_err().to_string(), "invalid initializer of global x");
+        let ir: IR = "global s 2 = \"abc\"".parse().unwrap();
+        assert_eq!(interpret(&ir), Err(RuntimeError::InvalidInit("s".to_string())));
+        let ir: IR = "global x 8\nglobal p 4 = &x".parse().unwrap();
+        assert_eq!(interpret(&ir), Err(RuntimeError::InvalidInit("p".to_string())));
+    }
+}
@@ -1,3 +1,8 @@
+use crate::Loc;
+use std::fmt;
+use std::str::FromStr;
+use Instruction::*;
+
 #[derive(PartialEq, Eq, Debug, Clone)]
 pub enum Instruction {
     PushI(i64),
@@ -6,11 +11,143 @@ pub enum Instruction {
     SubI,
     MulI,
     DivI,
+    RemI,
+    // Pushes the address of a global.
+    PushG(String),
+    // Pops an address and pushes the sign-extended value of the given size
+    // stored there.
+    Load(u8),
+    // Pops a value and an address, stores the low bytes of the value and pushes
+    // them back sign-extended, which is the value of a C assignment.
+    Store(u8),
+    // Returns from main with the popped value.
+    Ret,
+}
+
+// Access sizes of load and store, and the widths of values
+pub const SIZES: [u8; 3] = [1, 4, 8];
+
+// `i32` for a value of 4 bytes, as in the text formats and LLVM
+pub fn width_name(width: u8) -> String {
+    format!("i{}", width as u32 * 8)
+}
+
+pub fn parse_width(s: &str) -> Option<u8> {
+    SIZES.iter().copied().find(|w| width_name(*w) == s)
+}
+
+// Truncates `v` to `size` bytes and sign-extends it back.
+pub fn truncate(v: i64, size: u8) -> i64 {
+    let shift = 64 - 8 * size as u32;
+    (v << shift) >> shift
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PushI(i) => write!(f, "pushi {}", i),
+            PopI => write!(f, "popi"),
+            AddI => write!(f, "addi"),
+            SubI => write!(f, "subi"),
+            MulI => write!(f, "muli"),
+            DivI => write!(f, "divi"),
+            RemI => write!(f, "remi"),
+            PushG(name) => write!(f, "pushg {}", name),
+            Load(size) => write!(f, "load {}", size),
+            Store(size) => write!(f, "store {}", size),
+            Ret => write!(f, "ret"),
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum Init {
+    // Placed in .bss
+    Zero,
+    // Little endian in the size of the global
+    Int(i64),
+    // Address of a global plus a byte offset. The size is 8.
+    Addr(String, i64),
+    // Exactly the size of the global
+    Bytes(Vec<u8>),
+}
+
+// A variable with static storage duration or a string literal.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Global {
+    pub name: String,
+    pub size: usize,
+    pub init: Init,
+    // Internal linkage: static variables and string literals
+    pub local: bool,
+    pub readonly: bool,
+    // Declared with extern and defined in another object, so it has no data
+    pub external: bool,
+}
+
+impl Global {
+    pub fn new(name: impl Into<String>, size: usize, init: Init) -> Self {
+        Self {
+            name: name.into(),
+            size,
+            init,
+            local: false,
+            readonly: false,
+            external: false,
+        }
+    }
+
+    pub fn align(&self) -> usize {
+        match self.init {
+            Init::Bytes(_) => 1,
+            _ => self.size,
+        }
+    }
 }
 
+// `global x 4 = 3`, `static const .str.0 3 = "hi\x00"`, `extern y 8`. Bytes other than
+// printable ASCII, '"', '\\' and '#' are escaped as \xNN so that a global is
+// always one line of whitespace-separated words.
+impl fmt::Display for Global {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let linkage = match self {
+            _ if self.external => "extern",
+            _ if self.local => "static",
+            _ => "global",
+        };
+        write!(f, "{}", linkage)?;
+        if self.readonly {
+            write!(f, " const")?;
+        }
+        write!(f, " {} {}", self.name, self.size)?;
+        match &self.init {
+            Init::Zero => Ok(()),
+            Init::Int(v) => write!(f, " = {}", v),
+            Init::Addr(name, 0) => write!(f, " = &{}", name),
+            Init::Addr(name, offset) => write!(f, " = &{}{:+}", name, offset),
+            Init::Bytes(bytes) => {
+                write!(f, " = \"")?;
+                for b in bytes {
+                    match b {
+                        b'"' | b'\\' | b'#' => write!(f, "\\x{:02x}", b)?,
+                        0x21..=0x7e => write!(f, "{}", *b as char)?,
+                        _ => write!(f, "\\x{:02x}", b)?,
+                    }
+                }
+                write!(f, "\"")
+            }
+        }
+    }
+}
 #[derive(PartialEq, Eq, Debug, Clone, Default)]
 pub struct IR {
-    pub instructions: Vec<Instruction>
+    pub globals: Vec<Global>,
+    pub instructions: Vec<Instruction>,
+    // Source location of each instruction. Always as long as instructions.
+    pub locs: Vec<Option<Loc>>,
+    // Size of the C type of the value each instruction pushes, 8 without a
+    // value or a type. Always as long as instructions.
+    pub widths: Vec<u8>,
 }
 
 impl IR {
@@ -18,18 +155,419 @@ impl IR {
         Self::default()
     }
     pub fn push(&mut self, v :Instruction) {
-        self.instructions.push(v)
+        self.push_at(v, None)
+    }
+    pub fn push_at(&mut self, v :Instruction, loc: Option<Loc>) {
+        self.push_typed(v, loc, 8)
+    }
+    pub fn push_typed(&mut self, v :Instruction, loc: Option<Loc>, width: u8) {
+        self.instructions.push(v);
+        self.locs.push(loc);
+        self.widths.push(width);
     }
     pub fn pop(&mut self) -> Option<Instruction> {
+        self.locs.pop();
+        self.widths.pop();
         self.instructions.pop()
     }
+    pub fn truncate(&mut self, len: usize) {
+        self.instructions.truncate(len);
+        self.locs.truncate(len);
+        self.widths.truncate(len);
+    }
     pub fn clear(&mut self) {
-        self.instructions.clear()
+        self.truncate(0);
+    }
+    pub fn loc(&self, i: usize) -> Option<Loc> {
+        self.locs.get(i).copied().flatten()
+    }
+    pub fn width(&self, i: usize) -> u8 {
+        self.widths.get(i).copied().unwrap_or(8)
+    }
+    pub fn len(&self) -> usize {
+        self.instructions.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.instructions.is_empty()
+    }
+    pub fn global(&self, name: &str) -> Option<&Global> {
+        self.globals.iter().find(|g| g.name == name)
     }
 }
 
 impl From<Vec<Instruction>> for IR {
     fn from(instructions: Vec<Instruction>) -> IR {
-        Self { instructions }
+        Self {
+            globals: vec![],
+            locs: vec![None; instructions.len()],
+            widths: vec![8; instructions.len()],
+            instructions,
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum VerifyError {
+    // Every instruction must have a location and a width entry.
+    LocsLength,
+    StackUnderflow { pc: usize },
+    UnknownGlobal(String),
+    DuplicateGlobal(String),
+    // The size doesn't fit the initializer, or an extern global has one.
+    InvalidGlobal(String),
+    InvalidSize { pc: usize },
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VerifyError::LocsLength => write!(f, "locations or widths do not match instructions"),
+            VerifyError::StackUnderflow { pc } => write!(f, "{}: stack underflow", pc),
+            VerifyError::UnknownGlobal(name) => write!(f, "unknown global {}", name),
+            VerifyError::DuplicateGlobal(name) => write!(f, "duplicate global {}", name),
+            VerifyError::InvalidGlobal(name) => write!(f, "invalid global {}", name),
+            VerifyError::InvalidSize { pc } => write!(f, "{}: invalid access size", pc),
+        }
+    }
+}
+
+// Checks that no instruction pops more values than were pushed and that globals
+// are defined once and referenced by name.
+pub fn verify(ir: &IR) -> Result<(), VerifyError> {
+    if ir.locs.len() != ir.instructions.len() || ir.widths.len() != ir.instructions.len() {
+        return Err(VerifyError::LocsLength);
+    }
+    if let Some(pc) = ir.widths.iter().position(|w| !SIZES.contains(w)) {
+        return Err(VerifyError::InvalidSize { pc });
+    }
+    let known = |name: &String| match ir.global(name) {
+        Some(_) => Ok(()),
+        None => Err(VerifyError::UnknownGlobal(name.clone())),
+    };
+    for (i, g) in ir.globals.iter().enumerate() {
+        if ir.globals[..i].iter().any(|other| other.name == g.name) {
+            return Err(VerifyError::DuplicateGlobal(g.name.clone()));
+        }
+        let valid = match &g.init {
+            _ if g.external && (g.local || g.readonly) => false,
+            Init::Zero => SIZES.contains(&(g.size as u8)),
+            _ if g.external => false,
+            Init::Int(_) => SIZES.contains(&(g.size as u8)),
+            Init::Addr(name, _) => {
+                known(name)?;
+                g.size == 8
+            }
+            Init::Bytes(bytes) => bytes.len() == g.size,
+        };
+        if !valid {
+            return Err(VerifyError::InvalidGlobal(g.name.clone()));
+        }
+    }
+    let mut depth = 0usize;
+    for (pc, inst) in ir.instructions.iter().enumerate() {
+        let (pops, pushes) = match inst {
+            PushI(_) => (0, 1),
+            PushG(name) => {
+                known(name)?;
+                (0, 1)
+            }
+            Load(size) | Store(size) if !SIZES.contains(size) => {
+                return Err(VerifyError::InvalidSize { pc })
+            }
+            Load(_) => (1, 1),
+            PopI | Ret => (1, 0),
+            AddI | SubI | MulI | DivI | RemI | Store(_) => (2, 1),
+        };
+        depth = depth.checked_sub(pops).ok_or(VerifyError::StackUnderflow { pc })? + pushes;
     }
+    Ok(())
+}
+
+// One global or instruction per line, globals first.
+impl fmt::Display for IR {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for g in &self.globals {
+            writeln!(f, "{}", g)?;
+        }
+        for (i, inst) in self.instructions.iter().enumerate() {
+            write!(f, "{}", inst)?;
+            if self.width(i) != 8 {
+                write!(f, " {}", width_name(self.width(i)))?;
+            }
+            match self.loc(i) {
+                Some(loc) => writeln!(f, " @{}:{}", loc, loc.offset)?,
+                None => writeln!(f)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+// Textual IR (.fir)
+//
+//   program     := line*
+//   line        := [global | instruction [type] [loc]] [comment] "\n"
+//   global      := ("global" | "static" | "extern") ["const"] name size ["=" init]
+//   init        := integer | "&" name [("+" | "-") integer] | '"' bytes '"'
+//   instruction := "pushi" integer | "pushg" name | "load" size | "store" size
+//                | "popi" | "addi" | "subi" | "muli" | "divi" | "remi" | "ret"
+//   type        := "i8" | "i32" | "i64"
+//   loc         := "@" line ":" col ":" offset
+//   comment     := "#" any characters to the end of line
+//
+// Operands are separated by whitespace. The type of the pushed value is i64
+// unless given. The printer emits the same syntax, so `text.parse::<IR>()` of
+// `ir.to_string()` gives back `ir`.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct ParseError {
+    pub loc: Loc,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.loc, self.message)
+    }
+}
+
+fn parse_int<T: FromStr>(v: &str) -> Result<T, String> {
+    v.parse().map_err(|_| format!("Invalid integer: {}", v))
+}
+
+fn parse_bytes(s: &str) -> Result<Vec<u8>, String> {
+    let invalid = || format!("Invalid string: {}", s);
+    let inner = s.strip_prefix('"').and_then(|s| s.strip_suffix('"')).ok_or_else(invalid)?;
+    let mut bytes = vec![];
+    let mut rest = inner.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        if b == b'\\' {
+            let hex = tail.get(1..3).filter(|_| tail[0] == b'x').ok_or_else(invalid)?;
+            let hex = std::str::from_utf8(hex).map_err(|_| invalid())?;
+            bytes.push(u8::from_str_radix(hex, 16).map_err(|_| invalid())?);
+            rest = &tail[3..];
+        } else {
+            bytes.push(b);
+            rest = tail;
+        }
+    }
+    Ok(bytes)
+}
+
+fn parse_global(words: &[&str]) -> Result<Global, String> {
+    let mut words = words.iter().copied();
+    let linkage = words.next();
+    let (local, external) = (linkage == Some("static"), linkage == Some("extern"));
+    let mut name = words.next().ok_or("Expected name of global")?;
+    let readonly = name == "const";
+    if readonly {
+        name = words.next().ok_or("Expected name of global")?;
+    }
+    let size = parse_int(words.next().ok_or("Expected size of global")?)?;
+    let init = match (words.next(), words.next()) {
+        (None, _) => Init::Zero,
+        (Some("="), Some(v)) if v.starts_with('"') => Init::Bytes(parse_bytes(v)?),
+        (Some("="), Some(v)) if v.starts_with('&') => {
+            let v = &v[1..];
+            match v.find(['+', '-']) {
+                Some(i) => Init::Addr(v[..i].to_string(), parse_int(&v[i..])?),
+                None => Init::Addr(v.to_string(), 0),
+            }
+        }
+        (Some("="), Some(v)) => Init::Int(parse_int(v)?),
+        _ => return Err("Expected initializer".to_string()),
+    };
+    if let Some(v) = words.next() {
+        return Err(format!("Unexpected operand: {}", v));
+    }
+    Ok(Global {
+        name: name.to_string(),
+        size,
+        init,
+        local,
+        readonly,
+        external,
+    })
+}
+
+// `@2:3:5`, the source location of an instruction
+fn parse_loc(word: &str) -> Result<Loc, String> {
+    let invalid = || format!("Invalid location: {}", word);
+    let parts: Vec<&str> = word.strip_prefix('@').ok_or_else(invalid)?.split(':').collect();
+    match parts[..] {
+        [line, col, offset] => Ok(Loc::new(parse_int(offset)?, parse_int(line)?, parse_int(col)?)),
+        _ => Err(invalid()),
+    }
+}
+
+// The instruction and the width of its value
+fn parse_instruction(words: &[&str]) -> Result<(Instruction, u8), String> {
+    let operand = |i: usize| words.get(i).copied();
+    let expect = |i: usize| operand(i).ok_or_else(|| format!("Expected operand for {}", words[0]));
+    let inst = match words[0] {
+        "pushi" => PushI(parse_int(expect(1)?)?),
+        "pushg" => PushG(expect(1)?.to_string()),
+        "load" => Load(parse_int(expect(1)?)?),
+        "store" => Store(parse_int(expect(1)?)?),
+        "popi" => PopI,
+        "addi" => AddI,
+        "subi" => SubI,
+        "muli" => MulI,
+        "divi" => DivI,
+        "remi" => RemI,
+        "ret" => Ret,
+        op => return Err(format!("Unknown instruction: {}", op)),
+    };
+    let mut arity = if let PushI(_) | PushG(_) | Load(_) | Store(_) = inst { 2 } else { 1 };
+    let width = operand(arity).and_then(parse_width);
+    if width.is_some() {
+        arity += 1;
+    }
+    match operand(arity) {
+        Some(v) => Err(format!("Unexpected operand: {}", v)),
+        None => Ok((inst, width.unwrap_or(8))),
+    }
+}
+
+impl FromStr for IR {
+    type Err = ParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut ir = IR::new();
+        let mut offset = 0;
+        for (i, line) in s.lines().enumerate() {
+            let code = line.split('#').next().unwrap();
+            let words: Vec<&str> = code.split_whitespace().collect();
+            if !words.is_empty() {
+                let col = code.len() - code.trim_start().len() + 1;
+                let error = |message| ParseError {
+                    loc: Loc::new(offset + col - 1, i + 1, col),
+                    message,
+                };
+                if matches!(words[0], "global" | "static" | "extern") {
+                    ir.globals.push(parse_global(&words).map_err(error)?);
+                } else {
+                    let (words, loc) = match words.split_last() {
+                        Some((last, rest)) if last.starts_with('@') => (rest, Some(parse_loc(last).map_err(error)?)),
+                        _ => (&words[..], None),
+                    };
+                    let (inst, width) = parse_instruction(words).map_err(error)?;
+                    ir.push_typed(inst, loc, width);
+                }
+            }
+            offset += line.chars().count() + 1;
+        }
+        Ok(ir)
+    }
+}
+
+#[test]
+fn test_display() {
+    let ir: IR = vec![PushI(1), PushI(-2), AddI, SubI, MulI, DivI, PopI, Ret].into();
+    assert_eq!(ir.to_string(), "pushi 1\npushi -2\naddi\nsubi\nmuli\ndivi\npopi\nret\n");
+}
+
+#[test]
+fn test_parse() {
+    let ir: IR = "# comment\n  pushi 1\n\npushi -2 # two\naddi\npopi".parse().unwrap();
+    assert_eq!(ir, vec![PushI(1), PushI(-2), AddI, PopI].into());
+    let ir: IR = "pushi 1 @2:3:5 # one\npopi".parse().unwrap();
+    assert_eq!(ir.locs, vec![Some(Loc::new(5, 2, 3)), None]);
+    let ir: IR = "pushi 1 i8\nload 4 i32 @1:1:0\npopi".parse().unwrap();
+    assert_eq!(ir.widths, vec![1, 4, 8]);
+}
+
+#[test]
+fn test_parse_error() {
+    let err = "pushi 1\n  pushi x".parse::<IR>().unwrap_err();
+    assert_eq!(err.loc, Loc::new(10, 2, 3));
+    assert_eq!(err.to_string(), "2:3: Invalid integer: x");
+    assert!("pushi".parse::<IR>().is_err());
+    assert!("addi 1".parse::<IR>().is_err());
+    assert!("jmp".parse::<IR>().is_err());
+    assert_eq!("pushi 1 @2:3".parse::<IR>().unwrap_err().message, "Invalid location: @2:3");
+    assert!("pushi 1 @2:x:5".parse::<IR>().is_err());
+    assert!("pushi @2:3:5".parse::<IR>().is_err());
+    assert!("pushi 1 i16".parse::<IR>().is_err());
+}
+
+#[test]
+fn test_round_trip() {
+    let ir: IR = vec![PushI(i64::MIN), PushI(i64::MAX), AddI, SubI, MulI, DivI, PopI, Ret].into();
+    assert_eq!(ir.to_string().parse::<IR>(), Ok(ir));
+
+    let output = crate::Compiler::default().compile(&crate::Source::inline("static int x = 1;\nx = x * 2;\nreturn x;"));
+    let ir = output.ir.unwrap();
+    assert!(ir.locs.iter().any(|loc| loc.is_some()));
+    assert!(ir.to_string().contains("muli i32 @2:7:24\n"));
+    assert_eq!(ir.to_string().parse::<IR>(), Ok(ir));
+}
+
+#[test]
+fn test_globals() {
+    let text = "\
+        global x 4 = -3\n\
+        static n.1 1\n\
+        global p 8 = &x+4\n\
+        global q 8 = &p\n\
+        static const .str.0 6 = \"a\\x20\\x23\\x23\\x22\\x00\"\n\
+        pushg x\n\
+        pushg p\n\
+        load 8\n\
+        store 4\n\
+        ret\n";
+    let ir: IR = text.parse().unwrap();
+    assert_eq!(ir.globals[0], Global::new("x", 4, Init::Int(-3)));
+    assert_eq!(ir.globals[1].init, Init::Zero);
+    assert!(ir.globals[1].local);
+    assert_eq!(ir.globals[2].init, Init::Addr("x".to_string(), 4));
+    assert_eq!(ir.globals[3].init, Init::Addr("p".to_string(), 0));
+    assert_eq!(ir.globals[4].init, Init::Bytes(b"a ##\"\0".to_vec()));
+    assert!(ir.globals[4].readonly && ir.globals[4].local);
+    assert_eq!(ir.instructions, vec![PushG("x".to_string()), PushG("p".to_string()), Load(8), Store(4), Ret]);
+    assert_eq!(ir.to_string(), text);
+    assert_eq!(ir.to_string().parse::<IR>(), Ok(ir.clone()));
+    assert_eq!(verify(&ir), Ok(()));
+    assert!("global x".parse::<IR>().is_err());
+    assert!("global x 4 = \"\\x0\"".parse::<IR>().is_err());
+    assert!("pushg".parse::<IR>().is_err());
+    assert!("load 4 4".parse::<IR>().is_err());
+}
+
+#[test]
+fn test_truncate() {
+    assert_eq!(truncate(0x1ff, 1), -1);
+    assert_eq!(truncate(0x17f, 1), 127);
+    assert_eq!(truncate(1 << 31, 4), i32::MIN as i64);
+    assert_eq!(truncate(i64::MIN, 8), i64::MIN);
+}
+
+#[test]
+fn test_verify() {
+    let ir: IR = vec![PushI(1), PushI(2), AddI, Ret].into();
+    assert_eq!(verify(&ir), Ok(()));
+    let ir: IR = vec![PushI(1), AddI].into();
+    assert_eq!(verify(&ir), Err(VerifyError::StackUnderflow { pc: 1 }));
+    let mut ir: IR = vec![PushI(1)].into();
+    ir.locs.clear();
+    assert_eq!(verify(&ir), Err(VerifyError::LocsLength));
+
+    let ir: IR = "pushg x\npopi".parse().unwrap();
+    assert_eq!(verify(&ir), Err(VerifyError::UnknownGlobal("x".to_string())));
+    let ir: IR = "global x 8\npushg x\nload 2".parse().unwrap();
+    assert_eq!(verify(&ir), Err(VerifyError::InvalidSize { pc: 1 }));
+    let ir: IR = "global x 8\nstatic x 4".parse().unwrap();
+    assert_eq!(verify(&ir), Err(VerifyError::DuplicateGlobal("x".to_string())));
+    let ir: IR = "global x 4 = &x".parse().unwrap();
+    assert_eq!(verify(&ir), Err(VerifyError::InvalidGlobal("x".to_string())));
+    let ir: IR = "global x 2 = \"a\"".parse().unwrap();
+    assert_eq!(verify(&ir), Err(VerifyError::InvalidGlobal("x".to_string())));
+    let ir: IR = "global x 8 = &y".parse().unwrap();
+    assert_eq!(verify(&ir), Err(VerifyError::UnknownGlobal("y".to_string())));
+    let ir: IR = "extern x 4 = 1".parse().unwrap();
+    assert_eq!(verify(&ir), Err(VerifyError::InvalidGlobal("x".to_string())));
+    let ir: IR = "extern x 4\nglobal p 8 = &x".parse().unwrap();
+    assert_eq!(ir.to_string(), "extern x 4\nglobal p 8 = &x\n");
+    assert!(ir.globals[0].external);
+    assert_eq!(verify(&ir), Ok(()));
+    let ir: IR = "global x 4 = 1\npushg x\npushi 2\nstore 4\npushg x\nload 4\nret".parse().unwrap();
+    assert_eq!(verify(&ir), Ok(()));
 }
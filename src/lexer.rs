@@ -1,4 +1,4 @@
-use crate::{tok, Loc, Source, Symbol, Token, TokenKind};
+use crate::{tok, Keyword, Loc, Source, Symbol, Token, TokenError, TokenKind};
 use std::char;
 use std::iter::Iterator;
 
@@ -67,7 +67,48 @@ impl<'a> Lexer<'a> {
         let loc = self.loc;
         let first = self.peek_char();
         self.consume();
-        tok!(new_ident, first.to_string() + &self.read_while(is_ident_char), loc)
+        let ident = first.to_string() + &self.read_while(is_ident_char);
+        match ident.as_str() {
+            "return" => tok!(new_keyword, Keyword::Return, loc),
+            "int" => tok!(new_keyword, Keyword::Int, loc),
+            "char" => tok!(new_keyword, Keyword::Char, loc),
+            "long" => tok!(new_keyword, Keyword::Long, loc),
+            "static" => tok!(new_keyword, Keyword::Static, loc),
+            "extern" => tok!(new_keyword, Keyword::Extern, loc),
+            "typedef" => tok!(new_keyword, Keyword::Typedef, loc),
+            "sizeof" => tok!(new_keyword, Keyword::Sizeof, loc),
+            _ => tok!(new_ident, ident, loc),
+        }
+    }
+
+    fn read_string(&mut self) -> Token {
+        let loc = self.loc;
+        self.consume();
+        let mut s = String::new();
+        loop {
+            if self.eof() || self.peek_char() == '\n' {
+                let err = TokenError::Message("unterminated string literal".to_string());
+                return tok!(new_error, err, loc);
+            }
+            let c = self.peek_char();
+            self.consume();
+            match c {
+                '"' => return tok!(new_str, s, loc),
+                '\\' if !self.eof() => {
+                    let e = self.peek_char();
+                    self.consume();
+                    s.push(match e {
+                        'n' => '\n',
+                        't' => '\t',
+                        'r' => '\r',
+                        '0' => '\0',
+                        // \\ \" \' and anything unknown stand for themselves
+                        e => e,
+                    });
+                }
+                c => s.push(c),
+            }
+        }
     }
 
     fn consume(&mut self) {
@@ -133,8 +174,16 @@ impl<'a> Iterator for Lexer<'a> {
             '-' => read_sym1!(Minus),
             '*' => read_sym1!(Asterisk),
             '/' => read_sym1!(Slash),
+            '%' => read_sym1!(Percent),
             ';' => read_sym1!(Semicolon),
             '=' => read_sym1!(Equal),
+            '&' => read_sym1!(Ampersand),
+            '{' => read_sym1!(LeftBrace),
+            '}' => read_sym1!(RightBrace),
+            '(' => read_sym1!(LeftParen),
+            ')' => read_sym1!(RightParen),
+            ',' => read_sym1!(Comma),
+            '"' => self.read_string(),
             c if is_ident_first_char(c) => self.read_ident(),
             c if c.is_ascii_digit() => self.read_integer(),
             c => self.consume_and(tok!(new_invalid_char, c, loc)),
@@ -144,7 +193,7 @@ impl<'a> Iterator for Lexer<'a> {
 
 #[cfg(test)]
 mod tests {
-    use crate::{tok, head_tok, sym, Source, Lexer, Symbol, Token, Loc};
+    use crate::{tok, head_tok, sym, keyword, Source, Lexer, Symbol, Token, TokenError, Loc};
 
     fn test_lex(code :&str, expected :Vec<Token>) {
         let s = Source::new("", code);
@@ -170,14 +219,18 @@ mod tests {
 
     #[test]
     fn test_symbols() {
-        test_lex("+-*/;=", vec![
+        test_lex("+-*/;=&{},", vec![
             tok!(new, sym!(Plus), Loc::new(0, 1, 1)),
             tok!(new, sym!(Minus), Loc::new(1, 1, 2)),
             tok!(new, sym!(Asterisk), Loc::new(2, 1, 3)),
             tok!(new, sym!(Slash), Loc::new(3, 1, 4)),
             tok!(new, sym!(Semicolon), Loc::new(4, 1, 5)),
             tok!(new, sym!(Equal), Loc::new(5, 1, 6)),
-            tok!(new_eof, Loc::new(6, 1, 7)),
+            tok!(new, sym!(Ampersand), Loc::new(6, 1, 7)),
+            tok!(new, sym!(LeftBrace), Loc::new(7, 1, 8)),
+            tok!(new, sym!(RightBrace), Loc::new(8, 1, 9)),
+            tok!(new, sym!(Comma), Loc::new(9, 1, 10)),
+            tok!(new_eof, Loc::new(10, 1, 11)),
         ])
     }
 
@@ -198,4 +251,39 @@ mod tests {
             head_tok!(new_ident, "_abc"),
        ])
     }
+
+    #[test]
+    fn test_keyword() {
+        test_lex("return returned", vec![
+            tok!(new, keyword!(Return), Loc::new(0, 1, 1)),
+            tok!(new_ident, "returned", Loc::new(7, 1, 8)),
+        ]);
+        test_lex("static long int char extern", vec![
+            tok!(new, keyword!(Static), Loc::new(0, 1, 1)),
+            tok!(new, keyword!(Long), Loc::new(7, 1, 8)),
+            tok!(new, keyword!(Int), Loc::new(12, 1, 13)),
+            tok!(new, keyword!(Char), Loc::new(16, 1, 17)),
+            tok!(new, keyword!(Extern), Loc::new(21, 1, 22)),
+        ]);
+        test_lex("typedef sizeof(x)", vec![
+            tok!(new, keyword!(Typedef), Loc::new(0, 1, 1)),
+            tok!(new, keyword!(Sizeof), Loc::new(8, 1, 9)),
+            tok!(new, sym!(LeftParen), Loc::new(14, 1, 15)),
+            tok!(new_ident, "x", Loc::new(15, 1, 16)),
+            tok!(new, sym!(RightParen), Loc::new(16, 1, 17)),
+        ])
+    }
+
+    #[test]
+    fn test_string() {
+        test_lex(r#""a\n\"\0" "#, vec![
+            head_tok!(new_str, "a\n\"\0"),
+            tok!(new_eof, Loc::new(10, 1, 11)),
+        ]);
+        let unterminated = || TokenError::Message("unterminated string literal".to_string());
+        test_lex("\"ab\n\"", vec![
+            head_tok!(new_error, unterminated()),
+            tok!(new_error, unterminated(), Loc::new(4, 2, 1)),
+        ]);
+    }
 }
@@ -1,14 +1,31 @@
-pub use token::*;
 pub mod ast;
 pub use ast::*;
 pub mod translate;
 pub use translate::*;
+pub mod eval;
+pub use eval::*;
+pub mod types;
+pub use types::*;
+pub mod scope;
+pub use scope::*;
+pub mod sema;
+pub use sema::*;
 
+pub mod asm;
+pub mod elf;
 pub mod codegen;
 pub use codegen::*;
 
 pub mod ir;
 pub use ir::*;
+pub mod cfg;
+pub mod ssa;
+pub mod fold;
+pub mod dce;
+pub mod pass;
+pub mod regalloc;
+pub mod interpreter;
+pub use interpreter::*;
 
 pub mod lexer;
 pub use lexer::*;
@@ -21,15 +38,22 @@ pub use token::*;
 pub mod source;
 pub use source::*;
 
+pub mod diagnostic;
+pub use diagnostic::*;
+pub mod session;
+pub use session::*;
+
 #[macro_use]
 mod macros;
 
-pub fn compile<'a>(filename: impl Into<String>) -> parser::Result<String> {
+pub fn compile(filename: impl Into<String>) -> std::result::Result<String, Diagnostic> {
+    use std::convert::TryFrom;
     use std::fs;
     let filename_string: String = filename.into();
     let code = fs::read_to_string(&filename_string).unwrap();
     let source = &Source::new(filename_string, code);
-    let ast = Parser::new(lexer::Lexer::new(source)).parse()?;
-    let ir: IR = ast.into();
+    let mut ast = Parser::new(lexer::Lexer::new(source)).parse()?;
+    analyze(&mut ast)?;
+    let ir = IR::try_from(ast)?;
     Ok(x86_64::compile(&ir))
 }
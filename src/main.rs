@@ -1,10 +1,14 @@
 extern crate getopts;
 
-use getopts::Options;
+use getopts::{Matches, Options};
 use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
 use std::process::exit;
 
-use fenixcc::compile;
+use fenixcc::{interpret, Compiler, Emit, RelocationModel, Source, Target};
+use fenixcc::Options as CompileOptions;
 
 fn print_help(program: &str, opts: Options) {
     let brief = format!("Usage: {} INPUT [options]", program);
@@ -13,6 +17,49 @@ fn print_help(program: &str, opts: Options) {
 
 fn setup_opts(opts: &mut Options) {
     opts.optflag("h", "help", "Print help");
+    opts.optopt(
+        "",
+        "emit",
+        "Comma separated list of stages to print",
+        "tokens,ast,ir,cfg,asm,llvm,c,wasm",
+    );
+    opts.optopt("", "target", "Target triple. Global variables and string literals are only supported on x86_64", "x86_64-linux-gnu|aarch64-linux-gnu|riscv64-linux-gnu|wasm32");
+    opts.optopt("O", "", "Optimization level", "0|1|2");
+    opts.optopt("", "passes", "Comma separated list of passes to run instead of -O", "a,b,c");
+    opts.optmulti("", "print-after", "Print IR after the pass to stderr", "PASS");
+    opts.optmulti("f", "", "Enable a feature", "time-report|no-peephole|PIC|PIE|no-pic");
+    opts.optflag("", "from-ir", "Read INPUT as textual IR and start at codegen, or at the passes of -O or --passes");
+    opts.optflag("", "run", "Interpret IR and exit with the result of main");
+    opts.optflag("c", "", "Write an x86_64 object file without an external assembler");
+    opts.optopt("o", "", "Object file of -c, INPUT with .o by default", "FILE");
+    opts.optflag("g", "", "Emit DWARF debug info and line directives in x86_64 assembly, not with -c");
+    opts.optmulti("m", "", "Target-specific option", "asm=intel|att");
+    opts.optmulti("W", "", "Enable a warning", "unreachable-code");
+    opts.optflag("", "dump-tokens", "Print tokens (same as --emit=tokens)");
+    opts.optflag("", "dump-ast", "Print AST (same as --emit=ast)");
+    opts.optflag("", "dump-ir", "Print IR (same as --emit=ir)");
+}
+
+fn emit_kinds(matches: &Matches) -> Result<Vec<Emit>, String> {
+    let mut kinds = vec![];
+    for (flag, kind) in &[
+        ("dump-tokens", Emit::Tokens),
+        ("dump-ast", Emit::Ast),
+        ("dump-ir", Emit::Ir),
+    ] {
+        if matches.opt_present(flag) {
+            kinds.push(*kind);
+        }
+    }
+    if let Some(s) = matches.opt_str("emit") {
+        for v in s.split(',') {
+            kinds.push(v.parse()?);
+        }
+    }
+    if kinds.is_empty() && !matches.opt_present("run") && !matches.opt_present("c") {
+        kinds.push(Emit::Asm);
+    }
+    Ok(kinds)
 }
 
 fn main() {
@@ -24,7 +71,7 @@ fn main() {
 
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
-        Err(f) => panic!(f.to_string()),
+        Err(f) => panic!("{}", f.to_string()),
     };
 
     if matches.opt_present("h") {
@@ -32,6 +79,75 @@ fn main() {
         exit(0);
     }
 
+    let emits = match emit_kinds(&matches) {
+        Ok(v) => v,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            exit(1);
+        }
+    };
+
+    let mut options = CompileOptions::default();
+    let mut outputs = emits.clone();
+    if matches.opt_present("c") {
+        outputs.push(Emit::Object);
+    }
+    options.emit = Some(outputs);
+    if let Some(target) = matches.opt_str("target") {
+        options.target = target.parse().unwrap_or_else(|err| {
+            eprintln!("Error: {}", err);
+            exit(1);
+        });
+    }
+    if let Some(level) = matches.opt_str("O") {
+        options.opt_level = level.parse().unwrap_or_else(|err| {
+            eprintln!("Error: {}", err);
+            exit(1);
+        });
+    }
+    if let Some(passes) = matches.opt_str("passes") {
+        options.passes = Some(passes.split(',').filter(|s| !s.is_empty()).map(String::from).collect());
+    }
+    options.print_after = matches.opt_strs("print-after");
+    options.debug_info = matches.opt_present("g");
+    let mut time_report = false;
+    for feature in matches.opt_strs("f") {
+        match feature.as_str() {
+            "time-report" => time_report = true,
+            "no-peephole" => options.no_peephole = true,
+            "PIC" | "pic" => options.relocation_model = RelocationModel::Pic,
+            "PIE" | "pie" => options.relocation_model = RelocationModel::Pie,
+            "no-pic" | "no-pie" => options.relocation_model = RelocationModel::Static,
+            _ => {
+                eprintln!("Error: Unknown feature: {}", feature);
+                exit(1);
+            }
+        }
+    }
+    for option in matches.opt_strs("m") {
+        match option.split_once('=') {
+            Some(("asm", syntax)) => {
+                options.syntax = syntax.parse().unwrap_or_else(|err| {
+                    eprintln!("Error: {}", err);
+                    exit(1);
+                })
+            }
+            _ => {
+                eprintln!("Error: Unknown option: -m{}", option);
+                exit(1);
+            }
+        }
+    }
+    for warning in matches.opt_strs("W") {
+        match warning.as_str() {
+            "unreachable-code" => options.warn_unreachable_code = true,
+            _ => {
+                eprintln!("Error: Unknown warning: {}", warning);
+                exit(1);
+            }
+        }
+    }
+
     let filename = if !matches.free.is_empty() {
         if matches.free.len() > 1 {
             print_help(&program, opts);
@@ -43,8 +159,83 @@ fn main() {
         exit(0);
     };
 
-    match compile(filename) {
-        Ok(asm) => println!("{}", asm), // generate_code(&ast)),
-        Err(err) => println!("Error: {:#?}", err),
+    let code = match fs::read_to_string(&filename) {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("Error: {}: {}", filename, err);
+            exit(1);
+        }
+    };
+
+    if emits.contains(&Emit::Wasm) && options.target != Target::Wasm32 {
+        eprintln!("Error: --emit=wasm requires --target=wasm32");
+        exit(1);
+    }
+    if matches.opt_present("c") && options.target != Target::X86_64 {
+        eprintln!("Error: -c requires --target=x86_64");
+        exit(1);
+    }
+    // The line table is built by the assembler from .loc directives.
+    if options.debug_info && (matches.opt_present("c") || options.target != Target::X86_64) {
+        eprintln!("Error: -g requires x86_64 assembly output, without -c");
+        exit(1);
+    }
+
+    let source = Source::new(filename.clone(), code);
+    let output = if matches.opt_present("from-ir") {
+        Compiler::new(options).compile_ir(&source)
+    } else {
+        Compiler::new(options).compile(&source)
+    };
+    for (pass, ir) in &output.passes.dumps {
+        eprint!("*** IR Dump After {} ***\n{}", pass, ir);
+    }
+    if time_report {
+        eprint!("{}", output.passes.time_report());
+    }
+    for diagnostic in &output.diagnostics {
+        match diagnostic.loc {
+            Some(_) => eprintln!("{}:{}", filename, diagnostic),
+            None => eprintln!("{}: {}", filename, diagnostic),
+        }
+        eprint!("{}", diagnostic.excerpts(&source));
+    }
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    for kind in emits {
+        let result = match (kind, &output.asm, &output.wasm) {
+            (Emit::Asm, Some(asm), _) => asm.write(&mut out),
+            (Emit::Wasm, _, Some(bytes)) => out.write_all(bytes),
+            _ => match output.emit(kind) {
+                Some(s) => out.write_all(s.as_bytes()),
+                None => Ok(()),
+            },
+        };
+        result.unwrap();
+    }
+    out.flush().unwrap();
+    if output.has_errors() {
+        exit(1);
+    }
+
+    if matches.opt_present("c") {
+        let path = matches.opt_str("o").unwrap_or_else(|| {
+            let stem = Path::new(&filename).file_stem().unwrap().to_string_lossy();
+            format!("{}.o", stem)
+        });
+        if let Err(err) = fs::write(&path, output.object.as_ref().unwrap()) {
+            eprintln!("Error: {}: {}", path, err);
+            exit(1);
+        }
+    }
+
+    if matches.opt_present("run") {
+        match interpret(output.ir.as_ref().unwrap()) {
+            Ok(execution) => exit(execution.result as i32),
+            Err(err) => {
+                eprintln!("{}: runtime error: {}", filename, err);
+                exit(1);
+            }
+        }
     }
 }
@@ -1,6 +1,6 @@
 use crate::ast::AST;
 
-use crate::{ast, sym, Token, TokenKind};
+use crate::{ast, keyword, sym, Node, ScopeKind, ScopeTable, StorageClass, Token, TokenKind, Type};
 
 #[derive(PartialEq, Eq, Debug, Clone)]
 pub enum Error {
@@ -10,38 +10,156 @@ pub enum Error {
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+// What a parenthesis after sizeof or in an expression holds
+enum Parenthesized {
+    Type(Type),
+    Expr(AST),
+}
+
 use std::iter::{Iterator, Peekable};
 pub struct Parser<Tokens: Iterator<Item = Token>> {
     pub tokens: Peekable<Tokens>,
+    // The declarations seen so far, to tell type names from other identifiers
+    pub scopes: ScopeTable,
 }
 
 impl<Tokens: Iterator<Item = Token>> Parser<Tokens> {
     pub fn new(tokens: Tokens) -> Self {
         Self {
             tokens: tokens.peekable(),
+            scopes: ScopeTable::new(),
         }
     }
 
     pub fn parse(&mut self) -> Result<AST> {
         let mut items: Vec<AST> = vec![];
+        self.scopes.clear();
+        self.scopes.push(ScopeKind::File);
         while !self.eof() {
-            items.push(self.parse_statement()?);
+            items.extend(self.parse_item()?);
         }
         Ok(ast!(new_block, items))
     }
 
+    // A declaration yields one item per declarator.
+    fn parse_item(&mut self) -> Result<Vec<AST>> {
+        let kind = self.peek_token().kind.clone();
+        if matches!(kind, keyword!(Static) | keyword!(Extern) | keyword!(Typedef)) || self.is_type_start(&kind) {
+            self.parse_declaration()
+        } else {
+            Ok(vec![self.parse_statement()?])
+        }
+    }
+
+    // `T * x;` declares x if T is a typedef name and multiplies otherwise.
+    fn is_type_start(&self, kind: &TokenKind) -> bool {
+        match kind {
+            keyword!(Int) | keyword!(Char) | keyword!(Long) => true,
+            TokenKind::Ident(name) => self.scopes.is_type_name(name),
+            _ => false,
+        }
+    }
+
+    fn parse_type_specifier(&mut self) -> Result<Type> {
+        let tok = self.next_token();
+        match &tok.kind {
+            keyword!(Int) => Ok(Type::Int),
+            keyword!(Char) => Ok(Type::Char),
+            keyword!(Long) => {
+                if self.peek_token().kind == keyword!(Int) {
+                    self.next_token();
+                }
+                Ok(Type::Long)
+            }
+            TokenKind::Ident(name) if self.scopes.is_type_name(name) => Ok(self.scopes.lookup(name).unwrap().ty.clone()),
+            _ => Err(Error::Message(tok, "Expected type name".to_string())),
+        }
+    }
+
+    // A type without a declared name, as in casts and sizeof: `char *`.
+    fn parse_type_name(&mut self) -> Result<Type> {
+        let mut ty = self.parse_type_specifier()?;
+        while self.peek_token().kind == sym!(Asterisk) {
+            self.next_token();
+            ty = Type::pointer_to(ty);
+        }
+        Ok(ty)
+    }
+
+    fn parse_declaration(&mut self) -> Result<Vec<AST>> {
+        let storage = match self.peek_token().kind {
+            keyword!(Static) => Some(StorageClass::Static),
+            keyword!(Extern) => Some(StorageClass::Extern),
+            keyword!(Typedef) => Some(StorageClass::Typedef),
+            _ => None,
+        };
+        if storage.is_some() {
+            self.next_token();
+        }
+        let base = self.parse_type_specifier()?;
+        let mut decls = vec![];
+        loop {
+            let mut ty = base.clone();
+            while self.peek_token().kind == sym!(Asterisk) {
+                self.next_token();
+                ty = Type::pointer_to(ty);
+            }
+            let name = self.read_token_with_match(|t| matches!(t.kind, TokenKind::Ident(_)))?;
+            let mut decl = ast!(new_declaration, name, ty, storage, None);
+            // The scope of the name begins before its initializer (C11
+            // 6.2.1p7). Sema reports conflicting declarations.
+            if let Node::Declaration(v) = &decl.node {
+                let _ = self.scopes.declare(v);
+            }
+            if self.peek_token().kind == sym!(Equal) {
+                self.next_token();
+                let init = self.parse_assignment()?;
+                if let Node::Declaration(v) = &mut decl.node {
+                    v.init = Some(Box::new(init));
+                }
+            }
+            decls.push(decl);
+            if self.peek_token().kind != sym!(Comma) {
+                break;
+            }
+            self.next_token();
+        }
+        self.expect(sym!(Semicolon), "Expected semicolon")?;
+        Ok(decls)
+    }
 
     pub fn parse_statement(&mut self) -> Result<AST> {
-        let ast = ast!(new_expr_statement, self.parse_expr()?);
-        if self.peek_token().kind != sym!(Semicolon) {
-            return Err(
-                Error::Message(self.peek_token().clone(), "Expected semicolon".to_string())
-            )
+        if self.peek_token().kind == sym!(LeftBrace) {
+            self.next_token();
+            let mut items = vec![];
+            self.scopes.push(ScopeKind::Block);
+            while self.peek_token().kind != sym!(RightBrace) {
+                if self.eof() {
+                    return Err(Error::Message(self.peek_token().clone(), "Expected }".to_string()));
+                }
+                items.extend(self.parse_item()?);
+            }
+            self.scopes.pop();
+            self.next_token();
+            return Ok(ast!(new_block, items));
         }
-        self.next_token();
+        let ast = if self.peek_token().kind == keyword!(Return) {
+            let tok = self.next_token();
+            ast!(new_return, tok, self.parse_expr()?)
+        } else {
+            ast!(new_expr_statement, self.parse_expr()?)
+        };
+        self.expect(sym!(Semicolon), "Expected semicolon")?;
         Ok(ast)
     }
 
+    fn expect(&mut self, kind: TokenKind, message: &str) -> Result<Token> {
+        if self.peek_token().kind != kind {
+            return Err(Error::Message(self.peek_token().clone(), message.to_string()));
+        }
+        Ok(self.next_token())
+    }
+
     fn eof(&mut self) -> bool {
         self.peek_token().kind == TokenKind::EOF
     }
@@ -56,7 +174,18 @@ impl<Tokens: Iterator<Item = Token>> Parser<Tokens> {
     }
 
     fn parse_expr(&mut self) -> Result<AST> {
-        self.parse_add_sub()
+        self.parse_assignment()
+    }
+
+    // Right associative: `a = b = 1` is `a = (b = 1)`.
+    fn parse_assignment(&mut self) -> Result<AST> {
+        let lhs = self.parse_add_sub()?;
+        if self.peek_token().kind != sym!(Equal) {
+            return Ok(lhs);
+        }
+        let op = self.next_token();
+        let rhs = self.parse_assignment()?;
+        Ok(ast!(new_binary_expr, lhs, op, rhs))
     }
 
     fn parse_add_sub(&mut self) -> Result<AST> {
@@ -70,10 +199,10 @@ impl<Tokens: Iterator<Item = Token>> Parser<Tokens> {
     }
 
     fn parse_mul_div(&mut self) -> Result<AST> {
-        let mut ast = self.parse_value()?;
-        while matches!(self.peek_token().kind, sym!(Asterisk) | sym!(Slash)) {
+        let mut ast = self.parse_unary()?;
+        while matches!(self.peek_token().kind, sym!(Asterisk) | sym!(Slash) | sym!(Percent)) {
             let op = self.read_symbol()?;
-            let rhs = self.parse_value()?;
+            let rhs = self.parse_unary()?;
             ast = ast!(new_binary_expr, ast, op, rhs);
         }
         Ok(ast)
@@ -92,8 +221,63 @@ impl<Tokens: Iterator<Item = Token>> Parser<Tokens> {
         self.read_token_with_match(|t| matches!(t.kind, TokenKind::Symbol(_)))
     }
 
+    fn parse_unary(&mut self) -> Result<AST> {
+        match self.peek_token().kind {
+            sym!(Ampersand) => {
+                let op = self.next_token();
+                let operand = self.parse_unary()?;
+                Ok(ast!(new_address_of, op, operand))
+            }
+            keyword!(Sizeof) => {
+                let op = self.next_token();
+                if self.peek_token().kind != sym!(LeftParen) {
+                    let operand = self.parse_unary()?;
+                    return Ok(ast!(new_size_of, op, None, Some(operand)));
+                }
+                match self.parse_parenthesized()? {
+                    Parenthesized::Type(ty) => Ok(ast!(new_size_of, op, Some(ty), None)),
+                    Parenthesized::Expr(expr) => Ok(ast!(new_size_of, op, None, Some(expr))),
+                }
+            }
+            sym!(LeftParen) => {
+                let op = self.peek_token().clone();
+                match self.parse_parenthesized()? {
+                    Parenthesized::Type(ty) => {
+                        let operand = self.parse_unary()?;
+                        Ok(ast!(new_cast, op, ty, operand))
+                    }
+                    Parenthesized::Expr(expr) => Ok(expr),
+                }
+            }
+            _ => self.parse_value(),
+        }
+    }
+
+    // `(type-name)` or `(expr)`, told apart by the token after the parenthesis
+    fn parse_parenthesized(&mut self) -> Result<Parenthesized> {
+        self.expect(sym!(LeftParen), "Expected (")?;
+        let kind = self.peek_token().kind.clone();
+        let inner = if self.is_type_start(&kind) {
+            Parenthesized::Type(self.parse_type_name()?)
+        } else {
+            Parenthesized::Expr(self.parse_expr()?)
+        };
+        self.expect(sym!(RightParen), "Expected )")?;
+        Ok(inner)
+    }
+
     fn parse_value(&mut self) -> Result<AST> {
-        self.parse_int()
+        match self.peek_token().kind {
+            TokenKind::Ident(_) => {
+                let tok = self.next_token();
+                Ok(ast!(new_identifier, tok))
+            }
+            TokenKind::Str(_) => {
+                let tok = self.next_token();
+                Ok(ast!(new_literal, tok))
+            }
+            _ => self.parse_int(),
+        }
     }
 
     fn parse_int(&mut self) -> Result<AST> {
@@ -104,7 +288,7 @@ impl<Tokens: Iterator<Item = Token>> Parser<Tokens> {
 
 #[cfg(test)]
 mod tests {
-    use crate::{ast, tok, sym, Loc, Parser};
+    use crate::{ast, tok, sym, keyword, Lexer, Linkage, Loc, Parser, Source, StorageClass, Type};
 
     #[test]
     fn test_expr() {
@@ -148,4 +332,141 @@ mod tests {
             )]
         ));
     }
+
+    #[test]
+    fn test_return() {
+        let tokens = vec![
+            tok!(new, keyword!(Return), Loc::new(0, 1, 1)),
+            tok!(new_int, 0, Loc::new(7, 1, 8)),
+            tok!(new, sym!(Semicolon), Loc::new(8, 1, 9)),
+            tok!(new_eof, Loc::new(9, 1, 10)),
+        ];
+        let v = Parser::new(tokens.clone().into_iter()).parse().unwrap();
+        assert_eq!(v, ast!(
+            new_block,
+            vec![ast!(
+                new_return,
+                tokens[0].clone(),
+                ast!(new_literal, tokens[1].clone()),
+            )]
+        ));
+    }
+
+    fn parse_source(code: &str) -> String {
+        let source = Source::new("", code);
+        match Parser::new(Lexer::new(&source)).parse() {
+            Ok(ast) => ast.to_string(),
+            Err(err) => format!("{:?}", err),
+        }
+    }
+
+    #[test]
+    fn test_declaration() {
+        let tokens = vec![
+            tok!(new, keyword!(Static), Loc::new(0, 1, 1)),
+            tok!(new, keyword!(Long), Loc::new(7, 1, 8)),
+            tok!(new, sym!(Asterisk), Loc::new(12, 1, 13)),
+            tok!(new_ident, "p", Loc::new(13, 1, 14)),
+            tok!(new, sym!(Comma), Loc::new(14, 1, 15)),
+            tok!(new_ident, "q", Loc::new(16, 1, 17)),
+            tok!(new, sym!(Equal), Loc::new(18, 1, 19)),
+            tok!(new_int, 1, Loc::new(20, 1, 21)),
+            tok!(new, sym!(Semicolon), Loc::new(21, 1, 22)),
+            tok!(new_eof, Loc::new(22, 1, 23)),
+        ];
+        let v = Parser::new(tokens.clone().into_iter()).parse().unwrap();
+        assert_eq!(v, ast!(new_block, vec![
+            ast!(
+                new_declaration,
+                tokens[3].clone(),
+                Type::pointer_to(Type::Long),
+                Some(StorageClass::Static),
+                None,
+            ),
+            ast!(
+                new_declaration,
+                tokens[5].clone(),
+                Type::Long,
+                Some(StorageClass::Static),
+                Some(ast!(new_literal, tokens[7].clone())),
+            ),
+        ]));
+    }
+
+    #[test]
+    fn test_items() {
+        assert_eq!(parse_source("char *s = \"a\"; { x = y = &s; }"), "\
+            Block\n  \
+              Declaration char * s <1:7>\n    \
+                StringLiteral \"a\" <1:11>\n  \
+              Block\n    \
+                ExprStatement\n      \
+                  Assignment <1:20>\n        \
+                    Identifier x <1:18>\n        \
+                    Assignment <1:24>\n          \
+                      Identifier y <1:22>\n          \
+                      AddressOf <1:26>\n            \
+                        Identifier s <1:27>\n\
+        ");
+        assert!(parse_source("{ 1;").starts_with("Message(Token { kind: EOF"));
+        assert!(parse_source("int 1;").contains("Unexpected Token"));
+        assert!(parse_source("int x").contains("Expected semicolon"));
+        assert_eq!(parse_source("extern long y;"), "Block\n  Declaration extern long y <1:13>\n");
+    }
+
+    #[test]
+    fn test_typedef() {
+        assert_eq!(parse_source("typedef long T; T * x; { int T; T * x; }"), "\
+            Block\n  \
+              Declaration typedef long T <1:14>\n  \
+              Declaration long * x <1:21>\n  \
+              Block\n    \
+                Declaration int T <1:30>\n    \
+                ExprStatement\n      \
+                  Multiplication <1:35>\n        \
+                    Identifier T <1:33>\n        \
+                    Identifier x <1:37>\n\
+        ");
+        assert_eq!(parse_source("typedef char *S, C; S *p; C c;").lines().skip(3).collect::<Vec<_>>(), vec![
+            "  Declaration char ** p <1:24>",
+            "  Declaration char c <1:29>",
+        ]);
+    }
+
+    #[test]
+    fn test_cast_and_sizeof() {
+        assert_eq!(parse_source("typedef int T; (T *)(x) * sizeof(T) + sizeof (x) + sizeof x;").lines().skip(2).collect::<Vec<_>>(), vec![
+            "  ExprStatement",
+            "    Addition <1:50>",
+            "      Addition <1:37>",
+            "        Multiplication <1:25>",
+            "          Cast int * <1:16>",
+            "            Identifier x <1:22>",
+            "          SizeOf int <1:27>",
+            "        SizeOf <1:39>",
+            "          Identifier x <1:47>",
+            "      SizeOf <1:52>",
+            "        Identifier x <1:59>",
+        ]);
+        assert_eq!(parse_source("(char)1;").lines().nth(2), Some("    Cast char <1:1>"));
+        assert!(parse_source("(x;").contains("Expected )"));
+        assert!(parse_source("typedef int T; T;").contains("Unexpected Token"));
+        assert_eq!(parse_source("typedef int T; { static long T = sizeof(T); }").lines().skip(2).collect::<Vec<_>>(), vec![
+            "  Block",
+            "    Declaration static long T <1:30>",
+            "      SizeOf <1:34>",
+            "        Identifier T <1:41>",
+        ]);
+    }
+
+    #[test]
+    fn test_scopes() {
+        let source = Source::inline("int x; { static char *x, y; x; } extern long z;");
+        let mut parser = Parser::new(Lexer::new(&source));
+        parser.parse().unwrap();
+        assert_eq!(parser.scopes.depth(), 1);
+        assert_eq!(parser.scopes.lookup("x").unwrap().ty, Type::Int);
+        assert_eq!(parser.scopes.lookup("y"), None);
+        assert_eq!(parser.scopes.lookup("z").unwrap().linkage, Linkage::External);
+    }
 }
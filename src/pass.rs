@@ -0,0 +1,252 @@
+use crate::cfg::Function;
+use crate::{dce, fold, ir, ssa, Diagnostic, OptLevel, IR};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy)]
+pub enum PassKind {
+    // Runs on the stack IR and returns warnings.
+    Stack(fn(&mut IR) -> Vec<Diagnostic>),
+    // Runs on the CFG in or out of SSA form. The CFG is built from the stack IR
+    // before the first CFG pass.
+    Cfg(fn(&mut Function)),
+    // Requires and preserves SSA form.
+    Ssa(fn(&mut Function)),
+    IntoSsa(fn(&mut Function)),
+    OutOfSsa(fn(&mut Function)),
+}
+
+#[derive(Clone, Copy)]
+pub struct Pass {
+    pub name: &'static str,
+    pub kind: PassKind,
+}
+
+pub const PASSES: &[Pass] = &[
+    Pass { name: "fold", kind: PassKind::Stack(fold::fold) },
+    Pass { name: "dce", kind: PassKind::Stack(dce::dce) },
+    Pass { name: "mem2reg", kind: PassKind::IntoSsa(ssa::mem2reg) },
+    Pass { name: "sccp", kind: PassKind::Ssa(fold::sccp) },
+    Pass { name: "cfg-dce", kind: PassKind::Cfg(dce::dce_function) },
+    Pass { name: "out-of-ssa", kind: PassKind::OutOfSsa(ssa::destruct) },
+];
+
+pub fn find_pass(name: &str) -> Option<Pass> {
+    PASSES.iter().find(|p| p.name == name).copied()
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum PassError {
+    UnknownPass(String),
+    // Stack IR passes can't run once the CFG has been built.
+    StackAfterCfg(String),
+    RequiresSsa(String),
+}
+
+impl fmt::Display for PassError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PassError::UnknownPass(name) => write!(f, "Unknown pass: {}", name),
+            PassError::StackAfterCfg(name) => write!(f, "Pass {} must run before CFG passes", name),
+            PassError::RequiresSsa(name) => write!(f, "Pass {} requires SSA form (run mem2reg first)", name),
+        }
+    }
+}
+
+// Result of PassManager::run.
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct PassReport {
+    // Set if a CFG pass ran.
+    pub cfg: Option<Function>,
+    pub warnings: Vec<Diagnostic>,
+    pub timings: Vec<(&'static str, Duration)>,
+    // IR printed after each pass in print_after
+    pub dumps: Vec<(&'static str, String)>,
+}
+
+impl PassReport {
+    pub fn time_report(&self) -> String {
+        let mut s = String::from("===- Pass execution timing report -===\n  Time (s)  Name\n");
+        for (name, time) in &self.timings {
+            s += &format!("  {:.6}  {}\n", time.as_secs_f64(), name);
+        }
+        let total: Duration = self.timings.iter().map(|(_, t)| *t).sum();
+        s += &format!("  {:.6}  Total\n", total.as_secs_f64());
+        s
+    }
+}
+
+// Runs named passes in order. The IR is verified after every pass in debug builds.
+#[derive(Clone)]
+pub struct PassManager {
+    passes: Vec<Pass>,
+    print_after: Vec<&'static str>,
+    pub verify: bool,
+    // Keep source lines in the CFG, see Function::from_ir.
+    pub lines: bool,
+}
+
+impl PassManager {
+    pub fn new<S: AsRef<str>>(names: &[S]) -> Result<Self, PassError> {
+        let mut cfg = false;
+        let mut ssa = false;
+        let mut passes = vec![];
+        for name in names {
+            let name = name.as_ref();
+            let pass = find_pass(name).ok_or_else(|| PassError::UnknownPass(name.to_string()))?;
+            match pass.kind {
+                PassKind::Stack(_) if cfg => return Err(PassError::StackAfterCfg(name.to_string())),
+                PassKind::Stack(_) => {}
+                PassKind::Ssa(_) | PassKind::OutOfSsa(_) if !ssa => {
+                    return Err(PassError::RequiresSsa(name.to_string()))
+                }
+                _ => cfg = true,
+            }
+            match pass.kind {
+                PassKind::IntoSsa(_) => ssa = true,
+                PassKind::OutOfSsa(_) => ssa = false,
+                _ => {}
+            }
+            passes.push(pass);
+        }
+        Ok(Self {
+            passes,
+            print_after: vec![],
+            verify: cfg!(debug_assertions),
+            lines: false,
+        })
+    }
+
+    pub fn for_level(level: OptLevel) -> Self {
+        let names: &[&str] = match level {
+            OptLevel::O0 => &[],
+            OptLevel::O1 => &["fold", "dce"],
+            OptLevel::O2 => &["fold", "dce", "mem2reg", "sccp", "cfg-dce", "out-of-ssa"],
+        };
+        Self::new(names).unwrap()
+    }
+
+    pub fn print_after(&mut self, name: &str) -> Result<&mut Self, PassError> {
+        let pass = find_pass(name).ok_or_else(|| PassError::UnknownPass(name.to_string()))?;
+        self.print_after.push(pass.name);
+        Ok(self)
+    }
+
+    pub fn names(&self) -> Vec<&'static str> {
+        self.passes.iter().map(|p| p.name).collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.passes.is_empty()
+    }
+
+    // Fails if the CFG can't be built from the stack IR.
+    pub fn run(&self, ir: &mut IR) -> Result<PassReport, ir::VerifyError> {
+        let mut report = PassReport::default();
+        let mut ssa = false;
+        for pass in &self.passes {
+            let start = Instant::now();
+            match pass.kind {
+                PassKind::Stack(run) => report.warnings.extend(run(ir)),
+                PassKind::Cfg(run) | PassKind::Ssa(run) | PassKind::IntoSsa(run) | PassKind::OutOfSsa(run) => {
+                    let f = match report.cfg.take() {
+                        Some(f) => f,
+                        None => Function::from_ir(ir, self.lines)?,
+                    };
+                    run(report.cfg.insert(f))
+                }
+            }
+            report.timings.push((pass.name, start.elapsed()));
+            match pass.kind {
+                PassKind::IntoSsa(_) => ssa = true,
+                PassKind::OutOfSsa(_) => ssa = false,
+                _ => {}
+            }
+
+            if self.print_after.contains(&pass.name) {
+                let text = match &report.cfg {
+                    Some(f) => f.to_string(),
+                    None => ir.to_string(),
+                };
+                report.dumps.push((pass.name, text));
+            }
+            if self.verify {
+                let result = match &report.cfg {
+                    Some(f) if ssa => ssa::verify_ssa(f).map_err(|e| e.to_string()),
+                    Some(f) => f.verify().map_err(|e| e.to_string()),
+                    None => ir::verify(ir).map_err(|e| e.to_string()),
+                };
+                if let Err(err) = result {
+                    panic!("IR verification failed after {}: {}", pass.name, err);
+                }
+            }
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cfg::execute;
+    use crate::Instruction::*;
+    use crate::{Compiler, Source};
+
+    fn ir(code: &str) -> IR {
+        Compiler::default().compile(&Source::inline(code)).ir.unwrap()
+    }
+
+    #[test]
+    fn test_new() {
+        assert_eq!(PassManager::new(&["fold", "dce"]).unwrap().names(), vec!["fold", "dce"]);
+        assert_eq!(PassManager::new(&["x"]).err(), Some(PassError::UnknownPass("x".to_string())));
+        assert_eq!(PassManager::new(&["cfg-dce", "fold"]).err(), Some(PassError::StackAfterCfg("fold".to_string())));
+        assert_eq!(PassManager::new(&["sccp"]).err(), Some(PassError::RequiresSsa("sccp".to_string())));
+        assert!(PassManager::new(&["mem2reg", "out-of-ssa", "sccp"]).is_err());
+        assert!(PassManager::for_level(OptLevel::O0).is_empty());
+    }
+
+    #[test]
+    fn test_run() {
+        let mut code = ir("1 + 2; return 3 * 4; 5;");
+        let report = PassManager::for_level(OptLevel::O1).run(&mut code).unwrap();
+        assert_eq!(code.instructions, vec![PushI(12), Ret]);
+        assert_eq!(report.cfg, None);
+        assert_eq!(report.warnings.len(), 1);
+        assert_eq!(report.timings.iter().map(|(n, _)| *n).collect::<Vec<_>>(), vec!["fold", "dce"]);
+
+        let mut code = ir("7 / 2 + 1;");
+        let report = PassManager::for_level(OptLevel::O2).run(&mut code).unwrap();
+        let f = report.cfg.unwrap();
+        assert_eq!(execute(&f, 100), Some(4));
+        assert_eq!(f.to_string(), "fn main -> i32 {\nbb0:\n  ret 4\n}\n");
+    }
+
+    #[test]
+    fn test_print_after() {
+        let mut manager = PassManager::new(&["dce", "mem2reg", "sccp"]).unwrap();
+        manager.print_after("dce").unwrap().print_after("sccp").unwrap();
+        assert!(manager.print_after("x").is_err());
+        let report = manager.run(&mut ir("1; 2 * 3;")).unwrap();
+        assert_eq!(report.dumps, vec![
+            ("dce", "pushi 2 i32 @1:4:3\npushi 3 i32 @1:8:7\nmuli i32 @1:6:5\npopi\n".to_string()),
+            ("sccp", "fn main -> i32 {\nbb0:\n  ret 6\n}\n".to_string()),
+        ]);
+        assert!(report.time_report().ends_with("Total\n"));
+    }
+
+    #[test]
+    #[should_panic(expected = "IR verification failed after fold")]
+    fn test_verify() {
+        let mut manager = PassManager::new(&["fold"]).unwrap();
+        manager.verify = true;
+        manager.run(&mut vec![PushI(1), AddI].into()).unwrap();
+    }
+
+    #[test]
+    fn test_run_invalid() {
+        let manager = PassManager::new(&["mem2reg"]).unwrap();
+        let result = manager.run(&mut vec![PushI(1), AddI, Ret].into());
+        assert_eq!(result, Err(ir::VerifyError::StackUnderflow { pc: 1 }));
+    }
+}
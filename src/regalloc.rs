@@ -0,0 +1,307 @@
+use crate::cfg::{BlockId, Function, Inst, Operand, VReg};
+use std::collections::HashSet;
+
+// A machine register the allocator may assign. Registers reserved as scratch by
+// the backend are not listed.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct RegisterInfo {
+    pub name: &'static str,
+    // Must be saved by the prologue if used.
+    pub callee_saved: bool,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash)]
+pub enum Location {
+    // Index into the register list passed to allocate
+    Reg(usize),
+    // Index of the spill slot in the frame
+    Spill(usize),
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct Allocation {
+    // Location of each VReg. None if it's never live.
+    pub locations: Vec<Option<Location>>,
+    pub spill_count: usize,
+    // Callee-saved registers in use, by index
+    pub callee_saved: Vec<usize>,
+    // Blocks in the order they were numbered, which is also the layout order.
+    // Unreachable blocks are left out.
+    pub order: Vec<BlockId>,
+}
+
+impl Allocation {
+    pub fn location(&self, r: VReg) -> Location {
+        self.locations[r.0].expect("register without location")
+    }
+
+    pub fn operand(&self, v: Operand) -> Option<Location> {
+        v.reg().map(|r| self.location(r))
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct Interval {
+    pub vreg: VReg,
+    pub start: usize,
+    pub end: usize,
+}
+
+// Registers live at the start and at the end of each block.
+pub fn liveness(f: &Function) -> (Vec<HashSet<VReg>>, Vec<HashSet<VReg>>) {
+    let mut live_in: Vec<HashSet<VReg>> = vec![HashSet::new(); f.blocks.len()];
+    let mut live_out: Vec<HashSet<VReg>> = vec![HashSet::new(); f.blocks.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for id in (0..f.blocks.len()).rev().map(BlockId) {
+            let block = f.block(id);
+            let mut live: HashSet<VReg> = f.successors(id).iter().flat_map(|s| live_in[s.0].iter().copied()).collect();
+            if live != live_out[id.0] {
+                live_out[id.0] = live.clone();
+                changed = true;
+            }
+            live.extend(block.term.uses());
+            for inst in block.insts.iter().rev() {
+                if let Some(r) = inst.def() {
+                    live.remove(&r);
+                }
+                live.extend(inst.uses());
+            }
+            if live != live_in[id.0] {
+                live_in[id.0] = live;
+                changed = true;
+            }
+        }
+    }
+    (live_in, live_out)
+}
+
+// One interval per register from its first definition to its last use, with
+// instructions numbered along `order`. Sorted by start.
+pub fn intervals(f: &Function, order: &[BlockId]) -> Vec<Interval> {
+    let (live_in, live_out) = liveness(f);
+    let mut ranges: Vec<Option<(usize, usize)>> = vec![None; f.vreg_count];
+    let mut extend = |r: VReg, pos: usize| {
+        let range = ranges[r.0].get_or_insert((pos, pos));
+        range.0 = range.0.min(pos);
+        range.1 = range.1.max(pos);
+    };
+    let mut pos = 0;
+    for id in order {
+        let block = f.block(*id);
+        for r in &live_in[id.0] {
+            extend(*r, pos);
+        }
+        for inst in &block.insts {
+            for r in inst.uses() {
+                extend(r, pos);
+            }
+            if let Some(r) = inst.def() {
+                extend(r, pos);
+            }
+            pos += 1;
+        }
+        for r in block.term.uses() {
+            extend(r, pos);
+        }
+        for r in &live_out[id.0] {
+            extend(*r, pos);
+        }
+        pos += 1;
+    }
+    let mut intervals: Vec<Interval> = ranges
+        .iter()
+        .enumerate()
+        .filter_map(|(i, r)| r.map(|(start, end)| Interval { vreg: VReg(i), start, end }))
+        .collect();
+    intervals.sort_by_key(|i| (i.start, i.vreg));
+    intervals
+}
+
+// Linear scan register allocation (Poletto and Sarkar) for a function out of SSA
+// form. A register may be reused by a value defined by the instruction that last
+// uses its previous value, so backends must read operands before writing the
+// result. Caller-saved registers are preferred since there are no calls that
+// would clobber them. When no register is free, the interval that ends last is
+// spilled. Copies and the lhs of binary operations are coalesced by preferring
+// the register of the source when it becomes free at the definition.
+pub fn allocate(f: &Function, registers: &[RegisterInfo]) -> Allocation {
+    let order = f.reverse_postorder();
+    let intervals = intervals(f, &order);
+
+    let mut hints: Vec<Option<VReg>> = vec![None; f.vreg_count];
+    for inst in f.blocks.iter().flat_map(|b| &b.insts) {
+        match inst {
+            Inst::Copy { dst, src: Operand::Reg(src) } | Inst::Binary { dst, lhs: Operand::Reg(src), .. } => {
+                hints[dst.0] = Some(*src);
+            }
+            _ => {}
+        }
+    }
+
+    let mut allocation = Allocation {
+        locations: vec![None; f.vreg_count],
+        order,
+        ..Default::default()
+    };
+    let mut free = vec![true; registers.len()];
+    // (interval, register) sorted by end
+    let mut active: Vec<(Interval, usize)> = vec![];
+    for current in intervals {
+        active.retain(|(i, reg)| {
+            if i.end <= current.start {
+                free[*reg] = true;
+                false
+            } else {
+                true
+            }
+        });
+
+        let hint = hints[current.vreg.0]
+            .and_then(|r| allocation.locations[r.0])
+            .and_then(|l| match l {
+                Location::Reg(reg) if free[reg] => Some(reg),
+                _ => None,
+            });
+        let reg = hint
+            .or_else(|| (0..registers.len()).find(|r| free[*r] && !registers[*r].callee_saved))
+            .or_else(|| (0..registers.len()).find(|r| free[*r]));
+
+        let reg = match reg {
+            Some(reg) => reg,
+            None => {
+                let spill = Location::Spill(allocation.spill_count);
+                allocation.spill_count += 1;
+                match active.last() {
+                    Some((last, reg)) if last.end > current.end => {
+                        let reg = *reg;
+                        allocation.locations[last.vreg.0] = Some(spill);
+                        active.pop();
+                        free[reg] = true;
+                        reg
+                    }
+                    _ => {
+                        allocation.locations[current.vreg.0] = Some(spill);
+                        continue;
+                    }
+                }
+            }
+        };
+        free[reg] = false;
+        allocation.locations[current.vreg.0] = Some(Location::Reg(reg));
+        if registers[reg].callee_saved && !allocation.callee_saved.contains(&reg) {
+            allocation.callee_saved.push(reg);
+        }
+        let i = active.partition_point(|(i, _)| i.end <= current.end);
+        active.insert(i, (current, reg));
+    }
+    allocation.callee_saved.sort_unstable();
+    allocation
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REGISTERS: &[RegisterInfo] = &[
+        RegisterInfo { name: "r0", callee_saved: false },
+        RegisterInfo { name: "r1", callee_saved: true },
+    ];
+
+    fn conflicts(f: &Function, allocation: &Allocation) -> Vec<(VReg, VReg)> {
+        let intervals = intervals(f, &allocation.order);
+        let mut v = vec![];
+        for a in &intervals {
+            for b in &intervals {
+                let overlap = a.start < b.end && b.start < a.end;
+                if a.vreg < b.vreg && overlap && allocation.locations[a.vreg.0] == allocation.locations[b.vreg.0] {
+                    v.push((a.vreg, b.vreg));
+                }
+            }
+        }
+        v
+    }
+
+    #[test]
+    fn test_intervals() {
+        let f: Function = "
+            fn f {
+            bb0:
+              %0 = copy 1
+              %1 = copy 2
+              jmp bb1
+            bb1:
+              %2 = add %0, 1
+              br %2, bb1, bb2
+            bb2:
+              ret %1
+            }
+        ".parse().unwrap();
+        let order = f.reverse_postorder();
+        assert_eq!(intervals(&f, &order), vec![
+            Interval { vreg: VReg(0), start: 0, end: 4 },
+            Interval { vreg: VReg(1), start: 1, end: 5 },
+            Interval { vreg: VReg(2), start: 3, end: 4 },
+        ]);
+    }
+
+    #[test]
+    fn test_allocate() {
+        let f: Function = "
+            fn f {
+            bb0:
+              %0 = copy 1
+              %1 = add %0, 2
+              %2 = mul %1, %0
+              ret %2
+            }
+        ".parse().unwrap();
+        let allocation = allocate(&f, REGISTERS);
+        // Nothing spills. %0 is still live at the mul, so %1 gets another
+        // register, and %2 reuses the one of %1, which dies there.
+        assert_eq!(allocation.spill_count, 0);
+        assert_eq!(allocation.locations[0], Some(Location::Reg(0)));
+        assert_eq!(allocation.locations[1], Some(Location::Reg(1)));
+        assert_eq!(allocation.locations[2], Some(Location::Reg(1)));
+        assert_eq!(allocation.callee_saved, vec![1]);
+        assert!(conflicts(&f, &allocation).is_empty());
+    }
+
+    #[test]
+    fn test_spill() {
+        let f: Function = "
+            fn f {
+            bb0:
+              %0 = copy 1
+              %1 = copy 2
+              %2 = copy 3
+              %3 = add %1, %2
+              %4 = add %3, %0
+              ret %4
+            }
+        ".parse().unwrap();
+        let allocation = allocate(&f, REGISTERS);
+        // %0 lives longest.
+        assert_eq!(allocation.locations[0], Some(Location::Spill(0)));
+        assert_eq!(allocation.spill_count, 1);
+        assert!(conflicts(&f, &allocation).is_empty());
+    }
+
+    #[test]
+    fn test_coalesce() {
+        let f: Function = "
+            fn f {
+            bb0:
+              %0 = copy 1
+              %1 = copy 2
+              %2 = copy %1
+              %3 = add %0, %2
+              ret %3
+            }
+        ".parse().unwrap();
+        let allocation = allocate(&f, REGISTERS);
+        assert_eq!(allocation.locations[2], allocation.locations[1]);
+        assert_eq!(allocation.locations[3], allocation.locations[0]);
+    }
+}
@@ -0,0 +1,447 @@
+use crate::nodes::Declaration;
+use crate::{StorageClass, Type};
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum ScopeKind {
+    File,
+    // The outermost block of a function, which also holds its labels
+    Function,
+    Block,
+}
+
+// Whether declarations in other scopes or files refer to the same object
+// (C11 6.2.2)
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Linkage {
+    External,
+    Internal,
+    None,
+}
+
+// How much of an object the declarations so far provide, in increasing order
+#[derive(PartialEq, Eq, Debug, Clone, Copy, PartialOrd, Ord)]
+pub enum Definition {
+    // `extern int x;`
+    Declaration,
+    // `int x;` at file scope, a zero definition unless defined later
+    Tentative,
+    Definition,
+}
+
+// What an ordinary identifier names
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum NameKind {
+    Object,
+    Typedef,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Declared {
+    pub kind: NameKind,
+    pub ty: Type,
+    pub linkage: Linkage,
+    pub definition: Definition,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum TagKind {
+    Struct,
+    Union,
+    Enum,
+}
+
+impl fmt::Display for TagKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TagKind::Struct => write!(f, "struct"),
+            TagKind::Union => write!(f, "union"),
+            TagKind::Enum => write!(f, "enum"),
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum ScopeError {
+    ConflictingTypes(String),
+    Redefinition(String),
+    StaticFollowsNonStatic(String),
+    NonStaticFollowsStatic(String),
+    // A block scope declaration with linkage
+    ExternInitializer,
+    TypedefInitializer,
+    DifferentKind(String),
+    // The name and the types of the two typedefs
+    TypedefRedefinition(String, Type, Type),
+    TagMismatch(String),
+    LabelRedefinition(String),
+    UndeclaredLabel(String),
+    // A label defined or used in a table without a function scope
+    LabelOutsideFunction(String),
+}
+
+impl fmt::Display for ScopeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScopeError::ConflictingTypes(name) => write!(f, "conflicting types for '{}'", name),
+            ScopeError::Redefinition(name) => write!(f, "redefinition of '{}'", name),
+            ScopeError::StaticFollowsNonStatic(name) => {
+                write!(f, "static declaration of '{}' follows non-static declaration", name)
+            }
+            ScopeError::NonStaticFollowsStatic(name) => {
+                write!(f, "non-static declaration of '{}' follows static declaration", name)
+            }
+            ScopeError::ExternInitializer => write!(f, "'extern' variable cannot have an initializer"),
+            ScopeError::TypedefInitializer => write!(f, "illegal initializer (only variables can be initialized)"),
+            ScopeError::DifferentKind(name) => write!(f, "redefinition of '{}' as different kind of symbol", name),
+            ScopeError::TypedefRedefinition(_, prior, ty) => {
+                write!(f, "typedef redefinition with different types ('{}' vs '{}')", ty, prior)
+            }
+            ScopeError::TagMismatch(name) => {
+                write!(f, "use of '{}' with tag type that does not match previous declaration", name)
+            }
+            ScopeError::LabelRedefinition(name) => write!(f, "redefinition of label '{}'", name),
+            ScopeError::UndeclaredLabel(name) => write!(f, "use of undeclared label '{}'", name),
+            ScopeError::LabelOutsideFunction(name) => write!(f, "label '{}' outside of a function", name),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Scope {
+    kind: ScopeKind,
+    // Variables, and later functions, typedef names and enumerators
+    ordinary: HashMap<String, Declared>,
+    tags: HashMap<String, TagKind>,
+    // Labels of a function scope, and whether they are defined yet
+    labels: HashMap<String, bool>,
+}
+
+// The nested scopes of a translation unit, innermost last. The parser and
+// sema build one each while walking the declarations in order. Tags and
+// labels are kept for struct/union/enum and goto, which the parser doesn't
+// accept yet.
+#[derive(Debug, Clone, Default)]
+pub struct ScopeTable {
+    scopes: Vec<Scope>,
+}
+
+impl ScopeTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, kind: ScopeKind) {
+        self.scopes.push(Scope {
+            kind,
+            ordinary: HashMap::new(),
+            tags: HashMap::new(),
+            labels: HashMap::new(),
+        });
+    }
+
+    pub fn pop(&mut self) {
+        self.scopes.pop();
+    }
+
+    pub fn clear(&mut self) {
+        self.scopes.clear();
+    }
+
+    pub fn depth(&self) -> usize {
+        self.scopes.len()
+    }
+
+    pub fn is_file_scope(&self) -> bool {
+        self.scopes.last().is_none_or(|scope| scope.kind == ScopeKind::File)
+    }
+
+    // The innermost declaration of name
+    pub fn lookup(&self, name: &str) -> Option<&Declared> {
+        self.scopes.iter().rev().find_map(|scope| scope.ordinary.get(name))
+    }
+
+    // Whether the innermost declaration of name is a typedef
+    pub fn is_type_name(&self, name: &str) -> bool {
+        self.lookup(name).is_some_and(|declared| declared.kind == NameKind::Typedef)
+    }
+
+    fn current(&mut self) -> &mut Scope {
+        if self.scopes.is_empty() {
+            self.push(ScopeKind::File);
+        }
+        self.scopes.last_mut().unwrap()
+    }
+
+    // Adds decl to the current scope, merging it with an earlier declaration
+    // of the same object.
+    pub fn declare(&mut self, decl: &Declaration) -> Result<&Declared, ScopeError> {
+        if decl.storage == Some(StorageClass::Typedef) {
+            return self.declare_typedef(decl);
+        }
+        let file_scope = self.is_file_scope();
+        let has_init = decl.init.is_some();
+        // An extern declaration has the linkage of a visible one if any.
+        let linkage = match (decl.storage, file_scope) {
+            (Some(StorageClass::Extern), _) => match self.lookup(&decl.name) {
+                Some(prior) if prior.linkage != Linkage::None => prior.linkage,
+                _ => Linkage::External,
+            },
+            (Some(StorageClass::Static), true) => Linkage::Internal,
+            (None, true) => Linkage::External,
+            (Some(StorageClass::Typedef), _) | (_, false) => Linkage::None,
+        };
+        if !file_scope && linkage != Linkage::None && has_init {
+            return Err(ScopeError::ExternInitializer);
+        }
+        let definition = match decl.storage {
+            _ if has_init => Definition::Definition,
+            Some(StorageClass::Extern) => Definition::Declaration,
+            _ if file_scope => Definition::Tentative,
+            _ => Definition::Definition,
+        };
+        // A block scope extern declares the same object as the visible one.
+        if let Some(prior) = self.lookup(&decl.name) {
+            if linkage != Linkage::None && prior.linkage != Linkage::None && prior.ty != decl.ty {
+                return Err(ScopeError::ConflictingTypes(decl.name.clone()));
+            }
+        }
+        let name = decl.name.clone();
+        let mut declared = Declared { kind: NameKind::Object, ty: decl.ty.clone(), linkage, definition };
+        if let Some(prior) = self.current().ordinary.get(&name) {
+            match (prior.linkage, linkage) {
+                _ if prior.kind != NameKind::Object => return Err(ScopeError::DifferentKind(name)),
+                _ if prior.ty != declared.ty => return Err(ScopeError::ConflictingTypes(name)),
+                (Linkage::None, _) | (_, Linkage::None) => return Err(ScopeError::Redefinition(name)),
+                (Linkage::Internal, Linkage::External) => return Err(ScopeError::NonStaticFollowsStatic(name)),
+                (Linkage::External, Linkage::Internal) => return Err(ScopeError::StaticFollowsNonStatic(name)),
+                _ if prior.definition == Definition::Definition && definition == Definition::Definition => {
+                    return Err(ScopeError::Redefinition(name));
+                }
+                _ => declared.definition = prior.definition.max(definition),
+            }
+        }
+        let scope = self.current();
+        scope.ordinary.insert(name.clone(), declared);
+        Ok(&scope.ordinary[&name])
+    }
+
+    // A typedef may be repeated in a scope with the same type (C11 6.7p3).
+    fn declare_typedef(&mut self, decl: &Declaration) -> Result<&Declared, ScopeError> {
+        if decl.init.is_some() {
+            return Err(ScopeError::TypedefInitializer);
+        }
+        let name = decl.name.clone();
+        match self.current().ordinary.get(&name) {
+            Some(prior) if prior.kind != NameKind::Typedef => return Err(ScopeError::DifferentKind(name)),
+            Some(prior) if prior.ty != decl.ty => {
+                return Err(ScopeError::TypedefRedefinition(name, prior.ty.clone(), decl.ty.clone()));
+            }
+            _ => {}
+        }
+        let declared = Declared {
+            kind: NameKind::Typedef,
+            ty: decl.ty.clone(),
+            linkage: Linkage::None,
+            definition: Definition::Definition,
+        };
+        let scope = self.current();
+        scope.ordinary.insert(name.clone(), declared);
+        Ok(&scope.ordinary[&name])
+    }
+
+    // The kind of the innermost tag called name
+    pub fn lookup_tag(&self, name: &str) -> Option<TagKind> {
+        self.scopes.iter().rev().find_map(|scope| scope.tags.get(name)).copied()
+    }
+
+    // `struct s` declares or refers to the tag s of the current scope.
+    pub fn declare_tag(&mut self, name: &str, kind: TagKind) -> Result<(), ScopeError> {
+        match self.current().tags.insert(name.to_string(), kind) {
+            Some(prior) if prior != kind => {
+                self.current().tags.insert(name.to_string(), prior);
+                Err(ScopeError::TagMismatch(name.to_string()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // The innermost function scope, which holds the label name
+    fn function(&mut self, name: &str) -> Result<&mut Scope, ScopeError> {
+        self.scopes
+            .iter_mut()
+            .rev()
+            .find(|scope| scope.kind == ScopeKind::Function)
+            .ok_or_else(|| ScopeError::LabelOutsideFunction(name.to_string()))
+    }
+
+    // `name:`
+    pub fn define_label(&mut self, name: &str) -> Result<(), ScopeError> {
+        match self.function(name)?.labels.insert(name.to_string(), true) {
+            Some(true) => Err(ScopeError::LabelRedefinition(name.to_string())),
+            _ => Ok(()),
+        }
+    }
+
+    // `goto name;`, which may come before the label.
+    pub fn use_label(&mut self, name: &str) -> Result<(), ScopeError> {
+        self.function(name)?.labels.entry(name.to_string()).or_insert(false);
+        Ok(())
+    }
+
+    // Checks that the labels used in the innermost function are defined.
+    // Without a function scope there are no labels.
+    pub fn check_labels(&mut self) -> Result<(), ScopeError> {
+        let scope = match self.scopes.iter().rev().find(|scope| scope.kind == ScopeKind::Function) {
+            Some(scope) => scope,
+            None => return Ok(()),
+        };
+        let mut undefined: Vec<&String> = scope.labels.iter().filter(|(_, defined)| !**defined).map(|(name, _)| name).collect();
+        undefined.sort();
+        match undefined.first() {
+            Some(name) => Err(ScopeError::UndeclaredLabel(name.to_string())),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decl(name: &str, ty: Type, storage: Option<StorageClass>, init: bool) -> Declaration {
+        let init = init.then(|| Box::new(crate::AST::new_block(vec![])));
+        Declaration { name: name.to_string(), ty, storage, init }
+    }
+
+    fn declare(table: &mut ScopeTable, code: &[(&str, Option<StorageClass>, bool)]) -> Result<Declared, ScopeError> {
+        let mut last = None;
+        for (name, storage, init) in code {
+            last = Some(table.declare(&decl(name, Type::Int, *storage, *init))?.clone());
+        }
+        Ok(last.unwrap())
+    }
+
+    #[test]
+    fn test_shadowing() {
+        let mut table = ScopeTable::new();
+        table.push(ScopeKind::File);
+        table.declare(&decl("x", Type::Int, None, false)).unwrap();
+        table.push(ScopeKind::Block);
+        assert!(!table.is_file_scope());
+        table.declare(&decl("x", Type::Long, Some(StorageClass::Static), false)).unwrap();
+        assert_eq!(table.lookup("x").unwrap().ty, Type::Long);
+        assert_eq!(table.lookup("x").unwrap().linkage, Linkage::None);
+        table.pop();
+        assert_eq!(table.lookup("x").unwrap().ty, Type::Int);
+        assert_eq!(table.lookup("y"), None);
+        assert_eq!(table.depth(), 1);
+    }
+
+    #[test]
+    fn test_linkage() {
+        use StorageClass::*;
+        let mut table = ScopeTable::new();
+        let x = declare(&mut table, &[("x", Some(Static), false), ("x", Some(Extern), false)]).unwrap();
+        assert_eq!((x.linkage, x.definition), (Linkage::Internal, Definition::Tentative));
+        let y = declare(&mut table, &[("y", Some(Extern), false)]).unwrap();
+        assert_eq!((y.linkage, y.definition), (Linkage::External, Definition::Declaration));
+
+        table.push(ScopeKind::Block);
+        assert_eq!(declare(&mut table, &[("x", Some(Extern), false)]).unwrap().linkage, Linkage::Internal);
+        assert_eq!(declare(&mut table, &[("x", Some(Extern), true)]), Err(ScopeError::ExternInitializer));
+        assert_eq!(
+            table.declare(&decl("y", Type::Long, Some(Extern), false)),
+            Err(ScopeError::ConflictingTypes("y".to_string())),
+        );
+        assert_eq!(declare(&mut table, &[("z", Some(Static), false), ("z", Some(Extern), false)]).unwrap_err(), ScopeError::Redefinition("z".to_string()));
+
+        let mut table = ScopeTable::new();
+        assert_eq!(
+            declare(&mut table, &[("a", None, false), ("a", Some(Static), false)]),
+            Err(ScopeError::StaticFollowsNonStatic("a".to_string())),
+        );
+        assert_eq!(
+            declare(&mut table, &[("b", Some(Static), false), ("b", None, false)]),
+            Err(ScopeError::NonStaticFollowsStatic("b".to_string())),
+        );
+    }
+
+    #[test]
+    fn test_tentative_definitions() {
+        use StorageClass::*;
+        let mut table = ScopeTable::new();
+        let x = declare(&mut table, &[("x", None, false), ("x", None, true), ("x", Some(Extern), false), ("x", None, false)]).unwrap();
+        assert_eq!(x.definition, Definition::Definition);
+        assert_eq!(declare(&mut table, &[("x", None, true)]), Err(ScopeError::Redefinition("x".to_string())));
+        assert_eq!(
+            table.declare(&decl("x", Type::Char, None, false)),
+            Err(ScopeError::ConflictingTypes("x".to_string())),
+        );
+        table.push(ScopeKind::Block);
+        assert_eq!(declare(&mut table, &[("y", None, false)]).unwrap().definition, Definition::Definition);
+        assert_eq!(declare(&mut table, &[("y", None, false)]), Err(ScopeError::Redefinition("y".to_string())));
+    }
+
+    #[test]
+    fn test_typedefs() {
+        use StorageClass::*;
+        let mut table = ScopeTable::new();
+        declare(&mut table, &[("T", Some(Typedef), false), ("T", Some(Typedef), false)]).unwrap();
+        assert!(table.is_type_name("T"));
+        assert_eq!(
+            table.declare(&decl("T", Type::Long, Some(Typedef), false)).unwrap_err().to_string(),
+            "typedef redefinition with different types ('long' vs 'int')",
+        );
+        assert_eq!(declare(&mut table, &[("T", None, false)]), Err(ScopeError::DifferentKind("T".to_string())));
+        assert_eq!(declare(&mut table, &[("U", Some(Typedef), true)]), Err(ScopeError::TypedefInitializer));
+        assert_eq!(
+            declare(&mut table, &[("x", None, false), ("x", Some(Typedef), false)]),
+            Err(ScopeError::DifferentKind("x".to_string())),
+        );
+        table.push(ScopeKind::Block);
+        declare(&mut table, &[("T", Some(Static), false)]).unwrap();
+        assert!(!table.is_type_name("T"));
+        table.pop();
+        assert!(table.is_type_name("T") && !table.is_type_name("x"));
+    }
+
+    #[test]
+    fn test_tags() {
+        let mut table = ScopeTable::new();
+        table.declare(&decl("s", Type::Int, None, false)).unwrap();
+        table.declare_tag("s", TagKind::Struct).unwrap();
+        table.declare_tag("s", TagKind::Struct).unwrap();
+        assert_eq!(table.declare_tag("s", TagKind::Union), Err(ScopeError::TagMismatch("s".to_string())));
+        table.push(ScopeKind::Block);
+        table.declare_tag("s", TagKind::Enum).unwrap();
+        assert_eq!(table.lookup_tag("s"), Some(TagKind::Enum));
+        table.pop();
+        assert_eq!(table.lookup_tag("s"), Some(TagKind::Struct));
+        assert_eq!(table.lookup("s").unwrap().ty, Type::Int);
+        assert_eq!(ScopeError::TagMismatch("s".to_string()).to_string(), "use of 's' with tag type that does not match previous declaration");
+    }
+
+    #[test]
+    fn test_labels() {
+        let mut table = ScopeTable::new();
+        table.push(ScopeKind::File);
+        table.push(ScopeKind::Function);
+        table.use_label("out").unwrap();
+        table.push(ScopeKind::Block);
+        table.use_label("again").unwrap();
+        table.define_label("out").unwrap();
+        assert_eq!(table.define_label("out"), Err(ScopeError::LabelRedefinition("out".to_string())));
+        table.pop();
+        assert_eq!(table.check_labels(), Err(ScopeError::UndeclaredLabel("again".to_string())));
+        table.define_label("again").unwrap();
+        assert_eq!(table.check_labels(), Ok(()));
+
+        let mut table = ScopeTable::new();
+        table.push(ScopeKind::Block);
+        assert_eq!(table.use_label("out"), Err(ScopeError::LabelOutsideFunction("out".to_string())));
+        assert_eq!(table.define_label("out").unwrap_err().to_string(), "label 'out' outside of a function");
+        assert_eq!(table.check_labels(), Ok(()));
+    }
+}
@@ -0,0 +1,430 @@
+use crate::nodes::*;
+use crate::{eval_const, Diagnostic, Loc, NameKind, Node, ScopeKind, ScopeTable, Type, AST};
+use std::convert::TryFrom;
+
+// Resolves the identifiers of an AST and sets the type of every expression.
+// Conversions become ImplicitCast nodes and pointer arithmetic is scaled by
+// the size of the pointee, so later stages only see integers of the same
+// type on both sides of an operator.
+#[derive(Default)]
+pub struct Sema {
+    // The outermost block is the file scope.
+    scopes: ScopeTable,
+}
+
+// Everything is in main, which returns int.
+pub const RETURN_TYPE: Type = Type::Int;
+
+pub fn analyze(ast: &mut AST) -> Result<(), Diagnostic> {
+    Sema::new().analyze(ast)
+}
+
+// What a conversion as if by assignment is for, to describe it in errors
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+enum Conversion {
+    Assigning,
+    Initializing,
+    Returning,
+}
+
+impl Conversion {
+    fn describe(&self, to: &Type, from: &Type) -> String {
+        match self {
+            Conversion::Assigning => format!("assigning to '{}' from '{}'", to, from),
+            Conversion::Initializing => format!("initializing '{}' with an expression of type '{}'", to, from),
+            Conversion::Returning => format!("returning '{}' from a function with result type '{}'", from, to),
+        }
+    }
+}
+
+fn error(loc: Option<Loc>, message: impl Into<String>, operands: &[&AST]) -> Diagnostic {
+    Diagnostic::error(message, loc).with_spans(operands.iter().filter_map(|ast| ast.span()))
+}
+
+// Wraps ast in a conversion to ty unless it already has that type.
+fn convert(ast: &mut AST, ty: &Type) {
+    if ast.ty.as_ref() != Some(ty) {
+        let operand = std::mem::replace(ast, AST::new_block(vec![]));
+        *ast = AST::new_implicit_cast(operand, ty.clone());
+    }
+}
+
+// The integer operand of pointer arithmetic in bytes: ast * size as a long.
+fn scale(ast: &mut AST, size: usize) {
+    convert(ast, &Type::Long);
+    if size != 1 {
+        let operand = std::mem::replace(ast, AST::new_block(vec![]));
+        let mut size = AST::new(operand.token.clone(), Node::IntLiteral(IntLiteral { value: size as i64 }));
+        size.ty = Some(Type::Long);
+        *ast = AST::new(operand.token.clone(), Node::Multiplication(Multiplication {
+            lhs: Box::new(operand),
+            rhs: Box::new(size),
+        }));
+        ast.ty = Some(Type::Long);
+    }
+}
+
+// Only variables designate objects; there is no `*` or `[]` yet.
+fn is_lvalue(ast: &AST) -> bool {
+    matches!(ast.node, Node::Identifier(_))
+}
+
+// An integer constant expression with the value 0
+fn is_null_pointer_constant(ast: &AST) -> bool {
+    ast.ty.as_ref().is_some_and(|ty| ty.is_integer()) && eval_const(ast) == Ok(0)
+}
+
+impl Sema {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn analyze(&mut self, ast: &mut AST) -> Result<(), Diagnostic> {
+        let result = self.statement(ast);
+        self.scopes.clear();
+        result
+    }
+
+    fn statement(&mut self, ast: &mut AST) -> Result<(), Diagnostic> {
+        match &mut ast.node {
+            Node::Block(block) => {
+                let kind = if self.scopes.depth() == 0 { ScopeKind::File } else { ScopeKind::Block };
+                self.scopes.push(kind);
+                let result = block.items.iter_mut().try_for_each(|item| self.statement(item));
+                self.scopes.pop();
+                result
+            }
+            Node::ExprStatement(stmt) => self.expr(&mut stmt.expr).map(|_| ()),
+            Node::Return(ret) => {
+                self.expr(&mut ret.expr)?;
+                self.assign(&mut ret.expr, &RETURN_TYPE, Conversion::Returning)
+            }
+            Node::Declaration(decl) => {
+                let loc = ast.token.as_ref().map(|t| t.loc);
+                // The scope of a variable starts before its initializer.
+                self.scopes.declare(decl).map_err(|err| error(loc, err.to_string(), &[]))?;
+                if let Some(init) = &mut decl.init {
+                    self.expr(init)?;
+                    self.assign(init, &decl.ty, Conversion::Initializing)?;
+                }
+                Ok(())
+            }
+            _ => self.expr(ast).map(|_| ()),
+        }
+    }
+
+    // Converts the expression ast to ty as if by assignment (C11 6.5.16.1).
+    fn assign(&self, ast: &mut AST, ty: &Type, conversion: Conversion) -> Result<(), Diagnostic> {
+        let from = ast.ty.clone().unwrap();
+        let loc = ast.span().map(|span| span.start);
+        let message = match (ty.is_pointer(), from.is_pointer()) {
+            _ if from == *ty => return Ok(()),
+            (false, false) => None,
+            (true, true) => Some("incompatible pointer types"),
+            (true, false) if is_null_pointer_constant(ast) => None,
+            (true, false) => Some("incompatible integer to pointer conversion"),
+            (false, true) => Some("incompatible pointer to integer conversion"),
+        };
+        match message {
+            Some(message) => Err(error(loc, format!("{} {}", message, conversion.describe(ty, &from)), &[ast])),
+            None => {
+                convert(ast, ty);
+                Ok(())
+            }
+        }
+    }
+
+    // Types ast and its operands, returning the type of ast. Nodes that have a
+    // type are already analyzed. The left operands of a chain like 1 + 2 + 3
+    // are detached and typed in a loop, so long expressions don't take stack
+    // space per operator.
+    fn expr(&mut self, ast: &mut AST) -> Result<Type, Diagnostic> {
+        let mut chain = vec![];
+        let mut node = std::mem::replace(ast, AST::new_block(vec![]));
+        while let Some((lhs, _)) = node.arithmetic_operands_mut().filter(|(lhs, _)| lhs.ty.is_none()) {
+            let lhs = std::mem::replace(lhs, AST::new_block(vec![]));
+            chain.push(node);
+            node = lhs;
+        }
+        let mut result = self.expr_node(&mut node);
+        while let Some(mut parent) = chain.pop() {
+            *parent.arithmetic_operands_mut().unwrap().0 = node;
+            if result.is_ok() {
+                result = self.expr_node(&mut parent);
+            }
+            node = parent;
+        }
+        *ast = node;
+        result
+    }
+
+    // Types the node ast, whose operands are typed first unless they have a
+    // type already.
+    fn expr_node(&mut self, ast: &mut AST) -> Result<Type, Diagnostic> {
+        if let Some(ty) = &ast.ty {
+            return Ok(ty.clone());
+        }
+        let loc = ast.token.as_ref().map(|t| t.loc);
+        let ty = match &mut ast.node {
+            Node::IntLiteral(lit) => match i32::try_from(lit.value) {
+                Ok(_) => Type::Int,
+                Err(_) => Type::Long,
+            },
+            Node::StringLiteral(_) => Type::pointer_to(Type::Char),
+            Node::Identifier(ident) => match self.scopes.lookup(&ident.name) {
+                Some(declared) if declared.kind == NameKind::Typedef => {
+                    return Err(error(loc, format!("unexpected type name '{}': expected expression", ident.name), &[]));
+                }
+                Some(declared) => declared.ty.clone(),
+                None => return Err(error(loc, format!("use of undeclared identifier '{}'", ident.name), &[])),
+            },
+            Node::AddressOf(addr) => {
+                let ty = self.expr(&mut addr.operand)?;
+                if !is_lvalue(&addr.operand) {
+                    let message = format!("cannot take the address of an rvalue of type '{}'", ty);
+                    return Err(error(loc, message, &[&addr.operand]));
+                }
+                Type::pointer_to(ty)
+            }
+            // Every type is scalar, so any of them converts to any other.
+            Node::Cast(cast) => {
+                self.expr(&mut cast.operand)?;
+                cast.ty.clone()
+            }
+            Node::SizeOf(size_of) => {
+                if let Some(operand) = &mut size_of.operand {
+                    if let Node::StringLiteral(_) = operand.node {
+                        return Err(error(loc, "sizeof of a string literal is not supported yet", &[operand]));
+                    }
+                    size_of.ty = Some(self.expr(operand)?);
+                }
+                Type::Long
+            }
+            Node::Assignment(assign) => {
+                let ty = self.expr(&mut assign.lhs)?;
+                self.expr(&mut assign.rhs)?;
+                if !is_lvalue(&assign.lhs) {
+                    return Err(error(loc, "expression is not assignable", &[&assign.lhs]));
+                }
+                self.assign(&mut assign.rhs, &ty, Conversion::Assigning)?;
+                ty
+            }
+            Node::Addition(Addition { lhs, rhs }) => {
+                let (l, r) = (self.expr(lhs)?, self.expr(rhs)?);
+                match (l.pointee(), r.pointee()) {
+                    (None, None) => arithmetic(lhs, rhs, &l, &r),
+                    (Some(pointee), None) => {
+                        scale(rhs, pointee.size());
+                        l
+                    }
+                    (None, Some(pointee)) => {
+                        scale(lhs, pointee.size());
+                        r
+                    }
+                    _ => return Err(invalid_operands(loc, lhs, rhs)),
+                }
+            }
+            Node::Subtraction(Subtraction { lhs, rhs }) => {
+                let (l, r) = (self.expr(lhs)?, self.expr(rhs)?);
+                match (l.pointee(), r.pointee()) {
+                    (None, None) => arithmetic(lhs, rhs, &l, &r),
+                    (Some(pointee), None) => {
+                        scale(rhs, pointee.size());
+                        l
+                    }
+                    (Some(a), Some(b)) if a != b => {
+                        let message = format!("'{}' and '{}' are not pointers to compatible types", l, r);
+                        return Err(error(loc, message, &[lhs, rhs]));
+                    }
+                    // The difference in elements: the bytes divided by the size.
+                    (Some(pointee), Some(_)) => {
+                        let size = pointee.size();
+                        ast.ty = Some(Type::Long);
+                        if size != 1 {
+                            let diff = std::mem::replace(ast, AST::new_block(vec![]));
+                            let mut size = AST::new(diff.token.clone(), Node::IntLiteral(IntLiteral { value: size as i64 }));
+                            size.ty = Some(Type::Long);
+                            *ast = AST::new(diff.token.clone(), Node::Division(Division {
+                                lhs: Box::new(diff),
+                                rhs: Box::new(size),
+                            }));
+                        }
+                        Type::Long
+                    }
+                    _ => return Err(invalid_operands(loc, lhs, rhs)),
+                }
+            }
+            Node::Multiplication(Multiplication { lhs, rhs })
+            | Node::Division(Division { lhs, rhs })
+            | Node::Remainder(Remainder { lhs, rhs }) => {
+                let (l, r) = (self.expr(lhs)?, self.expr(rhs)?);
+                if l.is_pointer() || r.is_pointer() {
+                    return Err(invalid_operands(loc, lhs, rhs));
+                }
+                arithmetic(lhs, rhs, &l, &r)
+            }
+            Node::ImplicitCast(_) => unreachable!("implicit cast without a type"),
+            Node::Block(_) | Node::ExprStatement(_) | Node::Return(_) | Node::Declaration(_) => {
+                unreachable!("statement in an expression")
+            }
+        };
+        ast.ty = Some(ty.clone());
+        Ok(ty)
+    }
+}
+
+// The usual arithmetic conversions of integer operands (C11 6.3.1.8)
+fn arithmetic(lhs: &mut AST, rhs: &mut AST, l: &Type, r: &Type) -> Type {
+    let ty = Type::common(l, r);
+    convert(lhs, &ty);
+    convert(rhs, &ty);
+    ty
+}
+
+fn invalid_operands(loc: Option<Loc>, lhs: &AST, rhs: &AST) -> Diagnostic {
+    let (l, r) = (lhs.ty.as_ref().unwrap(), rhs.ty.as_ref().unwrap());
+    error(loc, format!("invalid operands to binary expression ('{}' and '{}')", l, r), &[lhs, rhs])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::analyze;
+    use crate::{Lexer, Parser, Source};
+
+    // The analyzed AST, or the error with the spans of its operands
+    fn sema(code: &str) -> String {
+        let mut ast = Parser::new(Lexer::new(&Source::inline(code))).parse().unwrap();
+        match analyze(&mut ast) {
+            Ok(()) => ast.to_string(),
+            Err(err) => {
+                let spans: Vec<String> = err.spans.iter().map(|s| s.to_string()).collect();
+                format!("{} [{}]", err, spans.join(", "))
+            }
+        }
+    }
+
+    #[test]
+    fn test_conversions() {
+        assert_eq!(sema("char c; long l; c + 1 * l;"), "\
+            Block\n  \
+              Declaration char c <1:6>\n  \
+              Declaration long l <1:14>\n  \
+              ExprStatement\n    \
+                Addition (long) <1:19>\n      \
+                  ImplicitCast (long) <1:17>\n        \
+                    Identifier c (char) <1:17>\n      \
+                  Multiplication (long) <1:23>\n        \
+                    ImplicitCast (long) <1:21>\n          \
+                      IntLiteral 1 (int) <1:21>\n        \
+                    Identifier l (long) <1:25>\n\
+        ");
+        assert_eq!(sema("char c = 300; return 4294967296;"), "\
+            Block\n  \
+              Declaration char c <1:6>\n    \
+                ImplicitCast (char) <1:10>\n      \
+                  IntLiteral 300 (int) <1:10>\n  \
+              Return <1:15>\n    \
+                ImplicitCast (int) <1:22>\n      \
+                  IntLiteral 4294967296 (long) <1:22>\n\
+        ");
+        assert!(sema("int *p = 0; p = 2 - 2;").contains("ImplicitCast (int *)"));
+    }
+
+    #[test]
+    fn test_pointer_arithmetic() {
+        assert_eq!(sema("int x; int *p = &x + 2;").lines().skip(2).collect::<Vec<_>>(), vec![
+            "  Declaration int * p <1:13>",
+            "    Addition (int *) <1:20>",
+            "      AddressOf (int *) <1:17>",
+            "        Identifier x (int) <1:18>",
+            "      Multiplication (long) <1:22>",
+            "        ImplicitCast (long) <1:22>",
+            "          IntLiteral 2 (int) <1:22>",
+            "        IntLiteral 4 (long) <1:22>",
+        ]);
+        assert_eq!(sema("long *p, *q; p - q;").lines().skip(3).collect::<Vec<_>>(), vec![
+            "  ExprStatement",
+            "    Division (long) <1:16>",
+            "      Subtraction (long) <1:16>",
+            "        Identifier p (long *) <1:14>",
+            "        Identifier q (long *) <1:18>",
+            "      IntLiteral 8 (long) <1:16>",
+        ]);
+        assert!(sema("char *s = 1 + \"ab\";").contains("    Addition (char *) <1:13>\n      ImplicitCast (long)"));
+        assert!(sema("char *s, *t; s - t;").contains("Subtraction (long) <1:16>\n      Identifier s"));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(sema("x;"), "1:1: error: use of undeclared identifier 'x' []");
+        assert_eq!(sema("{ int x; } x;"), "1:12: error: use of undeclared identifier 'x' []");
+        assert_eq!(sema("int x; extern char x;"), "1:20: error: conflicting types for 'x' []");
+        assert_eq!(sema("int x = 1; int x = 2;"), "1:16: error: redefinition of 'x' []");
+        assert_eq!(sema("int x; static int x;"), "1:19: error: static declaration of 'x' follows non-static declaration []");
+        assert_eq!(sema("{ extern int x = 1; }"), "1:14: error: 'extern' variable cannot have an initializer []");
+        assert!(sema("int x; int x; extern int x; int x = 2;").ends_with("Declaration int x <1:33>\n    IntLiteral 2 (int) <1:37>\n"));
+        assert_eq!(sema("int x; x + 1 = 2;"), "1:14: error: expression is not assignable [1:8-1:12]");
+        assert_eq!(sema("int *p = &1;"), "1:10: error: cannot take the address of an rvalue of type 'int' [1:11-1:11]");
+        assert_eq!(
+            sema("int *p; p + p;"),
+            "1:11: error: invalid operands to binary expression ('int *' and 'int *') [1:9-1:9, 1:13-1:13]",
+        );
+        assert_eq!(
+            sema("char c; 2 * c % \"s\";"),
+            "1:15: error: invalid operands to binary expression ('int' and 'char *') [1:9-1:13, 1:17-1:17]",
+        );
+        assert_eq!(sema("int x; 1 - &x;"), "1:10: error: invalid operands to binary expression ('int' and 'int *') [1:8-1:8, 1:12-1:13]");
+        assert_eq!(
+            sema("int *p; long *q; p - q;"),
+            "1:20: error: 'int *' and 'long *' are not pointers to compatible types [1:18-1:18, 1:22-1:22]",
+        );
+    }
+
+    #[test]
+    fn test_typedefs() {
+        assert_eq!(sema("typedef char *S; S s = (S)1 + sizeof s;").lines().skip(3).collect::<Vec<_>>(), vec![
+            "    Addition (char *) <1:29>",
+            "      Cast char * (char *) <1:24>",
+            "        IntLiteral 1 (int) <1:27>",
+            "      SizeOf (long) <1:31>",
+            "        Identifier s (char *) <1:38>",
+        ]);
+        assert_eq!(sema("typedef int T; T x = 1; typedef long T;"), "1:38: error: typedef redefinition with different types ('long' vs 'int') []");
+        assert_eq!(sema("int T; typedef int T;"), "1:20: error: redefinition of 'T' as different kind of symbol []");
+        assert_eq!(sema("typedef int T = 1;"), "1:13: error: illegal initializer (only variables can be initialized) []");
+        assert_eq!(sema("typedef int T; 1 + T;"), "1:20: error: unexpected type name 'T': expected expression []");
+        assert_eq!(sema("sizeof \"ab\";"), "1:1: error: sizeof of a string literal is not supported yet [1:8-1:8]");
+        assert_eq!(
+            sema("char *s = (long)&s;"),
+            "1:11: error: incompatible integer to pointer conversion initializing 'char *' with an expression of type 'long' [1:11-1:18]",
+        );
+    }
+
+    #[test]
+    fn test_assignment_errors() {
+        assert_eq!(
+            sema("int x; long *p = &x;"),
+            "1:18: error: incompatible pointer types initializing 'long *' with an expression of type 'int *' [1:18-1:19]",
+        );
+        assert_eq!(
+            sema("int *p = 1;"),
+            "1:10: error: incompatible integer to pointer conversion initializing 'int *' with an expression of type 'int' [1:10-1:10]",
+        );
+        assert_eq!(
+            sema("int x; x = \"a\" + 1;"),
+            "1:12: error: incompatible pointer to integer conversion assigning to 'int' from 'char *' [1:12-1:18]",
+        );
+        assert_eq!(
+            sema("return \"a\";"),
+            "1:8: error: incompatible pointer to integer conversion returning 'char *' from a function with result type 'int' [1:8-1:8]",
+        );
+    }
+
+    #[test]
+    fn test_long_chain() {
+        let code = format!("return {};", vec!["1"; 2000].join(" + "));
+        let ast = sema(&code);
+        assert_eq!(ast.lines().count(), 4001);
+        assert_eq!(ast.lines().nth(2).unwrap(), "    Addition (int) <1:8002>");
+    }
+}
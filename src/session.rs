@@ -0,0 +1,760 @@
+use crate::asm::x86_64::{encode, MInst};
+use crate::asm::{self, dwarf, FmtWriter, Label, Program, Syntax};
+use crate::cfg::Function;
+use crate::pass::{PassManager, PassReport};
+use crate::{aarch64, c, dce, ir, llvm, riscv64, sema, wasm, x86_64, Diagnostic, Global, IRTranslator, Lexer, Parser, Source, Token, TokenKind, AST, IR};
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub enum Target {
+    #[default]
+    X86_64,
+    Aarch64,
+    Riscv64,
+    Wasm32,
+}
+
+impl FromStr for Target {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "x86_64" | "x86_64-linux-gnu" | "x86_64-unknown-linux-gnu" => Ok(Target::X86_64),
+            "aarch64" | "aarch64-linux-gnu" | "aarch64-unknown-linux-gnu" => Ok(Target::Aarch64),
+            "riscv64" | "riscv64-linux-gnu" | "riscv64-unknown-linux-gnu" => Ok(Target::Riscv64),
+            "wasm32" | "wasm32-unknown-unknown" => Ok(Target::Wasm32),
+            _ => Err(format!("Unknown target: {}", s)),
+        }
+    }
+}
+
+impl Target {
+    pub fn triple(&self) -> &'static str {
+        match self {
+            Target::X86_64 => "x86_64-unknown-linux-gnu",
+            Target::Aarch64 => "aarch64-unknown-linux-gnu",
+            Target::Riscv64 => "riscv64-unknown-linux-gnu",
+            Target::Wasm32 => "wasm32-unknown-unknown",
+        }
+    }
+}
+
+// How x86_64 code reaches globals. The code is always position independent,
+// with rip-relative addresses. -fPIE reads extern globals from the GOT, and
+// -fPIC every global that another module of a shared library can preempt.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub enum RelocationModel {
+    #[default]
+    Static,
+    Pie,
+    Pic,
+}
+
+impl RelocationModel {
+    // Whether the address of g comes from the GOT
+    pub fn uses_got(&self, g: &Global) -> bool {
+        match self {
+            RelocationModel::Static => false,
+            RelocationModel::Pie => g.external,
+            RelocationModel::Pic => !g.local,
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy, PartialOrd, Ord, Default)]
+pub enum OptLevel {
+    #[default]
+    O0,
+    O1,
+    O2,
+}
+
+impl FromStr for OptLevel {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "0" => Ok(OptLevel::O0),
+            "1" => Ok(OptLevel::O1),
+            "2" => Ok(OptLevel::O2),
+            _ => Err(format!("Unknown optimization level: {}", s)),
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct Options {
+    // Only x86_64 has global variables. The other targets reject IR with
+    // globals, including string literals.
+    pub target: Target,
+    pub opt_level: OptLevel,
+    // Object-like macros. Each identifier is replaced with the tokens of its value.
+    pub defines: Vec<(String, String)>,
+    // -Wunreachable-code
+    pub warn_unreachable_code: bool,
+    // --passes replaces the pipeline of opt_level. The stack-machine lowering is
+    // used only at -O0 without --passes, otherwise registers are allocated.
+    pub passes: Option<Vec<String>>,
+    pub print_after: Vec<String>,
+    // -fno-peephole. The peephole pass cleans up the emitted x86_64 code at every
+    // level, including the stack-machine output of -O0.
+    pub no_peephole: bool,
+    // -masm=intel|att
+    pub syntax: Syntax,
+    // -g. Only the x86_64 assembly has debug info, so there is no object then.
+    pub debug_info: bool,
+    // -fPIE and -fPIC
+    pub relocation_model: RelocationModel,
+    // Backend outputs to build, from --emit and -c. None builds all of them.
+    pub emit: Option<Vec<Emit>>,
+}
+
+impl Options {
+    pub fn define(&mut self, name: impl Into<String>, value: impl Into<String>) -> &mut Self {
+        self.defines.push((name.into(), value.into()));
+        self
+    }
+}
+
+// Stages that can be printed with --emit.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Emit {
+    Tokens,
+    Ast,
+    Ir,
+    Cfg,
+    Asm,
+    Llvm,
+    C,
+    // The binary module of --target=wasm32. It isn't text, see Output::wasm.
+    Wasm,
+    // The object file of -c, see Output::object. It is never printed.
+    Object,
+}
+
+impl FromStr for Emit {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tokens" => Ok(Emit::Tokens),
+            "ast" => Ok(Emit::Ast),
+            "ir" => Ok(Emit::Ir),
+            "cfg" => Ok(Emit::Cfg),
+            "asm" => Ok(Emit::Asm),
+            "llvm" => Ok(Emit::Llvm),
+            "c" => Ok(Emit::C),
+            "wasm" => Ok(Emit::Wasm),
+            _ => Err(format!("Unknown emit kind: {}", s)),
+        }
+    }
+}
+
+// Result of every stage. A stage is None if an earlier stage failed or
+// Options::emit doesn't select it.
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct Output {
+    pub tokens: Vec<Token>,
+    pub ast: Option<AST>,
+    pub ir: Option<IR>,
+    // Set if the pipeline had CFG passes.
+    pub cfg: Option<Function>,
+    pub asm: Option<Asm>,
+    // Textual LLVM IR for the target
+    pub llvm: Option<String>,
+    // Portable C for differential testing
+    pub c: Option<String>,
+    // ELF relocatable object for -c, only for x86_64
+    pub object: Option<Vec<u8>>,
+    // Set for --target=wasm32, asm is then the .wat text.
+    pub wasm: Option<Vec<u8>>,
+    pub diagnostics: Vec<Diagnostic>,
+    pub passes: PassReport,
+}
+
+// Assembly of the target, kept structured until it is written
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum Asm {
+    X86_64(Program<MInst>, Syntax),
+    Aarch64(Program<asm::aarch64::MInst>),
+    Riscv64(Program<asm::riscv64::MInst>),
+    // The text format
+    Wasm(wasm::Module),
+}
+
+impl Asm {
+    // Streams the assembly to w without building the whole text first.
+    pub fn write(&self, w: &mut dyn io::Write) -> io::Result<()> {
+        match self {
+            Asm::X86_64(program, syntax) => program.write(w, *syntax),
+            Asm::Aarch64(program) => program.write(w, Syntax::default()),
+            Asm::Riscv64(program) => program.write(w, Syntax::default()),
+            Asm::Wasm(module) => write!(w, "{}", module),
+        }
+    }
+}
+
+impl fmt::Display for Asm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write(&mut FmtWriter(f)).map_err(|_| fmt::Error)
+    }
+}
+
+impl Output {
+    pub fn has_errors(&self) -> bool {
+        self.diagnostics.iter().any(|d| d.is_error())
+    }
+
+    pub fn emit(&self, kind: Emit) -> Option<String> {
+        match kind {
+            Emit::Tokens => Some(
+                self.tokens
+                    .iter()
+                    .map(|t| format!("{}\n", t))
+                    .collect(),
+            ),
+            Emit::Ast => self.ast.as_ref().map(|ast| ast.to_string()),
+            Emit::Ir => self.ir.as_ref().map(|ir| ir.to_string()),
+            Emit::Cfg => self.cfg.as_ref().map(|f| f.to_string()),
+            Emit::Asm => self.asm.as_ref().map(|asm| asm.to_string()),
+            Emit::Llvm => self.llvm.clone(),
+            Emit::C => self.c.clone(),
+            Emit::Wasm | Emit::Object => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Compiler {
+    pub options: Options,
+}
+
+impl Compiler {
+    pub fn new(options: Options) -> Self {
+        Self { options }
+    }
+
+    pub fn tokenize(&self, source: &Source) -> Vec<Token> {
+        let mut tokens = vec![];
+        for tok in Lexer::new(source) {
+            let eof = tok.kind == TokenKind::EOF;
+            match &tok.kind {
+                TokenKind::Ident(name) => match self.lookup_define(name) {
+                    Some(value) => tokens.extend(expand_define(value, &tok)),
+                    None => tokens.push(tok),
+                },
+                _ => tokens.push(tok),
+            }
+            if eof {
+                break;
+            }
+        }
+        tokens
+    }
+
+    fn lookup_define(&self, name: &str) -> Option<&str> {
+        self.options
+            .defines
+            .iter()
+            .rev()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn compile(&self, source: &Source) -> Output {
+        let mut output = Output {
+            tokens: self.tokenize(source),
+            ..Default::default()
+        };
+
+        let mut ast = match Parser::new(output.tokens.clone().into_iter()).parse() {
+            Ok(ast) => ast,
+            Err(err) => {
+                output.diagnostics.push(err.into());
+                return output;
+            }
+        };
+        let ir = sema::analyze(&mut ast).and_then(|_| IRTranslator::new().translate(&ast));
+        output.ast = Some(ast);
+        let mut ir = match ir {
+            Ok(ir) => ir,
+            Err(err) => {
+                output.diagnostics.push(err);
+                return output;
+            }
+        };
+        self.optimize(&mut ir, &mut output);
+        if output.has_errors() {
+            return output;
+        }
+        self.codegen(&source.filename, ir, output)
+    }
+
+    pub fn pass_manager(&self) -> Result<PassManager, String> {
+        let mut manager = match &self.options.passes {
+            Some(names) => PassManager::new(names).map_err(|e| e.to_string())?,
+            None => PassManager::for_level(self.options.opt_level),
+        };
+        for name in &self.options.print_after {
+            manager.print_after(name).map_err(|e| e.to_string())?;
+        }
+        manager.lines = self.options.debug_info;
+        Ok(manager)
+    }
+
+    fn optimize(&self, ir: &mut IR, output: &mut Output) {
+        let manager = match self.pass_manager() {
+            Ok(m) => m,
+            Err(err) => {
+                output.diagnostics.push(Diagnostic::error(err, None));
+                return;
+            }
+        };
+        let report = if manager.is_empty() {
+            // Warnings of -O1 are reported at -O0 too.
+            let warnings = PassManager::for_level(OptLevel::O1).run(&mut ir.clone()).map(|r| r.warnings);
+            warnings.and_then(|warnings| Ok(PassReport { warnings, ..manager.run(ir)? }))
+        } else {
+            manager.run(ir)
+        };
+        let mut report = match report {
+            Ok(report) => report,
+            Err(err) => {
+                output.diagnostics.push(Diagnostic::error(format!("invalid IR: {}", err), None));
+                return;
+            }
+        };
+        let warn_unreachable_code = self.options.warn_unreachable_code;
+        let warnings = report
+            .warnings
+            .drain(..)
+            .filter(|w| warn_unreachable_code || w.message != dce::UNREACHABLE_CODE);
+        output.diagnostics.extend(warnings);
+        output.cfg = report.cfg.take();
+        output.passes = report;
+    }
+
+    // Starts the pipeline after the front end with the textual IR in `source`.
+    // The IR goes straight to codegen unless -O or --passes asks for passes.
+    pub fn compile_ir(&self, source: &Source) -> Output {
+        let mut output = Output::default();
+        let mut ir = match source.code.iter().collect::<String>().parse::<IR>() {
+            Ok(ir) => ir,
+            Err(err) => {
+                output.diagnostics.push(err.into());
+                return output;
+            }
+        };
+        if let Err(err) = ir::verify(&ir) {
+            output.diagnostics.push(Diagnostic::error(format!("invalid IR: {}", err), None));
+            return output;
+        }
+        if self.options.opt_level != OptLevel::O0 || self.options.passes.is_some() {
+            self.optimize(&mut ir, &mut output);
+        }
+        if output.has_errors() {
+            return output;
+        }
+        self.codegen(&source.filename, ir, output)
+    }
+
+    fn codegen(&self, filename: &str, ir: IR, mut output: Output) -> Output {
+        if !ir.globals.is_empty() && self.options.target != Target::X86_64 {
+            let message = format!("global variables are not supported for {}", self.options.target.triple());
+            output.diagnostics.push(Diagnostic::error(message, None));
+            return output;
+        }
+        let function = match &output.cfg {
+            Some(f) => Ok(f.clone()),
+            None => Function::from_ir(&ir, self.options.debug_info),
+        };
+        let function = match function {
+            Ok(f) => f,
+            Err(err) => {
+                output.diagnostics.push(Diagnostic::error(format!("invalid IR: {}", err), None));
+                return output;
+            }
+        };
+        let stack_machine = self.options.opt_level == OptLevel::O0 && self.options.passes.is_none();
+        let debug_info = self.options.debug_info;
+        let wants = |kind| self.options.emit.as_ref().is_none_or(|kinds| kinds.contains(&kind));
+        output.asm = match self.options.target {
+            Target::X86_64 if wants(Emit::Asm) || wants(Emit::Object) => {
+                let (name, mut code) = match () {
+                    _ if stack_machine && debug_info => ("main", x86_64::lower_debug(&ir)),
+                    _ if stack_machine => ("main", x86_64::lower(&ir)),
+                    _ => (function.name.as_str(), x86_64::lower_function(&function)),
+                };
+                let first = ir.locs.iter().flatten().next();
+                let got: Vec<_> = ir.globals.iter().filter(|g| self.options.relocation_model.uses_got(g)).collect();
+                x86_64::use_got(&mut code, &got);
+                if !self.options.no_peephole {
+                    x86_64::peephole(&mut code);
+                }
+                let mut program = x86_64::assemble(name, code);
+                if debug_info {
+                    let return_type = if name == "main" { dwarf::INT } else { dwarf::LONG };
+                    let line = first.map_or(1, |loc| loc.line);
+                    // String literals aren't variables, and static locals are
+                    // named name.N.
+                    let variables: Vec<_> = ir
+                        .globals
+                        .iter()
+                        .filter(|g| !g.external && !g.name.starts_with('.'))
+                        .filter_map(|g| {
+                            Some(dwarf::Variable {
+                                name: g.name.split('.').next().unwrap().to_string(),
+                                label: x86_64::global_label(&g.name),
+                                ty: dwarf::BaseType::of_size(g.size)?,
+                                external: !g.local,
+                            })
+                        })
+                        .collect();
+                    dwarf::emit(&mut program, filename, &Label::new(name), line, return_type, &variables);
+                }
+                x86_64::data(&mut program, &ir.globals);
+                if !debug_info && wants(Emit::Object) {
+                    match encode::object(&program) {
+                        Ok(object) => output.object = Some(object.write()),
+                        Err(err) => output.diagnostics.push(Diagnostic::error(err.to_string(), None)),
+                    }
+                }
+                Some(Asm::X86_64(program, self.options.syntax))
+            }
+            // Always allocate registers. There is no stack-machine lowering.
+            Target::Aarch64 if wants(Emit::Asm) => {
+                Some(Asm::Aarch64(aarch64::assemble(&function.name, aarch64::lower_function(&function))))
+            }
+            Target::Riscv64 if wants(Emit::Asm) => {
+                Some(Asm::Riscv64(riscv64::assemble(&function.name, riscv64::lower_function(&function))))
+            }
+            Target::Wasm32 if wants(Emit::Asm) || wants(Emit::Wasm) => {
+                let module = if stack_machine { wasm::lower(&ir) } else { wasm::lower_function(&function) };
+                if wants(Emit::Wasm) {
+                    output.wasm = Some(wasm::encode(&module));
+                }
+                Some(Asm::Wasm(module))
+            }
+            _ => None,
+        };
+        if wants(Emit::Llvm) {
+            output.llvm = Some(llvm::compile_function(&function, &ir.globals, self.options.target.triple()));
+        }
+        if wants(Emit::C) {
+            output.c = Some(c::compile_function(&function, &ir.globals));
+        }
+        output.ir = Some(ir);
+        output
+    }
+}
+
+// Tokens of the macro value take the location of the expanded identifier.
+fn expand_define(value: &str, at: &Token) -> Vec<Token> {
+    let source = Source::inline(value);
+    Lexer::new(&source)
+        .take_while(|t| t.kind != TokenKind::EOF)
+        .map(|t| Token::new(t.kind, at.loc))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Instruction::*;
+    use crate::{Loc, Symbol};
+
+    #[test]
+    fn test_compile_inline() {
+        let output = Compiler::default().compile(&Source::inline("1 + 2;"));
+        assert!(!output.has_errors());
+        assert_eq!(output.tokens.len(), 5);
+        assert!(output.ast.is_some());
+        assert_eq!(output.ir.unwrap().instructions, vec![PushI(1), PushI(2), AddI, PopI]);
+        assert!(output.asm.unwrap().to_string().contains("main:"));
+    }
+
+    #[test]
+    fn test_compile_error() {
+        let output = Compiler::default().compile(&Source::inline("1 +;"));
+        assert!(output.has_errors());
+        assert_eq!(output.diagnostics[0].loc, Some(Loc::new(3, 1, 4)));
+        assert_eq!(output.ast, None);
+        assert_eq!(output.asm, None);
+    }
+
+    #[test]
+    fn test_optimize() {
+        let source = Source::inline("1 + 2 * 3; 4 / 0;");
+        let output = Compiler::default().compile(&source);
+        assert_eq!(output.ir.as_ref().unwrap().len(), 10);
+        assert_eq!(output.diagnostics, vec![Diagnostic::warning("division by zero", Some(Loc::new(13, 1, 14)))]);
+        assert!(!output.has_errors());
+
+        let options = Options { opt_level: OptLevel::O1, ..Default::default() };
+        let output = Compiler::new(options).compile(&source);
+        assert_eq!(output.ir.unwrap().instructions, vec![PushI(4), PushI(0), DivI, PopI]);
+        assert_eq!(output.diagnostics.len(), 1);
+    }
+
+    #[test]
+    fn test_passes() {
+        let source = Source::inline("1 + 2; 3 * 4;");
+        let options = Options { opt_level: OptLevel::O2, ..Default::default() };
+        let output = Compiler::new(options).compile(&source);
+        assert_eq!(output.emit(Emit::Cfg), Some("fn main -> i32 {\nbb0:\n  ret 12\n}\n".to_string()));
+        assert_eq!(output.passes.timings.len(), 6);
+        assert!(output.asm.unwrap().to_string().contains("\tmov rax, 12\n"));
+
+        let options = Options {
+            passes: Some(vec!["dce".to_string()]),
+            print_after: vec!["dce".to_string()],
+            ..Default::default()
+        };
+        let output = Compiler::new(options).compile(&source);
+        assert_eq!(output.ir.unwrap().len(), 4);
+        assert_eq!(output.cfg, None);
+        assert_eq!(output.passes.dumps.len(), 1);
+
+        let options = Options { passes: Some(vec!["sccp".to_string()]), ..Default::default() };
+        let output = Compiler::new(options).compile(&source);
+        assert!(output.has_errors());
+        assert_eq!(output.asm, None);
+    }
+
+    #[test]
+    fn test_peephole() {
+        let source = Source::inline("3 * 4;");
+        let output = Compiler::default().compile(&source);
+        assert!(!output.asm.unwrap().to_string().contains("push"));
+
+        let options = Options { no_peephole: true, ..Default::default() };
+        let output = Compiler::new(options).compile(&source);
+        assert_eq!(output.asm.as_ref().map(|asm| asm.to_string()), Some(x86_64::compile(output.ir.as_ref().unwrap())));
+    }
+
+    #[test]
+    fn test_object() {
+        let source = Source::inline("return 7 % 3;");
+        for opt_level in [OptLevel::O0, OptLevel::O2] {
+            let output = Compiler::new(Options { opt_level, ..Default::default() }).compile(&source);
+            assert!(output.object.unwrap().starts_with(b"\x7fELF"));
+        }
+        let options = Options { target: Target::Aarch64, ..Default::default() };
+        assert_eq!(Compiler::new(options).compile(&source).object, None);
+    }
+
+    #[test]
+    fn test_globals() {
+        let source = Source::inline("int x = 2;\nx = x * 3;\nreturn y;");
+        let output = Compiler::default().compile(&source);
+        assert!(output.ast.is_some());
+        assert_eq!(output.diagnostics, vec![Diagnostic::error("use of undeclared identifier 'y'", Some(Loc::new(29, 3, 8)))]);
+
+        let source = Source::inline("int x = 2;\nx = x * 3;\nreturn x;");
+        let output = Compiler::new(Options { opt_level: OptLevel::O2, ..Default::default() }).compile(&source);
+        assert!(output.asm.unwrap().to_string().contains("x:\n.long 2\n"));
+        assert!(output.object.unwrap().starts_with(b"\x7fELF"));
+        assert!(output.c.unwrap().contains("int32_t x = 2;\n"));
+
+        for (target, triple) in [
+            (Target::Aarch64, "aarch64-unknown-linux-gnu"),
+            (Target::Riscv64, "riscv64-unknown-linux-gnu"),
+            (Target::Wasm32, "wasm32-unknown-unknown"),
+        ] {
+            let output = Compiler::new(Options { target, ..Default::default() }).compile(&source);
+            assert_eq!((output.asm, output.wasm), (None, None));
+            assert_eq!(output.diagnostics[0].message, format!("global variables are not supported for {}", triple));
+        }
+        let output = Compiler::new(Options { target: Target::Riscv64, ..Default::default() }).compile(&Source::inline("\"a\";"));
+        assert_eq!(output.diagnostics[0].message, "global variables are not supported for riscv64-unknown-linux-gnu");
+    }
+
+    #[test]
+    fn test_sema() {
+        let source = Source::inline("long big = 4;\nint *p = &big;");
+        let output = Compiler::default().compile(&source);
+        assert!(output.ast.is_some() && output.ir.is_none());
+        let d = &output.diagnostics[0];
+        assert_eq!(d.to_string(), "2:10: error: incompatible pointer types initializing 'int *' with an expression of type 'long *'");
+        assert_eq!(d.excerpts(&source), "int *p = &big;\n         ^~\n");
+
+        // Pointer arithmetic is in elements of the pointee.
+        let source = Source::inline("long a, b;\nstatic long *p = &b + 1, *q = &a;\nreturn p - q;");
+        let output = Compiler::default().compile(&source);
+        assert!(output.ir.unwrap().to_string().contains("static p 8 = &b+8\n"));
+    }
+
+    #[test]
+    fn test_relocation_model() {
+        let source = Source::inline("extern int e;\nint x;\nstatic int s;\nreturn e + x + s;");
+        let asm = |relocation_model| {
+            let options = Options { relocation_model, ..Default::default() };
+            Compiler::new(options).compile(&source).asm.unwrap().to_string()
+        };
+        let got = |asm: &str| ["e", "x", "s"].map(|name| asm.contains(&format!("[rip + {}@GOTPCREL]", name)));
+        assert_eq!(got(&asm(RelocationModel::Static)), [false, false, false]);
+        assert_eq!(got(&asm(RelocationModel::Pie)), [true, false, false]);
+        assert_eq!(got(&asm(RelocationModel::Pic)), [true, true, false]);
+        assert!(asm(RelocationModel::Pie).contains("\tlea rax, [rip + s]\n"));
+    }
+
+    #[test]
+    fn test_debug_info() {
+        let source = Source::new("a.c", "1;\nreturn 2;");
+        let options = Options { debug_info: true, ..Default::default() };
+        let output = Compiler::new(options).compile(&source);
+        let asm = output.asm.unwrap().to_string();
+        assert!(asm.contains(".file 1 \"a.c\"\n"));
+        assert!(asm.contains(".loc 1 2 8\n"));
+        assert!(asm.contains(".section .debug_info,"));
+        assert_eq!(output.object, None);
+
+        let options = Options { debug_info: true, opt_level: OptLevel::O2, ..Default::default() };
+        let asm = Compiler::new(options.clone()).compile(&source).asm.unwrap().to_string();
+        // DCE removed the first statement.
+        assert!(asm.contains("main:\n.loc 1 2 1\n\tpush rbp\n"));
+        assert_eq!(asm.matches(".loc").count(), 1);
+
+        // Optimized code keeps a line per statement.
+        let source = Source::new("a.c", "int x;\nx = 1;\nx = x + 2;\nreturn x;");
+        let asm = Compiler::new(options).compile(&source).asm.unwrap().to_string();
+        let locs: Vec<_> = asm.lines().filter(|l| l.starts_with(".loc")).collect();
+        assert_eq!(locs, [".loc 1 2 3", ".loc 1 3 3", ".loc 1 4 8"]);
+
+        // Globals are described as variables, but string literals aren't.
+        let source = Source::new("a.c", "int x;\nstatic char c;\nextern long y;\nchar *s = \"a\";\nreturn x;");
+        let options = Options { debug_info: true, ..Default::default() };
+        let asm = Compiler::new(options).compile(&source).asm.unwrap().to_string();
+        assert!(asm.contains(".uleb128 4\n.asciz \"x\"\n.long .Ldebug_type0-.Ldebug_info0\n.uleb128 9\n.byte 3\n.quad x\n"));
+        assert!(asm.contains(".uleb128 5\n.asciz \"c\"\n.long .Ldebug_type1-.Ldebug_info0\n"));
+        assert!(asm.contains(".asciz \"s\"\n.long .Ldebug_type2-.Ldebug_info0\n"));
+        assert!(!asm.contains(".asciz \"y\"\n"));
+        assert_eq!(asm.matches(".byte 3\n.quad ").count(), 3);
+    }
+
+    #[test]
+    fn test_target() {
+        assert_eq!("aarch64-linux-gnu".parse(), Ok(Target::Aarch64));
+        let source = Source::inline("7 % 3;");
+        let options = Options { target: Target::Aarch64, ..Default::default() };
+        let output = Compiler::new(options).compile(&source);
+        assert_eq!(output.asm.as_ref().map(|asm| asm.to_string()), aarch64::compile(output.ir.as_ref().unwrap()).ok());
+        assert!(output.asm.unwrap().to_string().contains("\tmsub "));
+
+        assert_eq!("wasm32-unknown-unknown".parse(), Ok(Target::Wasm32));
+        let options = Options { target: Target::Wasm32, ..Default::default() };
+        let output = Compiler::new(options).compile(&source);
+        assert!(output.asm.as_ref().unwrap().to_string().contains("i64.rem_s"));
+        assert!(output.wasm.as_ref().unwrap().starts_with(wasm::encode::MAGIC));
+        assert_eq!(output.emit(Emit::Wasm), None);
+    }
+
+    #[test]
+    fn test_unreachable_code() {
+        let source = Source::inline("return 1;\n2;");
+        let output = Compiler::default().compile(&source);
+        assert!(output.diagnostics.is_empty());
+        assert_eq!(output.ir.unwrap().len(), 4);
+
+        let options = Options { warn_unreachable_code: true, ..Default::default() };
+        let output = Compiler::new(options).compile(&source);
+        assert_eq!(output.diagnostics, vec![Diagnostic::warning(dce::UNREACHABLE_CODE, Some(Loc::new(10, 2, 1)))]);
+        assert_eq!(output.diagnostics[0].to_string(), "2:1: warning: code will never be executed [-Wunreachable-code]");
+    }
+
+    #[test]
+    fn test_compile_ir() {
+        let output = Compiler::default().compile_ir(&Source::inline("pushi 1\npopi\n"));
+        assert!(!output.has_errors());
+        assert!(output.tokens.is_empty());
+        assert_eq!(output.ast, None);
+        assert_eq!(output.ir, Some(vec![PushI(1), PopI].into()));
+        assert!(output.asm.is_some());
+
+        // Only optimized when asked, without the warnings of -O1 at -O0.
+        let source = Source::inline("pushi 1\npushi 0\ndivi\npopi\npushi 2\npopi\n");
+        let output = Compiler::default().compile_ir(&source);
+        assert_eq!(output.diagnostics, vec![]);
+        assert_eq!(output.ir.unwrap().len(), 6);
+        let options = Options { opt_level: OptLevel::O1, ..Default::default() };
+        let output = Compiler::new(options).compile_ir(&source);
+        assert_eq!(output.diagnostics, vec![Diagnostic::warning("division by zero", None)]);
+        assert_eq!(output.ir.unwrap().instructions, vec![PushI(2), PopI]);
+
+        let output = Compiler::default().compile_ir(&Source::inline("pushi"));
+        assert!(output.has_errors());
+        assert_eq!(output.diagnostics[0].loc, Some(Loc::head()));
+        assert_eq!(output.asm, None);
+    }
+
+    #[test]
+    fn test_defines() {
+        let mut options = Options::default();
+        options.define("N", "2 * 3");
+        let compiler = Compiler::new(options);
+        let tokens = compiler.tokenize(&Source::inline("1 + N;"));
+        assert_eq!(tokens.len(), 7);
+        assert_eq!(tokens[2], Token::new_int(2, Loc::new(4, 1, 5)));
+        assert_eq!(tokens[3], Token::new_symbol(Symbol::Asterisk, Loc::new(4, 1, 5)));
+        let output = compiler.compile(&Source::inline("N;"));
+        assert!(!output.has_errors());
+    }
+
+    #[test]
+    fn test_emit() {
+        let output = Compiler::default().compile(&Source::inline("1;"));
+        assert_eq!(
+            output.emit(Emit::Tokens),
+            Some("1:1\tInt(1)\n1:2\tSymbol(;)\n1:3\tEOF\n".to_string())
+        );
+        assert_eq!(
+            output.emit(Emit::Ast),
+            Some("Block\n  ExprStatement\n    IntLiteral 1 (int) <1:1>\n".to_string())
+        );
+        assert_eq!(output.emit(Emit::Ir), Some("pushi 1 i32 @1:1:0\npopi\n".to_string()));
+        assert_eq!(output.emit(Emit::Asm), output.asm.map(|asm| asm.to_string()));
+
+        let output = Compiler::default().compile(&Source::inline("1"));
+        assert!(output.emit(Emit::Tokens).is_some());
+        assert_eq!(output.emit(Emit::Ast), None);
+    }
+
+    #[test]
+    fn test_emit_selects_outputs() {
+        let source = Source::inline("1 + 2;");
+        let output = Compiler::default().compile(&source);
+        assert!(output.asm.is_some() && output.object.is_some() && output.llvm.is_some() && output.c.is_some());
+
+        let options = Options { emit: Some(vec![Emit::Llvm]), ..Default::default() };
+        let output = Compiler::new(options).compile(&source);
+        assert!(output.llvm.is_some());
+        assert_eq!((output.asm, output.object, output.c), (None, None, None));
+
+        // The object needs the assembly.
+        let options = Options { emit: Some(vec![Emit::Object]), ..Default::default() };
+        let output = Compiler::new(options).compile(&source);
+        assert!(output.asm.is_some() && output.object.is_some());
+        assert_eq!(output.llvm, None);
+
+        let options = Options { target: Target::Wasm32, emit: Some(vec![Emit::Asm]), ..Default::default() };
+        let output = Compiler::new(options).compile(&source);
+        assert!(output.asm.is_some());
+        assert_eq!(output.wasm, None);
+
+        let options = Options { emit: Some(vec![]), ..Default::default() };
+        let output = Compiler::new(options).compile(&source);
+        assert!(output.ir.is_some());
+        assert_eq!(output.asm, None);
+    }
+
+    #[test]
+    fn test_options_from_str() {
+        assert_eq!("ir".parse(), Ok(Emit::Ir));
+        assert_eq!("llvm".parse(), Ok(Emit::Llvm));
+        assert_eq!("c".parse(), Ok(Emit::C));
+        assert!("obj".parse::<Emit>().is_err());
+        assert_eq!("x86_64-linux-gnu".parse(), Ok(Target::X86_64));
+        assert!("mips".parse::<Target>().is_err());
+        assert_eq!("2".parse(), Ok(OptLevel::O2));
+        assert!("3".parse::<OptLevel>().is_err());
+    }
+}
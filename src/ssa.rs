@@ -0,0 +1,621 @@
+use crate::cfg::*;
+use std::collections::HashMap;
+use std::fmt;
+
+// Dominator tree by Cooper, Harvey and Kennedy, "A Simple, Fast Dominance Algorithm".
+pub struct DomTree {
+    // idom[entry] is the entry itself. None for unreachable blocks.
+    idom: Vec<Option<BlockId>>,
+    children: Vec<Vec<BlockId>>,
+}
+
+impl DomTree {
+    pub fn new(f: &Function) -> Self {
+        let rpo = f.reverse_postorder();
+        let mut index = vec![usize::MAX; f.blocks.len()];
+        for (i, b) in rpo.iter().enumerate() {
+            index[b.0] = i;
+        }
+        let preds = f.predecessors();
+        let mut idom: Vec<Option<BlockId>> = vec![None; f.blocks.len()];
+        if let Some(entry) = rpo.first() {
+            idom[entry.0] = Some(*entry);
+        }
+
+        let intersect = |idom: &Vec<Option<BlockId>>, mut a: BlockId, mut b: BlockId| {
+            while a != b {
+                while index[a.0] > index[b.0] {
+                    a = idom[a.0].unwrap();
+                }
+                while index[b.0] > index[a.0] {
+                    b = idom[b.0].unwrap();
+                }
+            }
+            a
+        };
+
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &b in rpo.iter().skip(1) {
+                let mut processed = preds[b.0].iter().filter(|p| idom[p.0].is_some());
+                let first = *processed.next().unwrap();
+                let new_idom = processed.fold(first, |d, p| intersect(&idom, *p, d));
+                if idom[b.0] != Some(new_idom) {
+                    idom[b.0] = Some(new_idom);
+                    changed = true;
+                }
+            }
+        }
+
+        let mut children = vec![vec![]; f.blocks.len()];
+        for &b in rpo.iter().skip(1) {
+            children[idom[b.0].unwrap().0].push(b);
+        }
+        Self { idom, children }
+    }
+
+    // Immediate dominator. None for the entry and unreachable blocks.
+    pub fn idom(&self, b: BlockId) -> Option<BlockId> {
+        self.idom[b.0].filter(|d| *d != b)
+    }
+
+    pub fn children(&self, b: BlockId) -> &[BlockId] {
+        &self.children[b.0]
+    }
+
+    pub fn is_reachable(&self, b: BlockId) -> bool {
+        self.idom[b.0].is_some()
+    }
+
+    pub fn dominates(&self, a: BlockId, b: BlockId) -> bool {
+        if !self.is_reachable(a) || !self.is_reachable(b) {
+            return false;
+        }
+        let mut b = b;
+        loop {
+            if a == b {
+                return true;
+            }
+            match self.idom(b) {
+                Some(d) => b = d,
+                None => return false,
+            }
+        }
+    }
+
+    // Dominance frontier of every block, indexed by BlockId.
+    pub fn frontiers(&self, f: &Function) -> Vec<Vec<BlockId>> {
+        let mut df: Vec<Vec<BlockId>> = vec![vec![]; f.blocks.len()];
+        for (b, preds) in f.predecessors().iter().enumerate() {
+            let b = BlockId(b);
+            let preds: Vec<&BlockId> = preds.iter().filter(|p| self.is_reachable(**p)).collect();
+            // The entry is also reached from the function start.
+            let join = preds.len() >= 2 || (b == BlockId(0) && !preds.is_empty());
+            if !join || !self.is_reachable(b) {
+                continue;
+            }
+            // The entry has no immediate dominator, so walk up to the root for it.
+            let stop = self.idom(b);
+            for &p in preds {
+                let mut runner = Some(p);
+                while runner.is_some() && runner != stop {
+                    let r = runner.unwrap();
+                    if !df[r.0].contains(&b) {
+                        df[r.0].push(b);
+                    }
+                    runner = self.idom(r);
+                }
+            }
+        }
+        df
+    }
+}
+
+// Promotes every slot to SSA registers. Slots can't have their address taken, so all
+// of them are promotable. Reading a slot before any store gives 0.
+pub fn mem2reg(f: &mut Function) {
+    if f.slot_count == 0 || f.blocks.is_empty() {
+        return;
+    }
+    split_entry(f);
+    let dom = DomTree::new(f);
+    let df = dom.frontiers(f);
+
+    // Insert phis at the iterated dominance frontier of the stores.
+    let mut phi_slots: HashMap<VReg, Slot> = HashMap::new();
+    for slot in (0..f.slot_count).map(Slot) {
+        let mut has_phi = vec![false; f.blocks.len()];
+        let mut work: Vec<BlockId> = f
+            .block_ids()
+            .filter(|b| f.block(*b).insts.iter().any(|i| matches!(i, Inst::Store { slot: s, .. } if *s == slot)))
+            .collect();
+        while let Some(b) = work.pop() {
+            for &d in &df[b.0] {
+                if has_phi[d.0] {
+                    continue;
+                }
+                has_phi[d.0] = true;
+                let dst = f.new_vreg();
+                f.block_mut(d).insts.insert(0, Inst::Phi { dst, args: vec![] });
+                phi_slots.insert(dst, slot);
+                work.push(d);
+            }
+        }
+    }
+
+    // Rename along the dominator tree. Loads become aliases of the reaching value.
+    let mut aliases: HashMap<VReg, Operand> = HashMap::new();
+    let mut stacks: Vec<Vec<Operand>> = vec![vec![]; f.slot_count];
+    rename(f, &dom, &phi_slots, BlockId(0), &mut stacks, &mut aliases);
+
+    // Unreachable blocks only keep the slots' default value.
+    let preds = f.predecessors();
+    for b in f.block_ids().collect::<Vec<_>>() {
+        let reachable = dom.is_reachable(b);
+        let block = f.block_mut(b);
+        for inst in block.insts.iter_mut() {
+            match inst {
+                Inst::Load { dst, .. } if !reachable => {
+                    *inst = Inst::Copy { dst: *dst, src: Operand::Imm(0) };
+                }
+                Inst::Phi { dst, args } if phi_slots.contains_key(dst) => {
+                    for p in &preds[b.0] {
+                        if !args.iter().any(|(a, _)| a == p) {
+                            args.push((*p, Operand::Imm(0)));
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        block.insts.retain(|i| !matches!(i, Inst::Store { .. }));
+    }
+
+    f.map_operands(|v| resolve(&aliases, v));
+    f.slot_count = 0;
+}
+
+fn resolve(aliases: &HashMap<VReg, Operand>, mut v: Operand) -> Operand {
+    while let Some(next) = v.reg().and_then(|r| aliases.get(&r)) {
+        v = *next;
+    }
+    v
+}
+
+fn rename(
+    f: &mut Function,
+    dom: &DomTree,
+    phi_slots: &HashMap<VReg, Slot>,
+    b: BlockId,
+    stacks: &mut Vec<Vec<Operand>>,
+    aliases: &mut HashMap<VReg, Operand>,
+) {
+    let mut pushed = vec![];
+    let current = |stacks: &Vec<Vec<Operand>>, slot: Slot| *stacks[slot.0].last().unwrap_or(&Operand::Imm(0));
+    let mut insts = std::mem::take(&mut f.block_mut(b).insts);
+    insts.retain(|inst| match inst {
+        Inst::Phi { dst, .. } => {
+            if let Some(slot) = phi_slots.get(dst) {
+                stacks[slot.0].push(Operand::Reg(*dst));
+                pushed.push(*slot);
+            }
+            true
+        }
+        Inst::Load { dst, slot } => {
+            aliases.insert(*dst, current(stacks, *slot));
+            false
+        }
+        Inst::Store { slot, src } => {
+            stacks[slot.0].push(*src);
+            pushed.push(*slot);
+            false
+        }
+        _ => true,
+    });
+    f.block_mut(b).insts = insts;
+
+    for succ in f.successors(b) {
+        for inst in f.block_mut(succ).insts.iter_mut() {
+            if let Inst::Phi { dst, args } = inst {
+                if let Some(slot) = phi_slots.get(dst) {
+                    if !args.iter().any(|(p, _)| *p == b) {
+                        args.push((b, current(stacks, *slot)));
+                    }
+                }
+            }
+        }
+    }
+
+    for &child in dom.children(b) {
+        rename(f, dom, phi_slots, child, stacks, aliases);
+    }
+    for slot in pushed {
+        stacks[slot.0].pop();
+    }
+}
+
+// Orders a parallel copy so that no source is overwritten before it is read.
+// Cycles are broken with registers from new_temp.
+pub fn sequentialize<F: FnMut() -> VReg>(copies: &[(VReg, Operand)], mut new_temp: F) -> Vec<(VReg, Operand)> {
+    let mut pending: Vec<(VReg, Operand)> = copies
+        .iter()
+        .filter(|(dst, src)| *src != Operand::Reg(*dst))
+        .copied()
+        .collect();
+    let mut result = vec![];
+    while !pending.is_empty() {
+        let ready = pending
+            .iter()
+            .position(|(dst, _)| !pending.iter().any(|(_, src)| *src == Operand::Reg(*dst)));
+        match ready {
+            Some(i) => result.push(pending.remove(i)),
+            None => {
+                // Every destination is still read by another copy, so they form cycles.
+                let (dst, _) = pending[0];
+                let temp = new_temp();
+                result.push((temp, Operand::Reg(dst)));
+                for (_, src) in pending.iter_mut() {
+                    if *src == Operand::Reg(dst) {
+                        *src = Operand::Reg(temp);
+                    }
+                }
+            }
+        }
+    }
+    result
+}
+
+// Replaces phis with copies at the end of the predecessors, splitting critical edges.
+pub fn destruct(f: &mut Function) {
+    let phi_blocks: Vec<BlockId> = f
+        .block_ids()
+        .filter(|b| f.block(*b).insts.first().is_some_and(Inst::is_phi))
+        .collect();
+
+    for b in phi_blocks {
+        for p in f.predecessors()[b.0].clone() {
+            let pred = if f.successors(p).len() > 1 {
+                split_edge(f, p, b)
+            } else {
+                p
+            };
+            let copies: Vec<(VReg, Operand)> = f
+                .block(b)
+                .insts
+                .iter()
+                .filter_map(|inst| match inst {
+                    Inst::Phi { dst, args } => args.iter().find(|(a, _)| *a == p).map(|(_, v)| (*dst, *v)),
+                    _ => None,
+                })
+                .collect();
+            let copies = sequentialize(&copies, || f.new_vreg());
+            let insts = copies.into_iter().map(|(dst, src)| Inst::Copy { dst, src });
+            f.block_mut(pred).insts.extend(insts);
+        }
+        f.block_mut(b).insts.retain(|i| !i.is_phi());
+    }
+}
+
+// Moves the entry block into a new block if it has predecessors, so bb0 only
+// jumps to it and never needs a phi.
+fn split_entry(f: &mut Function) {
+    if f.predecessors()[0].is_empty() {
+        return;
+    }
+    let entry = std::mem::replace(f.block_mut(BlockId(0)), Block {
+        insts: vec![],
+        term: Terminator::Jump(BlockId(0)),
+    });
+    let new = f.push_block(entry);
+    for b in f.block_ids().collect::<Vec<_>>() {
+        for succ in f.block_mut(b).term.successors_mut() {
+            if *succ == BlockId(0) {
+                *succ = new;
+            }
+        }
+    }
+}
+
+// Inserts an empty block on the edge from -> to and returns it.
+fn split_edge(f: &mut Function, from: BlockId, to: BlockId) -> BlockId {
+    let new = f.push_block(Block {
+        insts: vec![],
+        term: Terminator::Jump(to),
+    });
+    for succ in f.block_mut(from).term.successors_mut() {
+        if *succ == to {
+            *succ = new;
+        }
+    }
+    new
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum SsaError {
+    Verify(VerifyError),
+    MultipleDefinitions { vreg: VReg },
+    NotDominated { block: BlockId, vreg: VReg },
+    // The entry is also reached from the function start, which has no value.
+    PhiInEntry,
+}
+
+impl fmt::Display for SsaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SsaError::Verify(err) => write!(f, "{}", err),
+            SsaError::MultipleDefinitions { vreg } => write!(f, "{} is defined more than once", vreg),
+            SsaError::NotDominated { block, vreg } => {
+                write!(f, "{}: use of {} is not dominated by its definition", block, vreg)
+            }
+            SsaError::PhiInEntry => write!(f, "{}: phi in the entry block", BlockId(0)),
+        }
+    }
+}
+
+// Checks the CFG invariants plus single definitions, no phis in the entry
+// block, and that every use in a reachable block is dominated by its
+// definition.
+pub fn verify_ssa(f: &Function) -> Result<(), SsaError> {
+    f.verify().map_err(SsaError::Verify)?;
+    if f.blocks.first().is_some_and(|b| b.insts.iter().any(Inst::is_phi)) {
+        return Err(SsaError::PhiInEntry);
+    }
+
+    // (block, index in block) of each definition
+    let mut defs: Vec<Option<(BlockId, usize)>> = vec![None; f.vreg_count];
+    for b in f.block_ids() {
+        for (i, inst) in f.block(b).insts.iter().enumerate() {
+            if let Some(dst) = inst.def() {
+                if defs[dst.0].is_some() {
+                    return Err(SsaError::MultipleDefinitions { vreg: dst });
+                }
+                defs[dst.0] = Some((b, i));
+            }
+        }
+    }
+
+    let dom = DomTree::new(f);
+    // Whether the definition of vreg is available at index i of block b.
+    let available = |vreg: VReg, b: BlockId, i: usize| {
+        let (db, di) = defs[vreg.0].unwrap();
+        if db == b { di < i } else { dom.dominates(db, b) }
+    };
+    for b in f.block_ids().filter(|b| dom.is_reachable(*b)) {
+        let block = f.block(b);
+        for (i, inst) in block.insts.iter().enumerate() {
+            let unavailable = match inst {
+                // Phi arguments are used at the end of the predecessor.
+                Inst::Phi { args, .. } => args
+                    .iter()
+                    .filter(|(p, _)| dom.is_reachable(*p))
+                    .filter_map(|(p, v)| v.reg().map(|r| (*p, r)))
+                    .find(|(p, r)| !available(*r, *p, usize::MAX))
+                    .map(|(_, r)| r),
+                _ => inst.uses().into_iter().find(|r| !available(*r, b, i)),
+            };
+            if let Some(vreg) = unavailable {
+                return Err(SsaError::NotDominated { block: b, vreg });
+            }
+        }
+        if let Some(vreg) = block.term.uses().into_iter().find(|r| !available(*r, b, usize::MAX)) {
+            return Err(SsaError::NotDominated { block: b, vreg });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> Function {
+        s.parse().unwrap()
+    }
+
+    // bb0 -> bb1 (loop header) -> bb2 (body) -> bb1, bb1 -> bb3 (exit)
+    // Returns 2 * n by counting n down to zero.
+    const LOOP: &str = "
+        fn f {
+        bb0:
+          store $0, 3
+          store $1, 0
+          jmp bb1
+        bb1:
+          %0 = load $0
+          br %0, bb2, bb3
+        bb2:
+          %1 = load $0
+          %2 = sub %1, 1
+          store $0, %2
+          %3 = load $1
+          %4 = add %3, 2
+          store $1, %4
+          jmp bb1
+        bb3:
+          %5 = load $1
+          ret %5
+        }
+    ";
+
+    const DIAMOND: &str = "
+        fn f {
+        bb0:
+          br 1, bb1, bb2
+        bb1:
+          store $0, 10
+          jmp bb3
+        bb2:
+          store $0, 20
+          jmp bb3
+        bb3:
+          %0 = load $0
+          %1 = add %0, 1
+          ret %1
+        bb4:
+          %2 = load $0
+          jmp bb3
+        }
+    ";
+
+    #[test]
+    fn test_dominators() {
+        let f = parse(DIAMOND);
+        let dom = DomTree::new(&f);
+        assert_eq!(dom.idom(BlockId(0)), None);
+        assert_eq!(dom.idom(BlockId(1)), Some(BlockId(0)));
+        assert_eq!(dom.idom(BlockId(3)), Some(BlockId(0)));
+        assert_eq!(dom.idom(BlockId(4)), None);
+        assert!(dom.dominates(BlockId(0), BlockId(3)));
+        assert!(!dom.dominates(BlockId(1), BlockId(3)));
+        assert!(!dom.dominates(BlockId(4), BlockId(3)));
+        assert_eq!(dom.children(BlockId(0)), &[BlockId(2), BlockId(1), BlockId(3)]);
+
+        let f = parse(LOOP);
+        let dom = DomTree::new(&f);
+        assert_eq!(dom.idom(BlockId(2)), Some(BlockId(1)));
+        assert_eq!(dom.idom(BlockId(3)), Some(BlockId(1)));
+        assert!(dom.dominates(BlockId(1), BlockId(2)));
+    }
+
+    #[test]
+    fn test_frontiers() {
+        let f = parse(DIAMOND);
+        let df = DomTree::new(&f).frontiers(&f);
+        assert_eq!(df, vec![vec![], vec![BlockId(3)], vec![BlockId(3)], vec![], vec![]]);
+
+        let f = parse(LOOP);
+        let df = DomTree::new(&f).frontiers(&f);
+        assert_eq!(df, vec![vec![], vec![BlockId(1)], vec![BlockId(1)], vec![]]);
+    }
+
+    #[test]
+    fn test_mem2reg_diamond() {
+        let mut f = parse(DIAMOND);
+        mem2reg(&mut f);
+        assert_eq!(f.to_string(), "\
+            fn f {\n\
+            bb0:\n  \
+              br 1, bb1, bb2\n\
+            bb1:\n  \
+              jmp bb3\n\
+            bb2:\n  \
+              jmp bb3\n\
+            bb3:\n  \
+              %3 = phi [bb2, 20], [bb1, 10], [bb4, 0]\n  \
+              %1 = add %3, 1\n  \
+              ret %1\n\
+            bb4:\n  \
+              %2 = copy 0\n  \
+              jmp bb3\n\
+            }\n\
+        ");
+        assert_eq!(verify_ssa(&f), Ok(()));
+        assert_eq!(execute(&f, 100), Some(11));
+    }
+
+    #[test]
+    fn test_mem2reg_loop() {
+        let mut f = parse(LOOP);
+        assert_eq!(execute(&f, 100), Some(6));
+        mem2reg(&mut f);
+        assert_eq!(f.slot_count, 0);
+        assert!(f.blocks.iter().flat_map(|b| &b.insts).all(|i| !matches!(i, Inst::Load { .. } | Inst::Store { .. })));
+        assert_eq!(f.block(BlockId(1)).insts.iter().filter(|i| i.is_phi()).count(), 2);
+        assert_eq!(verify_ssa(&f), Ok(()));
+        assert_eq!(execute(&f, 100), Some(6));
+
+        destruct(&mut f);
+        assert!(f.blocks.iter().flat_map(|b| &b.insts).all(|i| !i.is_phi()));
+        assert_eq!(f.verify(), Ok(()));
+        assert_eq!(execute(&f, 100), Some(6));
+    }
+
+    #[test]
+    fn test_mem2reg_entry_loop() {
+        // bb0 is the loop header, counting $0 up to 5.
+        let mut f = parse("
+            fn f {
+            bb0:
+              %0 = load $0
+              %1 = add %0, 1
+              store $0, %1
+              %2 = sub %1, 5
+              br %2, bb0, bb1
+            bb1:
+              ret %1
+            }
+        ");
+        assert_eq!(execute(&f, 100), Some(5));
+        mem2reg(&mut f);
+        assert_eq!(f.block(BlockId(0)), &Block { insts: vec![], term: Terminator::Jump(BlockId(2)) });
+        assert_eq!(f.successors(BlockId(2)), vec![BlockId(2), BlockId(1)]);
+        assert_eq!(f.block(BlockId(2)).insts.iter().filter(|i| i.is_phi()).count(), 1);
+        assert_eq!(verify_ssa(&f), Ok(()));
+        assert_eq!(execute(&f, 100), Some(5));
+    }
+
+    #[test]
+    fn test_sequentialize() {
+        let (a, b, c) = (VReg(0), VReg(1), VReg(2));
+        let copies = sequentialize(&[(a, Operand::Reg(a)), (b, Operand::Imm(1)), (c, Operand::Reg(b))], || unreachable!());
+        assert_eq!(copies, vec![(c, Operand::Reg(b)), (b, Operand::Imm(1))]);
+
+        let mut temps = 10..;
+        let copies = sequentialize(&[(a, Operand::Reg(b)), (b, Operand::Reg(a))], || VReg(temps.next().unwrap()));
+        assert_eq!(copies, vec![(VReg(10), Operand::Reg(a)), (a, Operand::Reg(b)), (b, Operand::Reg(VReg(10)))]);
+    }
+
+    // The swap problem: x and y are exchanged in every iteration.
+    #[test]
+    fn test_destruct_swap() {
+        let mut f = parse("
+            fn f {
+            bb0:
+              jmp bb1
+            bb1:
+              %0 = phi [bb0, 1], [bb1, %1]
+              %1 = phi [bb0, 2], [bb1, %0]
+              %2 = phi [bb0, 2], [bb1, %3]
+              %3 = sub %2, 1
+              br %3, bb1, bb2
+            bb2:
+              %4 = mul %0, 10
+              %5 = add %4, %1
+              ret %5
+            }
+        ");
+        assert_eq!(verify_ssa(&f), Ok(()));
+        assert_eq!(execute(&f, 100), Some(21));
+        destruct(&mut f);
+        assert_eq!(f.verify(), Ok(()));
+        // bb1 -> bb1 is critical and gets split.
+        assert_eq!(f.blocks.len(), 4);
+        assert_eq!(f.successors(BlockId(1)), vec![BlockId(3), BlockId(2)]);
+        assert_eq!(execute(&f, 100), Some(21));
+    }
+
+    #[test]
+    fn test_verify_ssa() {
+        let f = parse("fn f {\nbb0:\n  %0 = copy 1\n  %0 = copy 2\n  ret %0\n}");
+        assert_eq!(verify_ssa(&f), Err(SsaError::MultipleDefinitions { vreg: VReg(0) }));
+
+        let f = parse("fn f {\nbb0:\n  %1 = add %0, 1\n  %0 = copy 2\n  ret %1\n}");
+        assert_eq!(verify_ssa(&f), Err(SsaError::NotDominated { block: BlockId(0), vreg: VReg(0) }));
+
+        let mut f = parse(DIAMOND);
+        mem2reg(&mut f);
+        let v = f.new_vreg();
+        f.block_mut(BlockId(2)).insts.push(Inst::Copy { dst: v, src: Operand::Reg(VReg(3)) });
+        assert_eq!(verify_ssa(&f), Err(SsaError::NotDominated { block: BlockId(2), vreg: VReg(3) }));
+
+        let f = parse("fn f {\nbb0:\n  %0 = phi [bb0, 1]\n  br 1, bb0, bb1\nbb1:\n  ret %0\n}");
+        assert_eq!(verify_ssa(&f), Err(SsaError::PhiInEntry));
+
+        let f = parse("fn f {\nbb0:\n  ret %0\n}");
+        assert_eq!(
+            verify_ssa(&f),
+            Err(SsaError::Verify(VerifyError::UndefinedVReg { block: BlockId(0), vreg: VReg(0) }))
+        );
+    }
+}
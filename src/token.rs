@@ -1,3 +1,5 @@
+use std::fmt;
+
 #[derive(PartialEq, Eq, Debug, Clone, Copy)]
 pub struct Loc {
     pub offset: usize,
@@ -19,28 +21,98 @@ impl Loc {
     }
 }
 
+impl fmt::Display for Loc {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.col)
+    }
+}
+
+// Source range of a node, from its first token to the start of its last one
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct Span {
+    pub start: Loc,
+    pub end: Loc,
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}", self.start, self.end)
+    }
+}
+
 #[test]
 fn test_loc_new() {
     let loc = Loc::new(1, 2, 3);
     assert_eq!(loc.offset, 1);
     assert_eq!(loc.line, 2);
     assert_eq!(loc.col, 3);
+    assert_eq!(loc.to_string(), "2:3");
 }
 
-
 #[derive(PartialEq, Eq, Debug, Clone)]
 pub enum Symbol {
     Plus,
     Minus,
     Asterisk,
     Slash,
+    Percent,
     Semicolon,
     Equal,
+    Ampersand,
+    LeftBrace,
+    RightBrace,
+    LeftParen,
+    RightParen,
+    Comma,
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Symbol::Plus => "+",
+            Symbol::Minus => "-",
+            Symbol::Asterisk => "*",
+            Symbol::Slash => "/",
+            Symbol::Percent => "%",
+            Symbol::Semicolon => ";",
+            Symbol::Equal => "=",
+            Symbol::Ampersand => "&",
+            Symbol::LeftBrace => "{",
+            Symbol::RightBrace => "}",
+            Symbol::LeftParen => "(",
+            Symbol::RightParen => ")",
+            Symbol::Comma => ",",
+        };
+        write!(f, "{}", s)
+    }
 }
 
 #[derive(PartialEq, Eq, Debug, Clone)]
 pub enum Keyword {
     Return,
+    Int,
+    Char,
+    Long,
+    Static,
+    Extern,
+    Typedef,
+    Sizeof,
+}
+
+impl fmt::Display for Keyword {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Keyword::Return => "return",
+            Keyword::Int => "int",
+            Keyword::Char => "char",
+            Keyword::Long => "long",
+            Keyword::Static => "static",
+            Keyword::Extern => "extern",
+            Keyword::Typedef => "typedef",
+            Keyword::Sizeof => "sizeof",
+        };
+        write!(f, "{}", s)
+    }
 }
 
 #[derive(PartialEq, Eq, Debug, Clone)]
@@ -59,12 +131,30 @@ pub enum TokenKind {
     Keyword(Keyword),
     Ident(String),
     Int(i64),
+    Str(String),
     EOF,
 }
 
+impl fmt::Display for TokenKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TokenKind::Error(TokenError::UnexpectedChar { actual, expected }) => {
+                write!(f, "Error(unexpected {:?}, expected {:?})", actual, expected)
+            }
+            TokenKind::Error(TokenError::Message(s)) => write!(f, "Error({})", s),
+            TokenKind::Symbol(sym) => write!(f, "Symbol({})", sym),
+            TokenKind::Keyword(key) => write!(f, "Keyword({})", key),
+            TokenKind::Ident(s) => write!(f, "Ident({})", s),
+            TokenKind::Int(i) => write!(f, "Int({})", i),
+            TokenKind::Str(s) => write!(f, "Str({:?})", s),
+            TokenKind::EOF => write!(f, "EOF"),
+        }
+    }
+}
+
 impl TokenKind {
     pub fn is_literal(&self) -> bool {
-        matches!(self, TokenKind::Int(_))
+        matches!(self, TokenKind::Int(_) | TokenKind::Str(_))
     }
 }
 
@@ -73,6 +163,7 @@ fn test_token_kind_is_literal() {
     assert!(!TokenKind::Error(TokenError::Message("".to_string())).is_literal());
     assert!(!TokenKind::Symbol(Symbol::Plus).is_literal());
     assert!(TokenKind::Int(0).is_literal());
+    assert!(TokenKind::Str("".to_string()).is_literal());
     assert!(!TokenKind::EOF.is_literal());
 }
 
@@ -103,6 +194,10 @@ impl Token {
         Self::new(TokenKind::Ident(s.into()), loc)
     }
 
+    pub fn new_str(s: impl Into<String>, loc: Loc) -> Self {
+        Self::new(TokenKind::Str(s.into()), loc)
+    }
+
     pub fn new_error(err: TokenError, loc: Loc) -> Self {
         Self::new(TokenKind::Error(err), loc)
     }
@@ -120,9 +215,25 @@ impl Token {
     }
 }
 
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}\t{}", self.loc, self.kind)
+    }
+}
+
 #[test]
 fn test_token_new() {
     let tok = Token::new(TokenKind::Int(1), Loc::head());
     assert_eq!(tok.kind, TokenKind::Int(1));
     assert_eq!(tok.loc, Loc::head());
 }
+
+#[test]
+fn test_token_display() {
+    assert_eq!(Token::new_int(1, Loc::head()).to_string(), "1:1\tInt(1)");
+    assert_eq!(Token::new_symbol(Symbol::Plus, Loc::new(2, 1, 3)).to_string(), "1:3\tSymbol(+)");
+    assert_eq!(Token::new_ident("_a", Loc::head()).to_string(), "1:1\tIdent(_a)");
+    assert_eq!(Token::new_str("a\n", Loc::head()).to_string(), "1:1\tStr(\"a\\n\")");
+    assert_eq!(Token::new_invalid_char('$', Loc::head()).to_string(), "1:1\tError(unexpected Some('$'), expected None)");
+    assert_eq!(Token::new_eof(Loc::head()).to_string(), "1:1\tEOF");
+}
@@ -1,50 +1,296 @@
-use crate::{IR, Visitor, AST};
-use crate::Instruction::*;
+use crate::{cfg, eval_const, ir, Diagnostic, EvalErrorKind, Global, Init, Loc, Node, StorageClass, Type, IR, Visitor, AST};
+use crate::Instruction::{self, *};
 use crate::nodes::*;
+use std::collections::HashMap;
+use std::convert::TryFrom;
 
-impl From<AST> for IR {
-    fn from(ast: AST) -> IR {
+impl TryFrom<AST> for IR {
+    type Error = Diagnostic;
+    fn try_from(ast: AST) -> Result<IR, Diagnostic> {
         IRTranslator::new().translate(&ast)
     }
 }
 
+// A variable in scope and the global holding it
+#[derive(Debug, Clone)]
+struct Variable {
+    global: String,
+    ty: Type,
+}
+
+#[derive(Default)]
 pub struct IRTranslator {
     buffer: IR,
+    // Locations and sizes of the types of the nodes being visited
+    locs: Vec<Option<Loc>>,
+    widths: Vec<Option<u8>>,
+    // One per enclosing block, innermost last. The outermost block is the
+    // file scope.
+    scopes: Vec<HashMap<String, Variable>>,
+    // Numbers making the globals of static locals and string literals unique
+    statics: usize,
+    strings: usize,
 }
 
 impl IRTranslator {
     pub fn new() -> Self {
-        Self {
-            buffer: Default::default(),
-        }
+        Self::default()
+    }
+
+    // The pushed value has the type of the innermost typed node. Addresses
+    // are 8 bytes.
+    fn emit(&mut self, inst: Instruction) {
+        let width = match inst {
+            PushG(_) | PopI | Ret => 8,
+            _ => self.widths.iter().rev().find_map(|w| *w).unwrap_or(8),
+        };
+        self.emit_typed(inst, width);
     }
 
-    pub fn translate(&mut self, ast: &AST) -> IR {
-        self.visit(ast).unwrap();
-        self.take()
+    fn emit_typed(&mut self, inst: Instruction, width: u8) {
+        let loc = self.loc();
+        self.buffer.push_typed(inst, loc, width);
+    }
+
+    fn loc(&self) -> Option<Loc> {
+        self.locs.iter().rev().find_map(|l| *l)
+    }
+
+    fn error(&self, message: impl Into<String>) -> Diagnostic {
+        Diagnostic::error(message, self.loc())
+    }
+
+    // Initializers aren't visited, so their nodes aren't in locs.
+    fn error_at(&self, ast: &AST, message: impl Into<String>) -> Diagnostic {
+        Diagnostic::error(message, ast.token.as_ref().map(|t| t.loc).or_else(|| self.loc()))
+    }
+
+    pub fn translate(&mut self, ast: &AST) -> Result<IR, Diagnostic> {
+        let result = self.visit(ast);
+        self.scopes.clear();
+        let ir = self.take();
+        result.map(|_| ir)
+    }
+
+    pub fn translate_function(&mut self, ast: &AST) -> Result<cfg::Function, Diagnostic> {
+        let ir = self.translate(ast)?;
+        cfg::Function::try_from(&ir).map_err(|err| Diagnostic::error(format!("invalid IR: {}", err), None))
     }
 
     pub fn take(&mut self) -> IR {
         std::mem::take(&mut self.buffer)
     }
+
+    fn lookup(&self, name: &str) -> Result<Variable, Diagnostic> {
+        self.scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(name))
+            .cloned()
+            .ok_or_else(|| self.error(format!("use of undeclared identifier '{}'", name)))
+    }
+
+    // A read-only global with the bytes of s and a terminating NUL
+    fn string(&mut self, s: &str) -> String {
+        let name = format!(".str.{}", self.strings);
+        self.strings += 1;
+        let mut bytes = s.as_bytes().to_vec();
+        bytes.push(0);
+        let mut global = Global::new(name.clone(), bytes.len(), Init::Bytes(bytes));
+        global.local = true;
+        global.readonly = true;
+        self.buffer.globals.push(global);
+        name
+    }
+
+    // The global and byte offset of an address constant: `&x`, a string
+    // literal, or either plus or minus an integer constant. Sema has already
+    // scaled the constant by the size of the pointee.
+    fn address(&mut self, ast: &AST) -> Result<Option<(String, i64)>, Diagnostic> {
+        Ok(match &ast.node {
+            Node::AddressOf(addr) => match &addr.operand.node {
+                Node::Identifier(ident) => Some((self.lookup(&ident.name)?.global, 0)),
+                _ => return Err(self.error_at(ast, "cannot take the address of an rvalue")),
+            },
+            Node::StringLiteral(s) => Some((self.string(&s.value), 0)),
+            Node::Cast(cast) if cast.ty.size() == 8 => self.address(&cast.operand)?,
+            Node::Addition(v) => match (self.address(&v.lhs)?, self.address(&v.rhs)?) {
+                (Some((g, offset)), None) => Some((g, offset + constant(&v.rhs)?)),
+                (None, Some((g, offset))) => Some((g, offset + constant(&v.lhs)?)),
+                _ => None,
+            },
+            Node::Subtraction(v) => match self.address(&v.lhs)? {
+                Some((g, offset)) => Some((g, offset - constant(&v.rhs)?)),
+                None => None,
+            },
+            _ => None,
+        })
+    }
+
+    // The initial value of a global of type ty
+    fn initializer(&mut self, init: &AST, ty: &Type) -> Result<Init, Diagnostic> {
+        match self.address(init)? {
+            Some((global, offset)) if ty.size() == 8 => Ok(Init::Addr(global, offset)),
+            Some(_) => Err(self.error_at(init, "initializer element is not constant")),
+            None => Ok(Init::Int(ir::truncate(constant(init)?, ty.size() as u8))),
+        }
+    }
+}
+
+// Evaluates part of an initializer, which must be constant.
+fn constant(ast: &AST) -> Result<i64, Diagnostic> {
+    eval_const(ast).map_err(|err| match err.kind {
+        EvalErrorKind::NotConstant => Diagnostic::error("initializer element is not constant", err.loc),
+        _ => err.into(),
+    })
 }
 
 macro_rules! fn_translate_binary {
     ($method:ident, $opcode:expr) => {
-        fn $method(&mut self, _: (), _: ()) -> Result<(), ()>{
-            self.buffer.push($opcode);
+        fn $method(&mut self, _: (), _: ()) -> Result<(), Diagnostic> {
+            self.emit($opcode);
             Ok(())
         }
     };
 }
 
-impl Visitor<(), ()> for IRTranslator {
-    fn visit_expr_statement_right(&mut self, _: ()) -> Result<(), ()> {
-        self.buffer.push(PopI);
+// Variables are globals: those at file scope under their own name, static
+// locals as name.N. Automatic variables need a stack frame, which the stack
+// machine doesn't have yet.
+impl Visitor<(), Diagnostic> for IRTranslator {
+    fn enter(&mut self, ast: &AST) {
+        self.locs.push(ast.token.as_ref().map(|t| t.loc));
+        self.widths.push(ast.ty.as_ref().map(|ty| ty.size() as u8));
+        if let Node::Block(_) = ast.node {
+            self.scopes.push(HashMap::new());
+        }
+    }
+    fn leave(&mut self, ast: &AST) {
+        self.locs.pop();
+        self.widths.pop();
+        if let Node::Block(_) = ast.node {
+            self.scopes.pop();
+        }
+    }
+
+    fn visit_expr_statement_right(&mut self, _: ()) -> Result<(), Diagnostic> {
+        self.emit(PopI);
+        Ok(())
+    }
+    fn visit_return(&mut self, _: ()) -> Result<(), Diagnostic> {
+        self.emit(Ret);
+        Ok(())
+    }
+    fn visit_int_literal(&mut self, i: &IntLiteral) -> Result<(), Diagnostic> {
+        self.emit(PushI(i.value));
+        Ok(())
+    }
+    // Values are sign-extended to 64 bits, which every integer conversion
+    // keeps. Narrowing only matters when stored, and stores truncate.
+    fn visit_implicit_cast(&mut self, _ast: &AST, _: ()) -> Result<(), Diagnostic> {
+        Ok(())
+    }
+
+    fn visit_declaration(&mut self, _ast: &AST, decl: &Declaration) -> Result<(), Diagnostic> {
+        if decl.storage == Some(StorageClass::Typedef) {
+            return Ok(());
+        }
+        let file_scope = self.scopes.len() <= 1;
+        let local = decl.storage == Some(StorageClass::Static);
+        // Only refers to a variable defined elsewhere, maybe later in this file.
+        let reference = decl.storage == Some(StorageClass::Extern) && decl.init.is_none();
+        if !file_scope && decl.storage.is_none() {
+            return Err(self.error("automatic variables are not supported yet"));
+        }
+        let global = if file_scope || !local {
+            decl.name.clone()
+        } else {
+            self.statics += 1;
+            format!("{}.{}", decl.name, self.statics - 1)
+        };
+        // The scope of a variable starts before its initializer.
+        let var = Variable { global: global.clone(), ty: decl.ty.clone() };
+        if self.scopes.is_empty() {
+            self.scopes.push(HashMap::new());
+        }
+        self.scopes.last_mut().unwrap().insert(decl.name.clone(), var);
+        if reference {
+            if self.buffer.global(&global).is_none() {
+                let mut g = Global::new(global, decl.ty.size(), Init::Zero);
+                g.external = true;
+                self.buffer.globals.push(g);
+            }
+            return Ok(());
+        }
+        let init = match &decl.init {
+            Some(init) => self.initializer(init, &decl.ty)?,
+            None => Init::Zero,
+        };
+        let mut g = Global::new(global, decl.ty.size(), init);
+        g.local = local;
+        // Sema has checked the declarations of a global against each other.
+        // A definition replaces an extern declaration or a tentative one.
+        match self.buffer.globals.iter_mut().find(|other| other.name == g.name) {
+            Some(other) if other.external || decl.init.is_some() => *other = g,
+            Some(_) => {}
+            None => self.buffer.globals.push(g),
+        }
         Ok(())
     }
-    fn visit_int_literal(&mut self, i: &IntLiteral) -> Result<(), ()> {
-        self.buffer.push(PushI(i.value));
+
+    fn visit_assignment(&mut self, _ast: &AST, assign: &Assignment) -> Result<(), Diagnostic> {
+        let var = match &assign.lhs.node {
+            Node::Identifier(ident) => self.lookup(&ident.name)?,
+            _ => return Err(self.error("expression is not assignable")),
+        };
+        self.emit(PushG(var.global));
+        self.visit(&assign.rhs)?;
+        self.emit(Store(var.ty.size() as u8));
+        Ok(())
+    }
+
+    fn visit_address_of(&mut self, _ast: &AST, addr: &AddressOf) -> Result<(), Diagnostic> {
+        match &addr.operand.node {
+            Node::Identifier(ident) => {
+                let var = self.lookup(&ident.name)?;
+                self.emit(PushG(var.global));
+                Ok(())
+            }
+            _ => Err(self.error("cannot take the address of an rvalue")),
+        }
+    }
+
+    // Without a truncating instruction, narrowing to n bits is
+    // ((v % 2^n + 3 * 2^(n-1)) % 2^n) - 2^(n-1), where the second remainder
+    // has a positive dividend. Only the difference is of the narrow type.
+    fn visit_cast(&mut self, cast: &Cast, _: ()) -> Result<(), Diagnostic> {
+        let (from, to) = (cast.operand.ty.as_ref().map_or(8, |ty| ty.size()), cast.ty.size());
+        if to < from {
+            let modulus = 1i64 << (8 * to);
+            for inst in [PushI(modulus), RemI, PushI(modulus / 2 * 3), AddI, PushI(modulus), RemI, PushI(modulus / 2)] {
+                self.emit_typed(inst, from as u8);
+            }
+            self.emit_typed(SubI, to as u8);
+        }
+        Ok(())
+    }
+
+    fn visit_size_of(&mut self, size_of: &SizeOf) -> Result<(), Diagnostic> {
+        let ty = size_of.ty.as_ref().ok_or_else(|| self.error("invalid application of 'sizeof'"))?;
+        self.emit(PushI(ty.size() as i64));
+        Ok(())
+    }
+
+    fn visit_string_literal(&mut self, _ast: &AST, s: &StringLiteral) -> Result<(), Diagnostic> {
+        let global = self.string(&s.value);
+        self.emit(PushG(global));
+        Ok(())
+    }
+
+    fn visit_identifier(&mut self, _ast: &AST, ident: &Identifier) -> Result<(), Diagnostic> {
+        let var = self.lookup(&ident.name)?;
+        self.emit(PushG(var.global));
+        self.emit(Load(var.ty.size() as u8));
         Ok(())
     }
 
@@ -52,13 +298,14 @@ impl Visitor<(), ()> for IRTranslator {
     fn_translate_binary!(visit_subtraction, SubI);
     fn_translate_binary!(visit_multiplication, MulI);
     fn_translate_binary!(visit_division, DivI);
+    fn_translate_binary!(visit_remainder, RemI);
 }
 
 #[cfg(test)]
 mod tests {
     use super::IRTranslator;
     use crate::Instruction::*;
-    use crate::{ast, head_tok, sym};
+    use crate::{ast, head_tok, sym, Compiler, Loc, Source};
 
     #[test]
     fn test_translate() {
@@ -73,11 +320,110 @@ mod tests {
             ),
             head_tok!(new, sym!(Minus)),
             ast!(new_literal, head_tok!(new_int, -3)),
-        ));
-        assert_eq!(ir, vec![PushI(1), PushI(2), AddI, PushI(-3), SubI].into());
+        )).unwrap();
+        assert_eq!(ir.instructions, vec![PushI(1), PushI(2), AddI, PushI(-3), SubI]);
+        assert_eq!(ir.locs, vec![Some(Loc::head()); 5]);
         assert_eq!(t.buffer, vec![].into());
     }
 
+    // The text of the IR without locations and types, which test_translate
+    // and test_widths cover
+    fn translate(code: &str) -> String {
+        let output = Compiler::default().compile(&Source::inline(code));
+        match IRTranslator::new().translate(output.ast.as_ref().unwrap()) {
+            Ok(mut ir) => {
+                ir.locs = vec![None; ir.len()];
+                ir.widths = vec![8; ir.len()];
+                ir.to_string()
+            }
+            Err(err) => err.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_variables() {
+        assert_eq!(translate("\
+            int x = 2 * 3;\n\
+            static char *s = \"hi\" + 1, c;\n\
+            int *p = &x - 1;\n\
+            { static int x; x = c; }\n\
+            return x;\n\
+        ").lines().take(6).collect::<Vec<_>>(), vec![
+            "global x 4 = 6",
+            "static const .str.0 3 = \"hi\\x00\"",
+            "static s 8 = &.str.0+1",
+            "static c 1",
+            "global p 8 = &x-4",
+            "static x.0 4",
+        ]);
+        assert!(translate("char c; { static int x; x = c; }").ends_with("pushg x.0\npushg c\nload 1\nstore 4\npopi\n"));
+        assert!(translate("char c = 300;").starts_with("global c 1 = 44\n"));
+        assert_eq!(translate("extern int x; long *p = &x; { extern int x; x = 1; }"), "\
+            extern x 4\n\
+            global p 8 = &x\n\
+            pushg x\n\
+            pushi 1\n\
+            store 4\n\
+            popi\n\
+        ");
+        assert!(translate("extern int x; int x = 2; extern int x;").starts_with("global x 4 = 2\n"));
+        assert_eq!(translate("int x; int x = 2; int x;"), "global x 4 = 2\n");
+        assert!(translate("typedef char *str; str s = (str)0, t = (char *)&s + 8;").ends_with("global s 8 = 0\nglobal t 8 = &s+8\n"));
+        assert_eq!(translate("long x; (char)x + sizeof x;"), "\
+            global x 8\n\
+            pushg x\n\
+            load 8\n\
+            pushi 256\n\
+            remi\n\
+            pushi 384\n\
+            addi\n\
+            pushi 256\n\
+            remi\n\
+            pushi 128\n\
+            subi\n\
+            pushi 8\n\
+            addi\n\
+            popi\n\
+        ");
+    }
+
+    #[test]
+    fn test_widths() {
+        let output = Compiler::default().compile(&Source::inline("char c; long l; l = c * 2; (char)l;"));
+        let ir = IRTranslator::new().translate(output.ast.as_ref().unwrap()).unwrap();
+        let widths: Vec<_> = ir.instructions.iter().zip(&ir.widths).map(|(inst, w)| (inst.clone(), *w)).collect();
+        assert_eq!(widths, vec![
+            (PushG("l".to_string()), 8),
+            (PushG("c".to_string()), 8),
+            (Load(1), 1),
+            (PushI(2), 4),
+            (MulI, 4),
+            (Store(8), 8),
+            (PopI, 8),
+            (PushG("l".to_string()), 8),
+            (Load(8), 8),
+            (PushI(256), 8),
+            (RemI, 8),
+            (PushI(384), 8),
+            (AddI, 8),
+            (PushI(256), 8),
+            (RemI, 8),
+            (PushI(128), 8),
+            (SubI, 1),
+            (PopI, 8),
+        ]);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(translate("x;"), "1:1: error: use of undeclared identifier 'x'");
+        assert_eq!(translate("{ int x; }"), "1:7: error: automatic variables are not supported yet");
+        assert_eq!(translate("int x; 1 = x;"), "1:10: error: expression is not assignable");
+        assert_eq!(translate("int x; int y = x;"), "1:16: error: initializer element is not constant");
+        assert_eq!(translate("int x; int y = &x;"), "1:16: error: initializer element is not constant");
+        assert_eq!(translate("int *p = &1;"), "1:10: error: cannot take the address of an rvalue");
+    }
+
     #[test]
     fn test_take() {
         let mut t = IRTranslator::new();
@@ -0,0 +1,84 @@
+use std::fmt;
+
+// C types of values and objects.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum Type {
+    Char,
+    Int,
+    Long,
+    Pointer(Box<Type>),
+}
+
+impl Type {
+    pub fn pointer_to(ty: Type) -> Self {
+        Type::Pointer(Box::new(ty))
+    }
+
+    // Size in bytes on LP64 targets.
+    pub fn size(&self) -> usize {
+        match self {
+            Type::Char => 1,
+            Type::Int => 4,
+            Type::Long | Type::Pointer(_) => 8,
+        }
+    }
+
+    pub fn pointee(&self) -> Option<&Type> {
+        match self {
+            Type::Pointer(ty) => Some(ty),
+            _ => None,
+        }
+    }
+
+    pub fn is_integer(&self) -> bool {
+        !self.is_pointer()
+    }
+
+    pub fn is_pointer(&self) -> bool {
+        matches!(self, Type::Pointer(_))
+    }
+
+    // Integer promotion: char operands of arithmetic are int.
+    pub fn promote(&self) -> Type {
+        match self {
+            Type::Char => Type::Int,
+            ty => ty.clone(),
+        }
+    }
+
+    // The type both integer operands of a binary operator convert to
+    pub fn common(a: &Type, b: &Type) -> Type {
+        let (a, b) = (a.promote(), b.promote());
+        if a.size() >= b.size() {
+            a
+        } else {
+            b
+        }
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Type::Char => write!(f, "char"),
+            Type::Int => write!(f, "int"),
+            Type::Long => write!(f, "long"),
+            Type::Pointer(ty) if ty.pointee().is_some() => write!(f, "{}*", ty),
+            Type::Pointer(ty) => write!(f, "{} *", ty),
+        }
+    }
+}
+
+#[test]
+fn test_type() {
+    let p = Type::pointer_to(Type::pointer_to(Type::Char));
+    assert_eq!(p.size(), 8);
+    assert_eq!(p.pointee(), Some(&Type::pointer_to(Type::Char)));
+    assert_eq!(Type::Int.pointee(), None);
+    assert_eq!([Type::Char.size(), Type::Int.size(), Type::Long.size()], [1, 4, 8]);
+    assert_eq!(p.to_string(), "char **");
+    assert_eq!(Type::Long.to_string(), "long");
+    assert_eq!(Type::common(&Type::Char, &Type::Char), Type::Int);
+    assert_eq!(Type::common(&Type::Int, &Type::Long), Type::Long);
+    assert!(p.is_pointer() && Type::Char.is_integer());
+}